@@ -0,0 +1,85 @@
+/*
+ * C API of the mysgm secure group messaging agent.
+ *
+ * Link against the mysgm shared library built by `cargo build --release`. An agent is an
+ * opaque handle owning its state file, which is saved after each call that may change it.
+ * Calls block until the delivery service answers, and a handle must be used from one thread
+ * at a time.
+ *
+ * Calls returning int return 0 on success and -1 on failure; calls returning pointers return
+ * NULL on failure. mysgm_last_error() then describes the failure. Strings returned are owned
+ * by the caller and freed with mysgm_string_free().
+ *
+ * The ABI only grows: functions are added, never changed, and MYSGM_ABI_VERSION is bumped
+ * with each addition.
+ */
+
+#ifndef MYSGM_H
+#define MYSGM_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define MYSGM_ABI_VERSION 1
+
+typedef struct MySgm MySgm;
+
+/* Version of the library's ABI, to check against MYSGM_ABI_VERSION. */
+uint32_t mysgm_abi_version(void);
+
+/* Message of the last failure on this thread, or NULL; valid until the next failure. */
+const char *mysgm_last_error(void);
+
+/*
+ * Creates a fresh identity with the label and saves its state to the path, encrypted if the
+ * passphrase is not NULL; existing state is not replaced. Delivery goes through the directory
+ * file_dir if not NULL, and through the OpenDHT proxy configured in the state otherwise.
+ */
+MySgm *mysgm_new(const char *state_path, const char *label, const char *passphrase,
+                 const char *file_dir);
+
+/* Loads the state saved at the path; the other arguments are as for mysgm_new(). */
+MySgm *mysgm_load(const char *state_path, const char *passphrase, const char *file_dir);
+
+/* Frees the handle; NULL is ignored. */
+void mysgm_free(MySgm *mysgm);
+
+/* Frees a string returned by this API; NULL is ignored. */
+void mysgm_string_free(char *string);
+
+/* Pid of the agent, which others add to groups. */
+char *mysgm_pid(MySgm *mysgm);
+
+/* Publishes a key package valid for the lifetime in seconds. */
+int mysgm_advertise(MySgm *mysgm, uint64_t lifetime);
+
+/*
+ * Processes key packages, welcomes, and commits, then receives the messages of every group.
+ * Returns a JSON object with the "key_packages" and "welcomes" processed, the number of
+ * "commits" merged, the "messages" received as {"gid", "sender", "message"} objects, and the
+ * "errors" of slots that could not be processed.
+ */
+char *mysgm_sync(MySgm *mysgm);
+
+/* Creates a group and returns its gid, which extends the name given. */
+char *mysgm_create_group(MySgm *mysgm, const char *name);
+
+/* Adds the agents with the count pids to the group, once a sync got their key packages. */
+int mysgm_add_to_group(MySgm *mysgm, const char *gid, const char *const *pids, size_t count);
+
+/* Sends the length bytes of the message to the group. */
+int mysgm_send(MySgm *mysgm, const char *gid, const uint8_t *message, size_t length);
+
+/* Writes length bytes of secret exported from the group's current epoch under the label. */
+int mysgm_export_secret(MySgm *mysgm, const char *gid, const char *label, uint8_t *out,
+                        size_t length);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MYSGM_H */
@@ -0,0 +1,66 @@
+use super::{error::MySgmError, timer::sleep};
+
+use async_trait::async_trait;
+use core::{fmt::Debug, time::Duration};
+
+/// Key-value delivery service used to exchange key packages, welcomes, commits, and messages.
+///
+/// Under WebAssembly the returned futures need not be `Send`, since `fetch` futures are bound
+/// to the browser's single thread.
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+pub trait DeliveryAdapter: Debug + Send + Sync {
+    /// Returns the value stored under the key, or `None` if the key is unset.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, MySgmError>;
+    /// Stores the value under the key, failing with [`MySgmError::KeyExists`] if the key is set.
+    async fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), MySgmError>;
+    /// Stores the value under the key again, to keep it from expiring.
+    ///
+    /// Services whose values do not expire keep the default, which does nothing.
+    async fn republish(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
+        let _ = (key, value);
+        Ok(())
+    }
+    /// Deletes the value stored under the key, returning whether the service deleted it.
+    ///
+    /// Services that cannot delete values keep the default, which returns `false`.
+    async fn delete(&self, key: &str) -> Result<bool, MySgmError> {
+        let _ = key;
+        Ok(false)
+    }
+    /// Waits until a value may have been stored under one of the keys, or the timeout passed.
+    ///
+    /// Services without push notifications just wait out the timeout, so that callers poll.
+    async fn watch(&self, keys: &[String], timeout: Duration) -> Result<(), MySgmError> {
+        let _ = keys;
+        sleep(timeout).await;
+        Ok(())
+    }
+    /// Returns the pid-addressed directory of the service, if it offers one.
+    ///
+    /// Without a directory, key packages and welcomes go to sequentially numbered slots.
+    fn directory(&self) -> Option<&dyn KeyPackageDirectory> {
+        None
+    }
+    /// Returns the service's current Unix time in seconds, for checking the local clock against.
+    ///
+    /// Services that do not tell, or share the local clock, keep the default, which returns
+    /// `None`.
+    async fn server_time(&self) -> Result<Option<u64>, MySgmError> {
+        Ok(None)
+    }
+}
+
+/// Directory holding key packages and welcomes addressed by pid.
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+pub trait KeyPackageDirectory: Send + Sync {
+    /// Publishes the key package of the pid, replacing any earlier one.
+    async fn put_key_package(&self, pid: &str, key_package: &[u8]) -> Result<(), MySgmError>;
+    /// Returns the key package of the pid, or `None` if it never advertised.
+    async fn get_key_package(&self, pid: &str) -> Result<Option<Vec<u8>>, MySgmError>;
+    /// Appends a welcome to the ones addressed to the pid.
+    async fn post_welcome(&self, pid: &str, welcome: &[u8]) -> Result<(), MySgmError>;
+    /// Returns all welcomes addressed to the pid, oldest first.
+    async fn get_welcomes(&self, pid: &str) -> Result<Vec<Vec<u8>>, MySgmError>;
+}
@@ -0,0 +1,4874 @@
+use super::{
+    adapter::DeliveryAdapter,
+    error::{ArtifactViolation, MySgmError},
+    keys::SignatureKeyPair,
+    lock::lock_ignoring_poison,
+    observer::AgentObserver,
+    padding::{PaddingPolicy, pad_message, unpad_message},
+    provider::{KeyPairSigner, MySgmProvider, ScratchProvider},
+    redact::Secret,
+    state::{
+        ArtifactPolicy, AuditEvent, EpochExporter, GroupExport, HistoryEntry, JoinRecord,
+        KeyPackageLogEntry, MySgmState, OpenMlsKeyValueStore, PendingAdd, PendingInvite,
+        Publication, PublishedRecord, QuarantinedRecord, RecordKind,
+    },
+};
+
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit,
+    aead::{Aead, Payload},
+};
+use futures::{
+    future::join_all,
+    stream::{StreamExt, iter as stream_iter},
+};
+use hex::encode as hex_encode;
+use openmls::{
+    ciphersuite::hash_ref::KeyPackageRef,
+    credentials::{
+        BasicCredential, Credential, CredentialType, CredentialWithKey, NewSignerBundle,
+    },
+    extensions::{
+        Extension, ExtensionType, Extensions, RequiredCapabilitiesExtension, UnknownExtension,
+    },
+    framing::{
+        MlsMessageBodyIn, MlsMessageBodyOut, MlsMessageIn, MlsMessageOut, ProcessedMessageContent,
+        Sender, errors::MessageDecryptionError,
+    },
+    group::{
+        GroupId, MIXED_CIPHERTEXT_WIRE_FORMAT_POLICY, MlsGroup, MlsGroupJoinConfig,
+        ProcessMessageError, ProcessedWelcome, QueuedProposal, StagedCommit, ValidationError,
+        WelcomeError,
+    },
+    key_packages::{KeyPackage, KeyPackageBundle, Lifetime, errors::KeyPackageVerifyError},
+    messages::{Welcome, proposals::Proposal},
+    prelude::{Capabilities, LeafNodeIndex, SenderRatchetConfiguration},
+    schedule::{ExternalPsk, PreSharedKeyId, Psk, errors::PskError},
+    treesync::{LeafNode, LeafNodeParameters, RatchetTreeIn},
+    versions::ProtocolVersion,
+};
+use openmls_rust_crypto::RustCrypto;
+use openmls_traits::{
+    OpenMlsProvider,
+    crypto::OpenMlsCrypto,
+    random::OpenMlsRand,
+    storage::StorageProvider,
+    types::{Ciphersuite, CryptoError, HashType, SignatureScheme},
+};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize as SerdeDeserialize, Serialize as SerdeSerialize};
+use serde_json::{from_slice as json_decode, to_vec as json_encode};
+use serde_with::{hex::Hex, serde_as};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::{Deref, DerefMut},
+    slice::from_ref,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tls_codec::{Deserialize, Serialize, Size, TlsDeserialize, TlsSerialize, TlsSize, VLBytes};
+use web_time::{SystemTime, UNIX_EPOCH};
+
+/// Minimum number of digest bytes a fingerprint must cover to verify an agent.
+pub const MIN_FINGERPRINT_LEN: usize = 10;
+
+/// Number of key packages held beyond which they are garbage collected after syncing.
+pub const KEY_PACKAGE_GC_THRESHOLD: usize = 256;
+
+/// Seconds after its first put that a record other than a key package is kept from expiring.
+pub const RECORD_RETENTION: u64 = 24 * 60 * 60;
+
+/// Seconds after which a published record is put again; OpenDHT drops values ten minutes after
+/// they were put by default.
+pub const REPUBLISH_INTERVAL: u64 = 5 * 60;
+
+/// Seconds the local clock may differ from the delivery service's before key package lifetimes
+/// are at risk.
+pub const MAX_CLOCK_SKEW: u64 = 5 * 60;
+
+/// Label of the MLS export that epoch exporters derive secrets from.
+const EPOCH_EXPORTER_LABEL: &str = "mysgm epoch exporter";
+
+/// Number of delivery service slots fetched concurrently while downloading.
+const FETCH_WINDOW: u64 = 8;
+
+/// Number of groups whose next records are fetched concurrently while syncing, by default.
+pub const DEFAULT_SYNC_CONCURRENCY: usize = 8;
+
+/// Label of the key derived from the epoch exporter for sealed messages.
+const SEAL_KEY_LABEL: &str = "mysgm seal key";
+
+/// Length of the epoch, leaf index, and counter prefixing a sealed message.
+const SEAL_HEADER_LEN: usize = 8 + 4 + 8;
+
+/// Largest number of key package slots fetched at once.
+const MAX_KEY_PACKAGE_WINDOW: u64 = 256;
+
+/// Largest value accepted from the delivery service, after any reassembly of chunks.
+pub const MAX_PAYLOAD_SIZE: usize = 4 * 1024 * 1024;
+
+/// Label of the external pre-shared keys binding the successor of a reinitialized group to the
+/// group's last epoch, and prefix of their ids.
+const REINIT_PSK_LABEL: &str = "mysgm reinit";
+
+fn namespaced_key(namespace: &str, key: String) -> String {
+    match namespace.is_empty() {
+        true => key,
+        false => format!("{namespace}_{key}"),
+    }
+}
+
+pub fn key_package_key(namespace: &str, index: u64) -> String {
+    format!("{}{index}", key_package_prefix(namespace))
+}
+
+fn key_package_prefix(namespace: &str) -> String {
+    namespaced_key(namespace, "kp".to_string())
+}
+
+/// Key of the `index`th welcome addressed to the key package with ref `kp_ref`.
+pub fn welcome_message_key(namespace: &str, kp_ref: &KeyPackageRef, index: u64) -> String {
+    format!("{}{index}", welcome_message_prefix(namespace, kp_ref))
+}
+
+fn welcome_message_prefix(namespace: &str, kp_ref: &KeyPackageRef) -> String {
+    namespaced_key(namespace, format!("wm{}_", hex_encode(kp_ref.as_slice())))
+}
+
+pub fn commit_key(group: &MlsGroup, provider: &MySgmProvider) -> Result<String, MySgmError> {
+    Ok(format!(
+        "cm{}",
+        hex_encode(group.export_secret(provider, "post_commit", &[], 32)?)
+    ))
+}
+
+pub fn application_message_key(
+    group: &MlsGroup,
+    provider: &MySgmProvider,
+    index: u64,
+) -> Result<String, MySgmError> {
+    Ok(format!(
+        "{}{index}",
+        application_message_prefix(group, provider)?
+    ))
+}
+
+fn application_message_prefix(
+    group: &MlsGroup,
+    provider: &MySgmProvider,
+) -> Result<String, MySgmError> {
+    Ok(format!(
+        "am{}",
+        hex_encode(group.export_secret(provider, "application_message", &[], 32)?)
+    ))
+}
+
+pub fn proposal_key(
+    group: &MlsGroup,
+    provider: &MySgmProvider,
+    index: u64,
+) -> Result<String, MySgmError> {
+    Ok(format!(
+        "pr{}{index}",
+        hex_encode(group.export_secret(provider, "proposal", &[], 32)?)
+    ))
+}
+
+/// Key of the link of the group's commit chain holding the commit that ended the epoch.
+pub fn commit_chain_key(gid: &str, epoch: u64) -> String {
+    format!("cm_{}_{epoch}", hex_encode(gid))
+}
+
+/// Key of the ratchet tree posted for joiners of the group at the epoch.
+pub fn ratchet_tree_key(gid: &str, epoch: u64) -> String {
+    format!("rt{}_{epoch}", hex_encode(gid))
+}
+
+/// Group context extension listing the pids allowed to add and remove members.
+///
+/// Groups without it, created before admins existed, place no restrictions on members.
+pub const ADMINS_EXTENSION_TYPE: u16 = 0xff00;
+
+/// Group context extension holding the group's [`GroupMetadata`].
+pub const METADATA_EXTENSION_TYPE: u16 = 0xff01;
+
+/// Group context extension holding the group's [`GroupPolicy`].
+pub const POLICY_EXTENSION_TYPE: u16 = 0xff02;
+
+/// Id of the pre-shared key binding the successor of the reinitialized group to it.
+fn reinit_psk_id(gid: &str) -> Vec<u8> {
+    format!("{REINIT_PSK_LABEL} {gid}").into_bytes()
+}
+
+pub fn group_info_key(gid: &str, index: u64) -> String {
+    format!("gi{}_{index}", hex_encode(gid))
+}
+
+/// The pids in the group's admin list, or `None` if the group has no admin list.
+fn group_admins(group: &MlsGroup) -> Result<Option<Vec<String>>, MySgmError> {
+    match group.extensions().unknown(ADMINS_EXTENSION_TYPE) {
+        Some(UnknownExtension(bytes)) => Ok(Some(json_decode(bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// The group's metadata, empty if none was ever set.
+fn group_metadata(group: &MlsGroup) -> Result<GroupMetadata, MySgmError> {
+    match group.extensions().unknown(METADATA_EXTENSION_TYPE) {
+        Some(UnknownExtension(bytes)) => Ok(json_decode(bytes)?),
+        None => Ok(GroupMetadata::default()),
+    }
+}
+
+/// The group's policy, allowing anyone if none was ever set.
+fn group_policy(group: &MlsGroup) -> Result<GroupPolicy, MySgmError> {
+    match group.extensions().unknown(POLICY_EXTENSION_TYPE) {
+        Some(UnknownExtension(bytes)) => Ok(json_decode(bytes)?),
+        None => Ok(GroupPolicy::default()),
+    }
+}
+
+/// The pid in a basic credential.
+fn credential_pid(credential: &Credential) -> Result<String, MySgmError> {
+    let cred = BasicCredential::try_from(credential.clone())?;
+    Ok(String::from_utf8_lossy(cred.identity()).to_string())
+}
+
+/// Audit events for a commit of the group by the sender, staged but not yet merged.
+fn commit_audit_events(
+    group: &MlsGroup,
+    staged_commit: &StagedCommit,
+    sender: &Sender,
+) -> Result<Vec<AuditEvent>, MySgmError> {
+    let member_at = |index| group.members().find(|member| member.index == index);
+    let pid_at = |index| {
+        member_at(index)
+            .map(|member| credential_pid(&member.credential))
+            .transpose()
+    };
+    let (timestamp, epoch) = (unix_time(), group.epoch().as_u64() + 1);
+    let event = |kind: &str, actor: Option<String>, subject: Option<String>| AuditEvent {
+        seq: 0,
+        timestamp,
+        epoch,
+        kind: kind.to_string(),
+        actor,
+        subject,
+    };
+    // a new leaf of a member either rotates its keys or replaces its credential
+    let leaf_change = |index, leaf: &LeafNode| -> Result<AuditEvent, MySgmError> {
+        let kind = match member_at(index) {
+            Some(member)
+                if member.signature_key == leaf.signature_key().as_slice()
+                    && member.credential == *leaf.credential() =>
+            {
+                "key_rotation"
+            }
+            _ => "credential_change",
+        };
+        let pid = pid_at(index)?;
+        Ok(event(kind, pid.clone(), pid))
+    };
+    let mut events = Vec::new();
+    let actor = match sender {
+        Sender::Member(index) => {
+            let actor = pid_at(*index)?;
+            events.push(event("commit", actor.clone(), None));
+            actor
+        }
+        Sender::NewMemberCommit => {
+            let joiner = staged_commit
+                .update_path_leaf_node()
+                .map(|leaf| credential_pid(leaf.credential()))
+                .transpose()?;
+            events.push(event("join", joiner.clone(), joiner.clone()));
+            joiner
+        }
+        _ => None,
+    };
+    for queued in staged_commit.queued_proposals() {
+        let proposer = match queued.sender() {
+            Sender::Member(index) => pid_at(*index)?,
+            _ => actor.clone(),
+        };
+        let (kind, subject) = match queued.proposal() {
+            Proposal::Add(add) => (
+                "add",
+                Some(credential_pid(add.key_package().leaf_node().credential())?),
+            ),
+            Proposal::Remove(remove) => ("remove", pid_at(remove.removed())?),
+            Proposal::SelfRemove => ("remove", proposer.clone()),
+            Proposal::Update(update) => match queued.sender() {
+                Sender::Member(index) => {
+                    events.push(leaf_change(*index, update.leaf_node())?);
+                    continue;
+                }
+                _ => continue,
+            },
+            Proposal::GroupContextExtensions(_) => ("group_context", None),
+            Proposal::PreSharedKey(_) => ("psk", None),
+            _ => continue,
+        };
+        events.push(event(kind, proposer, subject));
+    }
+    if let (Sender::Member(index), Some(leaf)) = (sender, staged_commit.update_path_leaf_node()) {
+        events.push(leaf_change(*index, leaf)?);
+    }
+    Ok(events)
+}
+
+/// Whether the proposal is a member proposing its own removal, as when leaving.
+fn is_self_removal(proposal: &QueuedProposal) -> bool {
+    match (proposal.proposal(), proposal.sender()) {
+        (Proposal::Remove(remove), Sender::Member(index)) => remove.removed() == *index,
+        (Proposal::SelfRemove, _) => true,
+        _ => false,
+    }
+}
+
+/// Whether the member at the leaf index is the one to commit the group's queued self-removals:
+/// the remaining admin with the lowest leaf index, or, if no admin remains, the remaining member
+/// with the lowest leaf index, so that members do not race each other for the commit slot.
+fn commits_self_removals(group: &MlsGroup, index: LeafNodeIndex) -> Result<bool, MySgmError> {
+    let leaving: Vec<LeafNodeIndex> = group
+        .pending_proposals()
+        .filter_map(|proposal| match (proposal.proposal(), proposal.sender()) {
+            (Proposal::Remove(remove), _) => Some(remove.removed()),
+            (Proposal::SelfRemove, Sender::Member(sender)) => Some(*sender),
+            _ => None,
+        })
+        .collect();
+    let admins = group_admins(group)?;
+    let mut remaining = Vec::new();
+    for member in group.members() {
+        if !leaving.contains(&member.index) {
+            let admin = admins.as_ref().is_none_or(|admins| {
+                credential_pid(&member.credential).is_ok_and(|pid| admins.contains(&pid))
+            });
+            remaining.push((member.index, admin));
+        }
+    }
+    let committer = remaining
+        .iter()
+        .find(|(_, admin)| *admin)
+        .or(remaining.first())
+        .map(|(committer, _)| *committer);
+    Ok(committer == Some(index))
+}
+
+/// Logs a record moving over the delivery service as a structured `mysgm::wire` event.
+///
+/// Only the record's length is logged, with its content in hex if secrets are logged.
+fn wire_event(event: &str, kind: &str, key: &str, bytes: &[u8]) {
+    tracing::debug!(
+        target: "mysgm::wire",
+        event,
+        kind,
+        key,
+        length = bytes.len(),
+        content = ?Secret(bytes)
+    );
+}
+
+/// Decodes a TLS-serialized value fetched from under the key, rejecting values over
+/// [`MAX_PAYLOAD_SIZE`] and values with trailing bytes as [`MySgmError::MalformedPayload`].
+fn decode_untrusted<T: Deserialize>(key: &str, bytes: &[u8]) -> Result<T, MySgmError> {
+    let malformed = |detail: String| MySgmError::MalformedPayload(key.to_string(), detail);
+    if bytes.len() > MAX_PAYLOAD_SIZE {
+        return Err(malformed(format!(
+            "{} bytes exceed the limit of {MAX_PAYLOAD_SIZE}",
+            bytes.len()
+        )));
+    }
+    T::tls_deserialize_exact(bytes).map_err(|e| malformed(e.to_string()))
+}
+
+/// Verifies a signed record of the delivery service, returning the signature key and the value.
+fn open_record(
+    crypto: &RustCrypto,
+    key: &str,
+    bytes: Vec<u8>,
+) -> Result<(Vec<u8>, Vec<u8>), MySgmError> {
+    let invalid = || MySgmError::InvalidRecord(key.to_string());
+    let record: SignedRecord = decode_untrusted(key, &bytes)?;
+    crypto
+        .verify_signature(
+            record.signature_scheme,
+            &record_content(key, record.value.as_slice())?,
+            record.signature_key.as_slice(),
+            record.signature.as_slice(),
+        )
+        .map_err(|_| invalid())?;
+    Ok((record.signature_key.into(), record.value.into()))
+}
+
+/// Validates a serialized key package in one of the supported ciphersuites, and accepted by
+/// the policy; `key` names its source in errors.
+///
+/// A key package from a signed record must be signed with its own signature key.
+fn validate_key_package(
+    crypto: &RustCrypto,
+    mls_version: ProtocolVersion,
+    supported_ciphersuites: &[Ciphersuite],
+    policy: &ArtifactPolicy,
+    kp_bytes: Vec<u8>,
+    key: String,
+    signer: Option<&[u8]>,
+) -> Result<KeyPackage, MySgmError> {
+    wire_event("received", "key_package", &key, &kp_bytes);
+    let MlsMessageBodyIn::KeyPackage(kp_in) =
+        decode_untrusted::<MlsMessageIn>(&key, &kp_bytes)?.extract()
+    else {
+        return Err(MySgmError::UnexpectedMessage("KeyPackage"));
+    };
+    let kp = match kp_in.validate(crypto, mls_version) {
+        Ok(kp) => kp,
+        Err(KeyPackageVerifyError::InvalidLifetime) => {
+            return Err(MySgmError::KeyPackageExpired(key));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    tracing::debug!("Processed key package: {} bytes", kp_bytes.len());
+    if signer.is_some_and(|signer| signer != kp.leaf_node().signature_key().as_slice()) {
+        return Err(MySgmError::InvalidRecord(key));
+    }
+    if !supported_ciphersuites.contains(&kp.ciphersuite()) {
+        return Err(MySgmError::UnsupportedCiphersuite(kp.ciphersuite()));
+    }
+    // validating checked the key package's protocol version to be the own one
+    let credential_type = u16::from(kp.leaf_node().credential().credential_type());
+    policy
+        .check(kp.ciphersuite(), mls_version, [credential_type])
+        .map_err(|violation| MySgmError::ArtifactRejected(key, violation))?;
+    Ok(kp)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Published group info, together with the key its epoch's commit has to be posted under.
+///
+/// Joiners cannot derive the commit key themselves, since it comes from the epoch's exporter.
+#[serde_as]
+#[derive(SerdeSerialize, SerdeDeserialize)]
+struct GroupInfoRecord {
+    commit_key: String,
+    #[serde_as(as = "Hex")]
+    group_info: Vec<u8>,
+}
+
+/// Welcome and ratchet tree handed to a new member out of band.
+#[serde_as]
+#[derive(SerdeSerialize, SerdeDeserialize)]
+struct Invitation {
+    #[serde_as(as = "Hex")]
+    welcome: Vec<u8>,
+    #[serde_as(as = "Hex")]
+    ratchet_tree: Vec<u8>,
+}
+
+/// What a welcome staged against a copy of the storage tells about the group it joins.
+struct CheckedWelcome {
+    gid: String,
+    epoch: u64,
+    inviter: String,
+    external_tree: bool,
+    ratchet_tree: Option<RatchetTreeIn>,
+}
+
+/// Identity and groups of an agent, signed by it, for a new device to link to.
+#[serde_as]
+#[derive(SerdeSerialize, SerdeDeserialize)]
+struct DeviceLink {
+    pid: String,
+    ciphersuite: Ciphersuite,
+    #[serde_as(as = "Hex")]
+    signature_key: Vec<u8>,
+    gids: Vec<String>,
+    #[serde_as(as = "Hex")]
+    signature: Vec<u8>,
+}
+
+/// A value as stored on the delivery service, signed by its publisher over the key it is stored
+/// under and the value, so that values squatting a slot or copied from another one are detected.
+#[derive(TlsSerialize, TlsDeserialize, TlsSize)]
+struct SignedRecord {
+    signature_scheme: SignatureScheme,
+    signature_key: VLBytes,
+    signature: VLBytes,
+    value: VLBytes,
+}
+
+/// A commit as published in the group's commit chain, so that members can walk the commits in
+/// order by epoch.
+#[derive(TlsSerialize, TlsDeserialize, TlsSize)]
+struct CommitLink {
+    /// Hex SHA-256 digest of the commit merged before it, empty if none is known.
+    previous: VLBytes,
+    commit: VLBytes,
+}
+
+/// Bytes covered by the signature of a record stored under the key.
+fn record_content(key: &str, value: &[u8]) -> Result<Vec<u8>, MySgmError> {
+    let mut content = b"mysgm record".to_vec();
+    content.extend(VLBytes::new(key.as_bytes().to_vec()).tls_serialize_detached()?);
+    content.extend(value);
+    Ok(content)
+}
+
+impl DeviceLink {
+    /// Bytes covered by the signature.
+    fn signed_content(&self) -> Result<Vec<u8>, MySgmError> {
+        let mut content = b"mysgm device link".to_vec();
+        content.extend(json_encode(&(
+            &self.pid,
+            self.ciphersuite,
+            &self.signature_key,
+            &self.gids,
+        ))?);
+        Ok(content)
+    }
+}
+
+/// Sender pid and plaintext of a received application message.
+pub type ReceivedMessage = (String, Vec<u8>);
+
+/// A member of a group as seen in the local ratchet tree.
+#[derive(Clone, Debug)]
+pub struct GroupMember {
+    pub index: LeafNodeIndex,
+    pub pid: String,
+    pub signature_key: Vec<u8>,
+    pub admin: bool,
+}
+
+/// A proposal queued for the group's next commit.
+#[derive(Clone, Debug)]
+pub struct PendingProposal {
+    /// Kind of the proposal, e.g. `add` or `remove`.
+    pub kind: String,
+    /// pid of the member who proposed it, unless it came from outside the group.
+    pub sender: Option<String>,
+    /// pid of the agent added or the member removed.
+    pub subject: Option<String>,
+}
+
+/// What a [`MySgmAgent::sync`] downloaded and processed.
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// pids of the key packages stored.
+    pub key_packages: Vec<String>,
+    /// gids of the groups joined through welcomes.
+    pub welcomes: Vec<String>,
+    /// Number of commits merged, including own commits of pending proposals.
+    pub commits: usize,
+    /// Number of application messages decrypted.
+    pub messages: usize,
+    /// Quarantined pids added back to groups, with the gid.
+    pub reinvited: Vec<(String, String)>,
+    /// gids of the groups whose pending add was completed.
+    pub adds: Vec<String>,
+    /// gids of the groups the current signature key was committed to, after a rotation.
+    pub rotated: Vec<String>,
+    /// Slots, or gids for messages, that could not be processed, with the error.
+    pub errors: Vec<(String, MySgmError)>,
+}
+
+impl SyncReport {
+    /// Lists the error for the slot, unless it is transient and the sync should be retried.
+    fn record(&mut self, slot: String, e: MySgmError) -> Result<(), MySgmError> {
+        if e.is_transient() {
+            return Err(e);
+        }
+        self.errors.push((slot, e));
+        Ok(())
+    }
+}
+
+/// Outcome of [`MySgmAgent::add_available_to_group`] for each pid.
+#[derive(Debug, Default)]
+pub struct AddReport {
+    /// pids added to the group.
+    pub added: Vec<String>,
+    /// pids left out for lack of a valid key package that meets the group's policy, with the
+    /// error.
+    pub skipped: Vec<(String, MySgmError)>,
+    /// pids left out because their key package could not be looked up, with the error.
+    pub failed: Vec<(String, MySgmError)>,
+}
+
+/// Outcome of one check of [`MySgmAgent::diagnose`].
+#[derive(Clone, Debug)]
+pub struct Diagnosis {
+    /// What was checked.
+    pub check: String,
+    pub passed: bool,
+    /// What the check found, or why it failed.
+    pub detail: String,
+}
+
+impl Diagnosis {
+    pub fn new(check: impl Into<String>, result: Result<String, MySgmError>) -> Self {
+        let (passed, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(e) => (false, e.to_string()),
+        };
+        Self {
+            check: check.into(),
+            passed,
+            detail,
+        }
+    }
+}
+
+/// Snapshot of a group's local state, for debugging divergence between members.
+#[derive(Clone, Debug)]
+pub struct GroupStatus {
+    pub epoch: u64,
+    pub ciphersuite: Ciphersuite,
+    pub own_leaf_index: LeafNodeIndex,
+    pub member_count: usize,
+    pub pending_proposals: usize,
+    pub pending_commit: bool,
+    pub metadata: GroupMetadata,
+    pub policy: GroupPolicy,
+    /// Padding of the messages this agent sends to the group.
+    pub padding: Option<PaddingPolicy>,
+    /// How this agent joined the group, if through a welcome.
+    pub joined: Option<JoinRecord>,
+    /// Whether syncs skip the group.
+    pub archived: bool,
+    /// Whether this agent proposed to leave the group and waits for its removal to be committed.
+    pub leaving: bool,
+    /// Past epochs whose message secrets are retained.
+    pub retention: EpochRetention,
+}
+
+/// The past epochs of a group whose message secrets this agent retains, to decrypt messages
+/// sent before a commit, at the expense of forward secrecy for those epochs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EpochRetention {
+    /// Most past epochs retained, after which the oldest is dropped with each commit.
+    pub max_past_epochs: usize,
+    /// Epochs retained, oldest first.
+    pub past_epochs: Vec<u64>,
+}
+
+/// A key prefix of the delivery service that syncs scan slot by slot, named `kp`, `wm`,
+/// `cm_<gid>`, or `msg_<gid>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScanPrefix {
+    /// Key packages published in the current namespace.
+    KeyPackages,
+    /// Welcomes addressed to own key packages, or to this agent in the directory.
+    Welcomes,
+    /// Commits to the group, whose cursor is its epoch.
+    Commits(String),
+    /// Application messages sent to the group in its current epoch.
+    Messages(String),
+}
+
+impl core::fmt::Display for ScanPrefix {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ScanPrefix::KeyPackages => write!(f, "kp"),
+            ScanPrefix::Welcomes => write!(f, "wm"),
+            ScanPrefix::Commits(gid) => write!(f, "cm_{gid}"),
+            ScanPrefix::Messages(gid) => write!(f, "msg_{gid}"),
+        }
+    }
+}
+
+impl core::str::FromStr for ScanPrefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kp" => Ok(ScanPrefix::KeyPackages),
+            "wm" => Ok(ScanPrefix::Welcomes),
+            _ => match (s.strip_prefix("cm_"), s.strip_prefix("msg_")) {
+                (Some(gid), _) => Ok(ScanPrefix::Commits(gid.to_string())),
+                (_, Some(gid)) => Ok(ScanPrefix::Messages(gid.to_string())),
+                _ => Err("expected kp, wm, cm_<gid>, or msg_<gid>".to_string()),
+            },
+        }
+    }
+}
+
+/// The persisted position of a scan: the next slot of the prefix a sync reads, and its key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScanCursor {
+    pub prefix: ScanPrefix,
+    pub position: u64,
+    pub key: String,
+}
+
+/// A slot of a scanned key prefix, as probed in the delivery service.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoteSlot {
+    pub key: String,
+    pub index: u64,
+    /// Size of the value stored in the slot, or `None` if the slot is empty.
+    pub size: Option<usize>,
+    /// Whether the slot is behind the cursor, that is, already read by a sync.
+    pub processed: bool,
+}
+
+/// Human-readable information about a group, shared by all members through the group context.
+#[derive(Clone, Debug, Default, PartialEq, SerdeSerialize, SerdeDeserialize)]
+pub struct GroupMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// Hex digest of the group's avatar image, which is distributed separately.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_hash: Option<String>,
+    /// gid of the group this one was reinitialized from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub predecessor: Option<String>,
+    /// gid of the group that replaced this one, set by the commit reinitializing it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub successor: Option<String>,
+}
+
+/// Requirements new members of a group must meet, shared by all members through the group
+/// context; unset fields place no restriction.
+#[derive(Clone, Debug, Default, PartialEq, SerdeSerialize, SerdeDeserialize)]
+pub struct GroupPolicy {
+    /// Credential types members may use, by number.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub credential_types: Vec<u16>,
+    /// Extension types the leaf nodes of members must support, by number.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required_extensions: Vec<u16>,
+    /// Seconds a key package must still be valid for when it is used to add its agent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_key_package_lifetime: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_members: Option<usize>,
+}
+
+impl GroupPolicy {
+    /// Checks the leaf node of a prospective member, and the expiry of the key package it is
+    /// added with if any, at Unix time `now`.
+    fn check_candidate(
+        &self,
+        pid: &str,
+        leaf: &LeafNode,
+        not_after: Option<u64>,
+        now: u64,
+    ) -> Result<(), MySgmError> {
+        let violation = |reason: String| Err(MySgmError::PolicyViolation(pid.to_string(), reason));
+        let credential_type = u16::from(leaf.credential().credential_type());
+        if !self.credential_types.is_empty() && !self.credential_types.contains(&credential_type) {
+            return violation(format!("credential type {credential_type} is not allowed"));
+        }
+        for extension_type in &self.required_extensions {
+            if !leaf
+                .capabilities()
+                .extensions()
+                .contains(&ExtensionType::from(*extension_type))
+            {
+                return violation(format!("extension {extension_type:#06x} is not supported"));
+            }
+        }
+        if let (Some(min_lifetime), Some(not_after)) = (self.min_key_package_lifetime, not_after)
+            && not_after < now.saturating_add(min_lifetime)
+        {
+            return violation(format!(
+                "key package expires in {} seconds, less than {min_lifetime}",
+                not_after.saturating_sub(now)
+            ));
+        }
+        Ok(())
+    }
+    /// Checks that the group may have `members` members.
+    fn check_size(&self, gid: &str, members: usize) -> Result<(), MySgmError> {
+        match self.max_members {
+            Some(max_members) if members > max_members => {
+                Err(MySgmError::GroupFull(gid.to_string(), max_members))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Groups loaded from the key store, kept for the next load of their gid.
+///
+/// A group is lent out by [`GroupCache::take`] and comes back through [`GroupCache::give_back`]
+/// when its [`LoadedGroup`] is dropped. OpenMLS writes every change of a group to the store as it
+/// is made, so a group kept after changing still matches the store, unless another instance of
+/// it was loaded while it was lent out, or the store moved to a new generation after it was kept.
+#[derive(Clone, Default)]
+struct GroupCache(Arc<Mutex<BTreeMap<String, CachedGroup>>>);
+
+enum CachedGroup {
+    /// Kept at the store generation.
+    Kept(u64, Box<MlsGroup>),
+    /// Lent out that many times, and contended once lent out more than once at a time.
+    Lent { loans: usize, contended: bool },
+}
+
+impl GroupCache {
+    fn groups(&self) -> MutexGuard<'_, BTreeMap<String, CachedGroup>> {
+        lock_ignoring_poison(&self.0)
+    }
+    /// Takes the group kept for the gid if the store is still at the generation it was kept
+    /// at, noting the gid as lent out either way.
+    fn take(&self, gid: &str, generation: u64) -> Option<MlsGroup> {
+        let mut groups = self.groups();
+        let (group, loans) = match groups.remove(gid) {
+            Some(CachedGroup::Kept(kept_at, group)) if kept_at == generation => (Some(*group), 0),
+            Some(CachedGroup::Lent { loans, .. }) => (None, loans),
+            _ => (None, 0),
+        };
+        groups.insert(
+            gid.to_string(),
+            CachedGroup::Lent {
+                loans: loans + 1,
+                contended: loans > 0,
+            },
+        );
+        group
+    }
+    /// Notes the group lent out for the gid as given back, keeping it at the store generation
+    /// unless it was contended or, as `None`, deleted.
+    fn give_back(&self, gid: &str, generation: u64, group: Option<MlsGroup>) {
+        let mut groups = self.groups();
+        let Some(CachedGroup::Lent { loans, contended }) = groups.remove(gid) else {
+            return;
+        };
+        match (loans > 1, contended, group) {
+            (true, ..) => {
+                let loans = loans - 1;
+                groups.insert(gid.to_string(), CachedGroup::Lent { loans, contended });
+            }
+            (false, false, Some(group)) => {
+                groups.retain(|_, cached| !matches!(cached, CachedGroup::Kept(kept_at, _) if *kept_at != generation));
+                groups.insert(
+                    gid.to_string(),
+                    CachedGroup::Kept(generation, Box::new(group)),
+                );
+            }
+            _ => {}
+        }
+    }
+    /// Gids of the groups kept at the store generation.
+    fn kept(&self, generation: u64) -> Vec<String> {
+        self.groups()
+            .iter()
+            .filter(|(_, cached)| matches!(cached, CachedGroup::Kept(kept_at, _) if *kept_at == generation))
+            .map(|(gid, _)| gid.clone())
+            .collect()
+    }
+}
+
+/// Lists the gids only, since groups hold their epoch secrets.
+impl core::fmt::Debug for GroupCache {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_set().entries(self.groups().keys()).finish()
+    }
+}
+
+/// Group loaded by [`MySgmAgent::load_group`], given back to the cache for its next load when
+/// dropped.
+struct LoadedGroup {
+    gid: String,
+    group: Option<MlsGroup>,
+    deleted: bool,
+    cache: GroupCache,
+    generation: Arc<AtomicU64>,
+}
+
+impl LoadedGroup {
+    /// Deletes the group from the store, so that it is not kept once dropped.
+    fn delete(&mut self, storage: &OpenMlsKeyValueStore) -> Result<(), MySgmError> {
+        self.deleted = true;
+        Ok((**self).delete(storage)?)
+    }
+}
+
+impl Deref for LoadedGroup {
+    type Target = MlsGroup;
+    fn deref(&self) -> &MlsGroup {
+        self.group
+            .as_ref()
+            .expect("the group is only taken when dropped")
+    }
+}
+
+impl DerefMut for LoadedGroup {
+    fn deref_mut(&mut self) -> &mut MlsGroup {
+        self.group
+            .as_mut()
+            .expect("the group is only taken when dropped")
+    }
+}
+
+impl Drop for LoadedGroup {
+    fn drop(&mut self) {
+        let group = self.group.take().filter(|_| !self.deleted);
+        let generation = self.generation.load(Ordering::Relaxed);
+        self.cache.give_back(&self.gid, generation, group);
+    }
+}
+
+/// Secure group messaging agent tying together local state, crypto, and the delivery service.
+#[derive(Debug)]
+pub struct MySgmAgent {
+    provider: MySgmProvider,
+    adapter: Box<dyn DeliveryAdapter>,
+    supported_ciphersuites: Vec<Ciphersuite>,
+    capabilities: Capabilities,
+    cred_with_key: CredentialWithKey,
+    strict_trust: bool,
+    observers: Vec<Box<dyn AgentObserver>>,
+    sync_concurrency: usize,
+    /// Records fetched ahead for the groups being synced, taken when their key is first read.
+    prefetched: BTreeMap<String, Option<Vec<u8>>>,
+    /// Messages received ahead of merging the commit that ended their epoch, per gid, until
+    /// [`Self::process_next_message`] returns them.
+    drained: BTreeMap<String, VecDeque<ReceivedMessage>>,
+    groups: GroupCache,
+}
+
+impl MySgmAgent {
+    pub fn new(state: MySgmState, crypto: RustCrypto, adapter: Box<dyn DeliveryAdapter>) -> Self {
+        // credential
+        let cred_with_key = CredentialWithKey {
+            credential: BasicCredential::new(state.my_pid().as_bytes().to_vec()).into(),
+            signature_key: state.signature_key_pair().public_key_raw().into(),
+        };
+        // capabilities
+        let supported_ciphersuites = crypto.supported_ciphersuites();
+        let capabilities = Capabilities::new(
+            None,
+            Some(&supported_ciphersuites),
+            Some(&[
+                ExtensionType::LastResort,
+                ExtensionType::Unknown(ADMINS_EXTENSION_TYPE),
+                ExtensionType::Unknown(METADATA_EXTENSION_TYPE),
+                ExtensionType::Unknown(POLICY_EXTENSION_TYPE),
+            ]),
+            None,
+            Some(&[CredentialType::Basic]),
+        );
+        Self {
+            provider: MySgmProvider::new(state, crypto),
+            adapter,
+            supported_ciphersuites,
+            capabilities,
+            cred_with_key,
+            strict_trust: false,
+            observers: Vec::new(),
+            sync_concurrency: DEFAULT_SYNC_CONCURRENCY,
+            prefetched: BTreeMap::new(),
+            drained: BTreeMap::new(),
+            groups: GroupCache::default(),
+        }
+    }
+    pub fn state(&self) -> &MySgmState {
+        self.provider.state()
+    }
+    pub fn state_mut(&mut self) -> &mut MySgmState {
+        self.provider.state_mut()
+    }
+    /// Rejects key packages and commits carrying a signature key that differs from the one an
+    /// agent is trusted with, instead of only logging a warning.
+    pub fn set_strict_trust(&mut self, strict: bool) {
+        self.strict_trust = strict;
+    }
+    /// Sets how many groups have their next commit, proposal, and message fetched at once while
+    /// syncing; at least one.
+    pub fn set_sync_concurrency(&mut self, limit: usize) {
+        self.sync_concurrency = limit.max(1);
+    }
+    /// Adds an observer told about key packages, welcomes, commits, and messages from now on.
+    pub fn add_observer(&mut self, observer: Box<dyn AgentObserver>) {
+        self.observers.push(observer);
+    }
+    /// SHA-256 digest of a signature key, whose prefix serves as its fingerprint.
+    pub fn key_digest(&self, signature_key: &[u8]) -> Result<Vec<u8>, MySgmError> {
+        Ok(self
+            .provider
+            .crypto()
+            .hash(HashType::Sha2_256, signature_key)?)
+    }
+    /// Hex SHA-256 digest of a delivered artifact, to recognize it when delivered again.
+    fn digest(&self, bytes: &[u8]) -> Result<String, MySgmError> {
+        Ok(hex_encode(
+            self.provider.crypto().hash(HashType::Sha2_256, bytes)?,
+        ))
+    }
+    /// Marks the agent as verified if the fingerprint is a prefix of at least
+    /// [`MIN_FINGERPRINT_LEN`] bytes of the digest of its trusted or changed key, accepting the
+    /// changed key in the latter case.
+    pub fn verify_agent(&mut self, pid: &str, fingerprint: &[u8]) -> Result<(), MySgmError> {
+        let record = self
+            .state()
+            .trust(pid)
+            .cloned()
+            .ok_or_else(|| MySgmError::UnknownAgent(pid.to_string()))?;
+        for key in [Some(&record.signature_key), record.changed_key.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            if fingerprint.len() >= MIN_FINGERPRINT_LEN
+                && self.key_digest(key)?.starts_with(fingerprint)
+            {
+                self.provider.state_mut().set_verified(pid, key);
+                return Ok(());
+            }
+        }
+        Err(MySgmError::FingerprintMismatch(pid.to_string()))
+    }
+    /// Checks the signature key of the pid against the trusted one, trusting it on first use.
+    fn check_trust(&mut self, pid: &str, signature_key: &[u8]) -> Result<(), MySgmError> {
+        if self
+            .state()
+            .quarantine(pid)
+            .is_some_and(|quarantine| quarantine.signature_keys.iter().any(|k| k == signature_key))
+        {
+            return Err(MySgmError::Quarantined(pid.to_string()));
+        }
+        if pid == self.state().my_pid()
+            || self
+                .provider
+                .state_mut()
+                .observe_signature_key(pid, signature_key)
+        {
+            return Ok(());
+        }
+        if self.strict_trust {
+            return Err(MySgmError::SignatureKeyMismatch(pid.to_string()));
+        }
+        tracing::warn!(
+            "Signature key of {pid} differs from the trusted one; verify it with verify-agent"
+        );
+        Ok(())
+    }
+    /// Checks the signature keys of the leaves a commit adds or updates.
+    ///
+    /// A member committing a new signature key for its own leaf vouches for it with the key
+    /// it signed the commit with, so a trusted sender's new key is trusted in turn.
+    fn check_commit_trust(
+        &mut self,
+        group: &MlsGroup,
+        staged_commit: &StagedCommit,
+        sender: &Sender,
+    ) -> Result<(), MySgmError> {
+        if let Sender::Member(index) = sender
+            && let Some(leaf) = staged_commit.update_path_leaf_node()
+            && let Some(member) = group.members().find(|member| member.index == *index)
+        {
+            let pid = credential_pid(leaf.credential())?;
+            if credential_pid(&member.credential)? == pid {
+                self.provider.state_mut().rotate_trusted_key(
+                    &pid,
+                    &member.signature_key,
+                    leaf.signature_key().as_slice(),
+                );
+            }
+        }
+        let mut leaves: Vec<LeafNode> = staged_commit
+            .add_proposals()
+            .map(|add| add.add_proposal().key_package().leaf_node().clone())
+            .collect();
+        leaves.extend(
+            staged_commit
+                .update_proposals()
+                .map(|update| update.update_proposal().leaf_node().clone()),
+        );
+        leaves.extend(staged_commit.update_path_leaf_node().cloned());
+        for leaf in leaves {
+            let cred = BasicCredential::try_from(leaf.credential().clone())?;
+            let pid = String::from_utf8_lossy(cred.identity()).to_string();
+            self.check_trust(&pid, leaf.signature_key().as_slice())?;
+        }
+        Ok(())
+    }
+    /// Checks the members a commit adds, and the size it leaves the group at, against the
+    /// group's policy.
+    ///
+    /// Key package lifetimes are left to the adder, since members' clocks differ and all of
+    /// them have to come to the same decision.
+    fn check_commit_policy(
+        &self,
+        group: &MlsGroup,
+        staged_commit: &StagedCommit,
+        sender: &Sender,
+    ) -> Result<(), MySgmError> {
+        let policy = group_policy(group)?;
+        let mut leaves: Vec<LeafNode> = staged_commit
+            .add_proposals()
+            .map(|add| add.add_proposal().key_package().leaf_node().clone())
+            .collect();
+        if matches!(sender, Sender::NewMemberCommit) {
+            leaves.extend(staged_commit.update_path_leaf_node().cloned());
+        }
+        for leaf in &leaves {
+            policy.check_candidate(&credential_pid(leaf.credential())?, leaf, None, 0)?;
+        }
+        let gid = String::from_utf8_lossy(group.group_id().as_slice()).to_string();
+        let removed = staged_commit.remove_proposals().count();
+        policy.check_size(
+            &gid,
+            (group.members().count() + leaves.len()).saturating_sub(removed),
+        )
+    }
+    /// Checks the key packages about to be added to the group against its policy.
+    fn check_key_packages_policy(
+        &self,
+        group: &MlsGroup,
+        kps: &[KeyPackage],
+    ) -> Result<(), MySgmError> {
+        let policy = group_policy(group)?;
+        let now = unix_time();
+        for kp in kps {
+            policy.check_candidate(
+                &credential_pid(kp.leaf_node().credential())?,
+                kp.leaf_node(),
+                Some(kp.life_time().not_after()),
+                now,
+            )?;
+        }
+        Ok(())
+    }
+    /// The key pair of the own leaf in the group: the current one, or a previous one until the
+    /// group is rotated to the current key.
+    fn own_key_pair(&self, group: &MlsGroup) -> &SignatureKeyPair {
+        let leaf_key = group
+            .own_leaf_node()
+            .map(|leaf| leaf.signature_key().as_slice());
+        self.state()
+            .previous_signature_key_pairs()
+            .iter()
+            .find(|key_pair| Some(key_pair.public_key_raw()) == leaf_key)
+            .unwrap_or(self.state().signature_key_pair())
+    }
+    /// Public key of [`Self::own_key_pair`] for publications, or `None` if it is the current one.
+    fn own_signer(&self, group: &MlsGroup) -> Option<Vec<u8>> {
+        let key_pair = self.own_key_pair(group);
+        (key_pair.public_key_raw() != self.state().signature_key_pair().public_key_raw())
+            .then(|| key_pair.public_key_raw().to_vec())
+    }
+    /// The current or previous key pair with the public key, or the current one if there is no
+    /// such key pair anymore.
+    fn signer_key_pair(&self, signer: Option<&[u8]>) -> &SignatureKeyPair {
+        signer
+            .and_then(|signer| {
+                self.state()
+                    .previous_signature_key_pairs()
+                    .iter()
+                    .find(|key_pair| key_pair.public_key_raw() == signer)
+            })
+            .unwrap_or(self.state().signature_key_pair())
+    }
+    /// Configuration of the groups this agent joins, with the limits set in the state; welcomes
+    /// to groups whose ratchet tree is posted separately do not carry it either.
+    fn join_config(&self, external_tree: bool) -> MlsGroupJoinConfig {
+        let limits = self.state().join_limits();
+        let defaults = SenderRatchetConfiguration::default();
+        // mixed wire format, so that external commits (always public messages) are accepted
+        MlsGroupJoinConfig::builder()
+            .wire_format_policy(MIXED_CIPHERTEXT_WIRE_FORMAT_POLICY)
+            .use_ratchet_tree_extension(!external_tree)
+            .max_past_epochs(limits.max_past_epochs.unwrap_or_default())
+            .sender_ratchet_configuration(SenderRatchetConfiguration::new(
+                limits
+                    .out_of_order_tolerance
+                    .unwrap_or(defaults.out_of_order_tolerance()),
+                limits
+                    .maximum_forward_distance
+                    .unwrap_or(defaults.maximum_forward_distance()),
+            ))
+            .build()
+    }
+    /// Loads the group, reusing the instance kept when it was last dropped if the key store has
+    /// not changed since.
+    fn load_group(&self, gid: &str) -> Result<LoadedGroup, MySgmError> {
+        let storage = self.provider.storage();
+        // dropped on failure like any other, so that the gid is not left lent out
+        let mut group = LoadedGroup {
+            gid: gid.to_string(),
+            group: self.groups.take(gid, storage.generation()),
+            deleted: false,
+            cache: self.groups.clone(),
+            generation: storage.shared_generation(),
+        };
+        if group.group.is_none() {
+            group.group = MlsGroup::load(storage, &GroupId::from_slice(gid.as_bytes()))?;
+        }
+        match group.group.is_some() {
+            true => Ok(group),
+            false => Err(MySgmError::GroupNotFound(gid.to_string())),
+        }
+    }
+    /// Gids of the groups kept in memory, which their next load reuses for as long as the key
+    /// store does not change.
+    pub fn cached_groups(&self) -> Vec<String> {
+        self.groups.kept(self.provider.storage().generation())
+    }
+    /// Signs the value with the key pair for storing under the key.
+    fn seal_record(
+        &self,
+        key_pair: &SignatureKeyPair,
+        key: &str,
+        value: &[u8],
+    ) -> Result<Vec<u8>, MySgmError> {
+        let signature = key_pair.sign(self.provider.crypto(), &record_content(key, value)?)?;
+        Ok(SignedRecord {
+            signature_scheme: key_pair.signature_scheme(),
+            signature_key: key_pair.public_key_raw().to_vec().into(),
+            signature: signature.into(),
+            value: value.to_vec().into(),
+        }
+        .tls_serialize_detached()?)
+    }
+    /// Verifies a record fetched from under the key, returning the signer's signature key and
+    /// the value, or [`MySgmError::InvalidRecord`] if the record is not signed for the key.
+    fn open_record(&self, key: &str, bytes: Vec<u8>) -> Result<(Vec<u8>, Vec<u8>), MySgmError> {
+        open_record(self.provider.crypto(), key, bytes)
+    }
+    /// Verifies a record fetched from under the key like [`Self::open_record`], also requiring
+    /// a member of the group to have signed it.
+    fn open_member_record(
+        &self,
+        group: &MlsGroup,
+        key: &str,
+        bytes: Vec<u8>,
+    ) -> Result<Vec<u8>, MySgmError> {
+        let (signer, value) = self.open_record(key, bytes)?;
+        if !group.members().any(|member| member.signature_key == signer) {
+            return Err(MySgmError::InvalidRecord(key.to_string()));
+        }
+        Ok(value)
+    }
+    /// Puts the value, signed, under the key unless the key already holds a value.
+    async fn put_record(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
+        self.put_record_as(self.state().signature_key_pair(), key, value)
+            .await
+    }
+    /// Puts the value like [`Self::put_record`], signed with the key pair.
+    async fn put_record_as(
+        &self,
+        key_pair: &SignatureKeyPair,
+        key: &str,
+        value: &[u8],
+    ) -> Result<(), MySgmError> {
+        let record = self.seal_record(key_pair, key, value)?;
+        self.adapter.put_checked(key, &record).await?;
+        self.state()
+            .record_published(key, self.digest(&record)?, record, unix_time());
+        Ok(())
+    }
+    /// Whether a published record is still worth keeping from expiring: own key packages while
+    /// valid and unused, other records for [`RECORD_RETENTION`] seconds after their first put.
+    fn record_relevant(&self, key: &str, record: &PublishedRecord) -> Result<bool, MySgmError> {
+        let (_, value) = self.open_record(key, record.value.clone())?;
+        if let Ok(message) = MlsMessageIn::tls_deserialize_exact(value)
+            && let MlsMessageBodyIn::KeyPackage(kp_in) = message.extract()
+        {
+            let Ok(kp) = kp_in.validate(self.provider.crypto(), self.state().mls_version()) else {
+                return Ok(false);
+            };
+            let kp_ref = kp.hash_ref(self.provider.crypto())?;
+            let bundle: Option<KeyPackageBundle> = self.provider.storage().key_package(&kp_ref)?;
+            return Ok(bundle.is_some() && self.state().published_key_packages().contains(&kp_ref));
+        }
+        Ok(unix_time().saturating_sub(record.first_published_at) < RECORD_RETENTION)
+    }
+    /// Forgets published records that are no longer relevant, returning how many were dropped.
+    pub fn forget_stale_records(&self) -> Result<usize, MySgmError> {
+        let mut dropped = 0;
+        for (key, record) in self.state().published_records() {
+            if !self.record_relevant(&key, &record)? {
+                tracing::info!("Forgetting published record {key}");
+                self.state().forget_published(&key);
+                dropped += 1;
+            }
+        }
+        Ok(dropped)
+    }
+    /// Puts every relevant published record last put more than `max_age` seconds ago again,
+    /// returning the keys put; records whose key now holds a different value are forgotten.
+    pub async fn republish(&mut self, max_age: u64) -> Result<Vec<String>, MySgmError> {
+        self.forget_stale_records()?;
+        let now = unix_time();
+        let mut records: Vec<_> = self.state().published_records().into_iter().collect();
+        records.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut republished = Vec::new();
+        for (key, record) in records {
+            if now.saturating_sub(record.published_at) < max_age {
+                continue;
+            }
+            match self.adapter.get(&key).await {
+                Ok(Some(value)) if self.digest(&value)? != record.sha256 => {
+                    tracing::warn!("Forgetting published record {key}: key holds another value");
+                    self.state().forget_published(&key);
+                    continue;
+                }
+                // parts of a chunked value may have expired before its manifest
+                Ok(_) | Err(MySgmError::CorruptChunks(_)) => {}
+                Err(e) => return Err(e),
+            }
+            tracing::info!("Republishing {key}");
+            self.adapter.republish(&key, &record.value).await?;
+            self.state()
+                .record_published(&key, record.sha256, record.value, now);
+            republished.push(key);
+        }
+        Ok(republished)
+    }
+    /// Puts the value under the first free slot at or after `index`, returning the slot used.
+    async fn put_first_free(
+        &self,
+        index: u64,
+        slot_key: impl Fn(u64) -> Result<String, MySgmError>,
+        value: &[u8],
+    ) -> Result<u64, MySgmError> {
+        self.put_first_free_as(self.state().signature_key_pair(), index, slot_key, value)
+            .await
+    }
+    /// Puts the value like [`Self::put_first_free`], signed with the key pair.
+    async fn put_first_free_as(
+        &self,
+        key_pair: &SignatureKeyPair,
+        mut index: u64,
+        slot_key: impl Fn(u64) -> Result<String, MySgmError>,
+        value: &[u8],
+    ) -> Result<u64, MySgmError> {
+        loop {
+            let key = slot_key(index)?;
+            tracing::info!("Key to put: {key}");
+            match self.put_record_as(key_pair, &key, value).await {
+                Ok(()) => {
+                    return Ok(index);
+                }
+                Err(MySgmError::KeyExists) => {
+                    tracing::warn!("Failed to put {key}: key already exists");
+                    index += 1;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        }
+    }
+    /// Queues the publication for [`Self::flush`] if putting it failed with a transient error,
+    /// since its effects were already applied locally; other errors are returned.
+    fn queue_if_transient(
+        &mut self,
+        e: MySgmError,
+        publication: Publication,
+    ) -> Result<(), MySgmError> {
+        if !e.is_transient() {
+            return Err(e);
+        }
+        tracing::warn!("Queueing publication to retry later: {e}");
+        self.provider.state_mut().queue_publication(publication);
+        Ok(())
+    }
+    /// Puts a publication taken from the outbox.
+    async fn publish(&self, publication: &Publication) -> Result<(), MySgmError> {
+        match publication {
+            Publication::Slot {
+                prefix,
+                index,
+                value,
+                signer,
+            } => {
+                self.put_first_free_as(
+                    self.signer_key_pair(signer.as_deref()),
+                    *index,
+                    |index| Ok(format!("{prefix}{index}")),
+                    value,
+                )
+                .await?;
+            }
+            Publication::Record { key, value, signer } => match self
+                .put_record_as(self.signer_key_pair(signer.as_deref()), key, value)
+                .await
+            {
+                Ok(()) | Err(MySgmError::KeyExists) => {}
+                Err(e) => return Err(e),
+            },
+            Publication::KeyPackage { value } => match self.adapter.directory() {
+                Some(directory) => {
+                    directory
+                        .put_key_package(self.state().my_pid(), value)
+                        .await?
+                }
+                None => {
+                    self.put_first_free(
+                        self.state().key_package_counter(),
+                        |index| Ok(key_package_key(self.state().namespace(), index)),
+                        value,
+                    )
+                    .await?;
+                }
+            },
+            Publication::Welcome { pid, value } => {
+                self.adapter
+                    .directory()
+                    .ok_or(MySgmError::NoDirectory)?
+                    .post_welcome(pid, value)
+                    .await?
+            }
+        }
+        Ok(())
+    }
+    /// Retries the queued publications in order, returning how many were published.
+    ///
+    /// Retrying stops at the first transient error, leaving that publication and the later ones
+    /// queued; publications that fail otherwise are dropped.
+    pub async fn flush(&mut self) -> usize {
+        let mut published = 0;
+        while let Some(publication) = self.provider.state_mut().pop_publication() {
+            match self.publish(&publication).await {
+                Ok(()) => published += 1,
+                Err(e) if e.is_transient() => {
+                    tracing::warn!("Delivery service still unreachable, keeping queue: {e}");
+                    self.provider.state_mut().requeue_publication(publication);
+                    break;
+                }
+                Err(e) => tracing::warn!("Dropping queued publication: {e}"),
+            }
+        }
+        published
+    }
+    /// Fetches the values under the keys, at most [`Self::set_sync_concurrency`] at once, to be
+    /// taken by [`Self::get_prefetched`] in place of the records fetched before.
+    async fn prefetch(&mut self, keys: Vec<String>) -> Result<(), MySgmError> {
+        let adapter = &self.adapter;
+        let fetched: Vec<_> = stream_iter(keys)
+            .map(|key| async move {
+                let value = adapter.get(&key).await;
+                (key, value)
+            })
+            .buffer_unordered(self.sync_concurrency)
+            .collect()
+            .await;
+        self.prefetched.clear();
+        for (key, value) in fetched {
+            self.prefetched.insert(key, value?);
+        }
+        Ok(())
+    }
+    /// Takes the value under the key from the prefetched records, or fetches it.
+    async fn get_prefetched(&mut self, key: &str) -> Result<Option<Vec<u8>>, MySgmError> {
+        match self.prefetched.remove(key) {
+            Some(value) => Ok(value),
+            None => self.adapter.get(key).await,
+        }
+    }
+    /// Keys of the commit the group's next merge looks for, in its exporter slot and in the
+    /// commit chain, or none if commits are not downloaded for it.
+    fn next_commit_keys(&self, gid: &str) -> Vec<String> {
+        if self.state().pending_add(gid).is_some() {
+            return Vec::new();
+        }
+        let Ok(group) = self.load_group(gid) else {
+            return Vec::new();
+        };
+        match commit_key(&group, &self.provider) {
+            Ok(key) => vec![key, commit_chain_key(gid, group.epoch().as_u64())],
+            Err(_) => Vec::new(),
+        }
+    }
+    /// Key of the group's next proposal.
+    fn next_proposal_key(&self, gid: &str) -> Option<String> {
+        let group = self.load_group(gid).ok()?;
+        let counter = self.state().proposal_counter(gid, group.epoch().as_u64());
+        proposal_key(&group, &self.provider, counter).ok()
+    }
+    /// Key of the group's next application message.
+    fn next_message_key(&self, gid: &str) -> Option<String> {
+        let group = self.load_group(gid).ok()?;
+        let counter = self.state().message_counter(gid, group.epoch().as_u64());
+        application_message_key(&group, &self.provider, counter).ok()
+    }
+    /// Fetches the values under the keys concurrently, in the order of the keys.
+    async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, MySgmError> {
+        join_all(keys.iter().map(|key| self.adapter.get(key)))
+            .await
+            .into_iter()
+            .collect()
+    }
+    /// Posts a welcome message to the recipients in the directory, or, if there is no directory
+    /// or the recipients are unknown, to the first free welcome slot of each new member's key
+    /// package, starting after the slot this agent last posted to.
+    ///
+    /// For groups whose welcomes leave out the ratchet tree, the tree of the group's merged epoch
+    /// is posted first.
+    async fn publish_welcome(
+        &mut self,
+        group: &MlsGroup,
+        welcome: &MlsMessageOut,
+        recipients: &[String],
+    ) -> Result<(), MySgmError> {
+        tracing::debug!("Welcome message: {} bytes", welcome.tls_serialized_len());
+        let gid = String::from_utf8_lossy(group.group_id().as_slice()).to_string();
+        if self.state().external_tree(&gid) {
+            let tree = group.export_ratchet_tree().tls_serialize_detached()?;
+            self.post_ratchet_tree(&gid, group.epoch().as_u64(), tree, true)
+                .await?;
+        }
+        let MlsMessageBodyOut::Welcome(body) = welcome.body() else {
+            return Err(MySgmError::UnexpectedMessage("Welcome"));
+        };
+        self.post_welcome(&welcome.tls_serialize_detached()?, body, recipients, true)
+            .await
+    }
+    /// Posts the ratchet tree of the group's epoch, for joiners whose welcome leaves it out; with
+    /// `queue_failed` set, puts failing with a transient error are queued.
+    async fn post_ratchet_tree(
+        &mut self,
+        gid: &str,
+        epoch: u64,
+        tree: Vec<u8>,
+        queue_failed: bool,
+    ) -> Result<(), MySgmError> {
+        let key = ratchet_tree_key(gid, epoch);
+        tracing::info!("Ratchet tree key to put: {key}");
+        match self.put_record(&key, &tree).await {
+            Ok(()) | Err(MySgmError::KeyExists) => Ok(()),
+            Err(e) if queue_failed => {
+                let publication = Publication::Record {
+                    key,
+                    value: tree,
+                    signer: None,
+                };
+                self.queue_if_transient(e, publication)
+            }
+            Err(e) => Err(e),
+        }
+    }
+    /// Posts the serialized welcome as [`Self::publish_welcome`] does, without the ratchet tree;
+    /// with `queue_failed` set, puts failing with a transient error are queued.
+    async fn post_welcome(
+        &mut self,
+        wm_bytes: &[u8],
+        welcome: &Welcome,
+        recipients: &[String],
+        queue_failed: bool,
+    ) -> Result<(), MySgmError> {
+        if self.adapter.directory().is_some() && !recipients.is_empty() {
+            for pid in recipients {
+                let posted = match self.adapter.directory() {
+                    Some(directory) => directory.post_welcome(pid, wm_bytes).await,
+                    None => Err(MySgmError::NoDirectory),
+                };
+                match posted {
+                    Ok(()) => {}
+                    Err(e) if queue_failed => {
+                        let publication = Publication::Welcome {
+                            pid: pid.clone(),
+                            value: wm_bytes.to_vec(),
+                        };
+                        self.queue_if_transient(e, publication)?;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            return Ok(());
+        }
+        for secrets in welcome.secrets() {
+            let kp_ref = secrets.new_member();
+            let start = self.state().welcome_write_counter(&kp_ref);
+            let put = self
+                .put_first_free(
+                    start,
+                    |index| {
+                        Ok(welcome_message_key(
+                            self.state().namespace(),
+                            &kp_ref,
+                            index,
+                        ))
+                    },
+                    wm_bytes,
+                )
+                .await;
+            match put {
+                Ok(index) => self
+                    .provider
+                    .state_mut()
+                    .set_welcome_write_counter(&kp_ref, index + 1),
+                Err(e) if queue_failed => {
+                    let publication = Publication::Slot {
+                        prefix: welcome_message_prefix(self.state().namespace(), &kp_ref),
+                        index: start,
+                        value: wm_bytes.to_vec(),
+                        signer: None,
+                    };
+                    self.queue_if_transient(e, publication)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+    /// Posts a commit under the current epoch's commit key and merges it locally.
+    async fn publish_commit(
+        &mut self,
+        group: &mut MlsGroup,
+        commit: &MlsMessageOut,
+    ) -> Result<(), MySgmError> {
+        tracing::debug!(
+            "Commit for epoch {}: {} bytes",
+            group.epoch().as_u64(),
+            commit.tls_serialized_len()
+        );
+        let key = commit_key(group, &self.provider)?;
+        let cm_bytes = commit.tls_serialize_detached()?;
+        self.put_record_as(self.own_key_pair(group), &key, &cm_bytes)
+            .await?;
+        self.merge_published_commit(group, &cm_bytes).await
+    }
+    /// Links an own commit that won its slot into the group's commit chain and merges it.
+    async fn merge_published_commit(
+        &mut self,
+        group: &mut MlsGroup,
+        cm_bytes: &[u8],
+    ) -> Result<(), MySgmError> {
+        let gid = String::from_utf8_lossy(group.group_id().as_slice()).to_string();
+        // the commit already won its slot, so it is merged even if the link cannot be put
+        let link_key = commit_chain_key(&gid, group.epoch().as_u64());
+        let link = self.commit_link(&gid, cm_bytes)?;
+        match self
+            .put_record_as(self.own_key_pair(group), &link_key, &link)
+            .await
+        {
+            Ok(()) => {}
+            Err(e) if e.is_transient() => {
+                let publication = Publication::Record {
+                    key: link_key,
+                    value: link,
+                    signer: self.own_signer(group),
+                };
+                self.queue_if_transient(e, publication)?;
+            }
+            Err(e) => tracing::warn!("Failed to link commit into the chain of {gid}: {e}"),
+        }
+        let digest = self.digest(cm_bytes)?;
+        self.provider.state_mut().set_commit_head(&gid, digest);
+        let events = match group.pending_commit() {
+            Some(staged_commit) => commit_audit_events(
+                group,
+                staged_commit,
+                &Sender::Member(group.own_leaf_index()),
+            )?,
+            None => Vec::new(),
+        };
+        group.merge_pending_commit(&self.provider)?;
+        self.record_merged_commit(group, events);
+        self.retain_epoch_exporter(group)
+    }
+    /// Links the commit ending the current epoch into the group's commit chain, after the last
+    /// commit merged.
+    fn commit_link(&self, gid: &str, cm_bytes: &[u8]) -> Result<Vec<u8>, MySgmError> {
+        let link = CommitLink {
+            previous: self
+                .state()
+                .commit_head(gid)
+                .unwrap_or_default()
+                .as_bytes()
+                .to_vec()
+                .into(),
+            commit: cm_bytes.to_vec().into(),
+        };
+        Ok(link.tls_serialize_detached()?)
+    }
+    /// Fetches the commit ending the epoch from the group's commit chain, for when its
+    /// exporter-derived slot holds none, checking that it follows the last commit merged.
+    ///
+    /// Returns the link's key, the digest of its record, and the commit, or `None` if the chain
+    /// has no link for the epoch or its record is quarantined.
+    async fn fetch_commit_link(
+        &mut self,
+        group: &MlsGroup,
+        gid: &str,
+    ) -> Result<Option<(String, String, Result<Vec<u8>, MySgmError>)>, MySgmError> {
+        let epoch = group.epoch().as_u64();
+        let key = commit_chain_key(gid, epoch);
+        tracing::info!("Commit chain key to get: {key}");
+        let Some(record) = self.get_prefetched(&key).await? else {
+            return Ok(None);
+        };
+        let digest = self.digest(&record)?;
+        if self.state().record_quarantined(&key, &digest) {
+            return Ok(None);
+        }
+        // the commit itself is verified when processed; external joiners are no members yet
+        let commit = self
+            .open_record(&key, record)
+            .and_then(|(_, value)| decode_untrusted::<CommitLink>(&key, &value))
+            .and_then(|link| match self.state().commit_head(gid) {
+                // committers who joined by welcome know no previous commit
+                Some(head)
+                    if !link.previous.as_slice().is_empty()
+                        && head.as_bytes() != link.previous.as_slice() =>
+                {
+                    Err(MySgmError::BrokenCommitChain(gid.to_string(), epoch))
+                }
+                _ => Ok(link.commit.into()),
+            });
+        Ok(Some((key, digest, commit)))
+    }
+    /// Records the audit events of a commit just merged, telling the observers about it and
+    /// the members it added.
+    fn record_merged_commit(&mut self, group: &MlsGroup, events: Vec<AuditEvent>) {
+        let gid = String::from_utf8_lossy(group.group_id().as_slice()).to_string();
+        for observer in &self.observers {
+            observer.on_commit_merged(&gid, group.epoch().as_u64());
+            for event in &events {
+                if let ("add" | "join", Some(pid)) = (event.kind.as_str(), &event.subject) {
+                    observer.on_member_added(&gid, pid);
+                }
+            }
+        }
+        self.record_audit(group, events);
+    }
+    /// Appends the events to the group's audit log.
+    fn record_audit(&mut self, group: &MlsGroup, events: Vec<AuditEvent>) {
+        let gid = String::from_utf8_lossy(group.group_id().as_slice()).to_string();
+        for event in events {
+            tracing::info!("Audit event for {gid}: {event:?}");
+            self.provider.state_mut().append_audit(&gid, event);
+        }
+    }
+    /// Accepts an application message of the sender only if it is newer than the latest one
+    /// received from them.
+    fn check_sequence(
+        &mut self,
+        group: &MlsGroup,
+        sender: &str,
+        epoch: u64,
+        sequence: u64,
+    ) -> Result<(), MySgmError> {
+        let gid = String::from_utf8_lossy(group.group_id().as_slice()).to_string();
+        if let Some((last_epoch, last_sequence)) = self.state().received_sequence(&gid, sender)
+            && (epoch, sequence) <= (last_epoch, last_sequence)
+        {
+            let detail = format!(
+                "message {sequence} of epoch {epoch} from {sender}, \
+                 already received message {last_sequence} of epoch {last_epoch}"
+            );
+            return Err(self.reject_replay(group, Some(sender), detail));
+        }
+        self.provider
+            .state_mut()
+            .set_received_sequence(&gid, sender, epoch, sequence);
+        Ok(())
+    }
+    /// Records a rejected application message in the group's audit log and returns the reason
+    /// it was rejected.
+    fn reject_replay(
+        &mut self,
+        group: &MlsGroup,
+        sender: Option<&str>,
+        detail: String,
+    ) -> MySgmError {
+        let gid = String::from_utf8_lossy(group.group_id().as_slice()).to_string();
+        let event = AuditEvent {
+            seq: 0,
+            timestamp: unix_time(),
+            epoch: group.epoch().as_u64(),
+            kind: "replay".to_string(),
+            actor: sender.map(str::to_string),
+            subject: None,
+        };
+        self.record_audit(group, vec![event]);
+        MySgmError::ReplayedMessage(gid, detail)
+    }
+    /// Records the rejection of a welcome to, or group info of, the group by the artifact policy
+    /// in the group's audit log, returning the error.
+    fn reject_artifact(
+        &mut self,
+        gid: &str,
+        epoch: u64,
+        actor: Option<String>,
+        artifact: String,
+        violation: ArtifactViolation,
+    ) -> MySgmError {
+        let event = AuditEvent {
+            seq: 0,
+            timestamp: unix_time(),
+            epoch,
+            kind: "policy_violation".to_string(),
+            actor,
+            subject: Some(self.state().my_pid().to_string()),
+        };
+        tracing::info!("Audit event for {gid}: {event:?}");
+        self.provider.state_mut().append_audit(gid, event);
+        MySgmError::ArtifactRejected(artifact, violation)
+    }
+    /// Audit event for a change made by this agent itself, in the group's current epoch.
+    fn own_audit_event(&self, group: &MlsGroup, kind: &str) -> AuditEvent {
+        AuditEvent {
+            seq: 0,
+            timestamp: unix_time(),
+            epoch: group.epoch().as_u64(),
+            kind: kind.to_string(),
+            actor: Some(self.state().my_pid().to_string()),
+            subject: Some(self.state().my_pid().to_string()),
+        }
+    }
+    /// Builds the exporter of the group's current epoch.
+    fn current_epoch_exporter(&self, group: &MlsGroup) -> Result<EpochExporter, MySgmError> {
+        // the group context is only reachable through group info
+        let group_info = group.export_group_info(self.provider.crypto(), &self.provider, false)?;
+        let MlsMessageBodyOut::GroupInfo(group_info) = group_info.body() else {
+            unreachable!("group info export yields group info");
+        };
+        Ok(EpochExporter {
+            epoch: group.epoch().as_u64(),
+            group_context_hash: self
+                .key_digest(&group_info.group_context().tls_serialize_detached()?)?,
+            exporter_root: group.export_secret(&self.provider, EPOCH_EXPORTER_LABEL, &[], 32)?,
+        })
+    }
+    /// Retains the exporter of the group's current epoch, called whenever the epoch changes.
+    fn retain_epoch_exporter(&mut self, group: &MlsGroup) -> Result<(), MySgmError> {
+        // a commit that removed this agent leaves nothing to export
+        if !group.is_active() {
+            return Ok(());
+        }
+        let exporter = self.current_epoch_exporter(group)?;
+        let gid = String::from_utf8_lossy(group.group_id().as_slice()).to_string();
+        self.provider
+            .state_mut()
+            .retain_epoch_exporter(&gid, exporter);
+        Ok(())
+    }
+    /// Fetches and validates the key package in the next key package slot.
+    ///
+    /// Returns the pid of the key package, or [`MySgmError::NoNewKeyPackages`] if the slot is empty.
+    /// Expired key packages and key packages for ciphersuites the local crypto provider does not
+    /// support are rejected.
+    pub async fn process_next_key_package(&mut self) -> Result<String, MySgmError> {
+        let key = key_package_key(self.state().namespace(), self.state().key_package_counter());
+        tracing::info!("Key package key to get: {key}");
+        let kp_bytes = self
+            .adapter
+            .get(&key)
+            .await?
+            .ok_or(MySgmError::NoNewKeyPackages)?;
+        let slot = self.state().key_package_counter();
+        self.provider.state_mut().increment_key_package_counter();
+        let (signer, kp_bytes) = self.open_record(&key, kp_bytes)?;
+        self.process_key_package(kp_bytes, key, Some(&signer), Some(slot))
+    }
+    /// Looks up the key package of the pid in the directory of the delivery service, refusing
+    /// one of another pid.
+    pub async fn fetch_key_package(&mut self, pid: &str) -> Result<String, MySgmError> {
+        let Some(directory) = self.adapter.directory() else {
+            return Err(MySgmError::KeyPackageNotFound(pid.to_string()));
+        };
+        let kp_bytes = directory
+            .get_key_package(pid)
+            .await?
+            .ok_or_else(|| MySgmError::KeyPackageNotFound(pid.to_string()))?;
+        let kp = validate_key_package(
+            self.provider.crypto(),
+            self.state().mls_version(),
+            &self.supported_ciphersuites,
+            self.state().artifact_policy(),
+            kp_bytes,
+            pid.to_string(),
+            None,
+        )?;
+        // the directory may hand out another agent's key package
+        let cred = BasicCredential::try_from(kp.leaf_node().credential().clone())?;
+        if cred.identity() != pid.as_bytes() {
+            return Err(MySgmError::InvalidRecord(pid.to_string()));
+        }
+        self.store_key_package(kp, None)
+    }
+    /// Validates and stores a key package, returning its pid; `key` names its source in errors,
+    /// and `slot` the key package slot it came from, if any.
+    ///
+    /// A key package from a signed record must be signed with its own signature key.
+    fn process_key_package(
+        &mut self,
+        kp_bytes: Vec<u8>,
+        key: String,
+        signer: Option<&[u8]>,
+        slot: Option<u64>,
+    ) -> Result<String, MySgmError> {
+        let kp = validate_key_package(
+            self.provider.crypto(),
+            self.state().mls_version(),
+            &self.supported_ciphersuites,
+            self.state().artifact_policy(),
+            kp_bytes,
+            key,
+            signer,
+        )?;
+        self.store_key_package(kp, slot)
+    }
+    /// Stores a validated key package if its signature key is trusted for its pid, and logs it
+    /// with the slot it came from, returning the pid.
+    fn store_key_package(
+        &mut self,
+        kp: KeyPackage,
+        slot: Option<u64>,
+    ) -> Result<String, MySgmError> {
+        let cred = BasicCredential::try_from(kp.leaf_node().credential().clone())?;
+        let pid = String::from_utf8_lossy(cred.identity()).to_string();
+        tracing::info!("pid of key package: {pid}");
+        self.check_trust(&pid, kp.leaf_node().signature_key().as_slice())?;
+        let kp_ref = kp.hash_ref(self.provider.crypto())?;
+        self.provider
+            .state_mut()
+            .log_key_package(KeyPackageLogEntry {
+                pid: pid.clone(),
+                key_package_ref: hex_encode(kp_ref.as_slice()),
+                ciphersuite: kp.ciphersuite(),
+                not_before: kp.life_time().not_before(),
+                not_after: kp.life_time().not_after(),
+                last_resort: kp.last_resort(),
+                slot,
+                consumed: false,
+            });
+        if kp.last_resort() {
+            self.provider.state_mut().set_key_package(&pid, kp);
+        } else {
+            self.provider.state_mut().add_one_time_key_package(&pid, kp);
+        }
+        for observer in &self.observers {
+            observer.on_key_package(&pid);
+        }
+        Ok(pid)
+    }
+    /// Downloads key packages until the first empty slot.
+    ///
+    /// Slots are fetched concurrently, [`FETCH_WINDOW`] at first and twice as many after every
+    /// full batch, up to [`MAX_KEY_PACKAGE_WINDOW`]. The records and key packages of a batch are
+    /// verified on the rayon thread pool, then stored in slot order.
+    pub async fn download_key_packages(
+        &mut self,
+        report: &mut SyncReport,
+    ) -> Result<(), MySgmError> {
+        let mut window = FETCH_WINDOW;
+        loop {
+            let start = self.state().key_package_counter();
+            let keys: Vec<String> = (start..start + window)
+                .map(|index| key_package_key(self.state().namespace(), index))
+                .collect();
+            let values = self.get_many(&keys).await?;
+            let batch: Vec<(String, Vec<u8>)> = keys
+                .into_iter()
+                .zip(values)
+                .map_while(|(key, kp_bytes)| Some((key, kp_bytes?)))
+                .collect();
+            let full = batch.len() as u64 == window;
+            let digests = batch
+                .iter()
+                .map(|(_, kp_bytes)| self.digest(kp_bytes))
+                .collect::<Result<Vec<_>, _>>()?;
+            let crypto = self.provider.crypto();
+            let mls_version = self.state().mls_version();
+            let supported_ciphersuites = &self.supported_ciphersuites;
+            let policy = self.state().artifact_policy();
+            let validated: Vec<(String, Result<KeyPackage, MySgmError>)> = batch
+                .into_par_iter()
+                .map(|(key, kp_bytes)| {
+                    let validated =
+                        open_record(crypto, &key, kp_bytes).and_then(|(signer, kp_bytes)| {
+                            validate_key_package(
+                                crypto,
+                                mls_version,
+                                supported_ciphersuites,
+                                policy,
+                                kp_bytes,
+                                key.clone(),
+                                Some(&signer),
+                            )
+                        });
+                    (key, validated)
+                })
+                .collect();
+            for ((key, validated), digest) in validated.into_iter().zip(digests) {
+                let slot = self.state().key_package_counter();
+                self.provider.state_mut().increment_key_package_counter();
+                if self.state().record_quarantined(&key, &digest) {
+                    tracing::info!("Skipping quarantined key package {key}");
+                    continue;
+                }
+                match validated.and_then(|kp| self.store_key_package(kp, Some(slot))) {
+                    Ok(pid) => report.key_packages.push(pid),
+                    Err(e) => {
+                        tracing::warn!("Skipping key package {key}: {e}");
+                        self.quarantine_if_malformed(&key, digest, RecordKind::KeyPackage, &e);
+                        report.record(key, e)?;
+                    }
+                }
+            }
+            if !full {
+                tracing::info!("No more key packages to download");
+                return Ok(());
+            }
+            window = (window * 2).min(MAX_KEY_PACKAGE_WINDOW);
+        }
+    }
+    /// Fetches the next welcome addressed to one of this agent's published key packages and joins
+    /// its group.
+    ///
+    /// Returns the gid of the joined group, `None` if the welcome could not be processed, or
+    /// [`MySgmError::NoNewWelcomeMessages`] if no key package has a new welcome.
+    pub async fn process_next_welcome_message(&mut self) -> Result<Option<String>, MySgmError> {
+        for kp_ref in self.state().published_key_packages().to_vec() {
+            let key = welcome_message_key(
+                self.state().namespace(),
+                &kp_ref,
+                self.state().welcome_counter(&kp_ref),
+            );
+            tracing::info!("Welcome message key to get: {key}");
+            if let Some(wm_bytes) = self.adapter.get(&key).await? {
+                self.provider.state_mut().increment_welcome_counter(&kp_ref);
+                let processed = match self.open_record(&key, wm_bytes) {
+                    Ok((_, wm_bytes)) => self.process_welcome_message(&key, wm_bytes).await,
+                    Err(e) => Err(e),
+                };
+                return match processed {
+                    Ok(gid) => Ok(gid),
+                    Err(e) if !e.is_transient() => {
+                        tracing::warn!("Failed to process welcome: {e}");
+                        Ok(None)
+                    }
+                    Err(e) => Err(e),
+                };
+            }
+        }
+        Err(MySgmError::NoNewWelcomeMessages)
+    }
+    /// Joins the groups of welcomes addressed to this agent in the directory, if there is one.
+    pub async fn download_directory_welcomes(
+        &mut self,
+        report: &mut SyncReport,
+    ) -> Result<(), MySgmError> {
+        let Some(directory) = self.adapter.directory() else {
+            return Ok(());
+        };
+        let welcomes = directory.get_welcomes(self.state().my_pid()).await?;
+        for wm_bytes in welcomes
+            .into_iter()
+            .skip(self.state().directory_welcome_counter() as usize)
+        {
+            let slot = format!(
+                "directory welcome {}",
+                self.state().directory_welcome_counter()
+            );
+            self.provider
+                .state_mut()
+                .increment_directory_welcome_counter();
+            match self.process_welcome_message(&slot, wm_bytes).await {
+                Ok(gid) => report.welcomes.extend(gid),
+                Err(e) => report.record(slot, e)?,
+            }
+        }
+        Ok(())
+    }
+    /// Joins the group of the welcome fetched from under the key, returning its gid, or `None`
+    /// if the welcome was already processed or its group already joined.
+    async fn process_welcome_message(
+        &mut self,
+        key: &str,
+        wm_bytes: Vec<u8>,
+    ) -> Result<Option<String>, MySgmError> {
+        wire_event("received", "welcome", key, &wm_bytes);
+        let digest = self.digest(&wm_bytes)?;
+        if self.state().welcome_processed(&digest) {
+            tracing::info!("Skipping already processed welcome: {digest}");
+            return Ok(None);
+        }
+        match decode_untrusted::<MlsMessageIn>(key, &wm_bytes)?.extract() {
+            MlsMessageBodyIn::Welcome(welcome) => {
+                tracing::debug!("Processed welcome message: {} bytes", wm_bytes.len());
+                let joined = match self.receive_welcome(welcome, key).await {
+                    Ok(gid) => gid,
+                    Err(MySgmError::GroupExists(gid)) => {
+                        tracing::info!("Skipping welcome to already joined group: {gid}");
+                        None
+                    }
+                    Err(e) => return Err(e),
+                };
+                self.provider.state_mut().mark_welcome_processed(digest);
+                Ok(joined)
+            }
+            _ => Err(MySgmError::UnexpectedMessage("Welcome")),
+        }
+    }
+    /// Joins the group of the welcome fetched from the slot, returning its gid, or `None` if
+    /// the welcome was queued as a [`PendingInvite`] instead, since the
+    /// [`InvitePolicy`](super::state::InvitePolicy) does not admit its inviter.
+    async fn receive_welcome(
+        &mut self,
+        welcome: Welcome,
+        welcome_slot: &str,
+    ) -> Result<Option<String>, MySgmError> {
+        let checked = self.check_welcome(&welcome, None).await?;
+        if self.state().invite_policy().admits(&checked.inviter) {
+            return Ok(Some(self.join_checked_welcome(
+                welcome,
+                checked,
+                Some(welcome_slot),
+            )?));
+        }
+        let CheckedWelcome {
+            gid,
+            epoch,
+            inviter,
+            ..
+        } = checked;
+        let invite = PendingInvite {
+            id: 0,
+            gid: gid.clone(),
+            inviter: inviter.clone(),
+            epoch,
+            received_at: unix_time(),
+            welcome_slot: Some(welcome_slot.to_string()),
+            welcome: welcome.tls_serialize_detached()?,
+        };
+        let id = self.provider.state_mut().add_pending_invite(invite);
+        tracing::info!("Queued invite {id} to {gid} from {inviter}");
+        Ok(None)
+    }
+    /// Joins the group of the welcome, fetched from the slot if any, returning its gid.
+    async fn join_with_welcome(
+        &mut self,
+        welcome: Welcome,
+        ratchet_tree: Option<RatchetTreeIn>,
+        welcome_slot: Option<&str>,
+    ) -> Result<String, MySgmError> {
+        let checked = self.check_welcome(&welcome, ratchet_tree).await?;
+        self.join_checked_welcome(welcome, checked, welcome_slot)
+    }
+    /// Stages the welcome against a copy of the storage and checks it against the artifact
+    /// policy, without joining its group yet.
+    ///
+    /// Without a ratchet tree in the welcome or given, the tree published for the group's epoch
+    /// is fetched.
+    async fn check_welcome(
+        &mut self,
+        welcome: &Welcome,
+        ratchet_tree: Option<RatchetTreeIn>,
+    ) -> Result<CheckedWelcome, MySgmError> {
+        let join_config = self.join_config(false);
+        // staged against a copy of the storage first, since processing a welcome consumes its
+        // one-time key package even if the welcome is then queued
+        let mut scratch = ScratchProvider::new(self.provider.storage());
+        let processed_welcome =
+            match ProcessedWelcome::new_from_welcome(&scratch, &join_config, welcome.clone()) {
+                // a welcome to the successor of a reinitialized group may arrive before the commit
+                // reinitializing it, which provides the pre-shared key
+                Err(WelcomeError::Psk(PskError::KeyNotFound)) => {
+                    for gid in self.state().gids() {
+                        while self.process_next_commit(&gid).await? {}
+                    }
+                    scratch = ScratchProvider::new(self.provider.storage());
+                    ProcessedWelcome::new_from_welcome(&scratch, &join_config, welcome.clone())?
+                }
+                processed => processed?,
+            };
+        let group_info = processed_welcome.unverified_group_info();
+        let gid = String::from_utf8_lossy(group_info.group_id().as_slice()).to_string();
+        if self.state().gids().contains(&gid) {
+            return Err(MySgmError::GroupExists(gid));
+        }
+
+        let external_tree = group_info.extensions().ratchet_tree().is_none();
+        let ratchet_tree = match ratchet_tree {
+            Some(ratchet_tree) => Some(ratchet_tree),
+            None if external_tree => {
+                let key = ratchet_tree_key(&gid, group_info.epoch().as_u64());
+                tracing::info!("Ratchet tree key to get: {key}");
+                let rt_bytes = self
+                    .adapter
+                    .get(&key)
+                    .await?
+                    .ok_or_else(|| MySgmError::RatchetTreeNotFound(gid.clone()))?;
+                let (_, rt_bytes) = self.open_record(&key, rt_bytes)?;
+                Some(decode_untrusted(&key, &rt_bytes)?)
+            }
+            None => None,
+        };
+        let epoch = group_info.epoch().as_u64();
+        let staged_welcome =
+            processed_welcome.into_staged_welcome(&scratch, ratchet_tree.clone())?;
+        let inviter = credential_pid(staged_welcome.welcome_sender()?.credential())?;
+        let context = staged_welcome.group_context();
+        let credential_types = staged_welcome
+            .members()
+            .map(|member| u16::from(member.credential.credential_type()));
+        let accepted = self.state().artifact_policy().check(
+            context.ciphersuite(),
+            context.protocol_version(),
+            credential_types,
+        );
+        if let Err(violation) = accepted {
+            let artifact = format!("welcome to {gid}");
+            return Err(self.reject_artifact(&gid, epoch, Some(inviter), artifact, violation));
+        }
+        Ok(CheckedWelcome {
+            gid,
+            epoch,
+            inviter,
+            external_tree,
+            ratchet_tree,
+        })
+    }
+    /// Joins the group of the welcome checked by [`Self::check_welcome`], fetched from the slot
+    /// if any, returning its gid.
+    ///
+    /// Groups whose welcome left out the ratchet tree keep leaving it out of their own welcomes.
+    /// Who sent the welcome is kept as the group's [`JoinRecord`] and in its audit log.
+    fn join_checked_welcome(
+        &mut self,
+        welcome: Welcome,
+        checked: CheckedWelcome,
+        welcome_slot: Option<&str>,
+    ) -> Result<String, MySgmError> {
+        let CheckedWelcome {
+            gid,
+            inviter,
+            external_tree,
+            ratchet_tree,
+            ..
+        } = checked;
+        let join_config = self.join_config(false);
+        let mut group = ProcessedWelcome::new_from_welcome(&self.provider, &join_config, welcome)?
+            .into_staged_welcome(&self.provider, ratchet_tree)?
+            .into_group(&self.provider)?;
+        tracing::info!("Group with gid: {gid}, joined through {inviter}");
+        if external_tree {
+            group.set_configuration(self.provider.storage(), &self.join_config(true))?;
+            self.provider.state_mut().set_external_tree(&gid);
+        }
+        self.provider.state_mut().add_gid(gid.clone());
+        let record = JoinRecord {
+            inviter: inviter.clone(),
+            epoch: group.epoch().as_u64(),
+            timestamp: unix_time(),
+            welcome_slot: welcome_slot.map(str::to_string),
+        };
+        let event = AuditEvent {
+            seq: 0,
+            timestamp: record.timestamp,
+            epoch: record.epoch,
+            kind: "join".to_string(),
+            actor: Some(inviter),
+            subject: Some(self.state().my_pid().to_string()),
+        };
+        self.provider.state_mut().set_join_record(&gid, record);
+        self.record_audit(&group, vec![event]);
+        self.retain_epoch_exporter(&group)?;
+        for observer in &self.observers {
+            observer.on_welcome(&gid);
+        }
+        Ok(gid)
+    }
+    /// Joins the group of the pending invite with the id, returning its gid; the invite stays
+    /// queued if joining fails.
+    pub async fn accept_invite(&mut self, id: u64) -> Result<String, MySgmError> {
+        let invite = self
+            .state()
+            .pending_invites()
+            .iter()
+            .find(|invite| invite.id == id)
+            .cloned()
+            .ok_or(MySgmError::NoPendingInvite(id))?;
+        let welcome = decode_untrusted::<Welcome>("invite", &invite.welcome)?;
+        let gid = self
+            .join_with_welcome(welcome, None, invite.welcome_slot.as_deref())
+            .await?;
+        self.provider.state_mut().take_pending_invite(id);
+        Ok(gid)
+    }
+    /// Discards the pending invite with the id without joining its group.
+    pub fn decline_invite(&mut self, id: u64) -> Result<PendingInvite, MySgmError> {
+        self.provider
+            .state_mut()
+            .take_pending_invite(id)
+            .ok_or(MySgmError::NoPendingInvite(id))
+    }
+    /// Joins the group of an invitation written by [`Self::export_welcome`], returning its gid.
+    pub async fn import_welcome(&mut self, invitation: &[u8]) -> Result<String, MySgmError> {
+        let invitation: Invitation = json_decode(invitation)?;
+        let MlsMessageBodyIn::Welcome(welcome) =
+            decode_untrusted::<MlsMessageIn>("invitation", &invitation.welcome)?.extract()
+        else {
+            return Err(MySgmError::UnexpectedMessage("Welcome"));
+        };
+        let ratchet_tree = decode_untrusted("invitation", &invitation.ratchet_tree)?;
+        self.join_with_welcome(welcome, Some(ratchet_tree), None)
+            .await
+    }
+    /// Downloads the welcomes addressed to this agent, polling the next welcome slot of every
+    /// published key package concurrently.
+    pub async fn download_welcome_messages(
+        &mut self,
+        report: &mut SyncReport,
+    ) -> Result<(), MySgmError> {
+        self.download_directory_welcomes(report).await?;
+        loop {
+            let kp_refs = self.state().published_key_packages().to_vec();
+            let keys: Vec<String> = kp_refs
+                .iter()
+                .map(|kp_ref| {
+                    welcome_message_key(
+                        self.state().namespace(),
+                        kp_ref,
+                        self.state().welcome_counter(kp_ref),
+                    )
+                })
+                .collect();
+            let mut found = false;
+            let values = self.get_many(&keys).await?;
+            for ((kp_ref, key), wm_bytes) in kp_refs.iter().zip(keys).zip(values) {
+                if let Some(wm_bytes) = wm_bytes {
+                    found = true;
+                    self.provider.state_mut().increment_welcome_counter(kp_ref);
+                    let digest = self.digest(&wm_bytes)?;
+                    if self.state().record_quarantined(&key, &digest) {
+                        tracing::info!("Skipping quarantined welcome {key}");
+                        continue;
+                    }
+                    let processed = match self.open_record(&key, wm_bytes) {
+                        Ok((_, wm_bytes)) => self.process_welcome_message(&key, wm_bytes).await,
+                        Err(e) => Err(e),
+                    };
+                    match processed {
+                        Ok(gid) => report.welcomes.extend(gid),
+                        Err(e) => {
+                            tracing::warn!("Failed to process welcome {key}: {e}");
+                            self.quarantine_if_malformed(&key, digest, RecordKind::Welcome, &e);
+                            report.record(key, e)?;
+                        }
+                    }
+                }
+            }
+            if !found {
+                tracing::info!("No more welcome messages to download");
+                return Ok(());
+            }
+        }
+    }
+    /// Fetches the commit posted for the group's current epoch and merges it.
+    ///
+    /// Returns `false` once no further commit is available or the agent was evicted.
+    pub async fn process_next_commit(&mut self, gid: &str) -> Result<bool, MySgmError> {
+        match self.merge_next_commit(gid).await? {
+            Some((_, Ok(()))) => Ok(true),
+            Some((key, Err(e))) => {
+                tracing::warn!("Failed to merge commit {key}: {e}");
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+    /// Fetches the commit posted for the group's current epoch and merges it, returning its slot
+    /// and whether merging succeeded, or `None` once no further commit is available or the
+    /// agent was evicted.
+    async fn merge_next_commit(
+        &mut self,
+        gid: &str,
+    ) -> Result<Option<(String, Result<(), MySgmError>)>, MySgmError> {
+        // e.g. a commit just reinitialized the group
+        if !self.state().gids().iter().any(|g| g == gid) {
+            return Ok(None);
+        }
+        // the own commit of a pending add is merged by completing the add
+        if self.state().pending_add(gid).is_some() {
+            tracing::info!("Not downloading commits while an add is pending for gid: {gid}");
+            return Ok(None);
+        }
+        let mut group = self.load_group(gid)?;
+        let key = match commit_key(&group, &self.provider) {
+            Ok(k) => k,
+            Err(MySgmError::Evicted) => {
+                tracing::warn!("Evicted from group, stopping commit download for gid: {gid}");
+                let event = self.own_audit_event(&group, "evicted");
+                self.record_audit(&group, vec![event]);
+                group.delete(self.provider.storage())?;
+                let state = self.provider.state_mut();
+                match state.leaving(gid) {
+                    Some(_) => state.mark_gid_left(gid),
+                    None => state.remove_gid(gid),
+                }
+                return Ok(None);
+            }
+            Err(e) => {
+                tracing::warn!("Failed to derive commit key: {e}");
+                return Ok(None);
+            }
+        };
+        tracing::info!("Commit message key to get: {key}");
+        // a quarantined commit slot leaves the commit chain to fall back on
+        let record = match self.get_prefetched(&key).await? {
+            Some(record) => {
+                let digest = self.digest(&record)?;
+                match self.state().record_quarantined(&key, &digest) {
+                    true => None,
+                    false => Some((record, digest)),
+                }
+            }
+            None => None,
+        };
+        let (key, record_digest, cm_bytes) = match record {
+            Some((record, digest)) => {
+                let cm_bytes = self.open_record(&key, record).map(|(_, cm_bytes)| cm_bytes);
+                (key, digest, cm_bytes)
+            }
+            None => match self.fetch_commit_link(&group, gid).await? {
+                Some(link) => link,
+                None => {
+                    tracing::info!("No more commit messages to download for gid: {gid}");
+                    return Ok(None);
+                }
+            },
+        };
+        let kind = RecordKind::Commit(gid.to_string());
+        let cm_bytes = match cm_bytes {
+            Ok(cm_bytes) => cm_bytes,
+            Err(e) => {
+                self.quarantine_if_malformed(&key, record_digest, kind, &e);
+                return Ok(Some((key, Err(e))));
+            }
+        };
+        wire_event("received", "commit", &key, &cm_bytes);
+        let digest = self.digest(&cm_bytes)?;
+        if self.state().commit_processed(&digest) {
+            tracing::info!("Skipping already merged commit for gid {gid}: {digest}");
+            return Ok(None);
+        }
+        // messages of the ending epoch can no longer be decrypted once the commit is merged;
+        // draining them loads the group itself
+        drop(group);
+        self.drain_epoch_messages(gid).await?;
+        let mut group = self.load_group(gid)?;
+        let merged = match decode_untrusted::<MlsMessageIn>(&key, &cm_bytes)
+            .and_then(|message| Ok(message.try_into_protocol_message()?))
+            .and_then(|proto_msg| Ok(group.process_message(&self.provider, proto_msg)?))
+        {
+            Ok(processed_message) => {
+                let sender = processed_message.sender().clone();
+                match processed_message.into_content() {
+                    ProcessedMessageContent::StagedCommitMessage(commit_box) => self
+                        .check_commit_trust(&group, &commit_box, &sender)
+                        .and_then(|()| self.check_commit_policy(&group, &commit_box, &sender))
+                        .and_then(|()| commit_audit_events(&group, &commit_box, &sender))
+                        .and_then(|events| {
+                            group.merge_staged_commit(&self.provider, *commit_box)?;
+                            Ok(events)
+                        }),
+                    _ => Err(MySgmError::UnexpectedMessage("commit")),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        let merged = merged.map(|events| self.record_merged_commit(&group, events));
+        if let Err(e) = &merged {
+            self.quarantine_if_malformed(&key, record_digest, kind, e);
+        }
+        if merged.is_ok() {
+            tracing::info!("Merged commit into group state for gid: {gid}");
+            self.retain_epoch_exporter(&group)?;
+            self.provider
+                .state_mut()
+                .set_commit_head(gid, digest.clone());
+            self.provider.state_mut().mark_commit_processed(digest);
+            if let Some(successor) = group_metadata(&group)?.successor {
+                self.store_reinit_psk(gid, &successor)?;
+                self.retire_predecessor(gid, &successor)?;
+            }
+        }
+        Ok(Some((key, merged)))
+    }
+    /// Fetches the next proposal posted for the group's current epoch and queues it.
+    ///
+    /// Returns `false` once no further proposal is available.
+    pub async fn process_next_proposal(&mut self, gid: &str) -> Result<bool, MySgmError> {
+        let mut group = self.load_group(gid)?;
+        let epoch = group.epoch().as_u64();
+        let key = proposal_key(
+            &group,
+            &self.provider,
+            self.state().proposal_counter(gid, epoch),
+        )?;
+        tracing::info!("Proposal key to get: {key}");
+        let Some(pr_bytes) = self.get_prefetched(&key).await? else {
+            tracing::info!("No more proposals to download for gid: {gid}");
+            return Ok(false);
+        };
+        self.provider
+            .state_mut()
+            .increment_proposal_counter(gid, epoch);
+        wire_event("received", "proposal", &key, &pr_bytes);
+        let pr_bytes = match self.open_member_record(&group, &key, pr_bytes) {
+            Ok(pr_bytes) => pr_bytes,
+            Err(e) => {
+                tracing::warn!("Skipping proposal: {e}");
+                return Ok(true);
+            }
+        };
+        let proto_msg = match decode_untrusted::<MlsMessageIn>(&key, &pr_bytes)
+            .and_then(|message| Ok(message.try_into_protocol_message()?))
+        {
+            Ok(proto_msg) => proto_msg,
+            Err(e) => {
+                tracing::warn!("Skipping proposal: {e}");
+                return Ok(true);
+            }
+        };
+        match group.process_message(&self.provider, proto_msg) {
+            Ok(processed_message) => match processed_message.into_content() {
+                ProcessedMessageContent::ProposalMessage(proposal) => {
+                    group.store_pending_proposal(self.provider.storage(), *proposal)?;
+                    tracing::info!("Queued proposal for gid: {gid}");
+                }
+                _ => return Err(MySgmError::UnexpectedMessage("proposal")),
+            },
+            Err(ProcessMessageError::ValidationError(ValidationError::CannotDecryptOwnMessage)) => {
+                tracing::info!("Skipping own proposal for gid: {gid}");
+            }
+            Err(e) => {
+                tracing::warn!("Failed to process proposal: {e}");
+            }
+        }
+        Ok(true)
+    }
+    /// Commits all queued proposals of the group, if there are any.
+    ///
+    /// Returns `false` if there was nothing to commit or another member committed first.
+    ///
+    /// Proposals other than members leaving can only be committed by admins.
+    pub async fn commit_pending_proposals(&mut self, gid: &str) -> Result<bool, MySgmError> {
+        let mut group = self.load_group(gid)?;
+        if group.pending_proposals().next().is_none() {
+            return Ok(false);
+        }
+        if !group.pending_proposals().all(is_self_removal) {
+            self.require_admin(&group, gid)?;
+        }
+        let key_pair = self.own_key_pair(&group).clone();
+        let (commit, welcome_opt, _) = group.commit_to_pending_proposals(
+            &self.provider,
+            &KeyPairSigner::new(&key_pair, self.provider.crypto()),
+        )?;
+        match self.publish_commit(&mut group, &commit).await {
+            Ok(()) => {}
+            Err(MySgmError::KeyExists) => {
+                tracing::warn!("Another member committed first for gid: {gid}");
+                group.clear_pending_commit(self.provider.storage())?;
+                return Ok(false);
+            }
+            Err(e) => {
+                return Err(e);
+            }
+        }
+        if let Some(welcome) = welcome_opt {
+            self.publish_welcome(&group, &welcome, &[]).await?;
+        }
+        Ok(true)
+    }
+    /// Downloads the commits and proposals of every group but the archived ones.
+    ///
+    /// Groups are merged in rounds: the next commit of every group still advancing is fetched
+    /// concurrently, see [`Self::set_sync_concurrency`], and then merged group by group, since
+    /// all groups share the key store. Idle groups thus cost one round trip in total.
+    pub async fn download_commits(&mut self, report: &mut SyncReport) -> Result<(), MySgmError> {
+        let result = self.download_commits_in_rounds(report).await;
+        self.prefetched.clear();
+        result
+    }
+    async fn download_commits_in_rounds(
+        &mut self,
+        report: &mut SyncReport,
+    ) -> Result<(), MySgmError> {
+        let gids = self.state().synced_gids();
+        let mut advancing = gids.clone();
+        while !advancing.is_empty() {
+            let keys = advancing
+                .iter()
+                .flat_map(|gid| self.next_commit_keys(gid))
+                .collect();
+            self.prefetch(keys).await?;
+            let mut merged_any = Vec::new();
+            for gid in advancing {
+                match self.merge_next_commit(&gid).await? {
+                    Some((_, Ok(()))) => {
+                        report.commits += 1;
+                        merged_any.push(gid);
+                    }
+                    Some((key, Err(e))) => report.record(key, e)?,
+                    None => {}
+                }
+            }
+            advancing = merged_any;
+        }
+        let current = self.state().gids();
+        let gids: Vec<String> = gids.into_iter().filter(|g| current.contains(g)).collect();
+        let keys = gids
+            .iter()
+            .filter_map(|gid| self.next_proposal_key(gid))
+            .collect();
+        self.prefetch(keys).await?;
+        for gid in &gids {
+            self.download_group_proposals(gid, report).await?;
+        }
+        Ok(())
+    }
+    async fn download_group_commits(
+        &mut self,
+        gid: &str,
+        report: &mut SyncReport,
+    ) -> Result<(), MySgmError> {
+        while let Some((key, merged)) = self.merge_next_commit(gid).await? {
+            match merged {
+                Ok(()) => report.commits += 1,
+                Err(e) => {
+                    report.record(key, e)?;
+                    break;
+                }
+            }
+        }
+        if !self.state().gids().iter().any(|g| g == gid) {
+            return Ok(());
+        }
+        self.download_group_proposals(gid, report).await
+    }
+    /// Queues the group's new proposals, committing them right away if they only remove their
+    /// senders and this agent is the member designated to, see [`commits_self_removals`].
+    async fn download_group_proposals(
+        &mut self,
+        gid: &str,
+        report: &mut SyncReport,
+    ) -> Result<(), MySgmError> {
+        while self.process_next_proposal(gid).await? {}
+        let mut group = self.load_group(gid)?;
+        // a commit ended the epoch of the proposal to leave without removing this agent
+        if self
+            .state()
+            .leaving(gid)
+            .is_some_and(|epoch| epoch != group.epoch().as_u64())
+        {
+            tracing::info!("Proposing again to leave gid: {gid}");
+            self.propose_leaving(&mut group, gid).await?;
+        }
+        // other proposals wait for an explicit commit, so that they can be batched
+        let designated = group.pending_proposals().all(is_self_removal)
+            && commits_self_removals(&group, group.own_leaf_index())?;
+        drop(group);
+        if designated && self.commit_pending_proposals(gid).await? {
+            tracing::info!("Committed pending proposals for gid: {gid}");
+            report.commits += 1;
+        }
+        Ok(())
+    }
+    /// Receives the new application messages of every group but the archived ones into the
+    /// history, in rounds like [`Self::download_commits`], counting them in the report and
+    /// collecting them with their gid into `messages` if given.
+    async fn receive_messages(
+        &mut self,
+        report: &mut SyncReport,
+        messages: Option<&mut Vec<(String, ReceivedMessage)>>,
+    ) -> Result<(), MySgmError> {
+        let result = self.receive_messages_in_rounds(report, messages).await;
+        self.prefetched.clear();
+        result
+    }
+    async fn receive_messages_in_rounds(
+        &mut self,
+        report: &mut SyncReport,
+        mut messages: Option<&mut Vec<(String, ReceivedMessage)>>,
+    ) -> Result<(), MySgmError> {
+        let mut receiving = self.state().synced_gids();
+        while !receiving.is_empty() {
+            let keys = receiving
+                .iter()
+                .filter_map(|gid| self.next_message_key(gid))
+                .collect();
+            self.prefetch(keys).await?;
+            let mut received_any = Vec::new();
+            for gid in receiving {
+                match self.process_next_message(&gid).await {
+                    Ok(Some(message)) => {
+                        report.messages += 1;
+                        if let Some(messages) = messages.as_deref_mut() {
+                            messages.push((gid.clone(), message));
+                        }
+                        received_any.push(gid);
+                    }
+                    Ok(None) => {}
+                    Err(e) => report.record(gid, e)?,
+                }
+            }
+            receiving = received_any;
+        }
+        Ok(())
+    }
+    /// Receives the group's new application messages into the history.
+    async fn receive_group_messages(
+        &mut self,
+        gid: &str,
+        report: &mut SyncReport,
+    ) -> Result<(), MySgmError> {
+        loop {
+            match self.process_next_message(gid).await {
+                Ok(Some(_)) => report.messages += 1,
+                Ok(None) => return Ok(()),
+                Err(e) => return report.record(gid.to_string(), e),
+            }
+        }
+    }
+    /// Quarantines the record with the digest under the key if it failed for being malformed,
+    /// so that syncs skip it instead of failing on it again.
+    fn quarantine_if_malformed(
+        &mut self,
+        key: &str,
+        digest: String,
+        kind: RecordKind,
+        e: &MySgmError,
+    ) {
+        if !e.is_malformed() {
+            return;
+        }
+        tracing::warn!("Quarantining malformed record {key}: {e}");
+        self.provider
+            .state_mut()
+            .quarantine_record(QuarantinedRecord {
+                key: key.to_string(),
+                digest,
+                kind,
+                error: e.to_string(),
+                quarantined_at: unix_time(),
+            });
+    }
+    /// Releases the record under the key from quarantine and processes it again as a sync
+    /// would, quarantining it anew if it is still malformed; for a commit, the group's commits
+    /// are downloaded again.
+    pub async fn retry_quarantined(&mut self, key: &str) -> Result<SyncReport, MySgmError> {
+        let record = self
+            .provider
+            .state_mut()
+            .take_quarantined_record(key)
+            .ok_or_else(|| MySgmError::NotQuarantined(key.to_string()))?;
+        let mut report = SyncReport::default();
+        if let RecordKind::Commit(gid) = &record.kind {
+            self.download_group_commits(gid, &mut report).await?;
+            return Ok(report);
+        }
+        let Some(bytes) = self.adapter.get(key).await? else {
+            return Ok(report);
+        };
+        let digest = self.digest(&bytes)?;
+        let processed = match self.open_record(key, bytes) {
+            Ok((signer, value)) if record.kind == RecordKind::KeyPackage => self
+                .process_key_package(value, key.to_string(), Some(&signer), None)
+                .map(|pid| report.key_packages.push(pid)),
+            Ok((_, value)) => self
+                .process_welcome_message(key, value)
+                .await
+                .map(|gid| report.welcomes.extend(gid)),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = processed {
+            self.quarantine_if_malformed(key, digest, record.kind, &e);
+            report.record(key.to_string(), e)?;
+        }
+        Ok(report)
+    }
+    /// Stops syncing the group, keeping its MLS state, until [`Self::unarchive_group`].
+    pub fn archive_group(&mut self, gid: &str) -> Result<(), MySgmError> {
+        if !self.state().gids().iter().any(|g| g == gid) {
+            return Err(MySgmError::GroupNotFound(gid.to_string()));
+        }
+        self.provider.state_mut().set_archived(gid, true);
+        Ok(())
+    }
+    /// Syncs the archived group again, catching up on the commits and application messages
+    /// sent to it in the meantime.
+    pub async fn unarchive_group(&mut self, gid: &str) -> Result<SyncReport, MySgmError> {
+        if !self.state().archived(gid) {
+            return Err(MySgmError::GroupNotArchived(gid.to_string()));
+        }
+        self.provider.state_mut().set_archived(gid, false);
+        let mut report = SyncReport::default();
+        self.download_group_commits(gid, &mut report).await?;
+        if self.state().gids().iter().any(|g| g == gid) {
+            self.receive_group_messages(gid, &mut report).await?;
+        }
+        Ok(report)
+    }
+    /// The cursors syncs scan the delivery service from: one for key packages, one per own key
+    /// package (or the directory) for welcomes, and one for each group's commits and messages.
+    pub fn cursors(&self) -> Result<Vec<ScanCursor>, MySgmError> {
+        let state = self.state();
+        let namespace = state.namespace();
+        let mut cursors = Vec::new();
+        match self.adapter.directory() {
+            Some(_) => cursors.push(ScanCursor {
+                prefix: ScanPrefix::Welcomes,
+                position: state.directory_welcome_counter(),
+                key: "directory".to_string(),
+            }),
+            None => {
+                cursors.push(ScanCursor {
+                    prefix: ScanPrefix::KeyPackages,
+                    position: state.key_package_counter(),
+                    key: key_package_key(namespace, state.key_package_counter()),
+                });
+                cursors.extend(state.published_key_packages().iter().map(|kp_ref| {
+                    let position = state.welcome_counter(kp_ref);
+                    ScanCursor {
+                        prefix: ScanPrefix::Welcomes,
+                        position,
+                        key: welcome_message_key(namespace, kp_ref, position),
+                    }
+                }));
+            }
+        }
+        for gid in state.gids() {
+            let group = self.load_group(&gid)?;
+            let epoch = group.epoch().as_u64();
+            let position = state.message_counter(&gid, epoch);
+            cursors.push(ScanCursor {
+                prefix: ScanPrefix::Commits(gid.clone()),
+                position: epoch,
+                key: commit_chain_key(&gid, epoch),
+            });
+            cursors.push(ScanCursor {
+                prefix: ScanPrefix::Messages(gid),
+                position,
+                key: application_message_key(&group, &self.provider, position)?,
+            });
+        }
+        Ok(cursors)
+    }
+    /// Moves the cursor of the prefix back (or ahead) to the slot, so that the next sync scans
+    /// from there; for welcomes, the cursors of all own key packages are moved.
+    ///
+    /// Slots read again are skipped as duplicates or replays, so this recovers from a cursor
+    /// that ran past slots it failed to read. The commit cursor is the group's epoch, which
+    /// only merging commits moves.
+    pub fn reset_cursor(&mut self, prefix: &ScanPrefix, position: u64) -> Result<(), MySgmError> {
+        match prefix {
+            ScanPrefix::KeyPackages => {
+                self.provider.state_mut().set_key_package_counter(position);
+            }
+            ScanPrefix::Welcomes => {
+                let state = self.provider.state_mut();
+                for kp_ref in state.published_key_packages().to_vec() {
+                    state.set_welcome_counter(&kp_ref, position);
+                }
+                state.set_directory_welcome_counter(position);
+            }
+            ScanPrefix::Commits(gid) => {
+                self.load_group(gid)?;
+                return Err(MySgmError::CursorNotResettable(prefix.to_string()));
+            }
+            ScanPrefix::Messages(gid) => {
+                let epoch = self.load_group(gid)?.epoch().as_u64();
+                self.provider
+                    .state_mut()
+                    .set_message_counter(gid, epoch, position);
+            }
+        }
+        Ok(())
+    }
+    /// Probes the first `max` slots of the prefix in the delivery service, in order, to tell
+    /// whether this agent is behind or the slots are empty; welcomes are probed for each own
+    /// key package, and commits by the epoch they ended in the group's commit chain.
+    pub async fn list_remote(
+        &self,
+        prefix: &ScanPrefix,
+        max: u64,
+    ) -> Result<Vec<RemoteSlot>, MySgmError> {
+        let state = self.state();
+        let namespace = state.namespace();
+        let mut slots: Vec<(String, u64, bool)> = Vec::new();
+        match prefix {
+            ScanPrefix::KeyPackages => {
+                let cursor = state.key_package_counter();
+                slots.extend(
+                    (0..max)
+                        .map(|index| (key_package_key(namespace, index), index, index < cursor)),
+                );
+            }
+            ScanPrefix::Welcomes => {
+                for kp_ref in state.published_key_packages() {
+                    let cursor = state.welcome_counter(kp_ref);
+                    slots.extend((0..max).map(|index| {
+                        let key = welcome_message_key(namespace, kp_ref, index);
+                        (key, index, index < cursor)
+                    }));
+                }
+            }
+            ScanPrefix::Commits(gid) => {
+                let epoch = self.load_group(gid)?.epoch().as_u64();
+                slots.extend(
+                    (0..max).map(|index| (commit_chain_key(gid, index), index, index < epoch)),
+                );
+            }
+            ScanPrefix::Messages(gid) => {
+                let group = self.load_group(gid)?;
+                let cursor = state.message_counter(gid, group.epoch().as_u64());
+                for index in 0..max {
+                    let key = application_message_key(&group, &self.provider, index)?;
+                    slots.push((key, index, index < cursor));
+                }
+            }
+        }
+        let keys: Vec<String> = slots.iter().map(|(key, _, _)| key.clone()).collect();
+        let values = self.get_many(&keys).await?;
+        Ok(slots
+            .into_iter()
+            .zip(values)
+            .map(|((key, index, processed), value)| RemoteSlot {
+                key,
+                index,
+                size: value.map(|value| value.len()),
+                processed,
+            })
+            .collect())
+    }
+    /// Slots that the next sync would find filled first: the next key package and welcome
+    /// slots, and each unarchived group's commit slot and next proposal and message slots.
+    pub fn watched_keys(&self) -> Result<Vec<String>, MySgmError> {
+        let state = self.state();
+        let mut keys = Vec::new();
+        if self.adapter.directory().is_none() {
+            keys.push(key_package_key(
+                state.namespace(),
+                state.key_package_counter(),
+            ));
+            keys.extend(state.published_key_packages().iter().map(|kp_ref| {
+                welcome_message_key(state.namespace(), kp_ref, state.welcome_counter(kp_ref))
+            }));
+        }
+        for gid in state.synced_gids() {
+            let group = self.load_group(&gid)?;
+            let epoch = group.epoch().as_u64();
+            match commit_key(&group, &self.provider) {
+                Ok(key) => keys.push(key),
+                Err(MySgmError::Evicted) => continue,
+                Err(e) => return Err(e),
+            }
+            keys.push(proposal_key(
+                &group,
+                &self.provider,
+                state.proposal_counter(&gid, epoch),
+            )?);
+            keys.push(application_message_key(
+                &group,
+                &self.provider,
+                state.message_counter(&gid, epoch),
+            )?);
+        }
+        Ok(keys)
+    }
+    /// Waits until the delivery service pushes a value to one of the [`Self::watched_keys`], or
+    /// the timeout passes; services without push just wait out the timeout.
+    pub async fn wait_for_delivery(&self, timeout: Duration) -> Result<(), MySgmError> {
+        self.adapter.watch(&self.watched_keys()?, timeout).await
+    }
+    /// Downloads new welcome messages, commits, and key packages, and, with `receive` set,
+    /// decrypts new application messages into the history.
+    ///
+    /// Slots that cannot be processed are skipped and listed in the report; transient delivery
+    /// service errors abort the sync so that it can be retried. Key packages are garbage
+    /// collected afterwards once more than [`KEY_PACKAGE_GC_THRESHOLD`] are held, and published
+    /// records that are no longer relevant are forgotten.
+    pub async fn sync(&mut self, receive: bool) -> Result<SyncReport, MySgmError> {
+        let mut report = SyncReport::default();
+        self.download_welcome_messages(&mut report).await?;
+        self.resume_pending_adds(&mut report).await?;
+        self.download_commits(&mut report).await?;
+        self.resume_key_rotation(&mut report).await?;
+        // after the commits, which may vouch for the new signature key of a rotated identity
+        self.download_key_packages(&mut report).await?;
+        self.reinvite_quarantined(&mut report).await?;
+        if receive {
+            self.receive_messages(&mut report, None).await?;
+        }
+        if let Some(dropped) = self.gc_key_packages_if_needed()? {
+            tracing::info!("Garbage collected {dropped} key packages");
+        }
+        self.forget_stale_records()?;
+        Ok(report)
+    }
+    /// Syncs like [`Self::sync`], but returns the application messages received, each with the
+    /// gid of its group, for embedders that have no other way to read them.
+    pub async fn sync_and_receive(
+        &mut self,
+    ) -> Result<(SyncReport, Vec<(String, ReceivedMessage)>), MySgmError> {
+        let mut report = self.sync(false).await?;
+        let mut messages = Vec::new();
+        self.receive_messages(&mut report, Some(&mut messages))
+            .await?;
+        Ok((report, messages))
+    }
+    /// Creates a group and returns its gid.
+    ///
+    /// With `tree_in_welcome` unset, welcomes leave out the ratchet tree, which is posted to the
+    /// delivery service separately to keep welcomes small in large groups.
+    pub fn create_group(&mut self, gid: &str, tree_in_welcome: bool) -> Result<String, MySgmError> {
+        let gid_transformed = format!(
+            "{}_{}",
+            gid,
+            hex_encode(self.state().signature_key_pair().public_key_raw())
+                .chars()
+                .take(3)
+                .collect::<String>()
+        );
+        if self.state().gids().contains(&gid_transformed) {
+            return Err(MySgmError::GroupExists(gid_transformed));
+        }
+        // the creator starts out as the only admin, and joiners must understand the admin list
+        let required = Extension::RequiredCapabilities(RequiredCapabilitiesExtension::new(
+            &[
+                ExtensionType::Unknown(ADMINS_EXTENSION_TYPE),
+                ExtensionType::Unknown(METADATA_EXTENSION_TYPE),
+            ],
+            &[],
+            &[],
+        ));
+        let admins = Extension::Unknown(
+            ADMINS_EXTENSION_TYPE,
+            UnknownExtension(json_encode(&[self.state().my_pid()])?),
+        );
+        let group = self.build_group(
+            &gid_transformed,
+            self.state().my_ciphersuite(),
+            tree_in_welcome,
+            vec![required, admins],
+        )?;
+        if !tree_in_welcome {
+            self.provider
+                .state_mut()
+                .set_external_tree(&gid_transformed);
+        }
+        self.provider.state_mut().add_gid(gid_transformed.clone());
+        let event = self.own_audit_event(&group, "create");
+        self.record_audit(&group, vec![event]);
+        self.retain_epoch_exporter(&group)?;
+        Ok(gid_transformed)
+    }
+    /// Creates a group with this agent as its only member.
+    fn build_group(
+        &self,
+        gid: &str,
+        ciphersuite: Ciphersuite,
+        tree_in_welcome: bool,
+        extensions: Vec<Extension>,
+    ) -> Result<MlsGroup, MySgmError> {
+        Ok(MlsGroup::builder()
+            .with_group_id(GroupId::from_slice(gid.as_bytes()))
+            .ciphersuite(ciphersuite)
+            .with_wire_format_policy(MIXED_CIPHERTEXT_WIRE_FORMAT_POLICY)
+            .use_ratchet_tree_extension(tree_in_welcome)
+            .with_capabilities(self.capabilities.clone())
+            .with_group_context_extensions(Extensions::from_vec(extensions)?)?
+            .build(&self.provider, &self.provider, self.cred_with_key.clone())?)
+    }
+    /// Reinitializes the group under a new gid, optionally with another ciphersuite, the way an
+    /// MLS ReInit would, returning the new gid; only admins may do so.
+    ///
+    /// A commit to the old group names its successor, upon which members leave it. The new
+    /// group starts with fresh epoch secrets, bound to the last epoch of the old group through
+    /// an external pre-shared key derived from its exporter, so that only members of the old
+    /// group can join it. Its admins and metadata are carried over, and the other members are
+    /// welcomed to it.
+    pub async fn reinit_group(
+        &mut self,
+        gid: &str,
+        ciphersuite: Option<Ciphersuite>,
+    ) -> Result<String, MySgmError> {
+        let old_group = self.load_group(gid)?;
+        self.require_admin(&old_group, gid)?;
+        let ciphersuite = ciphersuite.unwrap_or(old_group.ciphersuite());
+        // the leaf in the new group is signed with this agent's existing key
+        if !self.supported_ciphersuites.contains(&ciphersuite)
+            || ciphersuite.signature_algorithm()
+                != self.state().my_ciphersuite().signature_algorithm()
+        {
+            return Err(MySgmError::UnsupportedCiphersuite(ciphersuite));
+        }
+        let new_gid = format!("{gid}.{}", old_group.epoch().as_u64());
+        if self.state().gids().contains(&new_gid) {
+            return Err(MySgmError::GroupExists(new_gid));
+        }
+        let pids: Vec<String> = self
+            .group_members(gid)?
+            .into_iter()
+            .map(|member| member.pid)
+            .filter(|pid| pid != self.state().my_pid())
+            .collect();
+        // fail before the old group is given up if a member cannot be welcomed
+        self.select_key_packages(&pids, ciphersuite).await?;
+        let metadata = GroupMetadata {
+            successor: Some(new_gid.clone()),
+            ..group_metadata(&old_group)?
+        };
+        self.set_group_extension(old_group, METADATA_EXTENSION_TYPE, json_encode(&metadata)?)
+            .await?;
+        self.store_reinit_psk(gid, &new_gid)?;
+        let old_group = self.load_group(gid)?;
+        let mut extensions: Vec<Extension> = old_group
+            .extensions()
+            .iter()
+            .filter(|extension| {
+                extension.extension_type() != ExtensionType::Unknown(METADATA_EXTENSION_TYPE)
+            })
+            .cloned()
+            .collect();
+        let metadata = GroupMetadata {
+            predecessor: Some(gid.to_string()),
+            successor: None,
+            ..metadata
+        };
+        extensions.push(Extension::Unknown(
+            METADATA_EXTENSION_TYPE,
+            UnknownExtension(json_encode(&metadata)?),
+        ));
+        let tree_in_welcome = !self.state().external_tree(gid);
+        let mut group = self.build_group(&new_gid, ciphersuite, tree_in_welcome, extensions)?;
+        let psk_id = PreSharedKeyId::new(
+            ciphersuite,
+            self.provider.rand(),
+            Psk::External(ExternalPsk::new(reinit_psk_id(gid))),
+        )?;
+        group.propose_external_psk_by_value(&self.provider, &self.provider, psk_id)?;
+        if !tree_in_welcome {
+            self.provider.state_mut().set_external_tree(&new_gid);
+        }
+        self.provider.state_mut().add_gid(new_gid.clone());
+        let event = self.own_audit_event(&group, "create");
+        self.record_audit(&group, vec![event]);
+        self.retire_predecessor(gid, &new_gid)?;
+        // the first commit of the new group injects the pre-shared key along with the adds
+        match pids.is_empty() {
+            true => {
+                let (commit, _, _) =
+                    group.commit_to_pending_proposals(&self.provider, &self.provider)?;
+                self.publish_commit(&mut group, &commit).await?;
+            }
+            false => self.add_to_group(&new_gid, &pids).await?,
+        }
+        Ok(new_gid)
+    }
+    /// Derives and stores the pre-shared key binding the successor of the reinitialized group to
+    /// the group's current, last epoch.
+    fn store_reinit_psk(&self, gid: &str, new_gid: &str) -> Result<(), MySgmError> {
+        let epoch = self.load_group(gid)?.epoch().as_u64();
+        let label = format!("{REINIT_PSK_LABEL} {new_gid}");
+        let secret = self.exporter_for_epoch(gid, epoch, &label, 32)?;
+        self.store_psk(&reinit_psk_id(gid), &secret)
+    }
+    /// Leaves the group replaced by its successor, recording the lineage.
+    fn retire_predecessor(&mut self, gid: &str, new_gid: &str) -> Result<(), MySgmError> {
+        let mut group = self.load_group(gid)?;
+        let event = AuditEvent {
+            subject: Some(new_gid.to_string()),
+            ..self.own_audit_event(&group, "reinit")
+        };
+        self.record_audit(&group, vec![event]);
+        group.delete(self.provider.storage())?;
+        let state = self.provider.state_mut();
+        state.mark_gid_left(gid);
+        state.set_successor(gid, new_gid);
+        tracing::info!("Group {gid} was reinitialized as {new_gid}");
+        Ok(())
+    }
+    /// Posts signed group info with the ratchet tree, letting other agents join externally.
+    pub async fn publish_group_info(&self, gid: &str) -> Result<(), MySgmError> {
+        let group = self.load_group(gid)?;
+        let group_info = group
+            .export_group_info(
+                self.provider.crypto(),
+                &KeyPairSigner::new(self.own_key_pair(&group), self.provider.crypto()),
+                true,
+            )?
+            .tls_serialize_detached()?;
+        let record = json_encode(&GroupInfoRecord {
+            commit_key: commit_key(&group, &self.provider)?,
+            group_info,
+        })?;
+        let index = self
+            .put_first_free_as(
+                self.own_key_pair(&group),
+                0,
+                |index| Ok(group_info_key(gid, index)),
+                &record,
+            )
+            .await?;
+        tracing::info!("Published group info for gid {gid} in slot {index}");
+        Ok(())
+    }
+    /// Joins the group through an external commit against its most recently published group info.
+    pub async fn external_join(&mut self, gid: &str) -> Result<(), MySgmError> {
+        if self.state().gids().iter().any(|known| known == gid) {
+            return Err(MySgmError::GroupExists(gid.to_string()));
+        }
+        let mut record = None;
+        let mut index = 0;
+        loop {
+            let key = group_info_key(gid, index);
+            let Some(bytes) = self.adapter.get(&key).await? else {
+                break;
+            };
+            match self.open_record(&key, bytes) {
+                Ok((_, bytes)) => record = Some((key, bytes)),
+                Err(e) => tracing::warn!("Skipping group info: {e}"),
+            }
+            index += 1;
+        }
+        let (key, record) = record.ok_or_else(|| MySgmError::GroupNotFound(gid.to_string()))?;
+        let record: GroupInfoRecord = json_decode(&record)?;
+        let verifiable_group_info =
+            match decode_untrusted::<MlsMessageIn>(&key, &record.group_info)?.extract() {
+                MlsMessageBodyIn::GroupInfo(group_info) => group_info,
+                _ => return Err(MySgmError::UnexpectedMessage("GroupInfo")),
+            };
+        let (mut group, commit, _) = MlsGroup::join_by_external_commit(
+            &self.provider,
+            &self.provider,
+            None,
+            verifiable_group_info,
+            &self.join_config(false),
+            Some(self.capabilities.clone()),
+            None,
+            &[],
+            self.cred_with_key.clone(),
+        )?;
+        tracing::debug!(
+            "External commit for epoch {}: {} bytes",
+            group.epoch().as_u64(),
+            commit.tls_serialized_len()
+        );
+        // the group context is only reachable through the external commit
+        let version = group
+            .pending_commit()
+            .map(|commit| commit.group_context().protocol_version())
+            .unwrap_or_default();
+        let credential_types: Vec<u16> = group
+            .members()
+            .map(|member| u16::from(member.credential.credential_type()))
+            .collect();
+        let accepted =
+            self.state()
+                .artifact_policy()
+                .check(group.ciphersuite(), version, credential_types);
+        if let Err(violation) = accepted {
+            let epoch = group.epoch().as_u64();
+            group.delete(self.provider.storage())?;
+            let artifact = format!("group info of {gid}");
+            return Err(self.reject_artifact(gid, epoch, None, artifact, violation));
+        }
+        let allowed = group_policy(&group).and_then(|policy| {
+            policy.check_size(gid, group.members().count() + 1)?;
+            match group
+                .pending_commit()
+                .and_then(StagedCommit::update_path_leaf_node)
+            {
+                Some(leaf) => policy.check_candidate(self.state().my_pid(), leaf, None, 0),
+                None => Ok(()),
+            }
+        });
+        if let Err(e) = allowed {
+            group.delete(self.provider.storage())?;
+            return Err(e);
+        }
+        match self
+            .put_record(&record.commit_key, &commit.tls_serialize_detached()?)
+            .await
+        {
+            Ok(()) => {}
+            Err(MySgmError::KeyExists) => {
+                group.delete(self.provider.storage())?;
+                return Err(MySgmError::StaleGroupInfo(gid.to_string()));
+            }
+            Err(e) => {
+                return Err(e);
+            }
+        }
+        group.merge_pending_commit(&self.provider)?;
+        self.provider.state_mut().add_gid(gid.to_string());
+        let event = self.own_audit_event(&group, "join");
+        self.record_audit(&group, vec![event]);
+        self.retain_epoch_exporter(&group)?;
+        Ok(())
+    }
+    /// Publishes a last-resort key package valid for `lifetime` seconds, queueing it for
+    /// [`Self::flush`] if the delivery service is unreachable.
+    pub async fn advertise(&mut self, lifetime: u64) -> Result<(), MySgmError> {
+        let bundle = self.new_last_resort_key_package(lifetime)?;
+        let kp_ref = bundle.key_package().hash_ref(self.provider.crypto())?;
+        let kp_msg = MlsMessageOut::from(bundle.key_package().clone()).tls_serialize_detached()?;
+        wire_event("publishing", "key_package", "", &kp_msg);
+        let publication = Publication::KeyPackage { value: kp_msg };
+        if let Err(e) = self.publish(&publication).await {
+            self.queue_if_transient(e, publication)?;
+        }
+        self.provider.state_mut().add_published_key_package(kp_ref);
+        self.provider.state_mut().set_advertised_at(unix_time());
+        Ok(())
+    }
+    /// Creates a last-resort key package valid for `lifetime` seconds, storing its private keys.
+    fn new_last_resort_key_package(&self, lifetime: u64) -> Result<KeyPackageBundle, MySgmError> {
+        Ok(KeyPackage::builder()
+            .leaf_node_capabilities(self.capabilities.clone())
+            .key_package_lifetime(Lifetime::new(lifetime))
+            .mark_as_last_resort()
+            .build(
+                self.state().my_ciphersuite(),
+                &self.provider,
+                &self.provider,
+                self.cred_with_key.clone(),
+            )?)
+    }
+    /// Returns the newest valid own last-resort key package as an MLS message, for handing to
+    /// other agents out of band; creates one valid for `lifetime` seconds if there is none.
+    ///
+    /// Nothing is posted to the delivery service, so welcomes for an exported key package
+    /// must be delivered through its directory or as invitation files.
+    pub fn export_key_package(&mut self, lifetime: u64) -> Result<Vec<u8>, MySgmError> {
+        let mut current = None;
+        for kp_ref in self.state().published_key_packages().iter().rev() {
+            let bundle: Option<KeyPackageBundle> = self.provider.storage().key_package(kp_ref)?;
+            if let Some(bundle) = bundle
+                && bundle.key_package().last_resort()
+                && bundle.key_package().life_time().is_valid()
+            {
+                current = Some(bundle);
+                break;
+            }
+        }
+        let bundle = match current {
+            Some(bundle) => bundle,
+            None => {
+                let bundle = self.new_last_resort_key_package(lifetime)?;
+                let kp_ref = bundle.key_package().hash_ref(self.provider.crypto())?;
+                self.provider.state_mut().add_published_key_package(kp_ref);
+                bundle
+            }
+        };
+        Ok(MlsMessageOut::from(bundle.key_package().clone()).tls_serialize_detached()?)
+    }
+    /// Validates and stores a key package received out of band exactly as
+    /// [`Self::process_next_key_package`] does, returning its pid.
+    pub fn import_key_package(&mut self, kp_bytes: Vec<u8>) -> Result<String, MySgmError> {
+        self.process_key_package(kp_bytes, "imported key package".to_string(), None, None)
+    }
+    /// Publishes `count` one-time key packages valid for `lifetime` seconds.
+    ///
+    /// Adders use these before falling back to the last-resort key package, so that joins do
+    /// not reuse init keys. The private keys stay in the OpenMLS storage until a welcome
+    /// consumes them.
+    pub async fn advertise_one_time(
+        &mut self,
+        count: u64,
+        lifetime: u64,
+    ) -> Result<(), MySgmError> {
+        self.prune_published_key_packages()?;
+        let mut index = self.state().key_package_counter();
+        for _ in 0..count {
+            let bundle = KeyPackage::builder()
+                .leaf_node_capabilities(self.capabilities.clone())
+                .key_package_lifetime(Lifetime::new(lifetime))
+                .build(
+                    self.state().my_ciphersuite(),
+                    &self.provider,
+                    &self.provider,
+                    self.cred_with_key.clone(),
+                )?;
+            let kp_ref = bundle.key_package().hash_ref(self.provider.crypto())?;
+            let kp_msg =
+                MlsMessageOut::from(bundle.key_package().clone()).tls_serialize_detached()?;
+            let put = self
+                .put_first_free(
+                    index,
+                    |index| Ok(key_package_key(self.state().namespace(), index)),
+                    &kp_msg,
+                )
+                .await;
+            match put {
+                Ok(put_index) => index = put_index + 1,
+                Err(e) => {
+                    let publication = Publication::Slot {
+                        prefix: key_package_prefix(self.state().namespace()),
+                        index,
+                        value: kp_msg,
+                        signer: None,
+                    };
+                    self.queue_if_transient(e, publication)?;
+                }
+            }
+            self.provider.state_mut().add_published_key_package(kp_ref);
+        }
+        Ok(())
+    }
+    /// Forgets published one-time key packages whose private keys were consumed by a welcome,
+    /// returning how many remain unused.
+    pub fn prune_published_key_packages(&mut self) -> Result<usize, MySgmError> {
+        let mut unused = Vec::new();
+        for kp_ref in self.state().published_key_packages() {
+            let bundle: Option<KeyPackageBundle> = self.provider.storage().key_package(kp_ref)?;
+            if bundle.is_some() {
+                unused.push(kp_ref.clone());
+            }
+        }
+        let remaining = unused.len();
+        self.provider.state_mut().set_published_key_packages(unused);
+        Ok(remaining)
+    }
+    /// Drops expired key packages of other agents and forgets own published key packages that
+    /// were consumed or expired, deleting the keys of expired ones; returns how many entries
+    /// were dropped.
+    ///
+    /// Welcomes addressed to own key packages that expired before being downloaded are lost, so
+    /// this is best run right after syncing.
+    pub fn gc_key_packages(&mut self) -> Result<usize, MySgmError> {
+        let state = self.provider.state_mut();
+        let mut dropped = state.retain_key_packages(|kp| kp.life_time().is_valid())
+            + state.retain_all_one_time_key_packages(|kp| kp.life_time().is_valid());
+        let now = unix_time();
+        state.retain_key_package_log(|entry| entry.not_after >= now);
+        for kp_ref in self.state().published_key_packages() {
+            let bundle: Option<KeyPackageBundle> = self.provider.storage().key_package(kp_ref)?;
+            if let Some(bundle) = bundle
+                && !bundle.key_package().life_time().is_valid()
+            {
+                tracing::info!("Deleting expired own key package: {kp_ref:?}");
+                self.provider.storage().delete_key_package(kp_ref)?;
+            }
+        }
+        let published = self.state().published_key_packages().len();
+        dropped += published - self.prune_published_key_packages()?;
+        let kp_refs = self.state().published_key_packages().to_vec();
+        self.provider.state_mut().retain_welcome_counters(&kp_refs);
+        Ok(dropped)
+    }
+    /// Runs [`Self::gc_key_packages`] once more than [`KEY_PACKAGE_GC_THRESHOLD`] key packages
+    /// are held, returning how many entries were dropped, if it ran.
+    pub fn gc_key_packages_if_needed(&mut self) -> Result<Option<usize>, MySgmError> {
+        if self.state().key_package_count() <= KEY_PACKAGE_GC_THRESHOLD {
+            return Ok(None);
+        }
+        self.gc_key_packages().map(Some)
+    }
+    /// Advertises only if nothing was advertised in the current namespace within `max_age`
+    /// seconds, returning whether a key package was published.
+    pub async fn advertise_if_stale(
+        &mut self,
+        lifetime: u64,
+        max_age: u64,
+    ) -> Result<bool, MySgmError> {
+        if let Some(advertised_at) = self.state().advertised_at()
+            && unix_time().saturating_sub(advertised_at) < max_age
+        {
+            tracing::info!("Last advertisement at {advertised_at} is still fresh");
+            return Ok(false);
+        }
+        self.advertise(lifetime).await?;
+        Ok(true)
+    }
+    /// Derives a secret of the length from the MLS exporter of the group's current epoch.
+    pub fn export_secret(
+        &self,
+        gid: &str,
+        label: &str,
+        length: usize,
+    ) -> Result<Vec<u8>, MySgmError> {
+        let group = self.load_group(gid)?;
+        Ok(group.export_secret(&self.provider, label, &[], length)?)
+    }
+    /// Returns the exporter of the group's current epoch or of one of the last
+    /// [`MySgmState::exporter_window`] epochs.
+    pub fn epoch_exporter(&self, gid: &str, epoch: u64) -> Result<EpochExporter, MySgmError> {
+        let group = self.load_group(gid)?;
+        if group.epoch().as_u64() == epoch {
+            return self.current_epoch_exporter(&group);
+        }
+        self.state()
+            .epoch_exporter(gid, epoch)
+            .cloned()
+            .ok_or_else(|| MySgmError::EpochNotRetained(gid.to_string(), epoch))
+    }
+    /// Derives a secret from the exporter of the epoch, as returned by [`Self::epoch_exporter`].
+    ///
+    /// Unlike [`Self::export_secret`], which uses the MLS exporter of the current epoch
+    /// directly, this stays derivable for recent past epochs, so consumers rotating keys can
+    /// catch up on epochs they missed. Retaining exporters weakens forward secrecy for the
+    /// window.
+    pub fn exporter_for_epoch(
+        &self,
+        gid: &str,
+        epoch: u64,
+        label: &str,
+        length: usize,
+    ) -> Result<Vec<u8>, MySgmError> {
+        let exporter = self.epoch_exporter(gid, epoch)?;
+        Ok(self
+            .provider
+            .crypto()
+            .hkdf_expand(
+                HashType::Sha2_256,
+                &exporter.exporter_root,
+                label.as_bytes(),
+                length,
+            )?
+            .as_slice()
+            .to_vec())
+    }
+    /// Encrypts the plaintext with ChaCha20-Poly1305 under a key derived from the group's
+    /// current epoch exporter, as a lightweight alternative to MLS application messages.
+    ///
+    /// The sealed message starts with the epoch, this agent's leaf index, and a counter kept in
+    /// state, which make up the nonce, so that no two messages under a key share one.
+    pub fn seal(&mut self, gid: &str, plaintext: &[u8]) -> Result<Vec<u8>, MySgmError> {
+        let group = self.load_group(gid)?;
+        let (epoch, own_leaf_index) = (group.epoch().as_u64(), group.own_leaf_index());
+        drop(group);
+        let key = self.exporter_for_epoch(gid, epoch, SEAL_KEY_LABEL, 32)?;
+        let counter = self.provider.state_mut().next_seal_counter(gid);
+        let mut sealed = epoch.to_be_bytes().to_vec();
+        sealed.extend(own_leaf_index.u32().to_be_bytes());
+        sealed.extend(counter.to_be_bytes());
+        let ciphertext = ChaCha20Poly1305::new_from_slice(&key)
+            .map_err(|_| MySgmError::SealedMessage)?
+            .encrypt(
+                sealed[8..SEAL_HEADER_LEN].into(),
+                Payload {
+                    msg: plaintext,
+                    aad: &[gid.as_bytes(), &sealed].concat(),
+                },
+            )
+            .map_err(|_| MySgmError::SealedMessage)?;
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+    /// Decrypts a message sealed with [`Self::seal`] in the current or a retained past epoch
+    /// of the group.
+    pub fn open(&self, gid: &str, sealed: &[u8]) -> Result<Vec<u8>, MySgmError> {
+        if sealed.len() < SEAL_HEADER_LEN {
+            return Err(MySgmError::SealedMessage);
+        }
+        let (header, ciphertext) = sealed.split_at(SEAL_HEADER_LEN);
+        let epoch = u64::from_be_bytes(header[..8].try_into().unwrap_or_default());
+        let key = self.exporter_for_epoch(gid, epoch, SEAL_KEY_LABEL, 32)?;
+        ChaCha20Poly1305::new_from_slice(&key)
+            .map_err(|_| MySgmError::SealedMessage)?
+            .decrypt(
+                header[8..].into(),
+                Payload {
+                    msg: ciphertext,
+                    aad: &[gid.as_bytes(), header].concat(),
+                },
+            )
+            .map_err(|_| MySgmError::SealedMessage)
+    }
+    /// Returns the current epoch and its epoch authenticator, which all members in the same
+    /// group state share.
+    pub fn epoch_authenticator(&self, gid: &str) -> Result<(u64, Vec<u8>), MySgmError> {
+        let group = self.load_group(gid)?;
+        Ok((
+            group.epoch().as_u64(),
+            group.epoch_authenticator().as_slice().to_vec(),
+        ))
+    }
+    /// Returns the group's current epoch.
+    pub fn group_epoch(&self, gid: &str) -> Result<u64, MySgmError> {
+        Ok(self.load_group(gid)?.epoch().as_u64())
+    }
+    /// Returns the epoch, ciphersuite, own leaf index, and pending changes of the group.
+    pub fn group_info(&self, gid: &str) -> Result<GroupStatus, MySgmError> {
+        let retention = self.epoch_retention(gid)?;
+        let group = self.load_group(gid)?;
+        Ok(GroupStatus {
+            epoch: group.epoch().as_u64(),
+            ciphersuite: group.ciphersuite(),
+            own_leaf_index: group.own_leaf_index(),
+            member_count: group.members().count(),
+            pending_proposals: group.pending_proposals().count(),
+            pending_commit: group.pending_commit().is_some(),
+            metadata: group_metadata(&group)?,
+            policy: group_policy(&group)?,
+            padding: self.state().padding_policy(gid).cloned(),
+            joined: self.state().join_record(gid).cloned(),
+            archived: self.state().archived(gid),
+            leaving: self.state().leaving(gid).is_some(),
+            retention,
+        })
+    }
+    /// The past epochs of the group whose message secrets are retained.
+    pub fn epoch_retention(&self, gid: &str) -> Result<EpochRetention, MySgmError> {
+        let group = self.load_group(gid)?;
+        let (max_past_epochs, past_epochs) = self
+            .provider
+            .storage()
+            .past_epochs(group.group_id())?
+            .unwrap_or_default();
+        Ok(EpochRetention {
+            max_past_epochs,
+            past_epochs,
+        })
+    }
+    /// Sets the most past epochs of the group whose message secrets are retained, dropping
+    /// those of older epochs right away, and returns how many were dropped.
+    pub fn set_max_past_epochs(&mut self, gid: &str, max: usize) -> Result<usize, MySgmError> {
+        // dropped before the store changes under it, so that the loaded group is not reused
+        let group_id = self.load_group(gid)?.group_id().clone();
+        let storage = self.provider.storage();
+        Ok(storage
+            .retain_past_epochs(&group_id, max, Some(max))?
+            .unwrap_or_default())
+    }
+    /// Drops the message secrets of all but the `keep` latest past epochs of the group, so
+    /// that messages from older epochs can no longer be decrypted, and returns how many were
+    /// dropped; later epochs are still retained up to the group's maximum.
+    pub fn purge_epochs(&mut self, gid: &str, keep: usize) -> Result<usize, MySgmError> {
+        let group_id = self.load_group(gid)?.group_id().clone();
+        let storage = self.provider.storage();
+        Ok(storage
+            .retain_past_epochs(&group_id, keep, None)?
+            .unwrap_or_default())
+    }
+    /// Checks that the signature key signs, that the delivery service stores and returns a
+    /// canary value, that the local clock is within [`MAX_CLOCK_SKEW`] of the service's, and
+    /// that every group loads from storage.
+    ///
+    /// The canary is put under a fresh random key and deleted again, or left to expire where the
+    /// service cannot delete values.
+    pub async fn diagnose(&self) -> Vec<Diagnosis> {
+        let mut diagnoses = vec![
+            Diagnosis::new("signature key", self.check_signature_key()),
+            Diagnosis::new("delivery service", self.check_delivery().await),
+            Diagnosis::new("clock", self.check_clock().await),
+        ];
+        for gid in self.state().gids() {
+            let result = self
+                .group_info(&gid)
+                .map(|status| format!("epoch {}, {} members", status.epoch, status.member_count));
+            diagnoses.push(Diagnosis::new(format!("group {gid}"), result));
+        }
+        diagnoses
+    }
+    fn check_signature_key(&self) -> Result<String, MySgmError> {
+        let key_pair = self.state().signature_key_pair();
+        let payload = b"mysgm diagnosis";
+        let signature = key_pair.sign(self.provider.crypto(), payload)?;
+        self.provider.crypto().verify_signature(
+            key_pair.signature_scheme(),
+            payload,
+            key_pair.public_key_raw(),
+            &signature,
+        )?;
+        Ok(match key_pair.token() {
+            Some(token) => format!(
+                "{:?} on token, {}",
+                key_pair.signature_scheme(),
+                token.label
+            ),
+            None => format!("{:?}", key_pair.signature_scheme()),
+        })
+    }
+    async fn check_delivery(&self) -> Result<String, MySgmError> {
+        let nonce: [u8; 16] = self
+            .provider
+            .rand()
+            .random_array()
+            .map_err(|_| CryptoError::InsufficientRandomness)?;
+        let key = namespaced_key(
+            self.state().namespace(),
+            format!("diagnosis_{}", hex_encode(nonce)),
+        );
+        self.adapter.put_checked(&key, &nonce).await?;
+        match self.adapter.get(&key).await? {
+            Some(value) if value == nonce => {}
+            _ => {
+                return Err(MySgmError::MalformedPayload(
+                    key,
+                    "canary not read back".to_string(),
+                ));
+            }
+        }
+        match self.adapter.delete(&key).await? {
+            true => Ok(format!("stored, read back, and deleted {key}")),
+            false => Ok(format!(
+                "stored and read back {key}, which the service cannot delete"
+            )),
+        }
+    }
+    async fn check_clock(&self) -> Result<String, MySgmError> {
+        let Some(server_time) = self.adapter.server_time().await? else {
+            return Ok("delivery service does not tell its time".to_string());
+        };
+        let skew = unix_time().abs_diff(server_time);
+        match skew <= MAX_CLOCK_SKEW {
+            true => Ok(format!("{skew}s from the delivery service")),
+            false => Err(MySgmError::ClockSkew(skew)),
+        }
+    }
+    /// Returns the leaf index, pid, and signature key of every member of the group.
+    pub fn group_members(&self, gid: &str) -> Result<Vec<GroupMember>, MySgmError> {
+        let group = self.load_group(gid)?;
+        let admins = group_admins(&group)?;
+        let mut members = Vec::new();
+        for member in group.members() {
+            let cred = BasicCredential::try_from(member.credential.clone())?;
+            let pid = String::from_utf8_lossy(cred.identity()).to_string();
+            members.push(GroupMember {
+                index: member.index,
+                admin: admins.as_ref().is_none_or(|admins| admins.contains(&pid)),
+                pid,
+                signature_key: member.signature_key,
+            });
+        }
+        Ok(members)
+    }
+    /// Adds the pids to the group in two phases: the add commit is staged, then the commit and
+    /// the welcome are published, and the commit is merged only once both are out.
+    ///
+    /// Transient delivery service errors leave the add pending, to be completed by
+    /// [`Self::resume_pending_add`] or the next sync.
+    pub async fn add_to_group(&mut self, gid: &str, pids: &[String]) -> Result<(), MySgmError> {
+        self.stage_add(gid, pids, true).await?;
+        self.complete_add(gid).await?;
+        Ok(())
+    }
+    /// Adds the pids that have a valid key package to the group in one commit, leaving out the
+    /// others instead of failing the whole add; nothing is committed if no pid can be added.
+    ///
+    /// Transient delivery service errors still abort the add so that it can be retried.
+    pub async fn add_available_to_group(
+        &mut self,
+        gid: &str,
+        pids: &[String],
+    ) -> Result<AddReport, MySgmError> {
+        let group = self.load_group(gid)?;
+        let mut report = AddReport::default();
+        for pid in pids {
+            let selected = self
+                .select_key_packages(from_ref(pid), group.ciphersuite())
+                .await
+                .and_then(|(kps, _)| self.check_key_packages_policy(&group, &kps));
+            match selected {
+                Ok(()) => report.added.push(pid.clone()),
+                Err(e) if e.is_transient() => return Err(e),
+                Err(
+                    e @ (MySgmError::KeyPackageNotFound(_)
+                    | MySgmError::KeyPackageExpired(_)
+                    | MySgmError::PolicyViolation(..)),
+                ) => {
+                    tracing::warn!("Skipping {pid}: {e}");
+                    report.skipped.push((pid.clone(), e));
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to look up key package of {pid}: {e}");
+                    report.failed.push((pid.clone(), e));
+                }
+            }
+        }
+        drop(group);
+        if !report.added.is_empty() {
+            self.add_to_group(gid, &report.added).await?;
+        }
+        Ok(report)
+    }
+    /// Adds the pid to the group, returning the welcome and ratchet tree as an invitation to be
+    /// handed over out of band instead of through the delivery service.
+    ///
+    /// The add commit is still published, so that the other members follow along.
+    pub async fn export_welcome(&mut self, gid: &str, pid: &str) -> Result<Vec<u8>, MySgmError> {
+        self.stage_add(gid, &[pid.to_string()], false).await?;
+        let (group, welcome) = self.complete_add(gid).await?;
+        let ratchet_tree = group.export_ratchet_tree();
+        Ok(json_encode(&Invitation {
+            welcome,
+            ratchet_tree: ratchet_tree.tls_serialize_detached()?,
+        })?)
+    }
+    /// Stages a commit adding the pids to the group and keeps it, with the welcome for them, as
+    /// the group's pending add; the welcome is posted when the add completes if `post_welcome`
+    /// is set.
+    ///
+    /// One-time key packages used are consumed right away, so that they are never handed out
+    /// again even if the add is resumed later.
+    async fn stage_add(
+        &mut self,
+        gid: &str,
+        pids: &[String],
+        post_welcome: bool,
+    ) -> Result<(), MySgmError> {
+        if self.state().pending_add(gid).is_some() {
+            return Err(MySgmError::AddPending(gid.to_string()));
+        }
+        let mut group = self.load_group(gid)?;
+        self.require_admin(&group, gid)?;
+        let (kps, one_time_kps) = self.select_key_packages(pids, group.ciphersuite()).await?;
+        self.check_key_packages_policy(&group, &kps)?;
+        group_policy(&group)?.check_size(gid, group.members().count() + kps.len())?;
+        let key_pair = self.own_key_pair(&group).clone();
+        let (commit, welcome, _) = group.add_members_without_update(
+            &self.provider,
+            &KeyPairSigner::new(&key_pair, self.provider.crypto()),
+            kps.as_slice(),
+        )?;
+        tracing::debug!(
+            "Add commit for epoch {}: {} bytes",
+            group.epoch().as_u64(),
+            commit.tls_serialized_len()
+        );
+        self.consume_one_time_key_packages(one_time_kps)?;
+        let pending_add = PendingAdd {
+            pids: pids.to_vec(),
+            commit: commit.tls_serialize_detached()?,
+            welcome: welcome.tls_serialize_detached()?,
+            post_welcome,
+            commit_published: false,
+        };
+        self.provider.state_mut().set_pending_add(gid, pending_add);
+        Ok(())
+    }
+    /// Publishes the commit and then the welcome of the group's pending add, merging the commit
+    /// only once both are out; returns the merged group and the welcome.
+    ///
+    /// Transient errors leave the add pending. If the commit loses its slot to another member's
+    /// commit, the add is dropped. Once the commit is out the other members follow it, so other
+    /// failures to post the welcome still merge the commit before they are returned.
+    async fn complete_add(&mut self, gid: &str) -> Result<(LoadedGroup, Vec<u8>), MySgmError> {
+        let pending_add = self
+            .state()
+            .pending_add(gid)
+            .cloned()
+            .ok_or_else(|| MySgmError::NoPendingAdd(gid.to_string()))?;
+        let mut group = self.load_group(gid)?;
+        if group.pending_commit().is_none() {
+            tracing::warn!("Dropping pending add to {gid}: its staged commit is gone");
+            self.provider.state_mut().remove_pending_add(gid);
+            return Err(MySgmError::NoPendingAdd(gid.to_string()));
+        }
+        if !pending_add.commit_published {
+            let key = commit_key(&group, &self.provider)?;
+            tracing::info!("Commit message key to put: {key}");
+            match self
+                .put_record_as(self.own_key_pair(&group), &key, &pending_add.commit)
+                .await
+            {
+                Ok(()) => self.provider.state_mut().mark_pending_add_published(gid),
+                Err(e) if e.is_transient() => return Err(e),
+                Err(e) => {
+                    tracing::warn!("Dropping pending add to {gid}: {e}");
+                    group.clear_pending_commit(self.provider.storage())?;
+                    self.provider.state_mut().remove_pending_add(gid);
+                    return Err(e);
+                }
+            }
+        }
+        let posted = match pending_add.post_welcome {
+            true => self.post_pending_welcome(&group, &pending_add).await,
+            false => Ok(()),
+        };
+        if let Err(e) = posted {
+            if e.is_transient() {
+                return Err(e);
+            }
+            tracing::warn!("Failed to post welcome to {gid}, merging the add anyway: {e}");
+            self.merge_published_commit(&mut group, &pending_add.commit)
+                .await?;
+            self.provider.state_mut().remove_pending_add(gid);
+            return Err(e);
+        }
+        self.merge_published_commit(&mut group, &pending_add.commit)
+            .await?;
+        self.provider.state_mut().remove_pending_add(gid);
+        Ok((group, pending_add.welcome))
+    }
+    /// Posts the welcome of the pending add, preceded by the ratchet tree it leads to for groups
+    /// whose welcomes leave the tree out.
+    async fn post_pending_welcome(
+        &mut self,
+        group: &MlsGroup,
+        pending_add: &PendingAdd,
+    ) -> Result<(), MySgmError> {
+        let gid = String::from_utf8_lossy(group.group_id().as_slice()).to_string();
+        if self.state().external_tree(&gid) {
+            let tree = self.staged_ratchet_tree(&gid)?;
+            self.post_ratchet_tree(&gid, group.epoch().as_u64() + 1, tree, false)
+                .await?;
+        }
+        let MlsMessageBodyIn::Welcome(welcome) =
+            MlsMessageIn::tls_deserialize_exact(&pending_add.welcome)?.extract()
+        else {
+            return Err(MySgmError::UnexpectedMessage("Welcome"));
+        };
+        self.post_welcome(&pending_add.welcome, &welcome, &pending_add.pids, false)
+            .await
+    }
+    /// Serialized ratchet tree of the epoch the group's staged commit leads to, worked out by
+    /// merging it into a copy of the storage.
+    fn staged_ratchet_tree(&self, gid: &str) -> Result<Vec<u8>, MySgmError> {
+        let scratch = ScratchProvider::new(self.provider.storage());
+        let mut group = MlsGroup::load(scratch.storage(), &GroupId::from_slice(gid.as_bytes()))?
+            .ok_or_else(|| MySgmError::GroupNotFound(gid.to_string()))?;
+        group.merge_pending_commit(&scratch)?;
+        Ok(group.export_ratchet_tree().tls_serialize_detached()?)
+    }
+    /// Completes the group's pending add, returning the pids added.
+    pub async fn resume_pending_add(&mut self, gid: &str) -> Result<Vec<String>, MySgmError> {
+        let pids = self
+            .state()
+            .pending_add(gid)
+            .map(|pending_add| pending_add.pids.clone())
+            .ok_or_else(|| MySgmError::NoPendingAdd(gid.to_string()))?;
+        self.complete_add(gid).await?;
+        Ok(pids)
+    }
+    /// Completes the pending adds of all groups, listing the gids in the report.
+    pub async fn resume_pending_adds(&mut self, report: &mut SyncReport) -> Result<(), MySgmError> {
+        for gid in self.state().pending_add_gids() {
+            match self.complete_add(&gid).await {
+                Ok(_) => {
+                    tracing::info!("Completed pending add to {gid}");
+                    report.adds.push(gid);
+                }
+                Err(e) => report.record(gid, e)?,
+            }
+        }
+        Ok(())
+    }
+    /// Picks a key package of the ciphersuite for each pid, preferring a random one-time key
+    /// package over the last-resort one; the one-time key packages picked are also returned with
+    /// their pid.
+    async fn select_key_packages<'a>(
+        &mut self,
+        pids: &'a [String],
+        ciphersuite: Ciphersuite,
+    ) -> Result<(Vec<KeyPackage>, Vec<(&'a String, KeyPackage)>), MySgmError> {
+        let mut kps = Vec::new();
+        let mut one_time_kps = Vec::new();
+        for pid in pids {
+            if self.state().key_package(pid).is_none()
+                && self.state().one_time_key_packages(pid).is_empty()
+                && self.adapter.directory().is_some()
+            {
+                self.fetch_key_package(pid).await?;
+            }
+            self.provider
+                .state_mut()
+                .retain_one_time_key_packages(pid, |kp| kp.life_time().is_valid());
+            let pool: Vec<&KeyPackage> = self
+                .state()
+                .one_time_key_packages(pid)
+                .iter()
+                .filter(|kp| kp.ciphersuite() == ciphersuite)
+                .collect();
+            if !pool.is_empty() {
+                // random pick, so that concurrent adders rarely consume the same key package
+                let pick = u64::from_le_bytes(
+                    self.provider
+                        .rand()
+                        .random_array()
+                        .map_err(|_| CryptoError::InsufficientRandomness)?,
+                );
+                let kp = pool[(pick % pool.len() as u64) as usize].clone();
+                tracing::debug!(
+                    "One-time key package for pid {pid}: {} bytes",
+                    kp.tls_serialized_len()
+                );
+                one_time_kps.push((pid, kp.clone()));
+                kps.push(kp);
+                continue;
+            }
+            match self
+                .state()
+                .key_package(pid)
+                .filter(|kp| kp.ciphersuite() == ciphersuite)
+            {
+                Some(kp) if !kp.life_time().is_valid() => {
+                    return Err(MySgmError::KeyPackageExpired(pid.clone()));
+                }
+                Some(kp) => {
+                    tracing::debug!(
+                        "Key package for pid {pid}: {} bytes",
+                        kp.tls_serialized_len()
+                    );
+                    kps.push(kp.clone());
+                }
+                None => {
+                    return Err(MySgmError::KeyPackageNotFound(pid.clone()));
+                }
+            }
+        }
+        Ok((kps, one_time_kps))
+    }
+    /// Forgets one-time key packages once used, so that no other add reuses them.
+    fn consume_one_time_key_packages(
+        &mut self,
+        used: Vec<(&String, KeyPackage)>,
+    ) -> Result<(), MySgmError> {
+        for (pid, used) in used {
+            let kp_ref = used.hash_ref(self.provider.crypto())?;
+            let state = self.provider.state_mut();
+            state.retain_one_time_key_packages(pid, |kp| kp != &used);
+            state.mark_key_package_consumed(&kp_ref);
+        }
+        Ok(())
+    }
+    /// Leaf indexes of the members with the pids.
+    fn member_indexes(&self, gid: &str, pids: &[String]) -> Result<Vec<LeafNodeIndex>, MySgmError> {
+        let members = self.group_members(gid)?;
+        let mut indexes = Vec::new();
+        for pid in pids {
+            match members.iter().find(|member| &member.pid == pid) {
+                Some(member) => {
+                    tracing::info!("Leaf index for pid {pid}: {}", member.index);
+                    indexes.push(member.index);
+                }
+                None => {
+                    return Err(MySgmError::MemberNotFound(pid.clone()));
+                }
+            }
+        }
+        Ok(indexes)
+    }
+    /// Proposes adding the pids to the group without committing, so that members who are not
+    /// admins can suggest additions for an admin to commit.
+    pub async fn propose_add(&mut self, gid: &str, pids: &[String]) -> Result<(), MySgmError> {
+        let mut group = self.load_group(gid)?;
+        let (kps, one_time_kps) = self.select_key_packages(pids, group.ciphersuite()).await?;
+        let key_pair = self.own_key_pair(&group).clone();
+        for kp in &kps {
+            let (proposal, _) = group.propose_add_member(
+                &self.provider,
+                &KeyPairSigner::new(&key_pair, self.provider.crypto()),
+                kp,
+            )?;
+            self.publish_proposal(&group, &proposal).await?;
+        }
+        self.consume_one_time_key_packages(one_time_kps)?;
+        Ok(())
+    }
+    /// Proposes removing the members with the pids from the group without committing.
+    pub async fn propose_remove(&mut self, gid: &str, pids: &[String]) -> Result<(), MySgmError> {
+        let indexes = self.member_indexes(gid, pids)?;
+        let mut group = self.load_group(gid)?;
+        let key_pair = self.own_key_pair(&group).clone();
+        for index in indexes {
+            let (proposal, _) = group.propose_remove_member(
+                &self.provider,
+                &KeyPairSigner::new(&key_pair, self.provider.crypto()),
+                index,
+            )?;
+            self.publish_proposal(&group, &proposal).await?;
+        }
+        Ok(())
+    }
+    /// Posts a proposal to the first free proposal slot of the group's current epoch.
+    async fn publish_proposal(
+        &self,
+        group: &MlsGroup,
+        proposal: &MlsMessageOut,
+    ) -> Result<(), MySgmError> {
+        tracing::debug!(
+            "Proposal for epoch {}: {} bytes",
+            group.epoch().as_u64(),
+            proposal.tls_serialized_len()
+        );
+        let gid = String::from_utf8_lossy(group.group_id().as_slice()).to_string();
+        self.put_first_free_as(
+            self.own_key_pair(group),
+            self.state().proposal_counter(&gid, group.epoch().as_u64()),
+            |index| proposal_key(group, &self.provider, index),
+            &proposal.tls_serialize_detached()?,
+        )
+        .await?;
+        Ok(())
+    }
+    /// Proposals queued for the group's next commit, own ones included.
+    pub fn pending_proposals(&self, gid: &str) -> Result<Vec<PendingProposal>, MySgmError> {
+        let group = self.load_group(gid)?;
+        let pid_at = |index| group.member(index).map(credential_pid).transpose();
+        let mut proposals = Vec::new();
+        for queued in group.pending_proposals() {
+            let sender = match queued.sender() {
+                Sender::Member(index) => pid_at(*index)?,
+                _ => None,
+            };
+            let (kind, subject) = match queued.proposal() {
+                Proposal::Add(add) => (
+                    "add".to_string(),
+                    Some(credential_pid(add.key_package().leaf_node().credential())?),
+                ),
+                Proposal::Remove(remove) => ("remove".to_string(), pid_at(remove.removed())?),
+                Proposal::SelfRemove => ("remove".to_string(), sender.clone()),
+                proposal => (
+                    format!("{:?}", proposal.proposal_type()).to_lowercase(),
+                    None,
+                ),
+            };
+            proposals.push(PendingProposal {
+                kind,
+                sender,
+                subject,
+            });
+        }
+        Ok(proposals)
+    }
+    /// Removes the members with the pids, looked up by their leaf indexes in the group, with a
+    /// commit that is posted before it is merged; only admins may do so.
+    pub async fn remove_from_group(
+        &mut self,
+        gid: &str,
+        pids: &[String],
+    ) -> Result<(), MySgmError> {
+        let indexes = self.member_indexes(gid, pids)?;
+        let mut group = self.load_group(gid)?;
+        self.require_admin(&group, gid)?;
+        let key_pair = self.own_key_pair(&group).clone();
+        let (commit, welcome_opt, _) = group.remove_members(
+            &self.provider,
+            &KeyPairSigner::new(&key_pair, self.provider.crypto()),
+            indexes.as_slice(),
+        )?;
+        self.publish_commit(&mut group, &commit).await?;
+        match welcome_opt {
+            Some(welcome) => self.publish_welcome(&group, &welcome, &[]).await,
+            None => Ok(()),
+        }
+    }
+    /// Removes the agent from every group shared with it, one commit per group, and refuses its
+    /// signature keys from now on; returns the outcome for each shared group.
+    ///
+    /// With `reinvite` set, the agent is added back to the groups it was removed from during a
+    /// later sync, once a key package with a new signature key arrived for it.
+    pub async fn quarantine(
+        &mut self,
+        pid: &str,
+        reinvite: bool,
+    ) -> Result<Vec<(String, Result<(), MySgmError>)>, MySgmError> {
+        let mut signature_keys: Vec<Vec<u8>> = self
+            .state()
+            .trust(pid)
+            .map(|record| {
+                let changed = record.changed_key.iter().cloned();
+                std::iter::once(record.signature_key.clone())
+                    .chain(changed)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut outcomes = Vec::new();
+        for gid in self.state().gids() {
+            let Some(member) = self
+                .group_members(&gid)?
+                .into_iter()
+                .find(|member| member.pid == pid)
+            else {
+                continue;
+            };
+            if !signature_keys.contains(&member.signature_key) {
+                signature_keys.push(member.signature_key);
+            }
+            let removed = self.remove_from_group(&gid, &[pid.to_string()]).await;
+            outcomes.push((gid, removed));
+        }
+        if signature_keys.is_empty() {
+            return Err(MySgmError::UnknownAgent(pid.to_string()));
+        }
+        let reinvite_gids = match reinvite {
+            true => outcomes
+                .iter()
+                .filter(|(_, removed)| removed.is_ok())
+                .map(|(gid, _)| gid.clone())
+                .collect(),
+            false => Vec::new(),
+        };
+        tracing::info!("Quarantining {pid}");
+        self.provider
+            .state_mut()
+            .quarantine_pid(pid, signature_keys, reinvite_gids);
+        Ok(outcomes)
+    }
+    /// Adds quarantined agents back to the groups they were removed from once a key package is
+    /// held for them, which can only carry a new signature key.
+    ///
+    /// A gid stays pending only if adding failed with a transient error.
+    pub async fn reinvite_quarantined(
+        &mut self,
+        report: &mut SyncReport,
+    ) -> Result<(), MySgmError> {
+        for (pid, gids) in self.state().pending_reinvites() {
+            if self.state().key_package(&pid).is_none()
+                && self.state().one_time_key_packages(&pid).is_empty()
+            {
+                continue;
+            }
+            for gid in gids {
+                let added = match self.state().gids().contains(&gid) {
+                    true => self.add_to_group(&gid, std::slice::from_ref(&pid)).await,
+                    false => Err(MySgmError::GroupNotFound(gid.clone())),
+                };
+                if added.as_ref().is_err_and(MySgmError::is_transient) {
+                    return added;
+                }
+                self.provider.state_mut().remove_reinvite(&pid, &gid);
+                match added {
+                    Ok(()) => {
+                        tracing::info!("Re-invited {pid} to {gid}");
+                        report.reinvited.push((pid.clone(), gid));
+                    }
+                    Err(e) => report.record(gid, e)?,
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Makes the member with the pid an admin of the group; only admins may do so.
+    pub async fn grant_admin(&mut self, gid: &str, pid: &str) -> Result<(), MySgmError> {
+        if !self
+            .group_members(gid)?
+            .iter()
+            .any(|member| member.pid == pid)
+        {
+            return Err(MySgmError::MemberNotFound(pid.to_string()));
+        }
+        let group = self.load_group(gid)?;
+        let mut admins = self.require_admin(&group, gid)?;
+        if !admins.iter().any(|admin| admin == pid) {
+            admins.push(pid.to_string());
+        }
+        self.set_group_admins(group, &admins).await
+    }
+    /// Takes the admin role from the pid; only admins may do so, and the last admin cannot be
+    /// revoked.
+    pub async fn revoke_admin(&mut self, gid: &str, pid: &str) -> Result<(), MySgmError> {
+        let group = self.load_group(gid)?;
+        let mut admins = self.require_admin(&group, gid)?;
+        admins.retain(|admin| admin != pid);
+        if admins.is_empty() {
+            return Err(MySgmError::LastAdmin(gid.to_string()));
+        }
+        self.set_group_admins(group, &admins).await
+    }
+    /// Returns the group's admins, or [`MySgmError::NotAdmin`] if this agent is not one of them.
+    ///
+    /// Groups without an admin list treat every member as admin.
+    fn require_admin(&self, group: &MlsGroup, gid: &str) -> Result<Vec<String>, MySgmError> {
+        let my_pid = self.state().my_pid();
+        match group_admins(group)? {
+            Some(admins) if !admins.iter().any(|admin| admin == my_pid) => {
+                Err(MySgmError::NotAdmin(gid.to_string()))
+            }
+            Some(admins) => Ok(admins),
+            None => Ok(vec![my_pid.to_string()]),
+        }
+    }
+    /// Commits a group context extensions proposal replacing the group's admin list.
+    async fn set_group_admins(
+        &mut self,
+        group: LoadedGroup,
+        admins: &[String],
+    ) -> Result<(), MySgmError> {
+        self.set_group_extension(group, ADMINS_EXTENSION_TYPE, json_encode(admins)?)
+            .await
+    }
+    /// Commits a group context extensions proposal replacing the unknown extension, adding it
+    /// to the group's required capabilities if needed; fails unless all members support it.
+    async fn set_group_extension(
+        &mut self,
+        mut group: LoadedGroup,
+        extension_type: u16,
+        data: Vec<u8>,
+    ) -> Result<(), MySgmError> {
+        let mut extensions = group.extensions().clone();
+        let required = extensions.required_capabilities().cloned();
+        let mut extension_types = required
+            .as_ref()
+            .map(|required| required.extension_types().to_vec())
+            .unwrap_or_default();
+        if !extension_types.contains(&ExtensionType::Unknown(extension_type)) {
+            extension_types.push(ExtensionType::Unknown(extension_type));
+            extensions.add_or_replace(Extension::RequiredCapabilities(
+                RequiredCapabilitiesExtension::new(
+                    &extension_types,
+                    required
+                        .as_ref()
+                        .map_or(&[], |required| required.proposal_types()),
+                    required
+                        .as_ref()
+                        .map_or(&[], |required| required.credential_types()),
+                ),
+            ));
+        }
+        extensions.add_or_replace(Extension::Unknown(extension_type, UnknownExtension(data)));
+        let key_pair = self.own_key_pair(&group).clone();
+        let (commit, _, _) = group.update_group_context_extensions(
+            &self.provider,
+            extensions,
+            &KeyPairSigner::new(&key_pair, self.provider.crypto()),
+        )?;
+        self.publish_commit(&mut group, &commit).await
+    }
+    /// Returns the group's metadata.
+    pub fn group_metadata(&self, gid: &str) -> Result<GroupMetadata, MySgmError> {
+        group_metadata(&self.load_group(gid)?)
+    }
+    /// Commits new metadata for the group, changing the fields that are set and clearing those
+    /// set to an empty string; only admins may do so.
+    pub async fn set_group_metadata(
+        &mut self,
+        gid: &str,
+        update: GroupMetadata,
+    ) -> Result<(), MySgmError> {
+        let group = self.load_group(gid)?;
+        self.require_admin(&group, gid)?;
+        let mut metadata = group_metadata(&group)?;
+        for (field, value) in [
+            (&mut metadata.name, update.name),
+            (&mut metadata.topic, update.topic),
+            (&mut metadata.avatar_hash, update.avatar_hash),
+        ] {
+            if let Some(value) = value {
+                *field = Some(value).filter(|value| !value.is_empty());
+            }
+        }
+        self.set_group_extension(group, METADATA_EXTENSION_TYPE, json_encode(&metadata)?)
+            .await
+    }
+    /// Returns the group's policy.
+    pub fn group_policy(&self, gid: &str) -> Result<GroupPolicy, MySgmError> {
+        group_policy(&self.load_group(gid)?)
+    }
+    /// Commits a new policy for the group, replacing the old one; only admins may do so.
+    ///
+    /// The policy applies to members added from then on; current members are not checked.
+    pub async fn set_group_policy(
+        &mut self,
+        gid: &str,
+        policy: GroupPolicy,
+    ) -> Result<(), MySgmError> {
+        let group = self.load_group(gid)?;
+        self.require_admin(&group, gid)?;
+        self.set_group_extension(group, POLICY_EXTENSION_TYPE, json_encode(&policy)?)
+            .await
+    }
+    /// Pads the application messages this agent sends to the group from now on, or sends them
+    /// unpadded again; other members strip the padding whatever their own policy is.
+    pub fn set_padding_policy(
+        &mut self,
+        gid: &str,
+        policy: Option<PaddingPolicy>,
+    ) -> Result<(), MySgmError> {
+        self.load_group(gid)?;
+        self.provider.state_mut().set_padding_policy(gid, policy);
+        Ok(())
+    }
+    /// Commits fresh leaf keys for this agent, providing post-compromise security.
+    pub async fn self_update(&mut self, gid: &str) -> Result<(), MySgmError> {
+        let mut group = self.load_group(gid)?;
+        let key_pair = self.own_key_pair(&group).clone();
+        let (commit, welcome_opt, _) = group
+            .self_update(
+                &self.provider,
+                &KeyPairSigner::new(&key_pair, self.provider.crypto()),
+                LeafNodeParameters::builder()
+                    .with_capabilities(self.capabilities.clone())
+                    .build(),
+            )?
+            .into_messages();
+        self.publish_commit(&mut group, &commit).await?;
+        match welcome_opt {
+            Some(welcome) => self.publish_welcome(&group, &welcome, &[]).await,
+            None => Ok(()),
+        }
+    }
+    /// Replaces this agent's signature key pair with a fresh one, keeping its pid, and commits
+    /// the new key to every group, then advertises a key package carrying it; returns the gids
+    /// of the groups rotated.
+    ///
+    /// Own key packages carrying the old key are deleted, so welcomes addressed to them can no
+    /// longer be joined. Groups whose commit fails keep signing with the old key until
+    /// [`Self::resume_key_rotation`] commits the new one, which syncing does; the old key is
+    /// retired once no group carries it.
+    pub async fn rotate_identity(&mut self, lifetime: u64) -> Result<Vec<String>, MySgmError> {
+        let signature_scheme = self.state().my_ciphersuite().signature_algorithm();
+        // a key kept on a token is replaced by another generated on the same token
+        let key_pair = match self.state().signature_key_pair().token() {
+            Some(token) => token.regenerate(self.provider.rand(), signature_scheme)?,
+            None => SignatureKeyPair::from_crypto(self.provider.crypto(), signature_scheme)?,
+        };
+        for kp_ref in self.state().published_key_packages().to_vec() {
+            self.provider.storage().delete_key_package(&kp_ref)?;
+        }
+        self.prune_published_key_packages()?;
+        self.cred_with_key.signature_key = key_pair.public_key_raw().into();
+        self.provider
+            .state_mut()
+            .rotate_signature_key_pair(key_pair);
+        let mut report = SyncReport::default();
+        self.resume_key_rotation(&mut report).await?;
+        for (gid, e) in report.errors {
+            tracing::warn!("Failed to rotate the signature key in {gid}: {e}");
+        }
+        self.advertise(lifetime).await?;
+        Ok(report.rotated)
+    }
+    /// Commits the current signature key to the groups whose own leaf still carries a previous
+    /// one, recording them in the report, and retires previous keys no group carries anymore.
+    pub async fn resume_key_rotation(&mut self, report: &mut SyncReport) -> Result<(), MySgmError> {
+        if self.state().previous_signature_key_pairs().is_empty() {
+            return Ok(());
+        }
+        // archived groups get the new key once they are synced again
+        for gid in self.state().synced_gids() {
+            let group = self.load_group(&gid)?;
+            if !group.is_active()
+                || group.own_leaf_node().is_none_or(|leaf| {
+                    leaf.signature_key().as_slice()
+                        == self.state().signature_key_pair().public_key_raw()
+                })
+            {
+                continue;
+            }
+            match self.rotate_group_key(group).await {
+                Ok(()) => {
+                    tracing::info!("Committed the new signature key to {gid}");
+                    report.rotated.push(gid);
+                }
+                Err(e) => report.record(gid, e)?,
+            }
+        }
+        let mut leaf_keys = Vec::new();
+        for gid in self.state().gids() {
+            if let Some(leaf) = self.load_group(&gid)?.own_leaf_node() {
+                leaf_keys.push(leaf.signature_key().as_slice().to_vec());
+            }
+        }
+        self.provider
+            .state_mut()
+            .retain_previous_signature_key_pairs(|key_pair| {
+                leaf_keys
+                    .iter()
+                    .any(|leaf_key| leaf_key == key_pair.public_key_raw())
+            });
+        Ok(())
+    }
+    /// Commits a self-update replacing the signature key of the own leaf with the current one,
+    /// signed with the key it replaces.
+    async fn rotate_group_key(&mut self, mut group: LoadedGroup) -> Result<(), MySgmError> {
+        let old_key_pair = self.own_key_pair(&group).clone();
+        let (commit, welcome_opt, _) = group
+            .self_update_with_new_signer(
+                &self.provider,
+                &KeyPairSigner::new(&old_key_pair, self.provider.crypto()),
+                NewSignerBundle {
+                    signer: &self.provider,
+                    credential_with_key: self.cred_with_key.clone(),
+                },
+                LeafNodeParameters::builder()
+                    .with_capabilities(self.capabilities.clone())
+                    .build(),
+            )?
+            .into_messages();
+        if let Err(e) = self.publish_commit(&mut group, &commit).await {
+            group.clear_pending_commit(self.provider.storage())?;
+            return Err(e);
+        }
+        match welcome_opt {
+            Some(welcome) => self.publish_welcome(&group, &welcome, &[]).await,
+            None => Ok(()),
+        }
+    }
+    /// Stores an external pre-shared key under the id, for use with [`Self::inject_psk`].
+    ///
+    /// Every member has to store the same secret under the same id before the commit injecting
+    /// it can be processed.
+    pub fn store_psk(&self, psk_id: &[u8], secret: &[u8]) -> Result<(), MySgmError> {
+        PreSharedKeyId::external(psk_id.to_vec(), Vec::new()).store(&self.provider, secret)?;
+        Ok(())
+    }
+    /// Commits a proposal injecting the stored external pre-shared key into the group's key
+    /// schedule.
+    pub async fn inject_psk(&mut self, gid: &str, psk_id: &[u8]) -> Result<(), MySgmError> {
+        let mut group = self.load_group(gid)?;
+        let psk_id = PreSharedKeyId::new(
+            group.ciphersuite(),
+            self.provider.rand(),
+            Psk::External(ExternalPsk::new(psk_id.to_vec())),
+        )?;
+        let key_pair = self.own_key_pair(&group).clone();
+        let signer = KeyPairSigner::new(&key_pair, self.provider.crypto());
+        let (_, proposal_ref) =
+            group.propose_external_psk_by_value(&self.provider, &signer, psk_id)?;
+        let (commit, welcome_opt, _) =
+            match group.commit_to_pending_proposals(&self.provider, &signer) {
+                Ok(messages) => messages,
+                Err(e) => {
+                    // e.g. the psk is not stored; keep the proposal from blocking later commits
+                    group.remove_pending_proposal(self.provider.storage(), &proposal_ref)?;
+                    return Err(e.into());
+                }
+            };
+        self.publish_commit(&mut group, &commit).await?;
+        match welcome_opt {
+            Some(welcome) => self.publish_welcome(&group, &welcome, &[]).await,
+            None => Ok(()),
+        }
+    }
+    /// Exports the group's state, for [`Self::import_group`] at another agent of this identity;
+    /// with `remove` set, the group is then dropped here, having moved.
+    ///
+    /// Two agents must not go on using the same leaf, so the group is best removed unless the
+    /// export is only kept as an archive.
+    pub fn export_group(&mut self, gid: &str, remove: bool) -> Result<GroupExport, MySgmError> {
+        let mut group = self.load_group(gid)?;
+        // storage keys of the group's entries embed its serialized id, except for the key pair
+        // of the own leaf, which is stored under its public key
+        let mut storage_ids = vec![json_encode(group.group_id())?];
+        if let Some(leaf) = group.own_leaf_node() {
+            storage_ids.push(json_encode(leaf.encryption_key())?);
+        }
+        let export = self.state().export_group(
+            gid,
+            self.own_key_pair(&group).public_key_raw(),
+            &storage_ids,
+        );
+        if remove {
+            group.delete(self.provider.storage())?;
+            self.provider.state_mut().remove_gid(gid);
+        }
+        Ok(export)
+    }
+    /// Merges a group exported by [`Self::export_group`] at another agent of this identity,
+    /// which must hold the signature key of the own leaf in it; returns the gid.
+    pub fn import_group(&mut self, export: GroupExport) -> Result<String, MySgmError> {
+        let held = |key_pair: &SignatureKeyPair| {
+            hex_encode(key_pair.public_key_raw()) == export.signature_key
+        };
+        if export.pid != self.state().my_pid()
+            || !(held(self.state().signature_key_pair())
+                || self.state().previous_signature_key_pairs().iter().any(held))
+        {
+            return Err(MySgmError::ForeignGroupExport(export.pid));
+        }
+        if self.state().gids().contains(&export.gid) {
+            return Err(MySgmError::GroupExists(export.gid));
+        }
+        let gid = export.gid.clone();
+        self.provider.state_mut().import_group(export);
+        self.load_group(&gid)?;
+        Ok(gid)
+    }
+    /// Publishes group info for all groups and returns a signed link payload from which a new
+    /// device can join them under this agent's pid.
+    pub async fn link_device(&self) -> Result<Vec<u8>, MySgmError> {
+        for gid in self.state().gids() {
+            self.publish_group_info(&gid).await?;
+        }
+        let mut link = DeviceLink {
+            pid: self.state().my_pid().to_string(),
+            ciphersuite: self.state().my_ciphersuite(),
+            signature_key: self.state().signature_key_pair().public_key_raw().to_vec(),
+            gids: self.state().gids(),
+            signature: Vec::new(),
+        };
+        link.signature = self
+            .state()
+            .signature_key_pair()
+            .sign(self.provider.crypto(), &link.signed_content()?)?;
+        Ok(json_encode(&link)?)
+    }
+    /// Links this agent as a device of the identity in a payload from [`Self::link_device`],
+    /// returning the gids joined.
+    ///
+    /// The agent takes the pid `<linked pid>:<own pid>` and joins the linked groups through
+    /// external commits, as a leaf of its own. Only fresh agents without groups can be linked;
+    /// linking again to the same identity retries the groups not joined yet.
+    pub async fn accept_link(&mut self, link: &[u8]) -> Result<Vec<String>, MySgmError> {
+        let link: DeviceLink = json_decode(link)?;
+        self.provider
+            .crypto()
+            .verify_signature(
+                link.ciphersuite.signature_algorithm(),
+                &link.signed_content()?,
+                &link.signature_key,
+                &link.signature,
+            )
+            .map_err(|_| MySgmError::InvalidDeviceLink)?;
+        let prefix = format!("{}:", link.pid);
+        if !self.state().my_pid().starts_with(&prefix) {
+            if !self.state().gids().is_empty() {
+                return Err(MySgmError::DeviceInUse);
+            }
+            let pid = format!("{prefix}{}", self.state().my_pid());
+            tracing::info!("Linking as device pid: {pid}");
+            self.cred_with_key.credential = BasicCredential::new(pid.as_bytes().to_vec()).into();
+            self.provider.state_mut().set_pid(&pid);
+        }
+        let mut joined = Vec::new();
+        for gid in &link.gids {
+            if self.state().gids().contains(gid) {
+                continue;
+            }
+            match self.external_join(gid).await {
+                Ok(()) => joined.push(gid.clone()),
+                Err(e) => tracing::warn!("Failed to join linked group {gid}: {e}"),
+            }
+        }
+        Ok(joined)
+    }
+    /// Posts a proposal removing this agent from the group and marks the group as leaving.
+    ///
+    /// MLS forbids members from committing their own removal, so the proposal is committed by
+    /// the first remaining admin once it syncs. The group is kept, and synced, until that commit
+    /// is merged; if another commit ends the epoch first, syncs propose the removal again.
+    pub async fn leave_group(&mut self, gid: &str) -> Result<(), MySgmError> {
+        let mut group = self.load_group(gid)?;
+        self.propose_leaving(&mut group, gid).await?;
+        let event = self.own_audit_event(&group, "leave");
+        self.record_audit(&group, vec![event]);
+        Ok(())
+    }
+    /// Posts a proposal removing this agent from the group in its current epoch.
+    async fn propose_leaving(&mut self, group: &mut MlsGroup, gid: &str) -> Result<(), MySgmError> {
+        let key_pair = self.own_key_pair(group).clone();
+        let proposal = group.leave_group(
+            &self.provider,
+            &KeyPairSigner::new(&key_pair, self.provider.crypto()),
+        )?;
+        self.publish_proposal(group, &proposal).await?;
+        let epoch = group.epoch().as_u64();
+        self.provider.state_mut().set_leaving(gid, epoch);
+        Ok(())
+    }
+    /// Encrypts an application message for the group and posts it to the first free message slot
+    /// of the current epoch, or queues it for [`Self::flush`] if the delivery service is
+    /// unreachable.
+    pub async fn send_message(&mut self, gid: &str, message: &[u8]) -> Result<(), MySgmError> {
+        let mut group = self.load_group(gid)?;
+        let am_bytes = self.create_application_message(&mut group, gid, message)?;
+        let epoch = group.epoch().as_u64();
+        let publication = Publication::Slot {
+            prefix: application_message_prefix(&group, &self.provider)?,
+            index: self.state().message_counter(gid, epoch),
+            value: am_bytes,
+            signer: self.own_signer(&group),
+        };
+        match self.publish(&publication).await {
+            Ok(()) => Ok(()),
+            Err(e) => self.queue_if_transient(e, publication),
+        }
+    }
+    /// Encrypts the message for the group, numbering it in the authenticated data so that
+    /// receivers can reject replays.
+    fn create_application_message(
+        &mut self,
+        group: &mut MlsGroup,
+        gid: &str,
+        message: &[u8],
+    ) -> Result<Vec<u8>, MySgmError> {
+        let epoch = group.epoch().as_u64();
+        let sequence = self.state().sent_message_counter(gid, epoch);
+        group.set_aad(sequence.to_be_bytes().to_vec());
+        let message = pad_message(self.state().padding_policy(gid), message)?;
+        let key_pair = self.own_key_pair(group).clone();
+        let am_bytes = group
+            .create_message(
+                &self.provider,
+                &KeyPairSigner::new(&key_pair, self.provider.crypto()),
+                &message,
+            )?
+            .tls_serialize_detached()?;
+        self.provider
+            .state_mut()
+            .increment_sent_message_counter(gid, epoch);
+        Ok(am_bytes)
+    }
+    /// Sends the message to each of the groups, encrypting for all of them before putting the
+    /// messages concurrently; returns the outcome for each gid, in the order given.
+    ///
+    /// Messages that cannot be put for a transient error are queued, as by [`Self::send_message`].
+    pub async fn broadcast(
+        &mut self,
+        gids: &[String],
+        message: &[u8],
+    ) -> Vec<(String, Result<(), MySgmError>)> {
+        let mut prepared = Vec::new();
+        let mut failed = Vec::new();
+        for gid in gids {
+            let encrypted = self.load_group(gid).and_then(|mut group| {
+                let am_bytes = self.create_application_message(&mut group, gid, message)?;
+                Ok(Publication::Slot {
+                    prefix: application_message_prefix(&group, &self.provider)?,
+                    index: self.state().message_counter(gid, group.epoch().as_u64()),
+                    value: am_bytes,
+                    signer: self.own_signer(&group),
+                })
+            });
+            match encrypted {
+                Ok(publication) => prepared.push((gid, publication)),
+                Err(e) => failed.push((gid.clone(), Err(e))),
+            }
+        }
+        let agent = &*self;
+        let puts = join_all(
+            prepared
+                .iter()
+                .map(|(gid, publication)| async move { (*gid, agent.publish(publication).await) }),
+        )
+        .await;
+        let mut published = Vec::new();
+        for ((gid, put), (_, publication)) in puts.into_iter().zip(prepared) {
+            let put = match put {
+                Ok(()) => Ok(()),
+                Err(e) => self.queue_if_transient(e, publication),
+            };
+            published.push((gid.clone(), put));
+        }
+        let mut outcomes: Vec<_> = failed.into_iter().chain(published).collect();
+        outcomes.sort_by_key(|(gid, _)| gids.iter().position(|g| g == gid));
+        outcomes
+    }
+    /// Returns the next application message of the group: first those of past epochs received
+    /// while syncing, just before merging the commit that ended their epoch, then those fetched
+    /// and decrypted from the group's current epoch.
+    ///
+    /// Returns the sender pid and plaintext, or `None` once no more messages are available.
+    /// Messages sent by this agent are skipped, since MLS does not let senders decrypt them, and
+    /// so are replayed and out-of-window messages, which are recorded in the audit log.
+    pub async fn process_next_message(
+        &mut self,
+        gid: &str,
+    ) -> Result<Option<ReceivedMessage>, MySgmError> {
+        if let Some(message) = self.drained.get_mut(gid).and_then(VecDeque::pop_front) {
+            return Ok(Some(message));
+        }
+        self.receive_next_message(gid).await
+    }
+    /// Receives the remaining application messages of the group's current epoch into the
+    /// history, keeping them for [`Self::process_next_message`], and returns how many there were.
+    async fn drain_epoch_messages(&mut self, gid: &str) -> Result<usize, MySgmError> {
+        let mut drained = 0;
+        while let Some(message) = self.receive_next_message(gid).await? {
+            self.drained
+                .entry(gid.to_string())
+                .or_default()
+                .push_back(message);
+            drained += 1;
+        }
+        Ok(drained)
+    }
+    /// Fetches and decrypts the next application message of the group's current epoch.
+    async fn receive_next_message(
+        &mut self,
+        gid: &str,
+    ) -> Result<Option<ReceivedMessage>, MySgmError> {
+        let mut group = self.load_group(gid)?;
+        let epoch = group.epoch().as_u64();
+        loop {
+            let key = application_message_key(
+                &group,
+                &self.provider,
+                self.state().message_counter(gid, epoch),
+            )?;
+            tracing::info!("Application message key to get: {key}");
+            let Some(am_bytes) = self.get_prefetched(&key).await? else {
+                tracing::info!("No more application messages to download for gid: {gid}");
+                return Ok(None);
+            };
+            self.provider
+                .state_mut()
+                .increment_message_counter(gid, epoch);
+            wire_event("received", "application_message", &key, &am_bytes);
+            let am_bytes = match self.open_member_record(&group, &key, am_bytes) {
+                Ok(am_bytes) => am_bytes,
+                Err(e) => {
+                    tracing::warn!("Skipping application message: {e}");
+                    continue;
+                }
+            };
+            let proto_msg = match decode_untrusted::<MlsMessageIn>(&key, &am_bytes)
+                .and_then(|message| Ok(message.try_into_protocol_message()?))
+            {
+                Ok(proto_msg) => proto_msg,
+                Err(e) => {
+                    tracing::warn!("Skipping application message: {e}");
+                    continue;
+                }
+            };
+            match group.process_message(&self.provider, proto_msg) {
+                Ok(processed_message) => {
+                    let cred = BasicCredential::try_from(processed_message.credential().clone())?;
+                    let sender = String::from_utf8_lossy(cred.identity()).to_string();
+                    // messages of older builds carry no sequence number
+                    if let Ok(sequence) = <[u8; 8]>::try_from(processed_message.aad()) {
+                        let message_epoch = processed_message.epoch().as_u64();
+                        let sequence = u64::from_be_bytes(sequence);
+                        if let Err(e) =
+                            self.check_sequence(&group, &sender, message_epoch, sequence)
+                        {
+                            tracing::warn!("Skipping application message: {e}");
+                            continue;
+                        }
+                    }
+                    match processed_message.into_content() {
+                        ProcessedMessageContent::ApplicationMessage(message) => {
+                            let message = match unpad_message(&key, message.into_bytes()) {
+                                Ok(message) => message,
+                                Err(e) => {
+                                    tracing::warn!("Skipping application message: {e}");
+                                    continue;
+                                }
+                            };
+                            self.provider.state_mut().append_history(
+                                gid,
+                                HistoryEntry {
+                                    seq: 0,
+                                    sender: sender.clone(),
+                                    epoch,
+                                    timestamp: unix_time(),
+                                    message: message.clone(),
+                                },
+                            );
+                            for observer in &self.observers {
+                                observer.on_message(gid, &sender, &message);
+                            }
+                            return Ok(Some((sender, message)));
+                        }
+                        _ => return Err(MySgmError::UnexpectedMessage("application")),
+                    }
+                }
+                Err(ProcessMessageError::ValidationError(
+                    ValidationError::CannotDecryptOwnMessage,
+                )) => {
+                    tracing::info!("Skipping own application message for gid: {gid}");
+                }
+                Err(ProcessMessageError::ValidationError(
+                    e @ (ValidationError::UnableToDecrypt(MessageDecryptionError::SecretTreeError(
+                        _,
+                    ))
+                    | ValidationError::NoPastEpochData
+                    | ValidationError::WrongEpoch),
+                )) => {
+                    // the sender is encrypted along with the content, so it remains unknown
+                    let e = self.reject_replay(&group, None, e.to_string());
+                    tracing::warn!("Skipping application message: {e}");
+                }
+                Err(e) => {
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+}
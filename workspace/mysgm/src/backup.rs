@@ -0,0 +1,112 @@
+use super::{
+    error::MySgmError,
+    migration,
+    persistence::{decrypt, encrypt},
+    state::MySgmState,
+};
+
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use openmls_rust_crypto::RustCrypto;
+use openmls_traits::{crypto::OpenMlsCrypto, types::HashType};
+use serde::{Deserialize, Serialize};
+use serde_json::{
+    Value, from_slice as json_decode_slice, from_str as json_decode, json,
+    to_string as json_encode, to_value, to_vec,
+};
+use serde_with::{hex::Hex, serde_as};
+use std::{
+    fs::{File, read_to_string as read_file_to_string},
+    io::{Read, Write},
+};
+use web_time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the backup archive format written by [`write_backup`].
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+
+/// Identifies a file as a backup archive.
+const BACKUP_MAGIC: &str = "mysgm backup";
+
+/// On-disk backup of agent state.
+///
+/// The payload is the gzip-compressed JSON of the state, encrypted like a JSON state file if a
+/// passphrase was given; the digest covers the payload as stored.
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+struct BackupArchive {
+    magic: String,
+    format_version: u32,
+    created_at: u64,
+    /// Whether the message history was included.
+    history: bool,
+    encrypted: bool,
+    #[serde_as(as = "Hex")]
+    sha256: Vec<u8>,
+    #[serde_as(as = "Hex")]
+    payload: Vec<u8>,
+}
+
+fn digest(payload: &[u8]) -> Result<Vec<u8>, MySgmError> {
+    Ok(RustCrypto::default().hash(HashType::Sha2_256, payload)?)
+}
+
+/// Writes a backup of the state to the file, leaving out the message history unless
+/// `include_history` is set, and encrypting it if a passphrase is given.
+pub fn write_backup(
+    path: &str,
+    state: &MySgmState,
+    include_history: bool,
+    passphrase: Option<&str>,
+) -> Result<(), MySgmError> {
+    let mut value = to_value(state)?;
+    if !include_history && let Value::Object(fields) = &mut value {
+        fields.insert("history".to_string(), json!({}));
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&to_vec(&value)?)?;
+    let compressed = encoder.finish()?;
+    let payload = match passphrase {
+        Some(passphrase) => encrypt(&compressed, state.version(), passphrase)?.into_bytes(),
+        None => compressed,
+    };
+    let archive = BackupArchive {
+        magic: BACKUP_MAGIC.to_string(),
+        format_version: BACKUP_FORMAT_VERSION,
+        created_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default(),
+        history: include_history,
+        encrypted: passphrase.is_some(),
+        sha256: digest(&payload)?,
+        payload,
+    };
+    let mut file = File::create(path)?;
+    file.write_all(json_encode(&archive)?.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Reads a backup written by [`write_backup`], checking its format version and digest before
+/// decrypting and decoding the state.
+pub fn read_backup(path: &str, passphrase: Option<&str>) -> Result<MySgmState, MySgmError> {
+    let archive: BackupArchive = json_decode(&read_file_to_string(path)?)?;
+    if archive.magic != BACKUP_MAGIC {
+        return Err(MySgmError::InvalidBackup(path.to_string()));
+    }
+    if archive.format_version != BACKUP_FORMAT_VERSION {
+        return Err(MySgmError::UnsupportedBackupVersion(archive.format_version));
+    }
+    if digest(&archive.payload)? != archive.sha256 {
+        return Err(MySgmError::BackupChecksum(path.to_string()));
+    }
+    let compressed = match (archive.encrypted, passphrase) {
+        (true, Some(passphrase)) => {
+            decrypt(&String::from_utf8_lossy(&archive.payload), passphrase)?
+        }
+        (true, None) => return Err(MySgmError::StateDecryption),
+        (false, _) => archive.payload,
+    };
+    let mut plaintext = Vec::new();
+    GzDecoder::new(compressed.as_slice()).read_to_end(&mut plaintext)?;
+    migration::decode(json_decode_slice(&plaintext)?)
+}
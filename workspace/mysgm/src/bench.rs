@@ -0,0 +1,150 @@
+//! Benchmark of one group of simulated agents sharing a [`MemoryAdapter`].
+//!
+//! The creator validates the key packages of the other members, adds them in one commit, and
+//! updates its leaf; the members process their welcomes and merge the update; then the creator
+//! sends messages that one member decrypts. Delivery costs nothing, so the timings are those
+//! of the MLS operations and the state bookkeeping around them.
+
+use super::{
+    agent::MySgmAgent, error::MySgmError, memory_adapter::MemoryAdapter, state::MySgmState,
+};
+
+use core::time::Duration;
+use openmls::prelude::Ciphersuite;
+use openmls_rust_crypto::RustCrypto;
+use std::time::Instant;
+
+/// Lifetime of the key packages the simulated agents advertise, in seconds.
+const KEY_PACKAGE_LIFETIME: u64 = 86400;
+
+/// Time spent on `count` operations of one kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub count: usize,
+    pub elapsed: Duration,
+}
+
+impl Timing {
+    /// Operations per second, or zero if nothing was timed.
+    pub fn per_second(&self) -> f64 {
+        match self.elapsed.is_zero() {
+            true => 0.0,
+            false => self.count as f64 / self.elapsed.as_secs_f64(),
+        }
+    }
+}
+
+/// Timings of a [`run`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BenchReport {
+    pub members: usize,
+    /// Key packages the creator downloaded and validated, its own included.
+    pub key_package_validation: Timing,
+    /// The creator's commit adding the other members.
+    pub add_commit: Timing,
+    /// Welcomes the other members processed.
+    pub welcome_processing: Timing,
+    /// Merges of the creator's update by the other members.
+    pub commit_merging: Timing,
+    /// Messages the creator encrypted and sent.
+    pub encryption: Timing,
+    /// Messages one member received and decrypted.
+    pub decryption: Timing,
+}
+
+/// Runs the benchmark with `members` agents, including the creator, and `messages` messages.
+pub async fn run(members: usize, messages: usize) -> Result<BenchReport, MySgmError> {
+    if members < 2 {
+        return Err(MySgmError::InvalidArgument(
+            "a benchmark needs at least 2 members",
+        ));
+    }
+    let adapter = MemoryAdapter::new();
+    let mut agents = Vec::with_capacity(members);
+    for index in 0..members {
+        let crypto = RustCrypto::default();
+        let state = MySgmState::generate(
+            &format!("bench{index}"),
+            Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519,
+            &crypto,
+        )?;
+        let mut agent = MySgmAgent::new(state, crypto, Box::new(adapter.clone()));
+        agent.advertise(KEY_PACKAGE_LIFETIME).await?;
+        agents.push(agent);
+    }
+    let pids: Vec<String> = agents[1..]
+        .iter()
+        .map(|agent| agent.state().my_pid().to_string())
+        .collect();
+    let (creator, others) = agents.split_first_mut().expect("at least 2 members");
+
+    let start = Instant::now();
+    let report = creator.sync(false).await?;
+    let key_package_validation = Timing {
+        count: report.key_packages.len(),
+        elapsed: start.elapsed(),
+    };
+
+    let gid = creator.create_group("bench", true)?;
+    let start = Instant::now();
+    creator.add_to_group(&gid, &pids).await?;
+    let add_commit = Timing {
+        count: 1,
+        elapsed: start.elapsed(),
+    };
+
+    let mut welcome_processing = Timing {
+        count: 0,
+        elapsed: Duration::ZERO,
+    };
+    for agent in others.iter_mut() {
+        let start = Instant::now();
+        let report = agent.sync(false).await?;
+        welcome_processing.elapsed += start.elapsed();
+        welcome_processing.count += report.welcomes.len();
+    }
+
+    creator.self_update(&gid).await?;
+    let mut commit_merging = Timing {
+        count: 0,
+        elapsed: Duration::ZERO,
+    };
+    for agent in others.iter_mut() {
+        let start = Instant::now();
+        let report = agent.sync(false).await?;
+        commit_merging.elapsed += start.elapsed();
+        commit_merging.count += report.commits;
+    }
+
+    let start = Instant::now();
+    for index in 0..messages {
+        creator
+            .send_message(&gid, format!("bench message {index}").as_bytes())
+            .await?;
+    }
+    let encryption = Timing {
+        count: messages,
+        elapsed: start.elapsed(),
+    };
+
+    let receiver = &mut others[0];
+    let start = Instant::now();
+    let mut received = 0;
+    while receiver.process_next_message(&gid).await?.is_some() {
+        received += 1;
+    }
+    let decryption = Timing {
+        count: received,
+        elapsed: start.elapsed(),
+    };
+
+    Ok(BenchReport {
+        members,
+        key_package_validation,
+        add_commit,
+        welcome_processing,
+        commit_merging,
+        encryption,
+        decryption,
+    })
+}
@@ -0,0 +1,83 @@
+//! Agent state kept in the browser's local storage, under WebAssembly.
+//!
+//! The state is stored in the same JSON format as state files, optionally encrypted, under a
+//! key per profile, so that several identities can share an origin. As with state files, a save
+//! is refused if another tab saved the state since it was loaded.
+
+use super::{
+    error::MySgmError,
+    persistence::{decode_state, encode_state, version_of},
+    state::MySgmState,
+};
+
+use web_sys::Storage;
+
+/// Prefix of the local storage keys holding agent state.
+pub const KEY_PREFIX: &str = "mysgm/";
+
+/// State of one profile in the browser's local storage.
+#[derive(Clone, Debug)]
+pub struct BrowserStorage {
+    key: String,
+}
+
+fn storage_error(e: wasm_bindgen::JsValue) -> MySgmError {
+    MySgmError::BrowserStorage(format!("{e:?}"))
+}
+
+impl BrowserStorage {
+    pub fn new(profile: &str) -> Self {
+        Self {
+            key: format!("{KEY_PREFIX}{profile}"),
+        }
+    }
+    fn storage() -> Result<Storage, MySgmError> {
+        web_sys::window()
+            .ok_or_else(|| MySgmError::BrowserStorage("no window".to_string()))?
+            .local_storage()
+            .map_err(storage_error)?
+            .ok_or_else(|| MySgmError::BrowserStorage("no local storage".to_string()))
+    }
+    fn contents(&self) -> Result<Option<String>, MySgmError> {
+        Self::storage()?.get_item(&self.key).map_err(storage_error)
+    }
+    /// Returns the version of the stored state, or `None` if there is no readable state.
+    pub fn stored_version(&self) -> Option<u64> {
+        version_of(&self.contents().ok()??)
+    }
+    /// Loads the state, decrypting it if a passphrase is given, or returns `None` if the
+    /// profile has no state yet.
+    pub fn load(&self, passphrase: Option<&str>) -> Result<Option<MySgmState>, MySgmError> {
+        self.contents()?
+            .map(|contents| decode_state(&contents, passphrase))
+            .transpose()
+    }
+    /// Saves the state, encrypting it if a passphrase is given.
+    ///
+    /// Refuses with [`MySgmError::StateConflict`] if the stored state changed since the state
+    /// was loaded; otherwise the state's version is incremented with the save.
+    pub fn save(&self, state: &MySgmState, passphrase: Option<&str>) -> Result<(), MySgmError> {
+        let loaded = state.version();
+        if let Some(stored) = self.stored_version()
+            && stored != loaded
+        {
+            return Err(MySgmError::StateConflict { loaded, stored });
+        }
+        state.set_version(loaded + 1);
+        let result = encode_state(state, passphrase).and_then(|contents| {
+            Self::storage()?
+                .set_item(&self.key, &contents)
+                .map_err(storage_error)
+        });
+        if result.is_err() {
+            state.set_version(loaded);
+        }
+        result
+    }
+    /// Deletes the stored state.
+    pub fn clear(&self) -> Result<(), MySgmError> {
+        Self::storage()?
+            .remove_item(&self.key)
+            .map_err(storage_error)
+    }
+}
@@ -0,0 +1,182 @@
+use super::{
+    adapter::{DeliveryAdapter, KeyPackageDirectory},
+    error::MySgmError,
+};
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use hex::encode as hex_encode;
+use openmls_rust_crypto::RustCrypto;
+use openmls_traits::{crypto::OpenMlsCrypto, types::HashType};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice as json_decode, to_vec as json_encode};
+use std::time::Duration;
+
+/// Largest value stored in one record by default, leaving room for OpenDHT's encoding overhead
+/// below its 64 KiB value limit.
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 32 * 1024;
+
+/// Largest chunked value reassembled by default; manifests are unauthenticated, so anything
+/// they claim beyond this is refused before fetching or allocating.
+pub const DEFAULT_MAX_CHUNKED_SIZE: usize = 16 * 1024 * 1024;
+
+/// Prefix marking a record as the manifest of a chunked value.
+const MANIFEST_MAGIC: &[u8] = b"mysgm chunked\0";
+
+/// Describes the parts a large value was split into.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    /// Total length of the value.
+    len: usize,
+    /// Number of parts.
+    parts: usize,
+    /// Hex SHA-256 digest of the value.
+    sha256: String,
+}
+
+/// Splits values larger than a size limit over several records of the wrapped adapter.
+///
+/// A large value is stored as parts under `<key>.<digest>.part<N>`, followed by a manifest
+/// under the key itself that lists the parts and the digest of the whole value. Reading the key
+/// reassembles the value and checks the digest. Parts are written before the manifest, so the
+/// key is never taken by an incomplete value.
+#[derive(Debug)]
+pub struct ChunkingAdapter {
+    inner: Box<dyn DeliveryAdapter>,
+    max_value_size: usize,
+    max_chunked_size: usize,
+}
+
+impl ChunkingAdapter {
+    pub fn new(inner: Box<dyn DeliveryAdapter>) -> Self {
+        Self::with_max_value_size(inner, DEFAULT_MAX_VALUE_SIZE)
+    }
+    pub fn with_max_value_size(inner: Box<dyn DeliveryAdapter>, max_value_size: usize) -> Self {
+        Self {
+            inner,
+            max_value_size: max_value_size.max(1),
+            max_chunked_size: DEFAULT_MAX_CHUNKED_SIZE,
+        }
+    }
+    /// Refuses to reassemble values longer than `max_chunked_size` bytes.
+    pub fn with_max_chunked_size(mut self, max_chunked_size: usize) -> Self {
+        self.max_chunked_size = max_chunked_size;
+        self
+    }
+    fn digest(value: &[u8]) -> Result<String, MySgmError> {
+        Ok(hex_encode(
+            RustCrypto::default().hash(HashType::Sha2_256, value)?,
+        ))
+    }
+    /// Returns the manifest to store the value under, or `None` if it fits in one record.
+    fn manifest(&self, value: &[u8]) -> Result<Option<Manifest>, MySgmError> {
+        if value.len() <= self.max_value_size && !value.starts_with(MANIFEST_MAGIC) {
+            return Ok(None);
+        }
+        Ok(Some(Manifest {
+            len: value.len(),
+            parts: value.len().div_ceil(self.max_value_size),
+            sha256: Self::digest(value)?,
+        }))
+    }
+    fn encode_manifest(manifest: &Manifest) -> Result<Vec<u8>, MySgmError> {
+        let mut bytes = MANIFEST_MAGIC.to_vec();
+        bytes.extend(json_encode(manifest)?);
+        Ok(bytes)
+    }
+    fn part_key(key: &str, digest: &str, index: usize) -> String {
+        format!("{key}.{digest}.part{index}")
+    }
+    async fn reassemble(&self, key: &str, manifest: Manifest) -> Result<Vec<u8>, MySgmError> {
+        let corrupt = || MySgmError::CorruptChunks(key.to_string());
+        // the manifest may be forged: check its claims before trusting them with allocations
+        if manifest.len > self.max_chunked_size
+            || manifest.parts != manifest.len.div_ceil(self.max_value_size)
+        {
+            return Err(corrupt());
+        }
+        let parts = join_all((0..manifest.parts).map(|index| {
+            let part_key = Self::part_key(key, &manifest.sha256, index);
+            async move { self.inner.get(&part_key).await }
+        }))
+        .await;
+        let mut value = Vec::with_capacity(manifest.len);
+        for part in parts {
+            let part = part?.ok_or_else(corrupt)?;
+            if value.len() + part.len() > manifest.len {
+                return Err(corrupt());
+            }
+            value.extend(part);
+        }
+        if value.len() != manifest.len || Self::digest(&value)? != manifest.sha256 {
+            return Err(corrupt());
+        }
+        Ok(value)
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl DeliveryAdapter for ChunkingAdapter {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, MySgmError> {
+        let Some(value) = self.inner.get(key).await? else {
+            return Ok(None);
+        };
+        let Some(manifest) = value.strip_prefix(MANIFEST_MAGIC) else {
+            return Ok(Some(value));
+        };
+        let manifest: Manifest = json_decode(manifest)?;
+        tracing::info!("Reassembling {key} from {} parts", manifest.parts);
+        Ok(Some(self.reassemble(key, manifest).await?))
+    }
+    async fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
+        let Some(manifest) = self.manifest(value)? else {
+            return self.inner.put_checked(key, value).await;
+        };
+        if self.inner.get(key).await?.is_some() {
+            return Err(MySgmError::KeyExists);
+        }
+        tracing::info!("Splitting {key} into {} parts", manifest.parts);
+        for (index, chunk) in value.chunks(self.max_value_size).enumerate() {
+            match self
+                .inner
+                .put_checked(&Self::part_key(key, &manifest.sha256, index), chunk)
+                .await
+            {
+                // left by an earlier attempt with the same value
+                Ok(()) | Err(MySgmError::KeyExists) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.inner
+            .put_checked(key, &Self::encode_manifest(&manifest)?)
+            .await
+    }
+    /// Puts the value again, refreshing every part and the manifest of a chunked value.
+    async fn republish(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
+        let Some(manifest) = self.manifest(value)? else {
+            return self.inner.republish(key, value).await;
+        };
+        for (index, chunk) in value.chunks(self.max_value_size).enumerate() {
+            self.inner
+                .republish(&Self::part_key(key, &manifest.sha256, index), chunk)
+                .await?;
+        }
+        self.inner
+            .republish(key, &Self::encode_manifest(&manifest)?)
+            .await
+    }
+    /// Deletes the value, leaving the parts of a chunked value to expire.
+    async fn delete(&self, key: &str) -> Result<bool, MySgmError> {
+        self.inner.delete(key).await
+    }
+    async fn watch(&self, keys: &[String], timeout: Duration) -> Result<(), MySgmError> {
+        self.inner.watch(keys, timeout).await
+    }
+    async fn server_time(&self) -> Result<Option<u64>, MySgmError> {
+        self.inner.server_time().await
+    }
+    fn directory(&self) -> Option<&dyn KeyPackageDirectory> {
+        self.inner.directory()
+    }
+}
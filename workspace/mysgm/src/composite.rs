@@ -0,0 +1,204 @@
+use super::{
+    adapter::{DeliveryAdapter, KeyPackageDirectory},
+    error::MySgmError,
+};
+
+use async_trait::async_trait;
+use core::future::Future;
+use futures::future::{join_all, select_all};
+use std::time::Duration;
+
+/// Publishes to several delivery services at once and merges what they return, so that
+/// delivery keeps working while one of them is down.
+///
+/// The adapters are ordered by preference. A put goes to the first adapter that answers, which
+/// decides whether the key is taken, and is then copied to the others; a get asks all adapters
+/// concurrently and returns the value of the first adapter holding one. A service being down
+/// only fails an operation if every adapter fails.
+#[derive(Debug)]
+pub struct CompositeAdapter {
+    inner: Vec<Box<dyn DeliveryAdapter>>,
+}
+
+impl CompositeAdapter {
+    pub fn new(inner: Vec<Box<dyn DeliveryAdapter>>) -> Self {
+        Self { inner }
+    }
+    fn directories(&self) -> Vec<&dyn KeyPackageDirectory> {
+        self.inner
+            .iter()
+            .filter_map(|adapter| adapter.directory())
+            .collect()
+    }
+    /// Runs the operation on every adapter, succeeding if any of them did and failing with the
+    /// first error otherwise.
+    async fn fan_out<'a, T, F>(
+        &'a self,
+        targets: Vec<T>,
+        operation: impl Fn(T) -> F,
+    ) -> Result<(), MySgmError>
+    where
+        F: Future<Output = Result<(), MySgmError>> + 'a,
+    {
+        let mut first_error = None;
+        let mut succeeded = false;
+        for result in join_all(targets.into_iter().map(operation)).await {
+            match result {
+                Ok(()) => succeeded = true,
+                Err(e) => {
+                    tracing::warn!("Delivery backend failed: {e}");
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match (succeeded, first_error) {
+            (false, Some(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+    /// Returns the first answer in preference order that `found` accepts, or else the first
+    /// answer, as long as some adapter answered.
+    fn merge<T>(
+        results: Vec<Result<T, MySgmError>>,
+        found: impl Fn(&T) -> bool,
+    ) -> Result<T, MySgmError> {
+        let mut first_answer = None;
+        let mut first_error = None;
+        for result in results {
+            match result {
+                Ok(value) if found(&value) => return Ok(value),
+                Ok(value) => {
+                    first_answer.get_or_insert(value);
+                }
+                Err(e) => {
+                    tracing::warn!("Delivery backend failed: {e}");
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match (first_answer, first_error) {
+            (Some(value), _) => Ok(value),
+            (None, Some(e)) => Err(e),
+            (None, None) => Err(MySgmError::NoDeliveryBackend),
+        }
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl DeliveryAdapter for CompositeAdapter {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, MySgmError> {
+        let results = join_all(self.inner.iter().map(|adapter| adapter.get(key))).await;
+        Self::merge(results, Option::is_some)
+    }
+    async fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
+        let mut first_error = None;
+        for (index, adapter) in self.inner.iter().enumerate() {
+            match adapter.put_checked(key, value).await {
+                Ok(()) => {
+                    let replicas = join_all(
+                        self.inner[index + 1..]
+                            .iter()
+                            .map(|replica| replica.put_checked(key, value)),
+                    )
+                    .await;
+                    for replica in replicas {
+                        match replica {
+                            // e.g. copied there by an earlier put that failed on this adapter
+                            Ok(()) | Err(MySgmError::KeyExists) => {}
+                            Err(e) => {
+                                tracing::warn!("Failed to copy {key} to delivery backend: {e}")
+                            }
+                        }
+                    }
+                    return Ok(());
+                }
+                Err(MySgmError::KeyExists) => return Err(MySgmError::KeyExists),
+                Err(e) => {
+                    tracing::warn!("Delivery backend failed, trying the next: {e}");
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        Err(first_error.unwrap_or(MySgmError::NoDeliveryBackend))
+    }
+    async fn republish(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
+        self.fan_out(self.inner.iter().collect(), |adapter| {
+            adapter.republish(key, value)
+        })
+        .await
+    }
+    /// Deletes the value from every adapter, returning whether any of them deleted it.
+    async fn delete(&self, key: &str) -> Result<bool, MySgmError> {
+        let results = join_all(self.inner.iter().map(|adapter| adapter.delete(key))).await;
+        Self::merge(results, |deleted| *deleted)
+    }
+    /// Waits until any of the adapters sees a new value or the timeout passes.
+    async fn watch(&self, keys: &[String], timeout: Duration) -> Result<(), MySgmError> {
+        if self.inner.is_empty() {
+            return Err(MySgmError::NoDeliveryBackend);
+        }
+        let watches = self
+            .inner
+            .iter()
+            .map(|adapter| adapter.watch(keys, timeout));
+        select_all(watches).await.0
+    }
+    /// Returns the time of the first adapter telling it.
+    async fn server_time(&self) -> Result<Option<u64>, MySgmError> {
+        for adapter in &self.inner {
+            if let Some(time) = adapter.server_time().await? {
+                return Ok(Some(time));
+            }
+        }
+        Ok(None)
+    }
+    fn directory(&self) -> Option<&dyn KeyPackageDirectory> {
+        match self.directories().is_empty() {
+            true => None,
+            false => Some(self),
+        }
+    }
+}
+
+/// Key packages and welcomes are posted to the directories of all adapters offering one, and
+/// read from the first directory that answers, so that welcome positions stay stable.
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl KeyPackageDirectory for CompositeAdapter {
+    async fn put_key_package(&self, pid: &str, key_package: &[u8]) -> Result<(), MySgmError> {
+        self.fan_out(self.directories(), |directory| {
+            directory.put_key_package(pid, key_package)
+        })
+        .await
+    }
+    async fn get_key_package(&self, pid: &str) -> Result<Option<Vec<u8>>, MySgmError> {
+        let directories = self.directories();
+        let results = join_all(
+            directories
+                .iter()
+                .map(|directory| directory.get_key_package(pid)),
+        )
+        .await;
+        Self::merge(results, Option::is_some)
+    }
+    async fn post_welcome(&self, pid: &str, welcome: &[u8]) -> Result<(), MySgmError> {
+        self.fan_out(self.directories(), |directory| {
+            directory.post_welcome(pid, welcome)
+        })
+        .await
+    }
+    async fn get_welcomes(&self, pid: &str) -> Result<Vec<Vec<u8>>, MySgmError> {
+        let mut first_error = None;
+        for directory in self.directories() {
+            match directory.get_welcomes(pid).await {
+                Ok(welcomes) => return Ok(welcomes),
+                Err(e) => {
+                    tracing::warn!("Directory failed, trying the next: {e}");
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        Err(first_error.unwrap_or(MySgmError::NoDeliveryBackend))
+    }
+}
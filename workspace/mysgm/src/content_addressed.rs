@@ -0,0 +1,196 @@
+use super::{
+    adapter::{DeliveryAdapter, KeyPackageDirectory},
+    error::MySgmError,
+};
+
+use async_trait::async_trait;
+use hex::encode as hex_encode;
+use openmls_rust_crypto::RustCrypto;
+use openmls_traits::{crypto::OpenMlsCrypto, types::HashType};
+use std::time::Duration;
+
+/// Prefix marking a record as a pointer to a content-addressed value.
+const POINTER_MAGIC: &[u8] = b"mysgm pointer\0";
+
+/// Stores values of the wrapped adapter under the digest of their content, leaving only small
+/// pointers under well-known keys.
+///
+/// A value put under a key is stored under `ca<sha256>`, and the key holds a pointer naming
+/// the digest; reading the key follows the pointer and checks the value against the digest,
+/// so tampering with a stored value is detected. Keys holding a value rather than a pointer,
+/// e.g. written without this mode, are read as they are.
+///
+/// Unless the wrapped adapter has one, this also provides a pid-addressed directory, so that
+/// agents no longer race for shared key package and welcome slots: each pid has a chain of
+/// pointers to its key packages, appended to by its own agent only and the latest of which is
+/// current, and a chain of pointers to the welcomes addressed to it.
+#[derive(Debug)]
+pub struct ContentAddressedAdapter {
+    inner: Box<dyn DeliveryAdapter>,
+}
+
+impl ContentAddressedAdapter {
+    pub fn new(inner: Box<dyn DeliveryAdapter>) -> Self {
+        Self { inner }
+    }
+    fn digest(value: &[u8]) -> Result<Vec<u8>, MySgmError> {
+        Ok(RustCrypto::default().hash(HashType::Sha2_256, value)?)
+    }
+    fn content_key(digest: &[u8]) -> String {
+        format!("ca{}", hex_encode(digest))
+    }
+    fn key_package_chain_key(pid: &str, index: u64) -> String {
+        format!("kpp{}_{index}", hex_encode(pid))
+    }
+    fn welcome_chain_key(pid: &str, index: u64) -> String {
+        format!("wmp{}_{index}", hex_encode(pid))
+    }
+    /// Stores the value under its digest and returns the pointer to it.
+    async fn put_content(&self, value: &[u8]) -> Result<Vec<u8>, MySgmError> {
+        let digest = Self::digest(value)?;
+        match self
+            .inner
+            .put_checked(&Self::content_key(&digest), value)
+            .await
+        {
+            // the same content, put before
+            Ok(()) | Err(MySgmError::KeyExists) => {}
+            Err(e) => return Err(e),
+        }
+        let mut pointer = POINTER_MAGIC.to_vec();
+        pointer.extend(digest);
+        Ok(pointer)
+    }
+    /// Follows the pointer stored under the key, checking the value it points to.
+    async fn resolve(&self, key: &str, record: Vec<u8>) -> Result<Vec<u8>, MySgmError> {
+        let Some(digest) = record.strip_prefix(POINTER_MAGIC) else {
+            return Ok(record);
+        };
+        let malformed = |detail: &str| MySgmError::MalformedPayload(key.to_string(), detail.into());
+        let value = self
+            .inner
+            .get(&Self::content_key(digest))
+            .await?
+            .ok_or_else(|| malformed("pointed-to value is missing"))?;
+        if Self::digest(&value)? != digest {
+            return Err(malformed("value does not match its digest"));
+        }
+        Ok(value)
+    }
+    /// Returns the values the chain of pointers points to, oldest first.
+    async fn read_chain(
+        &self,
+        chain_key: impl Fn(u64) -> String,
+    ) -> Result<Vec<Vec<u8>>, MySgmError> {
+        let mut values = Vec::new();
+        let mut index = 0;
+        while let Some(record) = self.inner.get(&chain_key(index)).await? {
+            match self.resolve(&chain_key(index), record).await {
+                Ok(value) => values.push(value),
+                // keeps the positions of later values, and fails to decode like any bad value
+                Err(e) if !e.is_transient() => {
+                    tracing::warn!("Skipping link {index} of chain: {e}");
+                    values.push(Vec::new());
+                }
+                Err(e) => return Err(e),
+            }
+            index += 1;
+        }
+        Ok(values)
+    }
+    /// Appends a pointer to the value to the chain.
+    async fn append_to_chain(
+        &self,
+        chain_key: impl Fn(u64) -> String,
+        value: &[u8],
+    ) -> Result<(), MySgmError> {
+        let pointer = self.put_content(value).await?;
+        let mut index = 0;
+        loop {
+            match self.inner.put_checked(&chain_key(index), &pointer).await {
+                Err(MySgmError::KeyExists) => index += 1,
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl DeliveryAdapter for ContentAddressedAdapter {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, MySgmError> {
+        match self.inner.get(key).await? {
+            Some(record) => Ok(Some(self.resolve(key, record).await?)),
+            None => Ok(None),
+        }
+    }
+    async fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
+        if self.inner.get(key).await?.is_some() {
+            return Err(MySgmError::KeyExists);
+        }
+        let pointer = self.put_content(value).await?;
+        self.inner.put_checked(key, &pointer).await
+    }
+    /// Puts the value and the pointer to it again.
+    async fn republish(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
+        let digest = Self::digest(value)?;
+        self.inner
+            .republish(&Self::content_key(&digest), value)
+            .await?;
+        let mut pointer = POINTER_MAGIC.to_vec();
+        pointer.extend(digest);
+        self.inner.republish(key, &pointer).await
+    }
+    /// Deletes the pointer, leaving the content, which other keys may share, to expire.
+    async fn delete(&self, key: &str) -> Result<bool, MySgmError> {
+        self.inner.delete(key).await
+    }
+    async fn watch(&self, keys: &[String], timeout: Duration) -> Result<(), MySgmError> {
+        self.inner.watch(keys, timeout).await
+    }
+    async fn server_time(&self) -> Result<Option<u64>, MySgmError> {
+        self.inner.server_time().await
+    }
+    fn directory(&self) -> Option<&dyn KeyPackageDirectory> {
+        match self.inner.directory() {
+            Some(directory) => Some(directory),
+            None => Some(self),
+        }
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl KeyPackageDirectory for ContentAddressedAdapter {
+    async fn put_key_package(&self, pid: &str, key_package: &[u8]) -> Result<(), MySgmError> {
+        self.append_to_chain(|index| Self::key_package_chain_key(pid, index), key_package)
+            .await
+    }
+    async fn get_key_package(&self, pid: &str) -> Result<Option<Vec<u8>>, MySgmError> {
+        let mut latest = None;
+        let mut index = 0;
+        while let Some(record) = self
+            .inner
+            .get(&Self::key_package_chain_key(pid, index))
+            .await?
+        {
+            latest = Some((index, record));
+            index += 1;
+        }
+        match latest {
+            Some((index, record)) => {
+                let key = Self::key_package_chain_key(pid, index);
+                Ok(Some(self.resolve(&key, record).await?))
+            }
+            None => Ok(None),
+        }
+    }
+    async fn post_welcome(&self, pid: &str, welcome: &[u8]) -> Result<(), MySgmError> {
+        self.append_to_chain(|index| Self::welcome_chain_key(pid, index), welcome)
+            .await
+    }
+    async fn get_welcomes(&self, pid: &str) -> Result<Vec<Vec<u8>>, MySgmError> {
+        self.read_chain(|index| Self::welcome_chain_key(pid, index))
+            .await
+    }
+}
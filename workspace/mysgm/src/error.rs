@@ -0,0 +1,322 @@
+use super::state::OpenMlsKeyValueStoreError;
+
+use openmls::{
+    error::LibraryError,
+    extensions::errors::InvalidExtensionError,
+    framing::errors::ProtocolMessageError,
+    group::{
+        AddMembersError, CommitToPendingProposalsError, CreateGroupContextExtProposalError,
+        CreateMessageError, ExportGroupInfoError, ExportSecretError, ExternalCommitError,
+        LeaveGroupError, MergeCommitError, MergePendingCommitError, MlsGroupStateError,
+        NewGroupError, ProcessMessageError, ProposalError, ProposeAddMemberError,
+        ProposeRemoveMemberError, RemoveMembersError, RemoveProposalError, SelfUpdateError,
+        WelcomeError,
+    },
+    prelude::{BasicCredentialError, KeyPackageNewError, KeyPackageVerifyError},
+    schedule::errors::PskError,
+};
+use openmls_traits::types::{Ciphersuite, CryptoError, SignatureScheme};
+use thiserror::Error;
+
+/// Errors returned by the agent, its delivery adapters, and state persistence.
+#[derive(Debug, Error)]
+pub enum MySgmError {
+    /// The next key package slot is empty.
+    #[error("No new key packages")]
+    NoNewKeyPackages,
+    /// The next welcome message slot is empty.
+    #[error("No new welcome messages")]
+    NoNewWelcomeMessages,
+    /// The delivery service already holds a value under the key.
+    #[error("Key already exists")]
+    KeyExists,
+    /// A composite delivery adapter was configured without any adapters.
+    #[error("No delivery backend configured")]
+    NoDeliveryBackend,
+    /// A welcome queued for a directory is flushed through a delivery backend without one.
+    #[error("Delivery backend has no key package directory")]
+    NoDirectory,
+    #[error("Group not found: {0}")]
+    GroupNotFound(String),
+    #[error("Group already exists: {0}")]
+    GroupExists(String),
+    /// An add to the group was staged but not yet completed.
+    #[error("An add to group {0} is pending; resume it first")]
+    AddPending(String),
+    #[error("No pending add for group: {0}")]
+    NoPendingAdd(String),
+    #[error("Group is not archived: {0}")]
+    GroupNotArchived(String),
+    #[error("No pending invite with id: {0}")]
+    NoPendingInvite(u64),
+    #[error("Cursor follows the group's epoch and cannot be reset: {0}")]
+    CursorNotResettable(String),
+    #[error("No quarantined record under: {0}")]
+    NotQuarantined(String),
+    /// An incoming key package, welcome, or group info is outside the artifact policy.
+    #[error("{0} rejected by the artifact policy: {1}")]
+    ArtifactRejected(String, ArtifactViolation),
+    /// A prospective member does not meet the group's policy.
+    #[error("{0} does not meet the group policy: {1}")]
+    PolicyViolation(String, String),
+    #[error("Group {0} cannot have more than {1} members")]
+    GroupFull(String, usize),
+    /// A group export made by an agent with another pid or signature key.
+    #[error("Group export belongs to another identity: {0}")]
+    ForeignGroupExport(String),
+    /// A padding policy without usable buckets, or a message too large to pad.
+    #[error("Invalid padding: {0}")]
+    InvalidPadding(String),
+    #[error("No PKCS#11 token found through module: {0}")]
+    NoPkcs11Token(String),
+    #[error("No key labelled {0} on the PKCS#11 token")]
+    TokenKeyNotFound(String),
+    #[error("Signature scheme not supported on PKCS#11 tokens: {0:?}")]
+    UnsupportedTokenScheme(SignatureScheme),
+    #[error("No key package for pid: {0}")]
+    KeyPackageNotFound(String),
+    #[error("Key package expired: {0}")]
+    KeyPackageExpired(String),
+    #[error("Unsupported ciphersuite: {0}")]
+    UnsupportedCiphersuite(Ciphersuite),
+    #[error("No member with pid: {0}")]
+    MemberNotFound(String),
+    /// Adding and removing members, and changing admins, requires the admin role.
+    #[error("Not an admin of group: {0}")]
+    NotAdmin(String),
+    #[error("Cannot revoke the last admin of group: {0}")]
+    LastAdmin(String),
+    /// No ratchet tree was posted for the epoch a welcome without one joins at.
+    #[error("No ratchet tree for group: {0}")]
+    RatchetTreeNotFound(String),
+    /// The group moved past the epoch of the published group info.
+    #[error("Group info is stale: {0}")]
+    StaleGroupInfo(String),
+    /// The agent was removed from the group.
+    #[error("Evicted from group")]
+    Evicted,
+    /// A key package or leaf of the pid carries a different signature key than the trusted one.
+    #[error("Signature key of {0} changed")]
+    SignatureKeyMismatch(String),
+    /// A key package or leaf of the pid carries a signature key it was quarantined for.
+    #[error("Signature key of {0} is quarantined")]
+    Quarantined(String),
+    #[error("Fingerprint does not match any signature key of {0}")]
+    FingerprintMismatch(String),
+    #[error("No signature key known for pid: {0}")]
+    UnknownAgent(String),
+    /// A delivery service record was not signed for the key it is stored under, or not by the
+    /// expected publisher.
+    #[error("Invalid record signature: {0}")]
+    InvalidRecord(String),
+    /// A value fetched from the delivery service is too large, does not decode, or has bytes
+    /// left over after decoding.
+    #[error("Malformed payload under {0}: {1}")]
+    MalformedPayload(String, String),
+    /// A link of a group's commit chain names another commit than the one merged before it.
+    #[error("Commit chain of group {0} breaks at epoch {1}")]
+    BrokenCommitChain(String, u64),
+    #[error("Exporter of epoch {1} of group {0} is not retained")]
+    EpochNotRetained(String, u64),
+    /// A chunked value had missing parts or did not match the digest in its manifest.
+    #[error("Corrupt chunked value: {0}")]
+    CorruptChunks(String),
+    /// A sealed message was truncated, or its key or tag did not match.
+    #[error("Failed to open sealed message")]
+    SealedMessage,
+    /// An application message was received before, or lies outside the window of messages the
+    /// group can still decrypt.
+    #[error("Replayed or out-of-window message in group {0}: {1}")]
+    ReplayedMessage(String, String),
+    /// A delivery service slot held a different kind of message than expected.
+    #[error("Expected {0} message")]
+    UnexpectedMessage(&'static str),
+    /// A device link payload failed signature verification.
+    #[error("Invalid device link")]
+    InvalidDeviceLink,
+    /// Only agents without groups can be linked as a device of another identity.
+    #[error("Agent already has groups; link a fresh state instead")]
+    DeviceInUse,
+    #[error("Unsupported state kdf: {0}")]
+    UnsupportedKdf(String),
+    #[error("Failed to derive state key: {0}")]
+    KeyDerivation(String),
+    /// Bootstrapping would replace existing state; reset it explicitly instead.
+    #[error("State already exists: {0}")]
+    StateExists(String),
+    /// Another process saved the state file after this state was loaded from it.
+    #[error("State file changed since loading (loaded version {loaded}, stored version {stored})")]
+    StateConflict { loaded: u64, stored: u64 },
+    #[error("Passphrase encryption is not supported for SQLite state")]
+    SqliteEncryption,
+    #[error("Failed to encrypt state")]
+    StateEncryption,
+    #[error("Failed to decrypt state; wrong passphrase?")]
+    StateDecryption,
+    /// The state was written by a newer build.
+    #[error("Unsupported state format version: {0}")]
+    UnsupportedStateFormat(u32),
+    /// The daemon stopped before answering a forwarded command.
+    #[error("Daemon stopped")]
+    DaemonStopped,
+    /// A command forwarded to the daemon failed there.
+    #[error("Daemon: {0}")]
+    DaemonCommand(String),
+    /// The local clock differs from the delivery service's by this many seconds.
+    #[error("Clock is {0}s off the delivery service's")]
+    ClockSkew(u64),
+    /// Diagnosis found this many failed checks.
+    #[error("{0} checks failed")]
+    ChecksFailed(usize),
+    #[error("Invalid config file {0}: {1}")]
+    InvalidConfig(String, String),
+    /// A step of a script failed, numbered from 1; the steps after it did not run.
+    #[error("Script step {0} failed: {1}")]
+    ScriptStep(usize, String),
+    #[error("Not a backup archive: {0}")]
+    InvalidBackup(String),
+    /// An entry of the journal kept next to the state does not fit the state.
+    #[error("Invalid state journal: {0}")]
+    InvalidJournal(String),
+    #[error("Unsupported backup format version: {0}")]
+    UnsupportedBackupVersion(u32),
+    /// The payload of a backup archive does not match its digest.
+    #[error("Backup checksum mismatch: {0}")]
+    BackupChecksum(String),
+    /// The browser's local storage is missing or refused to store the state.
+    #[error("Browser storage error: {0}")]
+    BrowserStorage(String),
+    /// An argument is out of range, or, passed through the C API, null or not UTF-8.
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(&'static str),
+    /// The feature is not available on this platform, e.g. SQLite under WebAssembly.
+    #[error("Not supported on this platform: {0}")]
+    UnsupportedPlatform(&'static str),
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+    #[error(transparent)]
+    Storage(#[from] OpenMlsKeyValueStoreError),
+    /// Any other error raised by OpenMLS.
+    #[error(transparent)]
+    Mls(Box<dyn core::error::Error + Send + Sync>),
+    /// A request failed in a way that may succeed if retried, e.g. a timeout or server error.
+    #[error("Transient delivery service error: {0}")]
+    Transient(reqwest::Error),
+    #[error(transparent)]
+    Http(reqwest::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Codec(#[from] tls_codec::Error),
+    #[error(transparent)]
+    Base64(#[from] base64::DecodeError),
+    #[error(transparent)]
+    Hex(#[from] hex::FromHexError),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error(transparent)]
+    Keyring(#[from] keyring::Error),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error(transparent)]
+    Pkcs11(#[from] cryptoki::error::Error),
+}
+
+/// What an incoming artifact uses that the [`crate::ArtifactPolicy`] does not accept.
+#[derive(Clone, Debug, PartialEq, Error)]
+pub enum ArtifactViolation {
+    #[error("ciphersuite {0} is not accepted")]
+    Ciphersuite(Ciphersuite),
+    #[error("protocol version {0} is not accepted")]
+    ProtocolVersion(u16),
+    #[error("credential type {0:#06x} is not accepted")]
+    CredentialType(u16),
+}
+
+impl MySgmError {
+    /// Whether the operation that failed may succeed if retried.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::Transient(_))
+    }
+    /// Whether the failure lies with a record of the delivery service itself, which fails the
+    /// same way however often it is retried.
+    pub fn is_malformed(&self) -> bool {
+        matches!(
+            self,
+            Self::InvalidRecord(_) | Self::MalformedPayload(..) | Self::UnexpectedMessage(_)
+        )
+    }
+}
+
+impl From<reqwest::Error> for MySgmError {
+    fn from(e: reqwest::Error) -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        let unreachable = e.is_connect();
+        // fetch does not tell failed connections apart
+        #[cfg(target_arch = "wasm32")]
+        let unreachable = e.is_request() && e.status().is_none();
+        let transient = e.is_timeout()
+            || unreachable
+            || e.status().is_some_and(|status| {
+                status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            });
+        match transient {
+            true => Self::Transient(e),
+            false => Self::Http(e),
+        }
+    }
+}
+
+impl From<ExportSecretError> for MySgmError {
+    fn from(e: ExportSecretError) -> Self {
+        match e {
+            ExportSecretError::GroupStateError(MlsGroupStateError::UseAfterEviction) => {
+                Self::Evicted
+            }
+            e => Self::Mls(Box::new(e)),
+        }
+    }
+}
+
+macro_rules! impl_from_mls_error {
+    ($($error:ty),* $(,)?) => {
+        $(
+            impl From<$error> for MySgmError {
+                fn from(e: $error) -> Self {
+                    Self::Mls(Box::new(e))
+                }
+            }
+        )*
+    };
+}
+
+impl_from_mls_error!(
+    AddMembersError<OpenMlsKeyValueStoreError>,
+    BasicCredentialError,
+    CommitToPendingProposalsError<OpenMlsKeyValueStoreError>,
+    CreateGroupContextExtProposalError<OpenMlsKeyValueStoreError>,
+    CreateMessageError,
+    InvalidExtensionError,
+    LibraryError,
+    ExportGroupInfoError,
+    ExternalCommitError<OpenMlsKeyValueStoreError>,
+    KeyPackageNewError,
+    KeyPackageVerifyError,
+    LeaveGroupError<OpenMlsKeyValueStoreError>,
+    MergeCommitError<OpenMlsKeyValueStoreError>,
+    MergePendingCommitError<OpenMlsKeyValueStoreError>,
+    NewGroupError<OpenMlsKeyValueStoreError>,
+    ProcessMessageError,
+    ProposalError<OpenMlsKeyValueStoreError>,
+    ProposeAddMemberError<OpenMlsKeyValueStoreError>,
+    ProposeRemoveMemberError<OpenMlsKeyValueStoreError>,
+    ProtocolMessageError,
+    PskError,
+    RemoveMembersError<OpenMlsKeyValueStoreError>,
+    RemoveProposalError<OpenMlsKeyValueStoreError>,
+    SelfUpdateError<OpenMlsKeyValueStoreError>,
+    WelcomeError<OpenMlsKeyValueStoreError>,
+);
@@ -0,0 +1,469 @@
+//! C API for embedding the agent in applications not written in Rust, declared in
+//! `include/mysgm.h`.
+//!
+//! An agent is an opaque [`MySgm`] handle owning its state file and a single-threaded runtime
+//! that its calls block on; state is saved after each call that may change it. Calls wait for
+//! the delivery service, so a handle must be used from one thread at a time.
+//!
+//! Calls returning `int` return 0 on success and -1 on failure, calls returning pointers return
+//! null on failure, and [`mysgm_last_error`] then describes the failure. Strings returned are
+//! owned by the caller and freed with [`mysgm_string_free`]. Panics are caught and reported
+//! as failures rather than unwinding into the caller.
+//!
+//! The ABI only grows: functions are added, never changed, and [`MYSGM_ABI_VERSION`] is bumped
+//! with each addition.
+
+use super::{
+    adapter::DeliveryAdapter,
+    agent::MySgmAgent,
+    chunking::ChunkingAdapter,
+    error::MySgmError,
+    file_adapter::FileAdapter,
+    opendht::OpenDhtRestAdapter,
+    persistence::{StateStorage, load_state, save_state, stored_version},
+    state::MySgmState,
+};
+
+use openmls::prelude::Ciphersuite;
+use openmls_rust_crypto::RustCrypto;
+use serde_json::json;
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString, c_char, c_int},
+    panic::{AssertUnwindSafe, catch_unwind},
+    ptr::null_mut,
+    slice,
+};
+use tokio::runtime::{Builder as RuntimeBuilder, Runtime};
+
+/// Version of the C API, as returned by [`mysgm_abi_version`].
+pub const MYSGM_ABI_VERSION: u32 = 1;
+
+thread_local! {
+    /// Message of the last failure on this thread.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// An agent embedded through the C API.
+pub struct MySgm {
+    runtime: Runtime,
+    agent: MySgmAgent,
+    state_path: String,
+    storage: StateStorage,
+    passphrase: Option<String>,
+}
+
+impl MySgm {
+    fn open(
+        state: MySgmState,
+        state_path: &str,
+        passphrase: Option<&str>,
+        file_dir: Option<&str>,
+    ) -> Result<Self, MySgmError> {
+        let adapter: Box<dyn DeliveryAdapter> = match file_dir {
+            Some(file_dir) => Box::new(FileAdapter::new(file_dir)),
+            None => Box::new(ChunkingAdapter::new(Box::new(OpenDhtRestAdapter::new(
+                state.dht_host(),
+                state.dht_port(),
+            )))),
+        };
+        Ok(Self {
+            runtime: RuntimeBuilder::new_current_thread().enable_all().build()?,
+            agent: MySgmAgent::new(state, RustCrypto::default(), adapter),
+            state_path: state_path.to_string(),
+            storage: StateStorage::from_path(state_path),
+            passphrase: passphrase.map(str::to_string),
+        })
+    }
+    fn save(&self) -> Result<(), MySgmError> {
+        save_state(
+            &self.state_path,
+            self.storage,
+            self.agent.state(),
+            self.passphrase.as_deref(),
+        )
+    }
+    /// Runs the call to completion on the handle's runtime, then saves the state even if the
+    /// call failed, since it may have published records before failing.
+    fn block_on_and_save<T>(
+        &mut self,
+        call: impl AsyncFnOnce(&mut MySgmAgent) -> Result<T, MySgmError>,
+    ) -> Result<T, MySgmError> {
+        let result = self.runtime.block_on(call(&mut self.agent));
+        self.save()?;
+        result
+    }
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Runs the call, recording its failure or panic as the last error.
+fn call<T>(f: impl FnOnce() -> Result<T, MySgmError>) -> Option<T> {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => Some(value),
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            None
+        }
+        Err(_) => {
+            set_last_error("panicked".to_string());
+            None
+        }
+    }
+}
+
+fn status(result: Option<()>) -> c_int {
+    match result {
+        Some(()) => 0,
+        None => -1,
+    }
+}
+
+fn to_c_string(string: String) -> Result<*mut c_char, MySgmError> {
+    Ok(CString::new(string)
+        .map_err(|_| MySgmError::InvalidArgument("string with NUL"))?
+        .into_raw())
+}
+
+/// Reads the string argument, which must not be null.
+///
+/// # Safety
+///
+/// The pointer must be null or point to a NUL-terminated string valid for the call.
+unsafe fn required<'a>(ptr: *const c_char, name: &'static str) -> Result<&'a str, MySgmError> {
+    // SAFETY: guaranteed by the caller
+    unsafe { optional(ptr, name) }?.ok_or(MySgmError::InvalidArgument(name))
+}
+
+/// Reads the string argument, null meaning `None`.
+///
+/// # Safety
+///
+/// The pointer must be null or point to a NUL-terminated string valid for the call.
+unsafe fn optional<'a>(
+    ptr: *const c_char,
+    name: &'static str,
+) -> Result<Option<&'a str>, MySgmError> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    // SAFETY: guaranteed by the caller
+    let string = unsafe { CStr::from_ptr(ptr) };
+    Ok(Some(
+        string
+            .to_str()
+            .map_err(|_| MySgmError::InvalidArgument(name))?,
+    ))
+}
+
+/// Reads the handle argument, which must not be null.
+///
+/// # Safety
+///
+/// The pointer must be null or a handle returned by [`mysgm_new`] or [`mysgm_load`] and not
+/// yet freed, used by no other call at the same time.
+unsafe fn handle<'a>(ptr: *mut MySgm) -> Result<&'a mut MySgm, MySgmError> {
+    // SAFETY: guaranteed by the caller
+    unsafe { ptr.as_mut() }.ok_or(MySgmError::InvalidArgument("handle"))
+}
+
+/// Returns [`MYSGM_ABI_VERSION`], for callers to check against the header they were built
+/// with.
+#[unsafe(no_mangle)]
+pub extern "C" fn mysgm_abi_version() -> u32 {
+    MYSGM_ABI_VERSION
+}
+
+/// Returns the message of the last failure on this thread, or null if none; the string stays
+/// valid until the next failing call on the thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn mysgm_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Creates a fresh identity with the label, saves its state to the path, encrypted if the
+/// passphrase is not null, and returns its handle.
+///
+/// Delivery goes through the directory `file_dir` if not null, and through the OpenDHT proxy
+/// configured in the state otherwise. Existing state at the path is not replaced.
+///
+/// # Safety
+///
+/// String arguments must be null or point to NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mysgm_new(
+    state_path: *const c_char,
+    label: *const c_char,
+    passphrase: *const c_char,
+    file_dir: *const c_char,
+) -> *mut MySgm {
+    call(|| {
+        // SAFETY: guaranteed by the caller
+        let (state_path, label, passphrase, file_dir) = unsafe {
+            (
+                required(state_path, "state_path")?,
+                required(label, "label")?,
+                optional(passphrase, "passphrase")?,
+                optional(file_dir, "file_dir")?,
+            )
+        };
+        let storage = StateStorage::from_path(state_path);
+        if stored_version(state_path, storage).is_some() {
+            return Err(MySgmError::StateExists(state_path.to_string()));
+        }
+        let state = MySgmState::generate(
+            label,
+            Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519,
+            &RustCrypto::default(),
+        )?;
+        let mysgm = MySgm::open(state, state_path, passphrase, file_dir)?;
+        mysgm.save()?;
+        Ok(Box::into_raw(Box::new(mysgm)))
+    })
+    .unwrap_or(null_mut())
+}
+
+/// Loads the state saved at the path, decrypting it if the passphrase is not null, and
+/// returns its handle; delivery is chosen as by [`mysgm_new`].
+///
+/// # Safety
+///
+/// String arguments must be null or point to NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mysgm_load(
+    state_path: *const c_char,
+    passphrase: *const c_char,
+    file_dir: *const c_char,
+) -> *mut MySgm {
+    call(|| {
+        // SAFETY: guaranteed by the caller
+        let (state_path, passphrase, file_dir) = unsafe {
+            (
+                required(state_path, "state_path")?,
+                optional(passphrase, "passphrase")?,
+                optional(file_dir, "file_dir")?,
+            )
+        };
+        let state = load_state(state_path, StateStorage::from_path(state_path), passphrase)?;
+        let mysgm = MySgm::open(state, state_path, passphrase, file_dir)?;
+        Ok(Box::into_raw(Box::new(mysgm)))
+    })
+    .unwrap_or(null_mut())
+}
+
+/// Frees the handle; null is ignored.
+///
+/// # Safety
+///
+/// The handle must be null or returned by [`mysgm_new`] or [`mysgm_load`] and not yet freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mysgm_free(mysgm: *mut MySgm) {
+    if !mysgm.is_null() {
+        // SAFETY: guaranteed by the caller
+        drop(unsafe { Box::from_raw(mysgm) });
+    }
+}
+
+/// Frees a string returned by this API; null is ignored.
+///
+/// # Safety
+///
+/// The string must be null or returned by this API and not yet freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mysgm_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        // SAFETY: guaranteed by the caller
+        drop(unsafe { CString::from_raw(string) });
+    }
+}
+
+/// Returns the pid of the agent, which others add to groups.
+///
+/// # Safety
+///
+/// The handle must be valid, as for [`mysgm_free`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mysgm_pid(mysgm: *mut MySgm) -> *mut c_char {
+    call(|| {
+        // SAFETY: guaranteed by the caller
+        let mysgm = unsafe { handle(mysgm) }?;
+        to_c_string(mysgm.agent.state().my_pid().to_string())
+    })
+    .unwrap_or(null_mut())
+}
+
+/// Publishes a key package valid for the lifetime in seconds.
+///
+/// # Safety
+///
+/// The handle must be valid, as for [`mysgm_free`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mysgm_advertise(mysgm: *mut MySgm, lifetime: u64) -> c_int {
+    status(call(|| {
+        // SAFETY: guaranteed by the caller
+        let mysgm = unsafe { handle(mysgm) }?;
+        mysgm.block_on_and_save(async |agent| agent.advertise(lifetime).await)
+    }))
+}
+
+/// Downloads and processes key packages, welcomes, and commits, then receives the application
+/// messages of every group, and returns a JSON report of them.
+///
+/// The report is an object with the `key_packages` and `welcomes` processed, the number of
+/// `commits` merged, the `messages` received as `{"gid", "sender", "message"}` objects with
+/// messages decoded as UTF-8, lossily, and the `errors` of slots that could not be processed.
+///
+/// # Safety
+///
+/// The handle must be valid, as for [`mysgm_free`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mysgm_sync(mysgm: *mut MySgm) -> *mut c_char {
+    call(|| {
+        // SAFETY: guaranteed by the caller
+        let mysgm = unsafe { handle(mysgm) }?;
+        let (report, messages) =
+            mysgm.block_on_and_save(async |agent| agent.sync_and_receive().await)?;
+        let messages: Vec<_> = messages
+            .into_iter()
+            .map(|(gid, (sender, message))| {
+                json!({
+                    "gid": gid,
+                    "sender": sender,
+                    "message": String::from_utf8_lossy(&message),
+                })
+            })
+            .collect();
+        to_c_string(
+            json!({
+                "key_packages": report.key_packages,
+                "welcomes": report.welcomes,
+                "commits": report.commits,
+                "messages": messages,
+                "errors": report
+                    .errors
+                    .iter()
+                    .map(|(slot, e)| json!({"slot": slot, "error": e.to_string()}))
+                    .collect::<Vec<_>>(),
+            })
+            .to_string(),
+        )
+    })
+    .unwrap_or(null_mut())
+}
+
+/// Creates a group and returns its gid, which extends the name given.
+///
+/// # Safety
+///
+/// The handle must be valid, as for [`mysgm_free`], and the name a NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mysgm_create_group(mysgm: *mut MySgm, name: *const c_char) -> *mut c_char {
+    call(|| {
+        // SAFETY: guaranteed by the caller
+        let (mysgm, name) = unsafe { (handle(mysgm)?, required(name, "name")?) };
+        let gid = mysgm.agent.create_group(name, true)?;
+        mysgm.save()?;
+        to_c_string(gid)
+    })
+    .unwrap_or(null_mut())
+}
+
+/// Adds the agents with the `count` pids to the group, once a sync downloaded the key packages
+/// they advertised.
+///
+/// # Safety
+///
+/// The handle must be valid, as for [`mysgm_free`], the gid a NUL-terminated string, and
+/// `pids` point to `count` NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mysgm_add_to_group(
+    mysgm: *mut MySgm,
+    gid: *const c_char,
+    pids: *const *const c_char,
+    count: usize,
+) -> c_int {
+    status(call(|| {
+        // SAFETY: guaranteed by the caller
+        let (mysgm, gid) = unsafe { (handle(mysgm)?, required(gid, "gid")?) };
+        if pids.is_null() && count > 0 {
+            return Err(MySgmError::InvalidArgument("pids"));
+        }
+        let pids = match count {
+            0 => &[][..],
+            // SAFETY: guaranteed by the caller
+            _ => unsafe { slice::from_raw_parts(pids, count) },
+        }
+        .iter()
+        // SAFETY: guaranteed by the caller
+        .map(|pid| unsafe { required(*pid, "pids") }.map(str::to_string))
+        .collect::<Result<Vec<_>, _>>()?;
+        mysgm.block_on_and_save(async |agent| agent.add_to_group(gid, &pids).await)
+    }))
+}
+
+/// Sends the `length` bytes of the message to the group.
+///
+/// # Safety
+///
+/// The handle must be valid, as for [`mysgm_free`], the gid a NUL-terminated string, and
+/// `message` point to `length` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mysgm_send(
+    mysgm: *mut MySgm,
+    gid: *const c_char,
+    message: *const u8,
+    length: usize,
+) -> c_int {
+    status(call(|| {
+        // SAFETY: guaranteed by the caller
+        let (mysgm, gid) = unsafe { (handle(mysgm)?, required(gid, "gid")?) };
+        let message = match length {
+            0 => &[][..],
+            _ if message.is_null() => return Err(MySgmError::InvalidArgument("message")),
+            // SAFETY: guaranteed by the caller
+            _ => unsafe { slice::from_raw_parts(message, length) },
+        };
+        mysgm.block_on_and_save(async |agent| agent.send_message(gid, message).await)
+    }))
+}
+
+/// Writes `length` bytes of secret exported from the group's current epoch under the label
+/// to `out`.
+///
+/// # Safety
+///
+/// The handle must be valid, as for [`mysgm_free`], the gid and label NUL-terminated strings,
+/// and `out` point to `length` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn mysgm_export_secret(
+    mysgm: *mut MySgm,
+    gid: *const c_char,
+    label: *const c_char,
+    out: *mut u8,
+    length: usize,
+) -> c_int {
+    status(call(|| {
+        // SAFETY: guaranteed by the caller
+        let (mysgm, gid, label) = unsafe {
+            (
+                handle(mysgm)?,
+                required(gid, "gid")?,
+                required(label, "label")?,
+            )
+        };
+        if out.is_null() {
+            return Err(MySgmError::InvalidArgument("out"));
+        }
+        let secret = mysgm.agent.export_secret(gid, label, length)?;
+        // SAFETY: guaranteed by the caller
+        unsafe { slice::from_raw_parts_mut(out, length) }.copy_from_slice(&secret);
+        Ok(())
+    }))
+}
@@ -1,7 +1,10 @@
-use core::error::Error;
+use super::{adapter::DeliveryAdapter, error::MySgmError};
+
+use async_trait::async_trait;
 use hex::{decode as hex_decode, encode as hex_encode};
 use std::fs::{
-    exists as file_exists, read_to_string as read_file_to_string, write as write_string_to_file,
+    exists as file_exists, read_to_string as read_file_to_string, remove_file,
+    write as write_string_to_file,
 };
 
 #[derive(Debug, Clone)]
@@ -13,7 +16,12 @@ impl FileAdapter {
     pub fn new(path: &str) -> Self {
         Self { path: path.into() }
     }
-    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl DeliveryAdapter for FileAdapter {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, MySgmError> {
         let file = format!("{}/{}", self.path, key);
         match file_exists(&file)? {
             true => {
@@ -23,14 +31,24 @@ impl FileAdapter {
             false => Ok(None),
         }
     }
-    pub fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+    async fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
         let file = format!("{}/{}", self.path, key);
         match file_exists(&file)? {
-            true => Err("Key already exists".into()),
+            true => Err(MySgmError::KeyExists),
             false => {
                 write_string_to_file(&file, hex_encode(value))?;
                 Ok(())
             }
         }
     }
+    async fn delete(&self, key: &str) -> Result<bool, MySgmError> {
+        let file = format!("{}/{}", self.path, key);
+        match file_exists(&file)? {
+            true => {
+                remove_file(&file)?;
+                Ok(true)
+            }
+            false => Ok(false),
+        }
+    }
 }
@@ -0,0 +1,225 @@
+//! gRPC control API of the daemon, defined in `proto/mysgm.proto`.
+//!
+//! The service does not touch the agent itself: each call is forwarded as a [`Control`] request
+//! to the daemon, which owns the agent and applies requests between syncs, and the daemon
+//! publishes the messages it receives to subscribers through the [`ControlServer`].
+
+use super::{agent::MySgmAgent, error::MySgmError};
+
+use futures::stream::{Stream, unfold};
+use std::{net::SocketAddr, pin::Pin};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc, oneshot,
+};
+use tonic::{Request, Response, Status, transport::Server};
+
+pub mod proto {
+    tonic::include_proto!("mysgm.v1");
+}
+
+use proto::{
+    AddToGroupRequest, AddToGroupResponse, CreateGroupRequest, CreateGroupResponse, Message,
+    SendRequest, SendResponse, SubscribeRequest,
+    agent_server::{Agent, AgentServer},
+};
+
+/// Number of requests queued for the daemon before callers wait.
+const REQUEST_QUEUE: usize = 64;
+
+/// Number of received messages buffered for slow subscribers before they miss some.
+const MESSAGE_BUFFER: usize = 1024;
+
+/// A call to the control API, answered once the daemon applied it to the agent.
+#[derive(Debug)]
+pub enum Control {
+    CreateGroup {
+        gid: String,
+        tree_in_welcome: bool,
+        reply: oneshot::Sender<Result<String, MySgmError>>,
+    },
+    AddToGroup {
+        gid: String,
+        pids: Vec<String>,
+        reply: oneshot::Sender<Result<(), MySgmError>>,
+    },
+    Send {
+        gid: String,
+        message: Vec<u8>,
+        reply: oneshot::Sender<Result<(), MySgmError>>,
+    },
+}
+
+impl Control {
+    /// Applies the request to the agent and answers the caller.
+    pub async fn apply(self, agent: &mut MySgmAgent) {
+        // the caller may have gone away, in which case the answer is dropped
+        match self {
+            Self::CreateGroup {
+                gid,
+                tree_in_welcome,
+                reply,
+            } => {
+                let _ = reply.send(agent.create_group(&gid, tree_in_welcome));
+            }
+            Self::AddToGroup { gid, pids, reply } => {
+                let pids: Vec<String> = pids
+                    .iter()
+                    .map(|pid| agent.state().resolve_pid(pid))
+                    .collect();
+                let _ = reply.send(agent.add_to_group(&gid, &pids).await);
+            }
+            Self::Send {
+                gid,
+                message,
+                reply,
+            } => {
+                let _ = reply.send(agent.send_message(&gid, &message).await);
+            }
+        }
+    }
+}
+
+/// Daemon end of the control API.
+#[derive(Debug)]
+pub struct ControlServer {
+    /// Requests to apply to the agent.
+    pub requests: mpsc::Receiver<Control>,
+    messages: broadcast::Sender<Message>,
+}
+
+impl ControlServer {
+    /// Starts serving the control API on the address in the background.
+    pub fn spawn(addr: SocketAddr) -> Self {
+        let (requests_tx, requests) = mpsc::channel(REQUEST_QUEUE);
+        let (messages, _) = broadcast::channel(MESSAGE_BUFFER);
+        let service = ControlService {
+            requests: requests_tx,
+            messages: messages.clone(),
+        };
+        tokio::spawn(async move {
+            tracing::info!("Serving control API on {addr}");
+            if let Err(e) = Server::builder()
+                .add_service(AgentServer::new(service))
+                .serve(addr)
+                .await
+            {
+                tracing::error!("Control API failed: {e}");
+            }
+        });
+        Self { requests, messages }
+    }
+    /// Passes a received message on to the subscribers.
+    pub fn publish(&self, gid: &str, sender: &str, message: &[u8]) {
+        // no subscribers is not an error
+        let _ = self.messages.send(Message {
+            gid: gid.to_string(),
+            sender: sender.to_string(),
+            message: message.to_vec(),
+        });
+    }
+}
+
+fn status(e: MySgmError) -> Status {
+    match e {
+        MySgmError::GroupNotFound(_)
+        | MySgmError::MemberNotFound(_)
+        | MySgmError::KeyPackageNotFound(_) => Status::not_found(e.to_string()),
+        MySgmError::GroupExists(_) => Status::already_exists(e.to_string()),
+        MySgmError::NotAdmin(_) => Status::permission_denied(e.to_string()),
+        MySgmError::AddPending(_) | MySgmError::PolicyViolation(..) | MySgmError::GroupFull(..) => {
+            Status::failed_precondition(e.to_string())
+        }
+        e => Status::internal(e.to_string()),
+    }
+}
+
+/// Forwards calls to the daemon and waits for its answer.
+struct ControlService {
+    requests: mpsc::Sender<Control>,
+    messages: broadcast::Sender<Message>,
+}
+
+impl ControlService {
+    async fn call<T>(
+        &self,
+        request: impl FnOnce(oneshot::Sender<Result<T, MySgmError>>) -> Control,
+    ) -> Result<T, Status> {
+        let (reply, answer) = oneshot::channel();
+        self.requests
+            .send(request(reply))
+            .await
+            .map_err(|_| Status::unavailable("Daemon stopped"))?;
+        answer
+            .await
+            .map_err(|_| Status::unavailable("Daemon stopped"))?
+            .map_err(status)
+    }
+}
+
+type MessageStream = Pin<Box<dyn Stream<Item = Result<Message, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Agent for ControlService {
+    async fn create_group(
+        &self,
+        request: Request<CreateGroupRequest>,
+    ) -> Result<Response<CreateGroupResponse>, Status> {
+        let CreateGroupRequest {
+            gid,
+            no_tree_in_welcome,
+        } = request.into_inner();
+        let gid = self
+            .call(|reply| Control::CreateGroup {
+                gid,
+                tree_in_welcome: !no_tree_in_welcome,
+                reply,
+            })
+            .await?;
+        Ok(Response::new(CreateGroupResponse { gid }))
+    }
+    async fn add_to_group(
+        &self,
+        request: Request<AddToGroupRequest>,
+    ) -> Result<Response<AddToGroupResponse>, Status> {
+        let AddToGroupRequest { gid, pids } = request.into_inner();
+        self.call(|reply| Control::AddToGroup { gid, pids, reply })
+            .await?;
+        Ok(Response::new(AddToGroupResponse {}))
+    }
+    async fn send(&self, request: Request<SendRequest>) -> Result<Response<SendResponse>, Status> {
+        let SendRequest { gid, message } = request.into_inner();
+        self.call(|reply| Control::Send {
+            gid,
+            message,
+            reply,
+        })
+        .await?;
+        Ok(Response::new(SendResponse {}))
+    }
+    type SubscribeStream = MessageStream;
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let SubscribeRequest { gid } = request.into_inner();
+        let messages = unfold(self.messages.subscribe(), move |mut receiver| {
+            let gid = gid.clone();
+            async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(message) if gid.is_empty() || message.gid == gid => {
+                            return Some((Ok(message), receiver));
+                        }
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(missed)) => {
+                            tracing::warn!("Subscriber missed {missed} messages");
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(messages)))
+    }
+}
@@ -0,0 +1,108 @@
+use super::{
+    adapter::{DeliveryAdapter, KeyPackageDirectory},
+    error::MySgmError,
+};
+
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use reqwest::{Client as ReqwestClient, Response, StatusCode};
+use serde_json::from_str as json_decode;
+
+/// Adapter for a REST key server with a pid-addressed key package and welcome directory.
+///
+/// Key packages live under `/key-packages/{pid}` and welcomes under `/welcome/{pid}`, where a
+/// GET returns a JSON array of base64-encoded welcomes. Commits and messages are stored under
+/// `/records/{key}`, where a POST to an existing key fails with 409 Conflict.
+#[derive(Clone, Debug)]
+pub struct HttpDirectoryAdapter {
+    url: String,
+    client: ReqwestClient,
+}
+
+impl HttpDirectoryAdapter {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').into(),
+            client: ReqwestClient::new(),
+        }
+    }
+    #[tracing::instrument(name = "http_get", skip(self), err(level = "debug"))]
+    async fn get_bytes(&self, path: &str) -> Result<Option<Response>, MySgmError> {
+        let response = self
+            .client
+            .get(format!("{}/{path}", self.url))
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Ok(Some(response.error_for_status()?)),
+        }
+    }
+    #[tracing::instrument(name = "http_post", skip(self, value), fields(length = value.len()), err(level = "warn"))]
+    async fn post_bytes(&self, path: &str, value: &[u8]) -> Result<Response, MySgmError> {
+        Ok(self
+            .client
+            .post(format!("{}/{path}", self.url))
+            .body(value.to_vec())
+            .send()
+            .await?)
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl DeliveryAdapter for HttpDirectoryAdapter {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, MySgmError> {
+        match self.get_bytes(&format!("records/{key}")).await? {
+            Some(response) => Ok(Some(response.bytes().await?.to_vec())),
+            None => Ok(None),
+        }
+    }
+    async fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
+        let response = self.post_bytes(&format!("records/{key}"), value).await?;
+        match response.status() {
+            StatusCode::CONFLICT => Err(MySgmError::KeyExists),
+            _ => {
+                response.error_for_status()?;
+                Ok(())
+            }
+        }
+    }
+    fn directory(&self) -> Option<&dyn KeyPackageDirectory> {
+        Some(self)
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl KeyPackageDirectory for HttpDirectoryAdapter {
+    async fn put_key_package(&self, pid: &str, key_package: &[u8]) -> Result<(), MySgmError> {
+        self.post_bytes(&format!("key-packages/{pid}"), key_package)
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+    async fn get_key_package(&self, pid: &str) -> Result<Option<Vec<u8>>, MySgmError> {
+        match self.get_bytes(&format!("key-packages/{pid}")).await? {
+            Some(response) => Ok(Some(response.bytes().await?.to_vec())),
+            None => Ok(None),
+        }
+    }
+    async fn post_welcome(&self, pid: &str, welcome: &[u8]) -> Result<(), MySgmError> {
+        self.post_bytes(&format!("welcome/{pid}"), welcome)
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+    async fn get_welcomes(&self, pid: &str) -> Result<Vec<Vec<u8>>, MySgmError> {
+        let Some(response) = self.get_bytes(&format!("welcome/{pid}")).await? else {
+            return Ok(Vec::new());
+        };
+        let encoded: Vec<String> = json_decode(&response.text().await?)?;
+        let mut welcomes = Vec::new();
+        for welcome in encoded {
+            welcomes.push(STANDARD.decode(welcome)?);
+        }
+        Ok(welcomes)
+    }
+}
@@ -0,0 +1,228 @@
+//! Append-only journal of the changes between saves of the state.
+//!
+//! Each journal entry lists what changed in the JSON of the state since the save before it: a
+//! field replaced, or, for maps such as the counters or the OpenMLS key store, the entries
+//! inserted and removed, and for lists such as the key package log, the elements put and the
+//! length cut to. Changes are found by comparing fingerprints of the fields and entries last
+//! persisted, so the previous state need not be kept around.
+
+use super::error::MySgmError;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hasher},
+    io::{Result as IoResult, Write},
+};
+
+/// One change to the JSON of the state.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum JournalOp {
+    /// Replaces a field.
+    Set { field: String, value: Value },
+    /// Drops a field.
+    Unset { field: String },
+    /// Inserts or replaces an entry of a map field.
+    Insert {
+        field: String,
+        key: String,
+        value: Value,
+    },
+    /// Drops an entry of a map field.
+    Remove { field: String, key: String },
+    /// Replaces the element of a list field at the index, or appends it at the list's length.
+    Put {
+        field: String,
+        index: usize,
+        value: Value,
+    },
+    /// Cuts a list field to the length.
+    Truncate { field: String, len: usize },
+}
+
+/// The changes made by one save, which left the state at the version.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub version: u64,
+    pub ops: Vec<JournalOp>,
+}
+
+/// Fingerprint of a field of the state.
+enum Fingerprint {
+    Value(u64),
+    Map(HashMap<String, u64>),
+    List(Vec<u64>),
+}
+
+/// Fingerprints of the fields of the state as last persisted, and the number of journal
+/// entries written since the state was last saved in full.
+pub struct JournalBase {
+    fields: HashMap<String, Fingerprint>,
+    pub entries: usize,
+}
+
+impl core::fmt::Debug for JournalBase {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "JournalBase {{ <{} fields>, entries: {} }}",
+            self.fields.len(),
+            self.entries
+        )
+    }
+}
+
+/// Feeds serialized JSON to a hasher without buffering it.
+struct HashWriter(DefaultHasher);
+
+impl Write for HashWriter {
+    fn write(&mut self, bytes: &[u8]) -> IoResult<usize> {
+        self.0.write(bytes);
+        Ok(bytes.len())
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+fn fingerprint(value: &Value) -> u64 {
+    let mut writer = HashWriter(DefaultHasher::new());
+    // serializing a value into a writer that never fails cannot fail
+    let _ = serde_json::to_writer(&mut writer, value);
+    writer.0.finish()
+}
+
+impl JournalBase {
+    /// Fingerprints the fields of the JSON of the state, with `entries` journal entries
+    /// written since it was last saved in full.
+    pub fn of(state: &Value, entries: usize) -> Self {
+        let fields = state
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(field, value)| {
+                let fingerprint = match value {
+                    Value::Object(map) => Fingerprint::Map(
+                        map.iter()
+                            .map(|(key, value)| (key.clone(), fingerprint(value)))
+                            .collect(),
+                    ),
+                    Value::Array(list) => Fingerprint::List(list.iter().map(fingerprint).collect()),
+                    value => Fingerprint::Value(fingerprint(value)),
+                };
+                (field.clone(), fingerprint)
+            })
+            .collect();
+        Self { fields, entries }
+    }
+    /// Lists the changes from the state these are the fingerprints of to the state.
+    pub fn diff(&self, state: &Value) -> Vec<JournalOp> {
+        let mut ops = Vec::new();
+        let empty = Map::new();
+        let fields = state.as_object().unwrap_or(&empty);
+        for (field, value) in fields {
+            match (self.fields.get(field), value) {
+                (Some(Fingerprint::Map(prints)), Value::Object(map)) => {
+                    for (key, value) in map {
+                        if prints.get(key) != Some(&fingerprint(value)) {
+                            ops.push(JournalOp::Insert {
+                                field: field.clone(),
+                                key: key.clone(),
+                                value: value.clone(),
+                            });
+                        }
+                    }
+                    for key in prints.keys().filter(|key| !map.contains_key(*key)) {
+                        ops.push(JournalOp::Remove {
+                            field: field.clone(),
+                            key: key.clone(),
+                        });
+                    }
+                }
+                (Some(Fingerprint::List(prints)), Value::Array(list)) => {
+                    if list.len() < prints.len() {
+                        ops.push(JournalOp::Truncate {
+                            field: field.clone(),
+                            len: list.len(),
+                        });
+                    }
+                    for (index, value) in list.iter().enumerate() {
+                        if prints.get(index) != Some(&fingerprint(value)) {
+                            ops.push(JournalOp::Put {
+                                field: field.clone(),
+                                index,
+                                value: value.clone(),
+                            });
+                        }
+                    }
+                }
+                (Some(Fingerprint::Value(print)), value) if *print == fingerprint(value) => {}
+                _ => ops.push(JournalOp::Set {
+                    field: field.clone(),
+                    value: value.clone(),
+                }),
+            }
+        }
+        for field in self
+            .fields
+            .keys()
+            .filter(|field| !fields.contains_key(*field))
+        {
+            ops.push(JournalOp::Unset {
+                field: field.clone(),
+            });
+        }
+        ops
+    }
+}
+
+/// Applies the changes of a journal entry to the JSON of the state.
+pub fn apply(state: &mut Value, ops: Vec<JournalOp>) -> Result<(), MySgmError> {
+    let Value::Object(fields) = state else {
+        return Err(MySgmError::InvalidJournal(
+            "state is not an object".to_string(),
+        ));
+    };
+    for op in ops {
+        match op {
+            JournalOp::Set { field, value } => {
+                fields.insert(field, value);
+            }
+            JournalOp::Unset { field } => {
+                fields.remove(&field);
+            }
+            JournalOp::Insert { field, key, value } => match fields.get_mut(&field) {
+                Some(Value::Object(map)) => {
+                    map.insert(key, value);
+                }
+                _ => return Err(MySgmError::InvalidJournal(format!("{field} is not a map"))),
+            },
+            JournalOp::Remove { field, key } => match fields.get_mut(&field) {
+                Some(Value::Object(map)) => {
+                    map.remove(&key);
+                }
+                _ => return Err(MySgmError::InvalidJournal(format!("{field} is not a map"))),
+            },
+            JournalOp::Put {
+                field,
+                index,
+                value,
+            } => match fields.get_mut(&field) {
+                Some(Value::Array(list)) if index < list.len() => list[index] = value,
+                Some(Value::Array(list)) if index == list.len() => list.push(value),
+                _ => {
+                    return Err(MySgmError::InvalidJournal(format!(
+                        "{field} has no index {index}"
+                    )));
+                }
+            },
+            JournalOp::Truncate { field, len } => match fields.get_mut(&field) {
+                Some(Value::Array(list)) => list.truncate(len),
+                _ => return Err(MySgmError::InvalidJournal(format!("{field} is not a list"))),
+            },
+        }
+    }
+    Ok(())
+}
@@ -4,10 +4,28 @@
 //! used in OpenMLS (Message Layer Security) credentials. It includes structures
 //! for public signature keys and signature key pairs, along with their
 //! associated methods and traits.
+//!
+//! A key pair may be generated on a PKCS#11 token instead, whose private key never leaves the
+//! token; the key pair then holds only a reference to it, and signs through the token. Tokens
+//! are not reachable from WebAssembly, where only software key pairs can sign.
+
+use super::error::MySgmError;
+use super::redact::Secret;
+#[cfg(not(target_arch = "wasm32"))]
+use super::token::{Pkcs11Target, open_session};
 
+#[cfg(not(target_arch = "wasm32"))]
+use cryptoki::{
+    mechanism::{
+        Mechanism,
+        eddsa::{EddsaParams, EddsaSignatureScheme},
+    },
+    object::{Attribute, AttributeType, ObjectClass},
+};
 use hex::encode as hex_encode;
 use openmls_traits::{
     crypto::OpenMlsCrypto,
+    random::OpenMlsRand,
     storage::{CURRENT_VERSION, Entity, Key, traits},
     types::{CryptoError, SignatureScheme},
 };
@@ -73,18 +91,141 @@ pub struct SignatureKeyPair {
     private: Vec<u8>,
     public: Vec<u8>,
     signature_scheme: SignatureScheme,
+    /// Token holding the private key, which is then left empty; not part of the TLS encoding.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[tls_codec(skip)]
+    token: Option<TokenKey>,
 }
 
 impl core::fmt::Debug for SignatureKeyPair {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("SignatureKeyPair")
-            .field("private", &format!("0x{}", hex_encode(&self.private)))
+            .field("private", &Secret(&self.private))
             .field("public", &format!("0x{}", hex_encode(&self.public)))
             .field("signature_scheme", &self.signature_scheme)
+            .field("token", &self.token)
             .finish()
     }
 }
 
+/// Reference to a private key generated on a PKCS#11 token.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenKey {
+    /// Path of the PKCS#11 module.
+    pub module: String,
+    /// ID of the slot holding the token.
+    pub slot: u64,
+    /// Label of the key pair on the token.
+    pub label: String,
+}
+
+#[cfg(target_arch = "wasm32")]
+impl TokenKey {
+    /// Generates another key pair on this key's token.
+    pub fn regenerate(
+        &self,
+        _: &impl OpenMlsRand,
+        _: SignatureScheme,
+    ) -> Result<SignatureKeyPair, MySgmError> {
+        Err(MySgmError::UnsupportedPlatform("PKCS#11 tokens"))
+    }
+    fn sign(&self, _: SignatureScheme, _: &[u8]) -> Result<Vec<u8>, MySgmError> {
+        Err(MySgmError::UnsupportedPlatform("PKCS#11 tokens"))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl TokenKey {
+    fn target(&self) -> Pkcs11Target<'_> {
+        Pkcs11Target {
+            module: &self.module,
+            slot: Some(self.slot),
+            pin: None,
+        }
+    }
+    /// Generates another key pair on this key's token.
+    pub fn regenerate(
+        &self,
+        rand: &impl OpenMlsRand,
+        signature_scheme: SignatureScheme,
+    ) -> Result<SignatureKeyPair, MySgmError> {
+        SignatureKeyPair::from_token(&self.target(), rand, signature_scheme)
+    }
+    fn sign(
+        &self,
+        signature_scheme: SignatureScheme,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, MySgmError> {
+        let (session, _) = open_session(&self.target())?;
+        let key = session
+            .find_objects(&[
+                Attribute::Class(ObjectClass::PRIVATE_KEY),
+                Attribute::Label(self.label.as_bytes().to_vec()),
+            ])?
+            .into_iter()
+            .next()
+            .ok_or_else(|| MySgmError::TokenKeyNotFound(self.label.clone()))?;
+        let signature = session.sign(&signing_mechanism(signature_scheme)?, key, payload)?;
+        Ok(match signature_scheme {
+            SignatureScheme::ED25519 => signature,
+            // tokens return r || s, OpenMLS expects DER
+            _ => ecdsa_der(&signature),
+        })
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// DER object identifier of the curve for the scheme, and its public key length in bytes.
+fn curve(signature_scheme: SignatureScheme) -> Result<(&'static [u8], usize), MySgmError> {
+    match signature_scheme {
+        SignatureScheme::ED25519 => Ok((&[0x06, 0x03, 0x2b, 0x65, 0x70], 32)),
+        SignatureScheme::ECDSA_SECP256R1_SHA256 => Ok((
+            &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07],
+            65,
+        )),
+        SignatureScheme::ECDSA_SECP384R1_SHA384 => {
+            Ok((&[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22], 97))
+        }
+        scheme => Err(MySgmError::UnsupportedTokenScheme(scheme)),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn signing_mechanism(signature_scheme: SignatureScheme) -> Result<Mechanism<'static>, MySgmError> {
+    match signature_scheme {
+        SignatureScheme::ED25519 => Ok(Mechanism::Eddsa(EddsaParams::new(
+            EddsaSignatureScheme::Ed25519,
+        ))),
+        SignatureScheme::ECDSA_SECP256R1_SHA256 => Ok(Mechanism::EcdsaSha256),
+        SignatureScheme::ECDSA_SECP384R1_SHA384 => Ok(Mechanism::EcdsaSha384),
+        scheme => Err(MySgmError::UnsupportedTokenScheme(scheme)),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Encodes an ECDSA signature given as r || s as a DER sequence of two integers.
+fn ecdsa_der(signature: &[u8]) -> Vec<u8> {
+    let integer = |bytes: &[u8]| {
+        let start = bytes
+            .iter()
+            .position(|byte| *byte != 0)
+            .unwrap_or(bytes.len() - 1);
+        let mut value = bytes[start..].to_vec();
+        if value[0] & 0x80 != 0 {
+            value.insert(0, 0);
+        }
+        let mut encoded = vec![0x02, value.len() as u8];
+        encoded.extend(value);
+        encoded
+    };
+    let (r, s) = signature.split_at(signature.len() / 2);
+    let mut body = integer(r);
+    body.extend(integer(s));
+    let mut der = vec![0x30, body.len() as u8];
+    der.extend(body);
+    der
+}
+
 impl Entity<CURRENT_VERSION> for SignatureKeyPair {}
 
 impl traits::SignatureKeyPair<CURRENT_VERSION> for SignatureKeyPair {}
@@ -106,6 +247,7 @@ impl SignatureKeyPair {
             private,
             public,
             signature_scheme,
+            token: None,
         }
     }
     /// Generates a new `SignatureKeyPair` using the provided cryptographic provider and signature scheme.
@@ -127,6 +269,79 @@ impl SignatureKeyPair {
             private,
             public,
             signature_scheme,
+            token: None,
+        })
+    }
+    /// Generates a new `SignatureKeyPair` on a PKCS#11 token, which keeps the private key and
+    /// performs the signing; only Ed25519 and ECDSA over P-256 and P-384 are supported.
+    ///
+    /// # Parameters
+    ///
+    /// - `target`: The token to generate the key pair on.
+    /// - `rand`: The source of the random label naming the key pair on the token.
+    /// - `signature_scheme`: The signature scheme to be used for key generation.
+    ///
+    /// # Returns
+    ///
+    /// A result containing the new `SignatureKeyPair` instance, holding no private key, or a
+    /// `MySgmError`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_token(
+        target: &Pkcs11Target<'_>,
+        rand: &impl OpenMlsRand,
+        signature_scheme: SignatureScheme,
+    ) -> Result<Self, MySgmError> {
+        let (curve, public_len) = curve(signature_scheme)?;
+        let mechanism = match signature_scheme {
+            SignatureScheme::ED25519 => Mechanism::EccEdwardsKeyPairGen,
+            _ => Mechanism::EccKeyPairGen,
+        };
+        let label_bytes: [u8; 8] = rand
+            .random_array()
+            .map_err(|_| CryptoError::InsufficientRandomness)?;
+        let label = format!("mysgm-{}", hex_encode(label_bytes));
+        let (session, slot) = open_session(target)?;
+        let (public_key, _) = session.generate_key_pair(
+            &mechanism,
+            &[
+                Attribute::Token(true),
+                Attribute::Verify(true),
+                Attribute::EcParams(curve.to_vec()),
+                Attribute::Label(label.as_bytes().to_vec()),
+            ],
+            &[
+                Attribute::Token(true),
+                Attribute::Private(true),
+                Attribute::Sensitive(true),
+                Attribute::Extractable(false),
+                Attribute::Sign(true),
+                Attribute::Label(label.as_bytes().to_vec()),
+            ],
+        )?;
+        let point = session
+            .get_attributes(public_key, &[AttributeType::EcPoint])?
+            .into_iter()
+            .find_map(|attribute| match attribute {
+                Attribute::EcPoint(point) => Some(point),
+                _ => None,
+            })
+            .ok_or_else(|| MySgmError::TokenKeyNotFound(label.clone()))?;
+        // most tokens wrap the point in a DER octet string
+        let public = match point.as_slice() {
+            [0x04, len, rest @ ..] if *len as usize == public_len && rest.len() == public_len => {
+                rest.to_vec()
+            }
+            _ => point,
+        };
+        Ok(Self {
+            private: Vec::new(),
+            public,
+            signature_scheme,
+            token: Some(TokenKey {
+                module: target.module.to_string(),
+                slot: slot.id(),
+                label,
+            }),
         })
     }
 }
@@ -163,6 +378,26 @@ impl SignatureKeyPair {
     pub fn signature_scheme(&self) -> SignatureScheme {
         self.signature_scheme
     }
+    /// Returns the token holding the private key, if the key pair was generated on one.
+    pub fn token(&self) -> Option<&TokenKey> {
+        self.token.as_ref()
+    }
+    /// Signs the payload with the private key, through the token if it holds the key.
+    ///
+    /// Token errors are logged and reported as a `CryptoError`, as OpenMLS expects of signers.
+    pub fn sign<T: OpenMlsCrypto>(
+        &self,
+        crypto: &T,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        match &self.token {
+            Some(token) => token.sign(self.signature_scheme, payload).map_err(|e| {
+                tracing::error!("Token failed to sign: {e}");
+                CryptoError::CryptoLibraryError
+            }),
+            None => crypto.sign(self.signature_scheme, payload, &self.private),
+        }
+    }
     /// Returns a copy of the signature public key structure.
     ///
     /// This method creates a new `SignaturePublicKey` instance containing the same
@@ -0,0 +1,80 @@
+//! Secure group messaging agent built on OpenMLS.
+//!
+//! The agent keeps its identity, key packages, and MLS group state in a serializable
+//! [`MySgmState`], and exchanges key packages, welcomes, commits, and application messages
+//! with other agents through a [`DeliveryAdapter`] such as an OpenDHT proxy, a REST key
+//! server, or a shared directory.
+//!
+//! The agent also compiles to `wasm32-unknown-unknown`, where the state is kept in the
+//! browser's local storage by [`browser::BrowserStorage`], requests to the OpenDHT proxy go
+//! through `fetch`, and [`wasm`] exposes it to JavaScript. Modules needing the operating
+//! system, such as SQLite storage, the daemon's control APIs, and PKCS#11 tokens, are native
+//! only.
+//!
+//! Natively, [`ffi`] exposes the agent to C and C++ applications through `include/mysgm.h`.
+
+pub mod adapter;
+pub mod agent;
+pub mod backup;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod bench;
+#[cfg(target_arch = "wasm32")]
+pub mod browser;
+pub mod chunking;
+pub mod composite;
+pub mod content_addressed;
+pub mod error;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod ffi;
+pub mod file_adapter;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod grpc;
+pub mod http_directory;
+pub mod journal;
+pub mod keys;
+mod lock;
+pub mod memory_adapter;
+pub mod migration;
+pub mod observer;
+pub mod opendht;
+pub mod padding;
+pub mod persistence;
+pub mod provider;
+pub mod redact;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod secret_sink;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod socket;
+#[cfg(not(target_arch = "wasm32"))]
+mod sqlite;
+pub mod state;
+mod timer;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod token;
+#[cfg(target_arch = "wasm32")]
+pub mod wasm;
+
+pub use adapter::{DeliveryAdapter, KeyPackageDirectory};
+pub use agent::{
+    AddReport, Diagnosis, EpochRetention, GroupMember, GroupMetadata, GroupPolicy, GroupStatus,
+    MySgmAgent, PendingProposal, ReceivedMessage, RemoteSlot, ScanCursor, ScanPrefix, SyncReport,
+};
+pub use backup::{read_backup, write_backup};
+pub use chunking::ChunkingAdapter;
+pub use composite::CompositeAdapter;
+pub use content_addressed::ContentAddressedAdapter;
+pub use error::{ArtifactViolation, MySgmError};
+pub use file_adapter::FileAdapter;
+pub use http_directory::HttpDirectoryAdapter;
+pub use memory_adapter::MemoryAdapter;
+pub use observer::AgentObserver;
+pub use opendht::{OpenDhtRestAdapter, RateLimit, RetryPolicy};
+pub use padding::PaddingPolicy;
+pub use persistence::{
+    StateStorage, load_state, save_state, save_state_incrementally, stored_version,
+};
+pub use state::{
+    ArtifactPolicy, AuditEvent, EpochExporter, GroupExport, HistoryEntry, InvitePolicy, JoinLimits,
+    JoinRecord, KeyPackageLogEntry, MySgmState, PendingAdd, PendingInvite, Publication,
+    PublishedRecord, Quarantine, QuarantinedRecord, RecordKind, TrustRecord,
+};
@@ -0,0 +1,22 @@
+//! Locking that carries on past poisoned locks.
+//!
+//! Every update of a value behind a lock in this crate leaves it consistent, be it a single
+//! insert, removal, or assignment, so a panic while holding the lock cannot leave the value
+//! half-updated, and the lock is used as if the panic had not poisoned it.
+
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Locks the mutex, even if it is poisoned.
+pub(crate) fn lock_ignoring_poison<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Locks the lock for reading, even if it is poisoned.
+pub(crate) fn read_ignoring_poison<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Locks the lock for writing, even if it is poisoned.
+pub(crate) fn write_ignoring_poison<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|e| e.into_inner())
+}
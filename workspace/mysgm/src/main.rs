@@ -1,67 +1,795 @@
-pub mod file_adapter;
-pub mod keys;
-pub mod opendht;
-pub mod provider;
-pub mod state;
-
-use file_adapter::FileAdapter;
-use keys::SignatureKeyPair;
-use opendht::OpenDhtRestAdapter;
-use provider::MySgmProvider;
-use state::MySgmState;
-
-use clap::{Parser, Subcommand};
-use core::error::Error;
-use hex::encode as hex_encode;
-use openmls::{
-    credentials::{BasicCredential, Credential, CredentialType, CredentialWithKey},
-    extensions::ExtensionType,
-    framing::{MlsMessageBodyIn, MlsMessageIn, MlsMessageOut, ProcessedMessageContent},
-    group::{
-        GroupId, MergeCommitError, MlsGroup, MlsGroupCreateConfig, MlsGroupJoinConfig,
-        MlsGroupStateError, ProcessMessageError, StagedWelcome,
-    },
-    key_packages::{KeyPackage, key_package_in::KeyPackageIn},
-    prelude::{Capabilities, LeafNodeIndex},
-    treesync::LeafNodeParameters,
-    versions::ProtocolVersion,
+// the command-line client is native only; browsers use the `wasm` bindings of the library
+#![cfg_attr(target_arch = "wasm32", no_main)]
+#![cfg(not(target_arch = "wasm32"))]
+
+use mysgm::{
+    AgentObserver, ArtifactPolicy, ChunkingAdapter, CompositeAdapter, ContentAddressedAdapter,
+    DeliveryAdapter, Diagnosis, FileAdapter, GroupMetadata, GroupPolicy, HttpDirectoryAdapter,
+    InvitePolicy, JoinLimits, KeyPackageLogEntry, MySgmAgent, MySgmError, MySgmState,
+    OpenDhtRestAdapter, PaddingPolicy, RateLimit, RetryPolicy, ScanPrefix, StateStorage,
+    agent::{DEFAULT_SYNC_CONCURRENCY, REPUBLISH_INTERVAL},
+    bench::{self, BenchReport},
+    chunking::{DEFAULT_MAX_CHUNKED_SIZE, DEFAULT_MAX_VALUE_SIZE},
+    grpc::ControlServer,
+    keys::SignatureKeyPair,
+    load_state, read_backup,
+    redact::{Secret, set_log_secrets},
+    save_state, save_state_incrementally,
+    secret_sink::{import_into_pkcs11, store_in_keyring, write_secret_file},
+    socket::{SocketRequest, SocketResponse, SocketServer, forward},
+    stored_version,
+    token::Pkcs11Target,
+    write_backup,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use clap::{
+    ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum, parser::ValueSource,
 };
+use clap_complete::{Shell, generate};
+use clap_mangen::Man;
+use hex::{decode as hex_decode, encode as hex_encode};
+use openmls::credentials::CredentialType;
 use openmls_rust_crypto::RustCrypto;
-use openmls_traits::{OpenMlsProvider, types::Ciphersuite};
-use serde_json::{from_str as json_decode, to_string as json_encode};
+use openmls_traits::{crypto::OpenMlsCrypto, types::Ciphersuite};
+use rustyline::{
+    Context, Editor, Helper, Highlighter, Hinter, Validator, completion::Completer,
+    error::ReadlineError, history::DefaultHistory,
+};
+use serde::Deserialize;
+use serde_json::{Value, from_slice as json_decode, json, to_vec as json_encode};
 use std::{
-    fs::{read_to_string as read_file_to_string, write as write_string_to_file},
-    io::{BufRead, stdin},
+    collections::HashMap,
+    env::{args as env_args, var as env_var},
+    fmt::{Debug, Formatter, Result as FmtResult},
+    fs::{
+        OpenOptions, create_dir_all, read as read_file, read_dir,
+        read_to_string as read_file_to_string, write as write_file,
+    },
+    future::pending,
+    io::{BufRead, ErrorKind, IsTerminal, Read, Write, stderr, stdin, stdout},
+    iter::once,
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tls_codec::{Deserialize, Serialize};
+use tokio::{select, sync::mpsc, time::sleep};
+use tracing::{Instrument, Span};
+use tracing_subscriber::{EnvFilter, fmt::writer::BoxMakeWriter};
+
+const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
+
+const DEFAULT_PROFILE: &str = "default";
+
+/// Names of the state file in a profile directory, by storage.
+const PROFILE_STATE_FILES: &[(&str, Storage)] = &[
+    ("state.json", Storage::Json),
+    ("state.json.zst", Storage::CompressedJson),
+    ("state.db", Storage::Sqlite),
+];
 
 /// CLI for secure group messsaging agent
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "Options not given fall back to their environment variables, then to \
+                  $XDG_CONFIG_HOME/mysgm/config.toml or the file named by MYSGM_CONFIG."
+)]
 struct CliArgs {
-    /// Path to a JSON file to read (required)
-    state_path: String,
+    /// Path of the state; by default the state of the profile
+    state_path: Option<String>,
+    /// Profile whose state under $XDG_DATA_HOME/mysgm to use when no state path is given
+    #[arg(long, env = "MYSGM_PROFILE", default_value = DEFAULT_PROFILE)]
+    profile: String,
+    /// Format of the state; SQLite for .db, .sqlite, and .sqlite3 paths, compressed JSON for
+    /// .zst paths, JSON otherwise
+    #[arg(long, value_enum)]
+    storage: Option<Storage>,
+    /// File holding the passphrase that encrypts the state; MYSGM_PASSPHRASE is used otherwise
+    #[arg(long)]
+    passphrase_file: Option<String>,
     /// Option to reset state
     #[arg(long)]
     reset: bool,
     /// Optional identifier to use in generating pid
     #[arg(long, default_value = "agent")]
     pid: String,
+    /// Ciphersuite for a reset identity, by name or number
+    #[arg(long, value_parser = parse_ciphersuite)]
+    ciphersuite: Option<Ciphersuite>,
+    /// PKCS#11 module of a token, e.g. an HSM or a YubiKey, to generate the signature key of a
+    /// reset identity on; the key never leaves the token, whose user PIN is read from
+    /// MYSGM_PKCS11_PIN
+    #[arg(long)]
+    pkcs11_key_module: Option<String>,
+    /// ID of the slot holding the token; the first slot with a token by default
+    #[arg(long, requires = "pkcs11_key_module")]
+    pkcs11_key_slot: Option<u64>,
+    /// OpenDHT proxy host; remembered in state once given
+    #[arg(long, env = "MYSGM_DHT_HOST")]
+    dht_host: Option<String>,
+    /// OpenDHT proxy port; remembered in state once given
+    #[arg(long, env = "MYSGM_DHT_PORT")]
+    dht_port: Option<u16>,
+    /// Timeout in seconds for each OpenDHT proxy request
+    #[arg(long, default_value_t = 10)]
+    request_timeout: u64,
+    /// Times a failed OpenDHT get is retried, with exponential backoff
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+    /// Requests per second sent to the OpenDHT proxy by this command, overriding set-rate-limit
+    #[arg(long)]
+    rate_limit: Option<f64>,
+    /// Requests in flight at once to the OpenDHT proxy for this command, overriding
+    /// set-rate-limit
+    #[arg(long)]
+    max_concurrent_requests: Option<usize>,
+    /// Maximum number of received messages kept per group; remembered in state once given
+    #[arg(long)]
+    history_limit: Option<usize>,
+    /// Number of past epochs per group whose exports stay derivable; remembered in state once given
+    #[arg(long)]
+    exporter_window: Option<usize>,
+    /// Reject key packages and commits whose signature key differs from the trusted one
+    #[arg(long)]
+    strict: bool,
+    /// Groups whose next commit, proposal, or message is fetched at once while syncing
+    #[arg(long, default_value_t = DEFAULT_SYNC_CONCURRENCY)]
+    sync_concurrency: usize,
+    /// Rendezvous namespace for key package and welcome slots; remembered in state once given
+    #[arg(long)]
+    namespace: Option<String>,
+    /// Delivery service backend; several, given comma-separated or repeated, are all published
+    /// to and read from, in order of preference
+    #[arg(
+        long,
+        env = "MYSGM_BACKEND",
+        value_enum,
+        value_delimiter = ',',
+        default_value = "opendht"
+    )]
+    backend: Vec<Backend>,
+    /// Format for command output
+    #[arg(long, env = "MYSGM_OUTPUT", value_enum, default_value_t = Output::Text, global = true)]
+    output: Output,
+    /// Directory holding records for the file backend
+    #[arg(long, env = "MYSGM_FILE_DIR", default_value = "/tmp")]
+    file_dir: String,
+    /// Base URL of the key server for the http backend
+    #[arg(long, env = "MYSGM_URL", required_if_eq("backend", "http"))]
+    url: Option<String>,
+    /// Store records under the digest of their content, with pointers under well-known keys and
+    /// a pid-addressed directory instead of shared key package and welcome slots
+    #[arg(long)]
+    content_addressed: bool,
+    /// Split values larger than this many bytes across several records [default for opendht: 32768]
+    #[arg(long)]
+    max_value_size: Option<usize>,
+    /// Refuse to reassemble split values claiming to be larger than this many bytes
+    #[arg(long, default_value_t = DEFAULT_MAX_CHUNKED_SIZE)]
+    max_chunked_size: usize,
+    /// Forward the command to a daemon listening on this socket instead of loading the state;
+    /// commands reading from stdin are not supported
+    #[arg(long)]
+    via_socket: Option<String>,
+    /// Include private keys, exporter secrets, and message contents in debug logs, for
+    /// development only
+    #[arg(long, env = "MYSGM_LOG_SECRETS")]
+    log_secrets: bool,
+    /// Format of log lines; JSON lines carry the spans they were logged in
+    #[arg(long, env = "MYSGM_LOG_FORMAT", value_enum, default_value_t = Output::Text)]
+    log_format: Output,
+    /// Append log lines to this file instead of writing them to stderr
+    #[arg(long, env = "MYSGM_TRACE_FILE")]
+    trace_file: Option<String>,
     /// Command to execute
     #[command(subcommand)]
     main_command: MainCommands,
 }
 
+/// Defaults for options not given on the command line or in their environment variables, read
+/// from the config file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    profile: Option<String>,
+    dht_host: Option<String>,
+    dht_port: Option<u16>,
+    backend: Option<Vec<String>>,
+    output: Option<String>,
+    file_dir: Option<String>,
+    url: Option<String>,
+    /// Log filter, as in RUST_LOG; MYSGM_LOG and RUST_LOG take precedence
+    log_level: Option<String>,
+}
+
+impl Config {
+    /// Path of the config file: MYSGM_CONFIG, or config.toml under $XDG_CONFIG_HOME/mysgm.
+    fn path() -> PathBuf {
+        if let Ok(path) = env_var("MYSGM_CONFIG") {
+            return PathBuf::from(path);
+        }
+        let config_home = match env_var("XDG_CONFIG_HOME") {
+            Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env_var("HOME").unwrap_or_default()).join(".config"),
+        };
+        config_home.join("mysgm/config.toml")
+    }
+    /// Reads the config file, if there is one.
+    fn load() -> Result<Self, MySgmError> {
+        let path = Self::path();
+        let invalid = |e: String| MySgmError::InvalidConfig(path.display().to_string(), e);
+        match read_file_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents).map_err(|e| invalid(e.to_string())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Sets the options left at their defaults by the command line and environment.
+    fn apply(&self, args: &mut CliArgs, matches: &ArgMatches) -> Result<(), MySgmError> {
+        let unset = |id: &str| {
+            matches
+                .value_source(id)
+                .is_none_or(|source| source == ValueSource::DefaultValue)
+        };
+        let invalid = |e: String| MySgmError::InvalidConfig(Self::path().display().to_string(), e);
+        if let Some(profile) = self.profile.as_ref().filter(|_| unset("profile")) {
+            args.profile = profile.clone();
+        }
+        if let Some(host) = self.dht_host.as_ref().filter(|_| unset("dht_host")) {
+            args.dht_host = Some(host.clone());
+        }
+        if let Some(port) = self.dht_port.filter(|_| unset("dht_port")) {
+            args.dht_port = Some(port);
+        }
+        if let Some(backends) = self.backend.as_ref().filter(|_| unset("backend")) {
+            args.backend = backends
+                .iter()
+                .map(|backend| Backend::from_str(backend, true))
+                .collect::<Result<_, _>>()
+                .map_err(invalid)?;
+        }
+        if let Some(output) = self.output.as_ref().filter(|_| unset("output")) {
+            args.output = Output::from_str(output, true).map_err(invalid)?;
+        }
+        if let Some(dir) = self.file_dir.as_ref().filter(|_| unset("file_dir")) {
+            args.file_dir = dir.clone();
+        }
+        if let Some(url) = self.url.as_ref().filter(|_| unset("url")) {
+            args.url = Some(url.clone());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Backend {
+    File,
+    Http,
+    Opendht,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Storage {
+    Json,
+    CompressedJson,
+    Sqlite,
+}
+
+impl From<Storage> for StateStorage {
+    fn from(storage: Storage) -> Self {
+        match storage {
+            Storage::Json => StateStorage::Json,
+            Storage::CompressedJson => StateStorage::CompressedJson,
+            Storage::Sqlite => StateStorage::Sqlite,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Output {
+    Text,
+    Json,
+}
+
+/// Where an exported secret goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum SecretSink {
+    /// Printed in hex
+    Stdout,
+    /// Stored in the OS keyring
+    Keyring,
+    /// Written in hex to a new file readable by its owner only
+    File,
+    /// Imported into a PKCS#11 token as a non-extractable secret key
+    Pkcs11,
+}
+
 #[derive(Debug, Subcommand)]
 enum MainCommands {
+    /// Create a fresh identity, advertise a key package, and optionally create or join groups
+    Bootstrap {
+        /// Label the new pid is derived from
+        #[arg(long)]
+        pid_label: String,
+        /// Create a group with this gid
+        #[arg(long, conflicts_with = "join_via")]
+        create_group: Option<String>,
+        /// Join the groups of a link payload written by link-device, as a device of its
+        /// identity
+        #[arg(long)]
+        join_via: Option<String>,
+        /// Days the key package stays valid
+        #[arg(long, default_value_t = 84)]
+        lifetime_days: u64,
+    },
     Me {},
-    Agents {},
+    /// Check the state, signature key, delivery service, clock, and groups, and print a
+    /// pass/fail report
+    Doctor {},
+    /// List the profiles holding state
+    ListProfiles {},
+    /// Print the completion script for the shell
+    Completions {
+        #[arg(long, value_enum)]
+        shell: Shell,
+    },
+    /// Print the manual page in roff
+    #[command(hide = true)]
+    Man {},
+    /// Time key package validation, welcome processing, commit merging, and message
+    /// encryption and decryption for simulated agents sharing an in-memory delivery service
+    Bench {
+        /// Agents in the group, including its creator
+        #[arg(long, default_value_t = 8)]
+        members: usize,
+        /// Messages the creator sends
+        #[arg(long, default_value_t = 100)]
+        messages: usize,
+    },
+    /// Encrypt stdin to stdout under a key derived from the group's epoch exporter
+    Seal {
+        /// gid of the group
+        #[arg(long)]
+        gid: String,
+    },
+    /// Decrypt stdin sealed by a member of the group to stdout
+    Open {
+        /// gid of the group
+        #[arg(long)]
+        gid: String,
+    },
+    Agents {
+        /// Show the alias of each agent next to its pid
+        #[arg(long)]
+        resolve: bool,
+    },
     Groups {},
-    Advertise {},
+    /// Publish a last-resort key package, or a batch of one-time key packages
+    Advertise {
+        /// Days the key package stays valid
+        #[arg(long, default_value_t = 84)]
+        lifetime_days: u64,
+        /// Only republish if the last advertisement is older than this many days
+        #[arg(long)]
+        if_stale: Option<u64>,
+        /// Publish this many one-time key packages instead of a last-resort one
+        #[arg(long, conflicts_with = "if_stale")]
+        one_time: Option<u64>,
+    },
+    /// Replace the signature key, keeping the pid, commit it to every group, and advertise a
+    /// key package carrying it; prints the gids of the groups rotated
+    RotateIdentity {
+        /// Days the new key package stays valid
+        #[arg(long, default_value_t = 84)]
+        lifetime_days: u64,
+    },
+    /// Download new key packages, welcome messages, and commits, and print a summary
+    Update {
+        /// Also decrypt new application messages into the history
+        #[arg(long)]
+        receive: bool,
+    },
+    /// Read commands interactively, keeping state loaded between them
+    Repl {},
+    /// Execute the commands of a YAML or JSON script against the state, and print a report of
+    /// their outputs
+    ///
+    /// The script holds a list of `steps`, each a command written as in the REPL, or an object
+    /// with the `command` and a variable to `save` its output in; `${name}` in later commands
+    /// is replaced by the variable's value, and `${pid}` by this agent's pid. State is saved
+    /// only if every step succeeds, though records published by the steps before a failure
+    /// stay on the delivery service.
+    Run {
+        /// Path of the script
+        #[arg(long)]
+        script: String,
+    },
+    /// Stay resident, periodically syncing, receiving messages, and saving state
+    Daemon {
+        /// Maximum seconds to wait between syncs; with OpenDHT, new values trigger a sync early
+        #[arg(long, default_value_t = 10)]
+        interval: u64,
+        /// Serve the gRPC control API on this address, e.g. 127.0.0.1:50051
+        #[arg(long)]
+        grpc: Option<SocketAddr>,
+        /// Accept commands from --via-socket on this Unix socket path
+        #[arg(long)]
+        socket: Option<String>,
+    },
     CreateGroup {
         /// Optional gid for the new group
         #[arg(long, default_value = "group")]
         gid: String,
+        /// Post the ratchet tree separately instead of including it in welcomes
+        #[arg(long)]
+        no_tree_in_welcome: bool,
+        /// Past epochs whose message secrets are retained, instead of none
+        #[arg(long)]
+        max_past_epochs: Option<usize>,
+    },
+    /// Replace a group by a new one with fresh epoch secrets, welcoming its members, and leave it
+    ReinitGroup {
+        /// gid of the group
+        #[arg(long)]
+        gid: String,
+        /// Ciphersuite of the new group, by name or number; the group's current one by default
+        #[arg(long, value_parser = parse_ciphersuite)]
+        ciphersuite: Option<Ciphersuite>,
+    },
+    /// Store an external pre-shared key for later injection into groups
+    AddPsk {
+        /// Identifier of the pre-shared key, shared by all members
+        #[arg(long)]
+        id: String,
+        /// Hex-encoded secret of the pre-shared key
+        #[arg(long, value_parser = parse_psk_secret)]
+        secret: PskSecret,
+    },
+    /// Join a group through an external commit against its published group info
+    ExternalJoin {
+        /// gid of the group to join
+        #[arg(long)]
+        gid: String,
+    },
+    /// Show epoch, ciphersuite, own leaf index, member count, and pending changes of a group
+    ShowGroup {
+        /// gid of the group to show
+        #[arg(long)]
+        gid: String,
+    },
+    /// Send the same message to every group, or to those whose gid label matches a glob
+    Broadcast {
+        /// Application message to send, or `-` to read it from stdin
+        message: String,
+        /// Glob such as `ops-*` that gid labels must match
+        #[arg(long)]
+        filter: Option<String>,
+    },
+    /// Set the name, topic, or avatar hash all members of a group see; an empty value clears it
+    SetGroupMeta {
+        /// gid of the group
+        #[arg(long)]
+        gid: String,
+        /// Name of the group
+        #[arg(long)]
+        name: Option<String>,
+        /// Topic of the group
+        #[arg(long)]
+        topic: Option<String>,
+        /// Hex digest of the group's avatar image
+        #[arg(long)]
+        avatar_hash: Option<String>,
+    },
+    /// Set the requirements agents must meet to be added to or join a group, replacing the old
+    /// ones; without options anyone may be added
+    SetGroupPolicy {
+        /// gid of the group
+        #[arg(long)]
+        gid: String,
+        /// Credential type members may use, by name (`basic`, `x509`) or number; repeatable
+        #[arg(long, value_parser = parse_credential_type)]
+        credential_type: Vec<u16>,
+        /// Extension type members must support, in decimal or `0x` hex; repeatable
+        #[arg(long, value_parser = parse_extension_type)]
+        require_extension: Vec<u16>,
+        /// Seconds a key package must still be valid for when its agent is added
+        #[arg(long)]
+        min_key_package_lifetime: Option<u64>,
+        /// Largest number of members the group may have
+        #[arg(long)]
+        max_members: Option<usize>,
+    },
+    /// Pad the messages sent to a group to fixed sizes, hiding their exact size from the
+    /// delivery service
+    SetPadding {
+        /// gid of the group
+        #[arg(long)]
+        gid: String,
+        /// Sizes in bytes to pad to, comma-separated; by default 256, 1024, and 4096
+        #[arg(long, value_delimiter = ',')]
+        buckets: Vec<usize>,
+        /// Send messages unpadded again
+        #[arg(long, conflicts_with = "buckets")]
+        off: bool,
+    },
+    /// Add an agent to a group and write its invitation to a file instead of posting it
+    ExportWelcome {
+        /// gid of the group
+        #[arg(long)]
+        gid: String,
+        /// pid of the agent to add
+        #[arg(long)]
+        pid: String,
+        /// File to write the invitation to
+        #[arg(long)]
+        out: String,
+    },
+    /// Join a group from an invitation file, without contacting the delivery service
+    ImportWelcome {
+        /// Invitation file written by export-welcome
+        #[arg(long = "in")]
+        input: String,
+    },
+    /// Stop fetching a group's commits and messages during syncs, keeping its MLS state
+    ArchiveGroup {
+        /// gid of the group
+        #[arg(long)]
+        gid: String,
+    },
+    /// Sync an archived group again, catching up on what was sent to it in the meantime
+    UnarchiveGroup {
+        /// gid of the group
+        #[arg(long)]
+        gid: String,
+    },
+    /// Set how many past epochs of a group keep their message secrets, to decrypt late messages
+    SetMaxPastEpochs {
+        /// gid of the group
+        #[arg(long)]
+        gid: String,
+        /// Past epochs retained; older ones are dropped right away
+        #[arg(long)]
+        max: usize,
+    },
+    /// Drop the message secrets of a group's older past epochs, so their messages can no longer
+    /// be decrypted
+    PurgeEpochs {
+        /// gid of the group
+        #[arg(long)]
+        gid: String,
+        /// Latest past epochs to keep
+        #[arg(long, default_value_t = 0)]
+        keep: usize,
+    },
+    /// List the next slot syncs read of each key prefix scanned, and its key
+    Cursors {},
+    /// List the malformed records syncs skip
+    ListQuarantined {},
+    /// Process a quarantined record again, releasing it from quarantine unless still malformed
+    RetryQuarantined {
+        /// Key the record is stored under
+        #[arg(long)]
+        key: String,
+    },
+    /// Probe the slots of a key prefix in the delivery service, showing which exist, their
+    /// sizes, and whether they were processed
+    ListRemote {
+        /// Prefix to probe: kp, wm, cm_<gid>, or msg_<gid>
+        #[arg(long)]
+        prefix: ScanPrefix,
+        /// Slots to probe, from the first
+        #[arg(long, default_value_t = 16)]
+        max: u64,
+    },
+    /// Move the cursor of a key prefix to a slot, to scan again from there
+    ResetCursor {
+        /// Prefix of the cursor: kp, wm, cm_<gid>, or msg_<gid>
+        #[arg(long)]
+        prefix: ScanPrefix,
+        /// Slot to scan from next
+        #[arg(long, default_value_t = 0)]
+        to: u64,
+    },
+    /// List the welcomes waiting to be accepted or declined
+    ListInvites {},
+    /// Join the group of a pending invite
+    AcceptInvite {
+        /// Id of the invite, as listed by list-invites
+        #[arg(long)]
+        id: u64,
+    },
+    /// Discard a pending invite without joining its group
+    DeclineInvite {
+        /// Id of the invite, as listed by list-invites
+        #[arg(long)]
+        id: u64,
+    },
+    /// Set which welcomes are joined as they arrive, replacing the old policy; without options
+    /// every welcome is
+    SetInvitePolicy {
+        /// Queue welcomes as invites until accepted
+        #[arg(long)]
+        confirm: bool,
+        /// pid or alias whose welcomes are joined right away, queueing everyone else's;
+        /// repeatable
+        #[arg(long)]
+        auto_accept_from: Vec<String>,
+    },
+    /// Set the ciphersuites, protocol versions, and credential types accepted in incoming key
+    /// packages, welcomes, and group infos, replacing the old ones; without options anything
+    /// supported is accepted
+    SetArtifactPolicy {
+        /// Ciphersuite accepted, by name or number; repeatable
+        #[arg(long, value_parser = parse_ciphersuite)]
+        ciphersuite: Vec<Ciphersuite>,
+        /// Protocol version accepted, by number; repeatable
+        #[arg(long)]
+        protocol_version: Vec<u16>,
+        /// Credential type accepted, by name (`basic`, `x509`) or number; repeatable
+        #[arg(long, value_parser = parse_credential_type)]
+        credential_type: Vec<u16>,
+    },
+    /// Write one group's state to a file, for import by another agent of the same identity
+    ExportGroup {
+        /// gid of the group
+        #[arg(long)]
+        gid: String,
+        /// File to write the group's state to
+        #[arg(long)]
+        out: String,
+        /// Drop the group here after writing it, having moved it
+        #[arg(long)]
+        remove: bool,
+    },
+    /// Merge a group's state written by export-group into this agent's state
+    ImportGroup {
+        /// File written by export-group
+        #[arg(long = "in")]
+        input: String,
+    },
+    /// Print own last-resort key package as base64, or write it to a file, for exchange out of band
+    ExportKeyPackage {
+        /// File to write the key package to instead of printing it
+        #[arg(long)]
+        out: Option<String>,
+        /// Days a newly created key package stays valid, if there is no current one
+        #[arg(long, default_value_t = 84)]
+        lifetime_days: u64,
+    },
+    /// Store a key package received out of band; reads base64 from stdin if no source is given
+    ImportKeyPackage {
+        /// Key package file written by export-key-package
+        #[arg(long = "in", conflicts_with = "base64")]
+        input: Option<String>,
+        /// Key package as printed by export-key-package
+        #[arg(long)]
+        base64: Option<String>,
+    },
+    /// Write a signed payload for linking a new device to this identity and its groups
+    LinkDevice {
+        /// File to write the link payload to
+        #[arg(long)]
+        out: String,
+    },
+    /// Link this fresh agent as a device of the identity in a link payload, joining its groups
+    AcceptLink {
+        /// Link payload written by link-device
+        #[arg(long = "in")]
+        input: String,
+    },
+    /// Upgrade the state to the current format version, or copy it into a new file in another
+    /// format, e.g. from JSON to SQLite
+    MigrateState {
+        /// Path of the new state; by default the state is rewritten in place
+        #[arg(long)]
+        out: Option<String>,
+        /// Format of the new state; by default picked from the path like --storage
+        #[arg(long, value_enum)]
+        to: Option<Storage>,
+    },
+    /// Write a compressed, checksummed backup archive of the state
+    Backup {
+        /// Path of the archive
+        #[arg(long)]
+        out: String,
+        /// Include the message history
+        #[arg(long)]
+        with_history: bool,
+    },
+    /// Replace the state with the one in a backup archive
+    Restore {
+        /// Archive written by backup
+        #[arg(long = "in")]
+        input: String,
+    },
+    /// Set the default limits on requests to the OpenDHT proxy; omitted limits are lifted
+    SetRateLimit {
+        /// Requests per second, in bursts of up to as many
+        #[arg(long)]
+        requests_per_second: Option<f64>,
+        /// Requests in flight at once, not counting listens
+        #[arg(long)]
+        max_concurrent: Option<usize>,
+    },
+    /// Set the limits of groups joined from now on; omitted limits keep the OpenMLS defaults
+    SetJoinLimits {
+        /// Past epochs whose messages can still be decrypted after a commit
+        #[arg(long)]
+        max_past_epochs: Option<usize>,
+        /// Generations a message may fall behind its sender's latest one
+        #[arg(long)]
+        out_of_order_tolerance: Option<u32>,
+        /// Generations a message may skip ahead of its sender's latest one
+        #[arg(long)]
+        maximum_forward_distance: Option<u32>,
+    },
+    /// Name an agent so that the name can be used wherever a pid is expected
+    SetAlias {
+        /// pid of the agent
+        #[arg(long)]
+        pid: String,
+        /// Name for the agent; removes its alias if not given
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Print the fingerprint of an agent's signature key, or mark the agent verified if it matches
+    VerifyAgent {
+        /// pid of the agent
+        #[arg(long)]
+        pid: String,
+        /// Fingerprint obtained from the agent out of band
+        #[arg(long)]
+        fingerprint: Option<String>,
+    },
+    /// Remove a compromised agent from every shared group and refuse its signature key
+    Quarantine {
+        /// pid of the agent
+        #[arg(long)]
+        pid: String,
+        /// Add the agent back once it publishes a key package with a new signature key
+        #[arg(long)]
+        reinvite: bool,
+    },
+    /// List the key packages downloaded or imported so far, with their validity, whether an add
+    /// consumed them, and the slot they came from
+    ListKeyPackages {},
+    /// Drop expired and consumed key packages
+    Gc {},
+    /// Retry the publications queued while the delivery service was unreachable
+    Flush {},
+    /// Put published records again so that the delivery service does not expire them
+    Republish {
+        /// Only republish records last put more than this many seconds ago
+        #[arg(long, default_value_t = REPUBLISH_INTERVAL)]
+        max_age: u64,
+    },
+    /// Print the log of membership, key, and context changes of a group
+    Audit {
+        /// gid of the group
+        #[arg(long)]
+        gid: String,
+        /// Only print events at or after this Unix time
+        #[arg(long)]
+        since: Option<u64>,
+    },
+    /// Print messages received in a group
+    History {
+        /// gid of the group
+        #[arg(long)]
+        gid: String,
+        /// Only print messages received at or after this Unix time
+        #[arg(long)]
+        since: Option<u64>,
+    },
+    /// Print a fingerprint of the group's epoch authenticator to compare with other members
+    VerifyEpoch {
+        /// gid of the group
+        #[arg(long)]
+        gid: String,
     },
     Group {
         /// gid for group commands
@@ -81,504 +809,2373 @@ enum GroupCommands {
         /// Length for the exported secret
         #[arg(long)]
         length: usize,
+        /// Derive from the retained exporter of this epoch, the current or a recent past one,
+        /// instead of the MLS exporter of the current epoch
+        #[arg(long)]
+        epoch: Option<u64>,
+        /// Print the epoch and group context hash before the secret
+        #[arg(long)]
+        with_epoch: bool,
+        /// Where to put the secret; sinks other than stdout print only a handle to it
+        #[arg(long, value_enum, default_value = "stdout")]
+        sink: SecretSink,
+        /// Path of the file to create for --sink file
+        #[arg(long, required_if_eq("sink", "file"))]
+        out: Option<String>,
+        /// Path of the PKCS#11 module for --sink pkcs11
+        #[arg(long, required_if_eq("sink", "pkcs11"))]
+        pkcs11_module: Option<String>,
+        /// ID of the slot holding the token; the first slot with a token by default
+        #[arg(long)]
+        pkcs11_slot: Option<u64>,
+        /// File holding the user PIN of the token; MYSGM_PKCS11_PIN is used otherwise
+        #[arg(long)]
+        pkcs11_pin_file: Option<String>,
     },
-    Add {},
-    Remove {},
+    Add {
+        /// pids to add; read from stdin if none are given
+        pids: Vec<String>,
+        /// Add the pids that have a key package and report the others instead of failing
+        #[arg(long)]
+        skip_missing: bool,
+    },
+    /// Publish and merge an add whose commit or welcome could not be published
+    ResumePendingAdd {},
+    /// Remove members from the group with a commit
+    Remove {
+        /// pids to remove; read from stdin if none are given
+        pids: Vec<String>,
+    },
+    /// List the members of the group with their leaf indexes
     Members {},
-    Update {},
+    /// Rotate own leaf keys with a self-update commit
+    #[command(alias = "update")]
+    Rotate {},
+    Send {
+        /// Application message to encrypt and send to the group, or `-` to read it from stdin
+        message: String,
+    },
+    Receive {
+        /// Keep running, printing each new message as a line of JSON as it arrives
+        #[arg(long)]
+        follow: bool,
+        /// Maximum seconds to wait between checks while following
+        #[arg(long, default_value_t = 10, requires = "follow")]
+        interval: u64,
+    },
+    /// Propose own removal, keeping the group until the removal is committed
+    Leave {},
+    /// Publish group info so that other agents can join externally
+    PublishGroupInfo {},
+    /// Inject a stored pre-shared key into the group's key schedule
+    UsePsk {
+        /// Identifier of the pre-shared key
+        #[arg(long)]
+        id: String,
+    },
+    /// Allow a member to add and remove members and change admins
+    GrantAdmin {
+        /// pid of the member
+        pid: String,
+    },
+    /// Take the admin role from a member
+    RevokeAdmin {
+        /// pid of the member
+        pid: String,
+    },
+    /// Propose adding agents, leaving the commit to an admin
+    ProposeAdd {
+        /// pids to add; read from stdin if none are given
+        pids: Vec<String>,
+    },
+    /// Propose removing members, leaving the commit to an admin
+    ProposeRemove {
+        /// pids to remove; read from stdin if none are given
+        pids: Vec<String>,
+    },
+    /// List the proposals queued for the next commit
+    Proposals {},
+    /// Commit all queued proposals at once
+    CommitPending {},
 }
 
-pub fn key_package_key(index: u64) -> String {
-    format!("kp{index}")
+impl MainCommands {
+    /// Name of the command's variant, as recorded on its span.
+    fn name(&self) -> String {
+        let debug = format!("{self:?}");
+        debug
+            .split([' ', '(', '{'])
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
+    /// Span that the command, and the sync before it, run in.
+    fn span(&self) -> Span {
+        tracing::info_span!("command", command = %self.name())
+    }
+    /// Whether the command needs a sync before it runs; offline commands and update, which
+    /// syncs itself, do not.
+    fn syncs_first(&self) -> bool {
+        !matches!(
+            self,
+            MainCommands::Bootstrap { .. }
+                // each step syncs first instead
+                | MainCommands::Run { .. }
+                // shows what the next sync would process
+                | MainCommands::ListRemote { .. }
+                // syncing fails if the delivery service is down
+                | MainCommands::Doctor {}
+                | MainCommands::Update { .. }
+                | MainCommands::ImportWelcome { .. }
+                | MainCommands::ImportGroup { .. }
+                | MainCommands::ExportKeyPackage { .. }
+                | MainCommands::ImportKeyPackage { .. }
+                | MainCommands::Flush {}
+                | MainCommands::Group {
+                    group_command: GroupCommands::ResumePendingAdd {},
+                    ..
+                }
+        )
+    }
+    /// Whether the command changes state beyond what syncing already does.
+    fn is_mutating(&self) -> bool {
+        match self {
+            MainCommands::Me {}
+            | MainCommands::Doctor {}
+            | MainCommands::ListProfiles {}
+            | MainCommands::Completions { .. }
+            | MainCommands::Man {}
+            | MainCommands::Bench { .. }
+            | MainCommands::Open { .. }
+            | MainCommands::Agents { .. }
+            | MainCommands::Groups {}
+            | MainCommands::ListKeyPackages {}
+            | MainCommands::ShowGroup { .. }
+            | MainCommands::ListInvites {}
+            | MainCommands::Cursors {}
+            | MainCommands::ListQuarantined {}
+            | MainCommands::ListRemote { .. }
+            | MainCommands::Audit { .. }
+            | MainCommands::History { .. }
+            | MainCommands::VerifyEpoch { .. } => false,
+            MainCommands::Group { group_command, .. } => !matches!(
+                group_command,
+                GroupCommands::ExportSecret { .. }
+                    | GroupCommands::Members {}
+                    | GroupCommands::Proposals {}
+            ),
+            _ => true,
+        }
+    }
 }
-pub fn welcome_message_key(index: u64) -> String {
-    format!("wm{index}")
+
+/// A single line entered in the REPL.
+#[derive(Parser, Debug)]
+#[command(no_binary_name = true)]
+struct ReplLine {
+    #[command(subcommand)]
+    command: MainCommands,
 }
 
-pub fn commit_key(group: &MlsGroup, provider: &MySgmProvider) -> Result<String, Box<dyn Error>> {
-    Ok(format!(
-        "cm{}",
-        hex_encode(group.export_secret(provider, "post_commit", &[], 32)?)
-    ))
+/// Completes command names at the start of REPL lines.
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct ReplHelper {
+    commands: Vec<String>,
 }
 
-fn main() {
-    pretty_env_logger::init();
-    // cli args
-    let args = CliArgs::parse();
-    log::info!("Command-line arguments: {args:?}");
-    // dht adapter
-    let adapter = OpenDhtRestAdapter::new("localhost", 8000);
-    log::info!("OpenDHT REST adapter: {adapter:?}");
-    // file adapter
-    //let adapter = FileAdapter::new("/tmp");
-    //log::info!("File adapter: {adapter:?}");
-    // crypto
-    let crypto: RustCrypto = Default::default();
-    // state
-    log::info!("Path to agent state: {}", args.state_path);
-    log::info!("Reset state? {}", args.reset);
-    let state = if args.reset {
-        log::warn!("Resetting state");
-        // ciphersuite
-        let ciphersuite = Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519;
-        // signature key pair
-        let signature_key_pair =
-            SignatureKeyPair::from_crypto(&crypto, ciphersuite.into()).unwrap();
-        // new provider; done
-        let pid_transformed = format!(
-            "{}_{}",
-            &args.pid,
-            hex_encode(signature_key_pair.public_key_raw())
-                .chars()
-                .take(3)
-                .collect::<String>()
-        );
-        MySgmState::new(
-            pid_transformed,
-            signature_key_pair,
-            ciphersuite,
-            ProtocolVersion::Mls10,
-        )
-    } else {
-        log::debug!("Attempting to load state from file");
-        json_decode(&read_file_to_string(&args.state_path).unwrap()).unwrap()
-    };
-    log::info!("State: {state:?}");
-    // credential
-    let cred_with_key = CredentialWithKey {
-        credential: BasicCredential::new(state.my_pid().as_bytes().to_vec()).into(),
-        signature_key: state.signature_key_pair().public_key_raw().into(),
-    };
-    // capabilities
-    let capabilities = Capabilities::new(
-        None,
-        None,
-        Some(&[ExtensionType::LastResort]),
-        None,
-        Some(&[CredentialType::Basic]),
-    );
-    // config
-    let group_config = MlsGroupCreateConfig::builder()
-        .ciphersuite(state.my_ciphersuite())
-        .use_ratchet_tree_extension(true)
-        .capabilities(capabilities.clone())
-        .build();
-    // provider
-    let mut provider = MySgmProvider::new(state, crypto);
-    // download key packages
-    loop {
-        let key = key_package_key(provider.state().key_package_counter());
-        log::info!("Key package key to get: {key}");
-        match adapter.get(&key) {
-            Ok(Some(kp_bytes)) => {
-                provider.state_mut().increment_key_package_counter();
-                log::info!("Got key package bytes: {}", hex_encode(&kp_bytes));
-                match MlsMessageIn::tls_deserialize_exact(kp_bytes)
-                    .unwrap()
-                    .extract()
-                {
-                    MlsMessageBodyIn::KeyPackage(kp_in) => {
-                        let kp = kp_in
-                            .validate(provider.crypto(), provider.state().mls_version())
-                            .unwrap();
-                        log::info!("Processed key package: {kp:?}");
-                        let cred =
-                            BasicCredential::try_from(kp.leaf_node().credential().clone()).unwrap();
-                        let pid = String::from_utf8_lossy(cred.identity()).to_string();
-                        log::info!("pid of key package: {pid}");
-                        provider.state_mut().set_key_package(&pid, kp);
-                    }
-                    _ => {
-                        panic!("Expected KeyPackage message");
-                    }
-                }
-            }
-            Ok(None) => {
-                log::info!("No more key packages to download");
-                break;
-            }
-            Err(e) => {
-                panic!("Failed to get key package: {e}");
-            }
+impl Completer for ReplHelper {
+    type Candidate = String;
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let prefix = &line[..pos];
+        if prefix.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
         }
+        let candidates = self
+            .commands
+            .iter()
+            .filter(|command| command.starts_with(prefix))
+            .cloned()
+            .collect();
+        Ok((0, candidates))
     }
-    // download welcoem messages
-    loop {
-        let key = welcome_message_key(provider.state().welcome_counter());
-        log::info!("Welcome message key to get: {key}");
-        match adapter.get(&key) {
-            Ok(Some(wm_bytes)) => {
-                provider.state_mut().increment_welcome_counter();
-                log::info!("Got welcome message bytes: {}", hex_encode(&wm_bytes));
-                match MlsMessageIn::tls_deserialize_exact(wm_bytes)
-                    .unwrap()
-                    .extract()
-                {
-                    MlsMessageBodyIn::Welcome(welcome) => {
-                        log::info!("Processed welcome message: {welcome:?}");
-                        match StagedWelcome::new_from_welcome(
-                            &provider,
-                            group_config.join_config(),
-                            welcome,
-                            None,
-                        ) {
-                            Ok(staged_welcome) => {
-                                let group = staged_welcome.into_group(&provider).unwrap();
-                                let gid = String::from_utf8_lossy(group.group_id().as_slice())
-                                    .to_string();
-                                log::info!("Group with gid: {gid}");
-                                provider.state_mut().add_gid(gid.clone());
-                            }
-                            Err(e) => {
-                                log::warn!("Failed to process welcome: {e}");
-                            }
-                        }
-                    }
-                    _ => {
-                        panic!("Not a welcome message");
-                    }
-                }
-            }
-            Ok(None) => {
-                log::info!("No more welcome messages to download");
-                break;
-            }
-            Err(e) => {
-                panic!("Failed to get welcome message: {e}");
-            }
-        }
-    }
-    // download commits
-    for gid in provider.state().gids() {
-        let mut group = MlsGroup::load(provider.storage(), &GroupId::from_slice(gid.as_bytes()))
-            .unwrap()
-            .unwrap();
-        loop {
-            let key = match commit_key(&group, &provider) {
-                Ok(k) => k,
-                Err(e) if e.to_string().contains("evict") => {
-                    log::warn!("Evicted from group, stopping commit download for gid: {gid}");
-                    group.delete(provider.storage()).unwrap();
-                    provider.state_mut().remove_gid(&gid);
-                    break;
-                }
-                Err(e) => {
-                    log::warn!("Failed to merge commit: {e}");
-                    break;
-                }
-            };
-            log::info!("Commit message key to get: {key}");
-            match adapter.get(&key) {
-                Ok(Some(cm_bytes)) => {
-                    log::info!("Got commit message bytes: {}", hex_encode(&cm_bytes));
-                    let proto_msg = MlsMessageIn::tls_deserialize_exact(cm_bytes)
-                        .unwrap()
-                        .try_into_protocol_message()
-                        .unwrap();
-                    match group.process_message(&provider, proto_msg) {
-                        Ok(processed_message) => match processed_message.into_content() {
-                            ProcessedMessageContent::StagedCommitMessage(commit_box) => {
-                                match group.merge_staged_commit(&provider, *commit_box) {
-                                    Ok(_) => {
-                                        log::info!("Merged commit into group state for gid: {gid}");
-                                    }
-                                    Err(e) if e.to_string().contains("UseAfterEviction") => {
-                                        log::warn!(
-                                            "Evicted from group, stopping commit download for gid: {gid}"
-                                        );
-                                        provider.state_mut().remove_gid(&gid);
-                                        break;
-                                    }
-                                    Err(e) => {
-                                        log::warn!("Failed to merge commit: {e}");
-                                        break;
-                                    }
-                                }
-                            }
-                            _ => panic!("Not a commit message"),
-                        },
-                        Err(e) => {
-                            log::warn!("Failed to process commit message: {e}");
-                            break;
-                        }
-                    }
-                }
-                Ok(None) => {
-                    log::info!("No more commit messages to download for gid: {gid}");
-                    break;
-                }
-                Err(e) => {
-                    panic!("Failed to get commit message: {e}");
-                }
-            }
+}
+
+/// Builds the adapter of one delivery service backend, splitting large values if the backend
+/// limits their size.
+fn delivery_adapter(
+    args: &CliArgs,
+    state: &MySgmState,
+    backend: Backend,
+) -> Box<dyn DeliveryAdapter> {
+    let adapter: Box<dyn DeliveryAdapter> = match backend {
+        Backend::File => Box::new(FileAdapter::new(&args.file_dir)),
+        Backend::Http => Box::new(HttpDirectoryAdapter::new(
+            args.url.as_deref().unwrap_or_default(),
+        )),
+        Backend::Opendht => {
+            let defaults = state.rate_limit();
+            Box::new(
+                OpenDhtRestAdapter::with_retry_policy(
+                    state.dht_host(),
+                    state.dht_port(),
+                    RetryPolicy {
+                        timeout: Duration::from_secs(args.request_timeout),
+                        max_retries: args.retries,
+                        ..Default::default()
+                    },
+                )
+                .with_rate_limit(RateLimit {
+                    requests_per_second: args.rate_limit.or(defaults.requests_per_second),
+                    max_concurrent: args.max_concurrent_requests.or(defaults.max_concurrent),
+                }),
+            )
         }
+    };
+    let max_value_size = match backend {
+        Backend::Opendht => Some(args.max_value_size.unwrap_or(DEFAULT_MAX_VALUE_SIZE)),
+        _ => args.max_value_size,
+    };
+    match max_value_size {
+        Some(max_value_size) => Box::new(
+            ChunkingAdapter::with_max_value_size(adapter, max_value_size)
+                .with_max_chunked_size(args.max_chunked_size),
+        ),
+        None => adapter,
     }
-    // execute command
-    log::info!("Command to process: {:?}", args.main_command);
-    match &args.main_command {
-        MainCommands::Me {} => {
-            println!("{}", provider.state().my_pid());
-        }
-        MainCommands::Agents {} => {
-            for pid in provider.state().pids() {
-                println!("{pid}");
-            }
-        }
-        MainCommands::Groups {} => {
-            for gid in provider.state().gids() {
-                println!("{gid}");
-            }
-        }
-        MainCommands::CreateGroup { gid } => {
-            let gid_transformed = format!(
-                "{}_{}",
-                gid,
-                hex_encode(provider.state().signature_key_pair().public_key_raw())
-                    .chars()
-                    .take(3)
-                    .collect::<String>()
-            );
-            match provider.state().gids().contains(&gid_transformed) {
-                true => {
-                    panic!("Group already exists");
-                }
-                false => {
-                    let _ = MlsGroup::new_with_group_id(
-                        &provider,
-                        &provider,
-                        &group_config,
-                        GroupId::from_slice(gid_transformed.as_bytes()),
-                        cred_with_key.clone(),
-                    )
-                    .unwrap();
-                    provider.state_mut().add_gid(gid_transformed.clone());
-                    println!("{gid_transformed}");
+}
+
+/// Logs the agent's events as structured events under the `mysgm::events` target.
+#[derive(Debug)]
+struct LogObserver;
+
+impl AgentObserver for LogObserver {
+    fn on_key_package(&self, pid: &str) {
+        tracing::info!(target: "mysgm::events", event = "key_package", pid);
+    }
+    fn on_welcome(&self, gid: &str) {
+        tracing::info!(target: "mysgm::events", event = "welcome", gid);
+    }
+    fn on_commit_merged(&self, gid: &str, epoch: u64) {
+        tracing::info!(target: "mysgm::events", event = "commit", gid, epoch);
+    }
+    fn on_member_added(&self, gid: &str, pid: &str) {
+        tracing::info!(target: "mysgm::events", event = "member_added", gid, pid);
+    }
+    fn on_message(&self, gid: &str, sender: &str, message: &[u8]) {
+        tracing::info!(
+            target: "mysgm::events",
+            event = "message",
+            gid,
+            sender,
+            bytes = message.len()
+        );
+    }
+}
+
+/// Secret of a pre-shared key given on the command line, redacted from `Debug` output so that
+/// logging the parsed arguments does not leak it.
+#[derive(Clone)]
+struct PskSecret(Vec<u8>);
+
+impl Debug for PskSecret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        Secret(&self.0).fmt(f)
+    }
+}
+
+/// Parses the hex-encoded secret of a pre-shared key.
+fn parse_psk_secret(s: &str) -> Result<PskSecret, String> {
+    hex_decode(s).map(PskSecret).map_err(|e| e.to_string())
+}
+
+/// Parses a ciphersuite supported by the crypto provider from its name or number.
+fn parse_ciphersuite(s: &str) -> Result<Ciphersuite, String> {
+    let supported = RustCrypto::default().supported_ciphersuites();
+    supported
+        .iter()
+        .find(|ciphersuite| {
+            ciphersuite.to_string() == s || u16::from(**ciphersuite).to_string() == s
+        })
+        .copied()
+        .ok_or_else(|| {
+            let names: Vec<String> = supported.iter().map(ToString::to_string).collect();
+            format!("expected one of {}", names.join(", "))
+        })
+}
+
+/// Parses a credential type from its name or number.
+fn parse_credential_type(s: &str) -> Result<u16, String> {
+    match s {
+        "basic" => Ok(u16::from(CredentialType::Basic)),
+        "x509" => Ok(u16::from(CredentialType::X509)),
+        _ => s
+            .parse()
+            .map_err(|_| "expected basic, x509, or a number".to_string()),
+    }
+}
+
+/// Parses an extension type given in decimal or `0x` hex.
+fn parse_extension_type(s: &str) -> Result<u16, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| e.to_string())
+}
+
+/// The pids given, or else those read from stdin, with aliases resolved.
+fn pids_or_stdin(state: &MySgmState, pids: &[String], action: &str) -> Vec<String> {
+    let pids = match pids.is_empty() {
+        true => {
+            tracing::debug!("Reading lines from stdin as agents to {action}");
+            read_stdin_lines()
+        }
+        false => pids.to_vec(),
+    };
+    resolve_pids(state, &pids)
+}
+
+/// Maps aliases among the pids to the pids they stand for.
+fn resolve_pids(state: &MySgmState, pids: &[String]) -> Vec<String> {
+    pids.iter().map(|pid| state.resolve_pid(pid)).collect()
+}
+
+/// The pid followed by its alias, if it has one.
+fn with_alias(state: &MySgmState, pid: &str) -> String {
+    match state.alias(pid) {
+        Some(alias) => format!("{pid} ({alias})"),
+        None => pid.to_string(),
+    }
+}
+
+/// Reads lines from stdin until EOF.
+fn read_stdin_lines() -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in stdin().lock().lines() {
+        match line {
+            Ok(l) => lines.push(l),
+            Err(e) => {
+                tracing::error!("Error reading line: {e}");
+                break;
+            }
+        }
+    }
+    tracing::debug!("Read {} lines from stdin", lines.len());
+    lines
+}
+
+/// The message given on the command line, or all of stdin for `-`.
+fn message_body(message: &str) -> Result<Vec<u8>, MySgmError> {
+    match message {
+        "-" => {
+            let mut body = Vec::new();
+            stdin().lock().read_to_end(&mut body)?;
+            Ok(body)
+        }
+        _ => Ok(message.as_bytes().to_vec()),
+    }
+}
+
+/// Whether the text matches the glob pattern, where `*` matches any run of characters and `?`
+/// any single character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    // positions after the last `*` in the pattern and the text it started matching at
+    let (mut p, mut t, mut star) = (0, 0, None);
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
                 }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// The label a gid was created with, without the creator's key suffix.
+fn gid_label(gid: &str) -> &str {
+    gid.rsplit_once('_').map_or(gid, |(label, _)| label)
+}
+
+/// Downloads new key packages, welcome messages, and commits, logging the slots skipped.
+async fn sync(agent: &mut MySgmAgent) -> Result<(), MySgmError> {
+    for (slot, e) in agent.sync(false).await?.errors {
+        tracing::warn!("Skipped {slot}: {e}");
+    }
+    Ok(())
+}
+
+/// Formats the first 10 bytes of a digest as five groups of four hex digits.
+fn fingerprint(digest: &[u8]) -> String {
+    digest
+        .chunks(2)
+        .take(5)
+        .map(hex_encode)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Prints the outcome of each check, failing with [`MySgmError::ChecksFailed`] if any failed.
+fn print_diagnoses(
+    writer: &mut dyn Write,
+    output: Output,
+    diagnoses: &[Diagnosis],
+) -> Result<(), MySgmError> {
+    print_output(
+        writer,
+        output,
+        diagnoses
+            .iter()
+            .map(|diagnosis| {
+                let outcome = if diagnosis.passed { "pass" } else { "FAIL" };
+                format!("{outcome} {}: {}", diagnosis.check, diagnosis.detail)
+            })
+            .collect(),
+        json!(
+            diagnoses
+                .iter()
+                .map(|diagnosis| json!({
+                    "check": diagnosis.check,
+                    "passed": diagnosis.passed,
+                    "detail": diagnosis.detail,
+                }))
+                .collect::<Vec<_>>()
+        ),
+    )?;
+    match diagnoses
+        .iter()
+        .filter(|diagnosis| !diagnosis.passed)
+        .count()
+    {
+        0 => Ok(()),
+        failed => Err(MySgmError::ChecksFailed(failed)),
+    }
+}
+
+/// Prints each timing of the benchmark with its rate.
+fn print_bench_report(
+    writer: &mut dyn Write,
+    output: Output,
+    report: &BenchReport,
+) -> Result<(), MySgmError> {
+    let timings = [
+        ("key_package_validation", report.key_package_validation),
+        ("add_commit", report.add_commit),
+        ("welcome_processing", report.welcome_processing),
+        ("commit_merging", report.commit_merging),
+        ("encryption", report.encryption),
+        ("decryption", report.decryption),
+    ];
+    let mut lines = vec![format!("{} members", report.members)];
+    let mut value = json!({"members": report.members});
+    for (name, timing) in timings {
+        lines.push(format!(
+            "{}: {} in {:.3} ms, {:.1}/s",
+            name.replace('_', " "),
+            timing.count,
+            timing.elapsed.as_secs_f64() * 1000.0,
+            timing.per_second(),
+        ));
+        value[name] = json!({
+            "count": timing.count,
+            "seconds": timing.elapsed.as_secs_f64(),
+            "per_second": timing.per_second(),
+        });
+    }
+    print_output(writer, output, lines, value)
+}
+
+/// Prints the lines as text, or the value as a single line of JSON.
+fn print_output(
+    writer: &mut dyn Write,
+    output: Output,
+    lines: Vec<String>,
+    value: Value,
+) -> Result<(), MySgmError> {
+    match output {
+        Output::Text => {
+            for line in lines {
+                writeln!(writer, "{line}")?;
             }
         }
-        MainCommands::Advertise {} => {
-            let kp_msg = MlsMessageOut::from(
-                KeyPackage::builder()
-                    .leaf_node_capabilities(capabilities.clone())
-                    .mark_as_last_resort()
-                    .build(
-                        provider.state().my_ciphersuite(),
-                        &provider,
-                        &provider,
-                        cred_with_key.clone(),
+        Output::Json => {
+            writeln!(writer, "{value}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Executes a single command against the agent.
+async fn execute(
+    agent: &mut MySgmAgent,
+    command: &MainCommands,
+    output: Output,
+    writer: &mut dyn Write,
+) -> Result<(), MySgmError> {
+    tracing::info!("Command to process: {}", command.name());
+    match command {
+        MainCommands::Doctor {} => {
+            let state = agent.state();
+            let mut diagnoses = vec![Diagnosis::new(
+                "state",
+                Ok(format!(
+                    "version {}, format {}",
+                    state.version(),
+                    state.format_version()
+                )),
+            )];
+            diagnoses.extend(agent.diagnose().await);
+            print_diagnoses(writer, output, &diagnoses)?;
+        }
+        MainCommands::Me {} => {
+            let state = agent.state();
+            print_output(
+                writer,
+                output,
+                vec![state.my_pid().to_string()],
+                json!({
+                    "pid": state.my_pid(),
+                    "ciphersuite": format!("{:?}", state.my_ciphersuite()),
+                    "signature_key": hex_encode(state.signature_key_pair().public_key_raw()),
+                }),
+            )?;
+        }
+        MainCommands::Agents { resolve } => {
+            let state = agent.state();
+            let pids = state.pids();
+            let value = json!(
+                pids.iter()
+                    .map(|pid| json!({"pid": pid, "alias": state.alias(pid)}))
+                    .collect::<Vec<_>>()
+            );
+            let lines = match resolve {
+                true => pids.iter().map(|pid| with_alias(state, pid)).collect(),
+                false => pids,
+            };
+            print_output(writer, output, lines, value)?;
+        }
+        MainCommands::Groups {} => {
+            let gids = agent.state().gids();
+            let mut groups = Vec::new();
+            for gid in &gids {
+                groups.push(json!({
+                    "gid": gid,
+                    "epoch": agent.group_epoch(gid)?,
+                    "archived": agent.state().archived(gid),
+                }));
+            }
+            print_output(writer, output, gids, json!(groups))?;
+        }
+        MainCommands::AddPsk { id, secret } => {
+            agent.store_psk(id.as_bytes(), &secret.0)?;
+        }
+        MainCommands::ExternalJoin { gid } => {
+            agent.external_join(gid).await?;
+        }
+        MainCommands::ExportWelcome { gid, pid, out } => {
+            let pid = agent.state().resolve_pid(pid);
+            let invitation = agent.export_welcome(gid, &pid).await?;
+            write_file(out, invitation)?;
+        }
+        MainCommands::ArchiveGroup { gid } => {
+            agent.archive_group(gid)?;
+        }
+        MainCommands::UnarchiveGroup { gid } => {
+            let report = agent.unarchive_group(gid).await?;
+            print_output(
+                writer,
+                output,
+                vec![format!(
+                    "{} commits and {} messages caught up on",
+                    report.commits, report.messages
+                )],
+                json!({"commits": report.commits, "messages": report.messages}),
+            )?;
+        }
+        MainCommands::SetMaxPastEpochs { gid, max } => {
+            let dropped = agent.set_max_past_epochs(gid, *max)?;
+            print_output(
+                writer,
+                output,
+                vec![format!("{dropped} past epochs dropped")],
+                json!({"dropped": dropped}),
+            )?;
+        }
+        MainCommands::PurgeEpochs { gid, keep } => {
+            let dropped = agent.purge_epochs(gid, *keep)?;
+            print_output(
+                writer,
+                output,
+                vec![format!("{dropped} past epochs dropped")],
+                json!({"dropped": dropped}),
+            )?;
+        }
+        MainCommands::Cursors {} => {
+            let cursors = agent.cursors()?;
+            print_output(
+                writer,
+                output,
+                cursors
+                    .iter()
+                    .map(|cursor| format!("{} {} {}", cursor.prefix, cursor.position, cursor.key))
+                    .collect(),
+                json!(
+                    cursors
+                        .iter()
+                        .map(|cursor| json!({
+                            "prefix": cursor.prefix.to_string(),
+                            "position": cursor.position,
+                            "key": cursor.key,
+                        }))
+                        .collect::<Vec<_>>()
+                ),
+            )?;
+        }
+        MainCommands::ListRemote { prefix, max } => {
+            let slots = agent.list_remote(prefix, *max).await?;
+            print_output(
+                writer,
+                output,
+                slots
+                    .iter()
+                    .map(|slot| {
+                        let processed = match slot.processed {
+                            true => "processed",
+                            false => "unprocessed",
+                        };
+                        match slot.size {
+                            Some(size) => format!("{} {size} bytes, {processed}", slot.key),
+                            None => format!("{} empty, {processed}", slot.key),
+                        }
+                    })
+                    .collect(),
+                json!(
+                    slots
+                        .iter()
+                        .map(|slot| json!({
+                            "key": slot.key,
+                            "index": slot.index,
+                            "size": slot.size,
+                            "processed": slot.processed,
+                        }))
+                        .collect::<Vec<_>>()
+                ),
+            )?;
+        }
+        MainCommands::ListQuarantined {} => {
+            let records = agent.state().quarantined_records();
+            print_output(
+                writer,
+                output,
+                records
+                    .iter()
+                    .map(|record| {
+                        format!(
+                            "{} at {}: {}",
+                            record.key, record.quarantined_at, record.error
+                        )
+                    })
+                    .collect(),
+                json!(records),
+            )?;
+        }
+        MainCommands::RetryQuarantined { key } => {
+            let report = agent.retry_quarantined(key).await?;
+            let mut lines = vec![format!(
+                "{} key packages, {} welcomes, and {} commits processed",
+                report.key_packages.len(),
+                report.welcomes.len(),
+                report.commits
+            )];
+            lines.extend(
+                report
+                    .errors
+                    .iter()
+                    .map(|(slot, e)| format!("  {slot}: {e}")),
+            );
+            print_output(
+                writer,
+                output,
+                lines,
+                json!({
+                    "key_packages": report.key_packages,
+                    "welcomes": report.welcomes,
+                    "commits": report.commits,
+                    "errors": report
+                        .errors
+                        .iter()
+                        .map(|(slot, e)| json!({"slot": slot, "error": e.to_string()}))
+                        .collect::<Vec<_>>(),
+                }),
+            )?;
+        }
+        MainCommands::ResetCursor { prefix, to } => {
+            agent.reset_cursor(prefix, *to)?;
+        }
+        MainCommands::ListInvites {} => {
+            let invites = agent.state().pending_invites();
+            let lines = invites
+                .iter()
+                .map(|invite| {
+                    format!(
+                        "{} {} from {} at epoch {}",
+                        invite.id, invite.gid, invite.inviter, invite.epoch
                     )
-                    .unwrap()
-                    .key_package()
-                    .clone(),
-            )
-            .tls_serialize_detached()
-            .unwrap();
-            log::info!("Key package to put: {}", hex_encode(&kp_msg));
-            let mut index = provider.state().key_package_counter();
-            loop {
-                let key = key_package_key(index);
-                log::info!("Key package key: {key}");
-                match adapter.put_checked(&key, &kp_msg) {
-                    Ok(()) => {
-                        break;
-                    }
-                    Err(e) if e.to_string() == "Key already exists" => {
-                        log::warn!("Failed to put key package: {e}");
-                        index += 1;
-                    }
-                    Err(e) => {
-                        panic!("{e}");
-                    }
+                })
+                .collect();
+            let invites: Vec<Value> = invites
+                .iter()
+                .map(|invite| {
+                    json!({
+                        "id": invite.id,
+                        "gid": invite.gid,
+                        "inviter": invite.inviter,
+                        "epoch": invite.epoch,
+                        "received_at": invite.received_at,
+                        "welcome_slot": invite.welcome_slot,
+                    })
+                })
+                .collect();
+            print_output(writer, output, lines, json!(invites))?;
+        }
+        MainCommands::AcceptInvite { id } => {
+            let gid = agent.accept_invite(*id).await?;
+            print_output(writer, output, vec![gid.clone()], json!({"gid": gid}))?;
+        }
+        MainCommands::DeclineInvite { id } => {
+            agent.decline_invite(*id)?;
+        }
+        MainCommands::SetInvitePolicy {
+            confirm,
+            auto_accept_from,
+        } => {
+            let auto_accept_from = auto_accept_from
+                .iter()
+                .map(|pid| agent.state().resolve_pid(pid))
+                .collect();
+            agent.state_mut().set_invite_policy(InvitePolicy {
+                confirm: *confirm,
+                auto_accept_from,
+            });
+        }
+        MainCommands::SetArtifactPolicy {
+            ciphersuite,
+            protocol_version,
+            credential_type,
+        } => {
+            agent.state_mut().set_artifact_policy(ArtifactPolicy {
+                ciphersuites: ciphersuite.iter().map(|c| u16::from(*c)).collect(),
+                protocol_versions: protocol_version.clone(),
+                credential_types: credential_type.clone(),
+            });
+        }
+        MainCommands::ExportGroup { gid, out, remove } => {
+            let export = agent.export_group(gid, *remove)?;
+            write_file(out, json_encode(&export)?)?;
+        }
+        MainCommands::ImportGroup { input } => {
+            let export = json_decode(&read_file(input)?)?;
+            writeln!(writer, "{}", agent.import_group(export)?)?;
+        }
+        MainCommands::Seal { gid } => {
+            let mut plaintext = Vec::new();
+            stdin().read_to_end(&mut plaintext)?;
+            writer.write_all(&agent.seal(gid, &plaintext)?)?;
+        }
+        MainCommands::Open { gid } => {
+            let mut sealed = Vec::new();
+            stdin().read_to_end(&mut sealed)?;
+            writer.write_all(&agent.open(gid, &sealed)?)?;
+        }
+        MainCommands::ImportWelcome { input } => {
+            writeln!(
+                writer,
+                "{}",
+                agent.import_welcome(&read_file(input)?).await?
+            )?;
+        }
+        MainCommands::ExportKeyPackage { out, lifetime_days } => {
+            let kp_bytes = agent.export_key_package(lifetime_days * SECONDS_PER_DAY)?;
+            match out {
+                Some(out) => write_file(out, kp_bytes)?,
+                None => writeln!(writer, "{}", STANDARD.encode(kp_bytes))?,
+            }
+        }
+        MainCommands::ImportKeyPackage { input, base64 } => {
+            let kp_bytes = match (input, base64) {
+                (Some(input), _) => read_file(input)?,
+                (None, Some(encoded)) => STANDARD.decode(encoded.trim())?,
+                (None, None) => STANDARD.decode(read_stdin_lines().concat().trim())?,
+            };
+            writeln!(writer, "{}", agent.import_key_package(kp_bytes)?)?;
+        }
+        MainCommands::LinkDevice { out } => {
+            write_file(out, agent.link_device().await?)?;
+        }
+        MainCommands::AcceptLink { input } => {
+            let gids = agent.accept_link(&read_file(input)?).await?;
+            print_output(
+                writer,
+                output,
+                gids.clone(),
+                json!({"pid": agent.state().my_pid(), "gids": gids}),
+            )?;
+        }
+        MainCommands::SetRateLimit {
+            requests_per_second,
+            max_concurrent,
+        } => {
+            agent.state_mut().set_rate_limit(RateLimit {
+                requests_per_second: *requests_per_second,
+                max_concurrent: *max_concurrent,
+            });
+        }
+        MainCommands::SetJoinLimits {
+            max_past_epochs,
+            out_of_order_tolerance,
+            maximum_forward_distance,
+        } => {
+            agent.state_mut().set_join_limits(JoinLimits {
+                max_past_epochs: *max_past_epochs,
+                out_of_order_tolerance: *out_of_order_tolerance,
+                maximum_forward_distance: *maximum_forward_distance,
+            });
+        }
+        MainCommands::SetAlias { pid, name } => {
+            let pid = agent.state().resolve_pid(pid);
+            match name {
+                Some(name) => agent.state_mut().set_alias(&pid, name),
+                None => agent.state_mut().remove_alias(&pid),
+            }
+        }
+        MainCommands::VerifyAgent {
+            pid,
+            fingerprint: Some(expected),
+        } => {
+            let pid = agent.state().resolve_pid(pid);
+            let expected: String = expected.split_whitespace().collect();
+            agent.verify_agent(&pid, &hex_decode(expected)?)?;
+        }
+        MainCommands::VerifyAgent {
+            pid,
+            fingerprint: None,
+        } => {
+            let pid = agent.state().resolve_pid(pid);
+            let (key, verified, changed_key) = match agent.state().trust(&pid) {
+                _ if pid == agent.state().my_pid() => (
+                    agent.state().signature_key_pair().public_key_raw().to_vec(),
+                    true,
+                    None,
+                ),
+                Some(record) => (
+                    record.signature_key.clone(),
+                    record.verified,
+                    record.changed_key.clone(),
+                ),
+                None => return Err(MySgmError::UnknownAgent(pid)),
+            };
+            let trusted = fingerprint(&agent.key_digest(&key)?);
+            let changed = match &changed_key {
+                Some(key) => Some(fingerprint(&agent.key_digest(key)?)),
+                None => None,
+            };
+            let mut lines = vec![match verified {
+                true => format!("{trusted} (verified)"),
+                false => format!("{trusted} (unverified)"),
+            }];
+            lines.extend(changed.iter().map(|changed| format!("{changed} (changed)")));
+            print_output(
+                writer,
+                output,
+                lines,
+                json!({
+                    "pid": pid,
+                    "fingerprint": trusted,
+                    "verified": verified,
+                    "changed_fingerprint": changed,
+                }),
+            )?;
+        }
+        MainCommands::ListKeyPackages {} => {
+            let state = agent.state();
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default();
+            let valid =
+                |entry: &KeyPackageLogEntry| (entry.not_before..=entry.not_after).contains(&now);
+            let entries = state.key_package_log();
+            print_output(
+                writer,
+                output,
+                entries
+                    .iter()
+                    .map(|entry| {
+                        format!(
+                            "{} {} {:?} {}-{} {} {} {} slot {}",
+                            with_alias(state, &entry.pid),
+                            entry.key_package_ref,
+                            entry.ciphersuite,
+                            entry.not_before,
+                            entry.not_after,
+                            if valid(entry) { "valid" } else { "invalid" },
+                            if entry.last_resort {
+                                "last-resort"
+                            } else {
+                                "one-time"
+                            },
+                            if entry.consumed { "consumed" } else { "unused" },
+                            entry.slot.map_or("-".to_string(), |slot| slot.to_string()),
+                        )
+                    })
+                    .collect(),
+                json!(
+                    entries
+                        .iter()
+                        .map(|entry| json!({
+                            "pid": entry.pid,
+                            "key_package_ref": entry.key_package_ref,
+                            "ciphersuite": format!("{:?}", entry.ciphersuite),
+                            "not_before": entry.not_before,
+                            "not_after": entry.not_after,
+                            "valid": valid(entry),
+                            "last_resort": entry.last_resort,
+                            "consumed": entry.consumed,
+                            "slot": entry.slot,
+                        }))
+                        .collect::<Vec<_>>()
+                ),
+            )?;
+        }
+        MainCommands::Gc {} => {
+            let dropped = agent.gc_key_packages()?;
+            print_output(
+                writer,
+                output,
+                vec![format!("dropped {dropped} key packages")],
+                json!({"dropped": dropped}),
+            )?;
+        }
+        MainCommands::Flush {} => {
+            let published = agent.flush().await;
+            let queued = agent.state().outbox().len();
+            print_output(
+                writer,
+                output,
+                vec![format!("published {published}, {queued} still queued")],
+                json!({"published": published, "queued": queued}),
+            )?;
+        }
+        MainCommands::Republish { max_age } => {
+            let keys = agent.republish(*max_age).await?;
+            print_output(writer, output, keys.clone(), json!({"republished": keys}))?;
+        }
+        MainCommands::Audit { gid, since } => {
+            let events: Vec<_> = agent
+                .state()
+                .audit_log(gid)
+                .iter()
+                .filter(|event| since.is_none_or(|since| event.timestamp >= since))
+                .collect();
+            print_output(
+                writer,
+                output,
+                events
+                    .iter()
+                    .map(|event| {
+                        format!(
+                            "{} {} {} {} {}",
+                            event.timestamp,
+                            event.epoch,
+                            event.kind,
+                            event.actor.as_deref().unwrap_or("-"),
+                            event.subject.as_deref().unwrap_or("-"),
+                        )
+                    })
+                    .collect(),
+                json!(events),
+            )?;
+        }
+        MainCommands::History { gid, since } => {
+            let entries: Vec<_> = agent
+                .state()
+                .history(gid)
+                .filter(|entry| since.is_none_or(|since| entry.timestamp >= since))
+                .collect();
+            print_output(
+                writer,
+                output,
+                entries
+                    .iter()
+                    .map(|entry| {
+                        format!(
+                            "{} {} {} {}",
+                            entry.timestamp,
+                            entry.epoch,
+                            entry.sender,
+                            String::from_utf8_lossy(&entry.message)
+                        )
+                    })
+                    .collect(),
+                json!(
+                    entries
+                        .iter()
+                        .map(|entry| json!({
+                            "timestamp": entry.timestamp,
+                            "epoch": entry.epoch,
+                            "sender": entry.sender,
+                            "message": String::from_utf8_lossy(&entry.message),
+                        }))
+                        .collect::<Vec<_>>()
+                ),
+            )?;
+        }
+        MainCommands::VerifyEpoch { gid } => {
+            let (epoch, authenticator) = agent.epoch_authenticator(gid)?;
+            let fingerprint = fingerprint(&authenticator);
+            print_output(
+                writer,
+                output,
+                vec![format!("epoch {epoch}: {fingerprint}")],
+                json!({
+                    "gid": gid,
+                    "epoch": epoch,
+                    "fingerprint": fingerprint,
+                    "epoch_authenticator": hex_encode(&authenticator),
+                }),
+            )?;
+        }
+        MainCommands::ShowGroup { gid } => {
+            let info = agent.group_info(gid)?;
+            let metadata = &info.metadata;
+            let mut lines: Vec<String> = [
+                ("name", &metadata.name),
+                ("topic", &metadata.topic),
+                ("avatar hash", &metadata.avatar_hash),
+                ("predecessor", &metadata.predecessor),
+            ]
+            .into_iter()
+            .filter_map(|(field, value)| Some(format!("{field}: {}", value.as_ref()?)))
+            .collect();
+            lines.extend([
+                format!("epoch: {}", info.epoch),
+                format!("ciphersuite: {:?}", info.ciphersuite),
+                format!("own leaf index: {}", info.own_leaf_index),
+                format!("members: {}", info.member_count),
+                format!("pending proposals: {}", info.pending_proposals),
+                format!("pending commit: {}", info.pending_commit),
+            ]);
+            let policy = &info.policy;
+            let join = |types: &[u16]| {
+                let types: Vec<String> = types.iter().map(|t| format!("{t:#06x}")).collect();
+                types.join(", ")
+            };
+            if !policy.credential_types.is_empty() {
+                lines.push(format!(
+                    "allowed credential types: {}",
+                    join(&policy.credential_types)
+                ));
+            }
+            if !policy.required_extensions.is_empty() {
+                lines.push(format!(
+                    "required extensions: {}",
+                    join(&policy.required_extensions)
+                ));
+            }
+            if let Some(lifetime) = policy.min_key_package_lifetime {
+                lines.push(format!("min key package lifetime: {lifetime}s"));
+            }
+            if let Some(max_members) = policy.max_members {
+                lines.push(format!("max members: {max_members}"));
+            }
+            if let Some(padding) = &info.padding {
+                let buckets: Vec<String> = padding.buckets().iter().map(usize::to_string).collect();
+                lines.push(format!("padding buckets: {}", buckets.join(", ")));
+            }
+            if info.archived {
+                lines.push("archived: true".to_string());
+            }
+            if info.leaving {
+                lines.push("leaving: true".to_string());
+            }
+            let retention = &info.retention;
+            let epochs: Vec<String> = retention.past_epochs.iter().map(u64::to_string).collect();
+            lines.push(format!(
+                "past epochs retained: {} of at most {}{}",
+                epochs.len(),
+                retention.max_past_epochs,
+                match epochs.is_empty() {
+                    true => String::new(),
+                    false => format!(" ({})", epochs.join(", ")),
                 }
+            ));
+            if let Some(joined) = &info.joined {
+                let slot = joined
+                    .welcome_slot
+                    .as_deref()
+                    .unwrap_or("an imported invitation");
+                lines.push(format!(
+                    "joined: invited by {} at epoch {}, at {}, through {slot}",
+                    joined.inviter, joined.epoch, joined.timestamp
+                ));
             }
+            print_output(
+                writer,
+                output,
+                lines,
+                json!({
+                    "gid": gid,
+                    "metadata": metadata,
+                    "policy": policy,
+                    "padding": info.padding,
+                    "epoch": info.epoch,
+                    "ciphersuite": format!("{:?}", info.ciphersuite),
+                    "own_leaf_index": info.own_leaf_index.u32(),
+                    "member_count": info.member_count,
+                    "pending_proposals": info.pending_proposals,
+                    "pending_commit": info.pending_commit,
+                    "joined": info.joined,
+                    "archived": info.archived,
+                    "leaving": info.leaving,
+                    "max_past_epochs": retention.max_past_epochs,
+                    "past_epochs": retention.past_epochs,
+                }),
+            )?;
+        }
+        MainCommands::Broadcast { message, filter } => {
+            let gids: Vec<String> = agent
+                .state()
+                .gids()
+                .into_iter()
+                .filter(|gid| {
+                    filter
+                        .as_ref()
+                        .is_none_or(|filter| glob_match(filter, gid_label(gid)))
+                })
+                .collect();
+            let outcomes = agent.broadcast(&gids, &message_body(message)?).await;
+            print_output(
+                writer,
+                output,
+                outcomes
+                    .iter()
+                    .map(|(gid, outcome)| match outcome {
+                        Ok(()) => format!("{gid} sent"),
+                        Err(e) => format!("{gid} failed: {e}"),
+                    })
+                    .collect(),
+                json!(
+                    outcomes
+                        .iter()
+                        .map(|(gid, outcome)| json!({
+                            "gid": gid,
+                            "sent": outcome.is_ok(),
+                            "error": outcome.as_ref().err().map(ToString::to_string),
+                        }))
+                        .collect::<Vec<_>>()
+                ),
+            )?;
+        }
+        MainCommands::Quarantine { pid, reinvite } => {
+            let pid = agent.state().resolve_pid(pid);
+            let outcomes = agent.quarantine(&pid, *reinvite).await?;
+            print_output(
+                writer,
+                output,
+                outcomes
+                    .iter()
+                    .map(|(gid, outcome)| match outcome {
+                        Ok(()) => format!("{gid} removed"),
+                        Err(e) => format!("{gid} failed: {e}"),
+                    })
+                    .collect(),
+                json!(
+                    outcomes
+                        .iter()
+                        .map(|(gid, outcome)| json!({
+                            "gid": gid,
+                            "removed": outcome.is_ok(),
+                            "error": outcome.as_ref().err().map(ToString::to_string),
+                        }))
+                        .collect::<Vec<_>>()
+                ),
+            )?;
+        }
+        MainCommands::SetGroupMeta {
+            gid,
+            name,
+            topic,
+            avatar_hash,
+        } => {
+            agent
+                .set_group_metadata(
+                    gid,
+                    GroupMetadata {
+                        name: name.clone(),
+                        topic: topic.clone(),
+                        avatar_hash: avatar_hash.clone(),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+        }
+        MainCommands::SetGroupPolicy {
+            gid,
+            credential_type,
+            require_extension,
+            min_key_package_lifetime,
+            max_members,
+        } => {
+            agent
+                .set_group_policy(
+                    gid,
+                    GroupPolicy {
+                        credential_types: credential_type.clone(),
+                        required_extensions: require_extension.clone(),
+                        min_key_package_lifetime: *min_key_package_lifetime,
+                        max_members: *max_members,
+                    },
+                )
+                .await?;
+        }
+        MainCommands::SetPadding { gid, buckets, off } => {
+            let policy = match (off, buckets.is_empty()) {
+                (true, _) => None,
+                (false, true) => Some(PaddingPolicy::default()),
+                (false, false) => Some(PaddingPolicy::new(buckets.iter().copied())?),
+            };
+            agent.set_padding_policy(gid, policy)?;
         }
-        MainCommands::Group { gid, group_command } => {
-            let mut group =
-                MlsGroup::load(provider.storage(), &GroupId::from_slice(gid.as_bytes()))
-                    .unwrap()
-                    .unwrap();
-            match group_command {
-                GroupCommands::ExportSecret { label, length } => {
-                    println!(
-                        "{}",
-                        hex_encode(group.export_secret(&provider, label, &[], *length).unwrap())
-                    );
+        MainCommands::CreateGroup {
+            gid,
+            no_tree_in_welcome,
+            max_past_epochs,
+        } => {
+            let gid = agent.create_group(gid, !no_tree_in_welcome)?;
+            if let Some(max) = max_past_epochs {
+                agent.set_max_past_epochs(&gid, *max)?;
+            }
+            writeln!(writer, "{gid}")?;
+        }
+        MainCommands::ReinitGroup { gid, ciphersuite } => {
+            writeln!(writer, "{}", agent.reinit_group(gid, *ciphersuite).await?)?;
+        }
+        MainCommands::Advertise {
+            lifetime_days,
+            if_stale,
+            one_time,
+        } => {
+            let lifetime = lifetime_days * SECONDS_PER_DAY;
+            match (one_time, if_stale) {
+                (Some(count), _) => {
+                    agent.advertise_one_time(*count, lifetime).await?;
                 }
-                GroupCommands::Members {} => {
-                    let mut pids: Vec<String> = Vec::new();
-                    for member in group.members() {
-                        let cred = BasicCredential::try_from(member.credential.clone()).unwrap();
-                        println!(
-                            "{} {}",
-                            member.index,
-                            String::from_utf8_lossy(cred.identity())
-                        );
-                    }
+                (None, Some(days)) => {
+                    agent
+                        .advertise_if_stale(lifetime, days * SECONDS_PER_DAY)
+                        .await?;
                 }
-                GroupCommands::Remove {} => {
-                    let handle = stdin().lock();
-                    log::debug!("Reading lines from stdin as agents to add");
-                    let mut indexes = Vec::new();
-                    for line in handle.lines() {
-                        match line {
-                            Ok(l) => {
-                                log::info!("index: {l}");
-                                indexes.push(LeafNodeIndex::new(l.parse::<u32>().unwrap()));
-                            }
-                            Err(e) => {
-                                log::error!("Error reading line: {e}");
-                                break;
-                            }
-                        }
-                    }
-                    let (commit, welcome_opt, _) = group
-                        .remove_members(&provider, &provider, indexes.as_slice())
-                        .unwrap();
-                    log::info!("Commit message: {:?}", commit);
-                    let key = commit_key(&group, &provider).unwrap();
-                    adapter
-                        .put_checked(&key, &commit.tls_serialize_detached().unwrap())
-                        .unwrap();
-                    group.merge_pending_commit(&provider).unwrap();
-                    if let Some(welcome) = welcome_opt {
-                        log::info!("Welcome message: {:?}", welcome);
-                        let mut index = provider.state().welcome_counter();
-                        loop {
-                            let key = welcome_message_key(index);
-                            log::info!("Welcome message key: {key}");
-                            match adapter
-                                .put_checked(&key, &welcome.tls_serialize_detached().unwrap())
-                            {
-                                Ok(()) => {
-                                    break;
-                                }
-                                Err(e) if e.to_string() == "Key already exists" => {
-                                    log::warn!("Failed to put key package: {e}");
-                                    index += 1;
-                                }
-                                Err(e) => {
-                                    panic!("{e}");
-                                }
-                            }
-                        }
-                    }
+                (None, None) => {
+                    agent.advertise(lifetime).await?;
                 }
-                GroupCommands::Add {} => {
-                    let handle = stdin().lock();
-                    log::debug!("Reading lines from stdin as agents to add");
-                    let mut kps = Vec::new();
-                    for line in handle.lines() {
-                        match line {
-                            Ok(l) => {
-                                log::info!("pid: {l}");
-                                match provider.state().key_package(&l) {
-                                    Some(kp) => {
-                                        log::info!("Key package for pid: {kp:?}");
-                                        kps.push(kp.clone());
-                                    }
-                                    None => {
-                                        panic!("No key package for pid: {l}");
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                log::error!("Error reading line: {e}");
-                                break;
-                            }
-                        }
-                    }
-                    let (commit, welcome, _) = group
-                        .add_members_without_update(&provider, &provider, kps.as_slice())
-                        .unwrap();
-                    log::info!("Commit message: {:?}", commit);
-                    let key = commit_key(&group, &provider).unwrap();
-                    adapter
-                        .put_checked(&key, &commit.tls_serialize_detached().unwrap())
-                        .unwrap();
-                    group.merge_pending_commit(&provider).unwrap();
-                    log::info!("Welcome message: {:?}", welcome);
-                    let mut index = provider.state().welcome_counter();
-                    loop {
-                        let key = welcome_message_key(index);
-                        log::info!("Welcome message key: {key}");
-                        match adapter.put_checked(&key, &welcome.tls_serialize_detached().unwrap())
-                        {
-                            Ok(()) => {
-                                break;
-                            }
-                            Err(e) if e.to_string() == "Key already exists" => {
-                                log::warn!("Failed to put key package: {e}");
-                                index += 1;
-                            }
-                            Err(e) => {
-                                panic!("{e}");
-                            }
-                        }
+            }
+        }
+        MainCommands::RotateIdentity { lifetime_days } => {
+            let rotated = agent
+                .rotate_identity(lifetime_days * SECONDS_PER_DAY)
+                .await?;
+            print_output(
+                writer,
+                output,
+                rotated.clone(),
+                json!({
+                    "signature_key": hex_encode(agent.state().signature_key_pair().public_key_raw()),
+                    "rotated": rotated,
+                }),
+            )?;
+        }
+        MainCommands::Update { receive } => {
+            let report = agent.sync(*receive).await?;
+            let mut lines = vec![
+                format!("key packages {:>6}", report.key_packages.len()),
+                format!("welcomes     {:>6}", report.welcomes.len()),
+                format!("commits      {:>6}", report.commits),
+                format!("messages     {:>6}", report.messages),
+                format!("reinvited    {:>6}", report.reinvited.len()),
+                format!("adds resumed {:>6}", report.adds.len()),
+                format!("keys rotated {:>6}", report.rotated.len()),
+                format!("errors       {:>6}", report.errors.len()),
+            ];
+            lines.extend(
+                report
+                    .errors
+                    .iter()
+                    .map(|(slot, e)| format!("  {slot}: {e}")),
+            );
+            print_output(
+                writer,
+                output,
+                lines,
+                json!({
+                    "key_packages": report.key_packages,
+                    "welcomes": report.welcomes,
+                    "commits": report.commits,
+                    "messages": report.messages,
+                    "reinvited": report
+                        .reinvited
+                        .iter()
+                        .map(|(pid, gid)| json!({"pid": pid, "gid": gid}))
+                        .collect::<Vec<_>>(),
+                    "adds": report.adds,
+                    "rotated": report.rotated,
+                    "errors": report
+                        .errors
+                        .iter()
+                        .map(|(slot, e)| json!({"slot": slot, "error": e.to_string()}))
+                        .collect::<Vec<_>>(),
+                }),
+            )?;
+        }
+        MainCommands::Repl {}
+        | MainCommands::Run { .. }
+        | MainCommands::Daemon { .. }
+        | MainCommands::Bootstrap { .. }
+        | MainCommands::ListProfiles {}
+        | MainCommands::Completions { .. }
+        | MainCommands::Man {}
+        | MainCommands::Bench { .. }
+        | MainCommands::MigrateState { .. }
+        | MainCommands::Backup { .. }
+        | MainCommands::Restore { .. } => {
+            tracing::warn!("Cannot start {} from the REPL", command.name());
+        }
+        MainCommands::Group { gid, group_command } => match group_command {
+            GroupCommands::ExportSecret {
+                label,
+                length,
+                epoch,
+                with_epoch,
+                sink,
+                out,
+                pkcs11_module,
+                pkcs11_slot,
+                pkcs11_pin_file,
+            } => {
+                let (exporter, secret) = match epoch {
+                    Some(epoch) => (
+                        agent.epoch_exporter(gid, *epoch)?,
+                        agent.exporter_for_epoch(gid, *epoch, label, *length)?,
+                    ),
+                    None => (
+                        agent.epoch_exporter(gid, agent.group_epoch(gid)?)?,
+                        agent.export_secret(gid, label, *length)?,
+                    ),
+                };
+                let name = format!("{gid}/{label}/{}", exporter.epoch);
+                let handle = match sink {
+                    SecretSink::Stdout => None,
+                    SecretSink::Keyring => Some(store_in_keyring(&name, &secret)?),
+                    SecretSink::File => Some(write_secret_file(
+                        out.as_deref().unwrap_or_default(),
+                        &secret,
+                    )?),
+                    SecretSink::Pkcs11 => {
+                        let pin = match pkcs11_pin_file {
+                            Some(path) => Some(
+                                read_file_to_string(path)?
+                                    .trim_end_matches(['\r', '\n'])
+                                    .to_string(),
+                            ),
+                            None => None,
+                        };
+                        let target = Pkcs11Target {
+                            module: pkcs11_module.as_deref().unwrap_or_default(),
+                            slot: *pkcs11_slot,
+                            pin: pin.as_deref(),
+                        };
+                        Some(import_into_pkcs11(&target, &name, &secret)?)
                     }
-                    //let pid_strs: Vec<&str> = pids.iter().map(String::as_str).collect();
-                    //agent.add_to_group(gid, &pid_strs).unwrap();
+                };
+                let group_context_hash = hex_encode(&exporter.group_context_hash);
+                let shown = handle.clone().unwrap_or_else(|| hex_encode(&secret));
+                let line = match with_epoch {
+                    true => format!("{} {group_context_hash} {shown}", exporter.epoch),
+                    false => shown,
+                };
+                let mut value = json!({
+                    "gid": gid,
+                    "epoch": exporter.epoch,
+                    "group_context_hash": group_context_hash,
+                    "label": label,
+                    "length": length,
+                });
+                match handle {
+                    Some(handle) => value["handle"] = json!(handle),
+                    None => value["secret"] = json!(hex_encode(&secret)),
                 }
-                GroupCommands::Update {} => {
-                    let (commit, welcome_opt, _) = group
-                        .self_update(
-                            &provider,
-                            &provider,
-                            LeafNodeParameters::builder()
-                                .with_capabilities(capabilities.clone())
-                                .build(),
-                        )
-                        .unwrap()
-                        .into_messages();
-                    log::info!("Commit message: {:?}", commit);
-                    let key = commit_key(&group, &provider).unwrap();
-                    adapter
-                        .put_checked(&key, &commit.tls_serialize_detached().unwrap())
-                        .unwrap();
-                    group.merge_pending_commit(&provider).unwrap();
-                    if let Some(welcome) = welcome_opt {
-                        log::info!("Welcome message: {:?}", welcome);
-                        let mut index = provider.state().welcome_counter();
-                        loop {
-                            let key = welcome_message_key(index);
-                            log::info!("Welcome message key: {key}");
-                            match adapter
-                                .put_checked(&key, &welcome.tls_serialize_detached().unwrap())
-                            {
-                                Ok(()) => {
-                                    break;
-                                }
-                                Err(e) if e.to_string() == "Key already exists" => {
-                                    log::warn!("Failed to put key package: {e}");
-                                    index += 1;
-                                }
-                                Err(e) => {
-                                    panic!("{e}");
-                                }
-                            }
-                        }
-                    }
+                print_output(writer, output, vec![line], value)?;
+            }
+            GroupCommands::Members {} => {
+                let members = agent.group_members(gid)?;
+                print_output(
+                    writer,
+                    output,
+                    members
+                        .iter()
+                        .map(|member| {
+                            format!(
+                                "{} {}",
+                                member.index,
+                                with_alias(agent.state(), &member.pid)
+                            )
+                        })
+                        .collect(),
+                    json!(
+                        members
+                            .iter()
+                            .map(|member| json!({
+                                "index": member.index.u32(),
+                                "pid": member.pid,
+                                "alias": agent.state().alias(&member.pid),
+                                "credential_type": "basic",
+                                "signature_key": hex_encode(&member.signature_key),
+                                "admin": member.admin,
+                            }))
+                            .collect::<Vec<_>>()
+                    ),
+                )?;
+            }
+            GroupCommands::GrantAdmin { pid } => {
+                let pid = agent.state().resolve_pid(pid);
+                agent.grant_admin(gid, &pid).await?;
+            }
+            GroupCommands::RevokeAdmin { pid } => {
+                let pid = agent.state().resolve_pid(pid);
+                agent.revoke_admin(gid, &pid).await?;
+            }
+            GroupCommands::Remove { pids } => {
+                let pids = pids_or_stdin(agent.state(), pids, "remove");
+                agent.remove_from_group(gid, &pids).await?;
+            }
+            GroupCommands::Add {
+                pids,
+                skip_missing: false,
+            } => {
+                let pids = pids_or_stdin(agent.state(), pids, "add");
+                agent.add_to_group(gid, &pids).await?;
+            }
+            GroupCommands::Add {
+                pids,
+                skip_missing: true,
+            } => {
+                let pids = pids_or_stdin(agent.state(), pids, "add");
+                let report = agent.add_available_to_group(gid, &pids).await?;
+                let left_out = |outcomes: &[(String, MySgmError)]| {
+                    outcomes
+                        .iter()
+                        .map(|(pid, e)| json!({"pid": pid, "error": e.to_string()}))
+                        .collect::<Vec<_>>()
+                };
+                let mut lines: Vec<String> = report
+                    .added
+                    .iter()
+                    .map(|pid| format!("{pid} added"))
+                    .collect();
+                lines.extend(
+                    report
+                        .skipped
+                        .iter()
+                        .map(|(pid, e)| format!("{pid} skipped: {e}")),
+                );
+                lines.extend(
+                    report
+                        .failed
+                        .iter()
+                        .map(|(pid, e)| format!("{pid} failed: {e}")),
+                );
+                print_output(
+                    writer,
+                    output,
+                    lines,
+                    json!({
+                        "added": report.added,
+                        "skipped": left_out(&report.skipped),
+                        "failed": left_out(&report.failed),
+                    }),
+                )?;
+            }
+            GroupCommands::ResumePendingAdd {} => {
+                let pids = agent.resume_pending_add(gid).await?;
+                print_output(
+                    writer,
+                    output,
+                    pids.iter().map(|pid| format!("{pid} added")).collect(),
+                    json!({"added": pids}),
+                )?;
+            }
+            GroupCommands::ProposeAdd { pids } => {
+                let pids = pids_or_stdin(agent.state(), pids, "add");
+                agent.propose_add(gid, &pids).await?;
+            }
+            GroupCommands::ProposeRemove { pids } => {
+                let pids = pids_or_stdin(agent.state(), pids, "remove");
+                agent.propose_remove(gid, &pids).await?;
+            }
+            GroupCommands::Proposals {} => {
+                let proposals = agent.pending_proposals(gid)?;
+                print_output(
+                    writer,
+                    output,
+                    proposals
+                        .iter()
+                        .map(|proposal| {
+                            format!(
+                                "{} {} by {}",
+                                proposal.kind,
+                                proposal.subject.as_deref().unwrap_or("-"),
+                                proposal.sender.as_deref().unwrap_or("-"),
+                            )
+                        })
+                        .collect(),
+                    json!(
+                        proposals
+                            .iter()
+                            .map(|proposal| json!({
+                                "kind": proposal.kind,
+                                "subject": proposal.subject,
+                                "sender": proposal.sender,
+                            }))
+                            .collect::<Vec<_>>()
+                    ),
+                )?;
+            }
+            GroupCommands::CommitPending {} => {
+                if !agent.commit_pending_proposals(gid).await? {
+                    tracing::warn!("Nothing committed for gid: {gid}");
+                }
+            }
+            GroupCommands::Rotate {} => {
+                agent.self_update(gid).await?;
+            }
+            GroupCommands::Send { message } => {
+                agent.send_message(gid, &message_body(message)?).await?;
+            }
+            GroupCommands::Leave {} => {
+                agent.leave_group(gid).await?;
+            }
+            GroupCommands::PublishGroupInfo {} => {
+                agent.publish_group_info(gid).await?;
+            }
+            GroupCommands::UsePsk { id } => {
+                agent.inject_psk(gid, id.as_bytes()).await?;
+            }
+            GroupCommands::Receive { follow: true, .. } => {
+                tracing::warn!("Cannot follow a group from the REPL");
+            }
+            GroupCommands::Receive { follow: false, .. } => {
+                let mut lines = Vec::new();
+                let mut messages = Vec::new();
+                while let Some((sender, message)) = agent.process_next_message(gid).await? {
+                    let message = String::from_utf8_lossy(&message).to_string();
+                    lines.push(format!("{sender} {message}"));
+                    messages.push(json!({"sender": sender, "message": message}));
+                }
+                print_output(writer, output, lines, json!(messages))?;
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Reads and executes commands until EOF, syncing before each one and saving state after each
+/// mutating command and on exit.
+async fn repl(
+    agent: &mut MySgmAgent,
+    state_path: &str,
+    storage: StateStorage,
+    passphrase: Option<&str>,
+    output: Output,
+) -> Result<(), MySgmError> {
+    let mut editor: Editor<ReplHelper, DefaultHistory> =
+        Editor::new().map_err(|e| MySgmError::Io(std::io::Error::other(e)))?;
+    editor.set_helper(Some(ReplHelper {
+        commands: ReplLine::command()
+            .get_subcommands()
+            .map(|command| command.get_name().to_string())
+            .collect(),
+    }));
+    loop {
+        let line = match editor.readline("mysgm> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(MySgmError::Io(std::io::Error::other(e))),
+        };
+        let Some(words) = shlex::split(&line) else {
+            eprintln!("Unbalanced quotes");
+            continue;
+        };
+        if words.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+        let command = match ReplLine::try_parse_from(words) {
+            Ok(repl_line) => repl_line.command,
+            Err(e) => {
+                let _ = e.print();
+                continue;
+            }
+        };
+        let result = async {
+            if command.syncs_first() {
+                sync(agent).await?;
+            }
+            execute(agent, &command, output, &mut stdout()).await
+        }
+        .instrument(command.span())
+        .await;
+        if let Err(e) = result {
+            eprintln!("Error: {e}");
+        }
+        if command.is_mutating() {
+            save_state_incrementally(state_path, storage, agent.state(), passphrase)?;
+        }
+    }
+    save_state(state_path, storage, agent.state(), passphrase)
+}
+
+/// Script executed by [`MainCommands::Run`].
+#[derive(Debug, Deserialize)]
+struct Script {
+    steps: Vec<ScriptStep>,
+}
+
+/// A step of a [`Script`].
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ScriptStep {
+    Command(String),
+    Saving {
+        command: String,
+        /// Variable holding the output of the command, as text
+        save: Option<String>,
+    },
+}
+
+/// Replaces each `${name}` in the line by the value of the variable.
+fn substitute(line: &str, variables: &HashMap<String, String>) -> Result<String, String> {
+    let mut substituted = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find("${") {
+        substituted.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| "Unterminated variable".to_string())?;
+        let name = &rest[start + 2..start + end];
+        let value = variables
+            .get(name)
+            .ok_or_else(|| format!("Unknown variable: {name}"))?;
+        substituted.push_str(value);
+        rest = &rest[start + end + 1..];
+    }
+    substituted.push_str(rest);
+    Ok(substituted)
+}
+
+/// Executes one step of a script like a REPL line, returning the JSON output of its command,
+/// or its text output if not in JSON.
+async fn run_step(agent: &mut MySgmAgent, line: &str) -> Result<Value, String> {
+    let words = shlex::split(line).ok_or_else(|| "Unbalanced quotes".to_string())?;
+    let command = ReplLine::try_parse_from(words)
+        .map_err(|e| e.render().to_string().trim_end().to_string())?
+        .command;
+    let mut buffer = Vec::new();
+    async {
+        if command.syncs_first() {
+            sync(agent).await?;
+        }
+        execute(agent, &command, Output::Json, &mut buffer).await
+    }
+    .instrument(command.span())
+    .await
+    .map_err(|e| e.to_string())?;
+    Ok(json_decode(&buffer)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&buffer).trim_end().to_string())))
+}
+
+/// Executes the steps of the script in order, stopping at the first failure, and prints a
+/// report of the output or error of each step run.
+async fn run_script(
+    agent: &mut MySgmAgent,
+    script: &str,
+    output: Output,
+) -> Result<(), MySgmError> {
+    let script: Script = serde_yaml::from_str(script)
+        .map_err(|e| MySgmError::ScriptStep(0, format!("Invalid script: {e}")))?;
+    let mut variables = HashMap::from([("pid".to_string(), agent.state().my_pid().to_string())]);
+    let mut lines = Vec::new();
+    let mut steps = Vec::new();
+    let mut failure = None;
+    for (index, step) in script.steps.iter().enumerate() {
+        let (command, save) = match step {
+            ScriptStep::Command(command) => (command, None),
+            ScriptStep::Saving { command, save } => (command, save.as_ref()),
+        };
+        let result = match substitute(command, &variables) {
+            Ok(line) => run_step(agent, &line).await.map(|value| (line, value)),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok((line, value)) => {
+                if let Some(name) = save {
+                    let text = match &value {
+                        Value::String(text) => text.clone(),
+                        value => value.to_string(),
+                    };
+                    variables.insert(name.clone(), text);
                 }
+                lines.push(format!("ok    {line}"));
+                steps.push(json!({"command": line, "output": value}));
+            }
+            Err(e) => {
+                lines.push(format!("error {command}: {e}"));
+                steps.push(json!({"command": command, "error": e}));
+                failure = Some(MySgmError::ScriptStep(index + 1, e));
+                break;
+            }
+        }
+    }
+    print_output(
+        &mut stdout(),
+        output,
+        lines,
+        json!({"steps": steps, "ok": failure.is_none()}),
+    )?;
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Retries queued publications, then syncs like the `sync` command, logging an event for each
+/// artifact, writing received messages to stdout, and passing them on to subscribers of the
+/// control API.
+async fn daemon_tick(
+    agent: &mut MySgmAgent,
+    output: Output,
+    control: Option<&ControlServer>,
+) -> Result<(), MySgmError> {
+    if !agent.state().outbox().is_empty() {
+        let published = agent.flush().await;
+        tracing::info!(
+            target: "mysgm::daemon",
+            event = "flush",
+            published,
+            queued = agent.state().outbox().len()
+        );
+    }
+    let (report, messages) = agent.sync_and_receive().await?;
+    for pid in report.key_packages {
+        tracing::info!(target: "mysgm::daemon", event = "key_package", pid);
+    }
+    for gid in report.welcomes {
+        tracing::info!(target: "mysgm::daemon", event = "welcome", gid);
+    }
+    for gid in report.adds {
+        tracing::info!(target: "mysgm::daemon", event = "add_resumed", gid);
+    }
+    if report.commits > 0 {
+        tracing::info!(target: "mysgm::daemon", event = "commits", count = report.commits);
+    }
+    for gid in report.rotated {
+        tracing::info!(target: "mysgm::daemon", event = "rotated", gid);
+    }
+    for (pid, gid) in report.reinvited {
+        tracing::info!(target: "mysgm::daemon", event = "reinvite", pid, gid);
+    }
+    for (slot, e) in report.errors {
+        tracing::warn!(target: "mysgm::daemon", event = "skipped", slot, error = %e);
+    }
+    let mut out = stdout();
+    for (gid, (sender, message)) in messages {
+        tracing::info!(
+            target: "mysgm::daemon",
+            event = "message",
+            gid,
+            sender,
+            len = message.len()
+        );
+        if let Some(control) = control {
+            control.publish(&gid, &sender, &message);
+        }
+        let message = String::from_utf8_lossy(&message).to_string();
+        print_output(
+            &mut out,
+            output,
+            vec![format!("{gid} {sender} {message}")],
+            json!({"gid": gid, "sender": sender, "message": message}),
+        )?;
+    }
+    for key in agent.republish(REPUBLISH_INTERVAL).await? {
+        tracing::info!(target: "mysgm::daemon", event = "republish", key);
+    }
+    Ok(())
+}
+
+/// Syncs and saves state whenever the delivery service pushes a value to a watched slot, or at
+/// least every `interval` seconds, until the process is killed.
+///
+/// Errors during a sync are logged and retried on the next one. With the control API or the
+/// command socket, a call also ends the wait; it is applied and the state saved before the next
+/// sync.
+async fn daemon(
+    agent: &mut MySgmAgent,
+    state_path: &str,
+    storage: StateStorage,
+    passphrase: Option<&str>,
+    interval: u64,
+    output: Output,
+    mut control: Option<ControlServer>,
+    mut socket: Option<SocketServer>,
+) -> Result<(), MySgmError> {
+    loop {
+        let tick = daemon_tick(agent, output, control.as_ref());
+        if let Err(e) = tick.instrument(tracing::info_span!("daemon_tick")).await {
+            tracing::error!(target: "mysgm::daemon", event = "sync_failed", error = %e);
+        }
+        save_state_incrementally(state_path, storage, agent.state(), passphrase)?;
+        let timeout = Duration::from_secs(interval);
+        let waited = select! {
+            waited = agent.wait_for_delivery(timeout) => waited,
+            Some(request) = next_request(control.as_mut().map(|control| &mut control.requests)) => {
+                tracing::info!(target: "mysgm::daemon", event = "control", request = ?request);
+                request
+                    .apply(agent)
+                    .instrument(tracing::info_span!("control_request"))
+                    .await;
+                save_state_incrementally(state_path, storage, agent.state(), passphrase)?;
+                continue;
+            }
+            Some(command) = next_request(socket.as_mut().map(|socket| &mut socket.commands)) => {
+                tracing::info!(
+                    target: "mysgm::daemon",
+                    event = "socket_command",
+                    arg_count = command.request.args.len()
+                );
+                let response = run_socket_command(agent, command.request).await;
+                // the client may have gone away, in which case the answer is dropped
+                let _ = command.reply.send(response);
+                save_state_incrementally(state_path, storage, agent.state(), passphrase)?;
+                continue;
+            }
+        };
+        if let Err(e) = waited {
+            tracing::error!(target: "mysgm::daemon", event = "watch_failed", error = %e);
+            sleep(timeout).await;
+        }
+    }
+}
+
+/// Waits for the next request on the channel, or forever without one.
+async fn next_request<T>(requests: Option<&mut mpsc::Receiver<T>>) -> Option<T> {
+    match requests {
+        Some(requests) => requests.recv().await,
+        None => pending().await,
+    }
+}
+
+/// Runs a command forwarded with --via-socket, as the REPL runs a line, capturing its output.
+async fn run_socket_command(agent: &mut MySgmAgent, request: SocketRequest) -> SocketResponse {
+    let args = match CliArgs::try_parse_from(once("mysgm".to_string()).chain(request.args)) {
+        Ok(args) => args,
+        Err(e) => {
+            return SocketResponse {
+                error: Some(e.to_string()),
+                ..Default::default()
+            };
+        }
+    };
+    let command = args.main_command;
+    let mut output = Vec::new();
+    let result = async {
+        if command.syncs_first() {
+            sync(agent).await?;
+        }
+        execute(agent, &command, args.output, &mut output).await
+    }
+    .instrument(command.span())
+    .await;
+    SocketResponse {
+        output: String::from_utf8_lossy(&output).to_string(),
+        error: result.err().map(|e| e.to_string()),
+    }
+}
+
+/// Receives messages of the group, writing each to stdout as a line of JSON and saving state
+/// after every batch, until the agent leaves the group or stdout is closed.
+///
+/// Errors while receiving are logged and retried, as in [`daemon`].
+async fn follow(
+    agent: &mut MySgmAgent,
+    gid: &str,
+    state_path: &str,
+    storage: StateStorage,
+    passphrase: Option<&str>,
+    interval: u64,
+) -> Result<(), MySgmError> {
+    let mut out = stdout();
+    loop {
+        let mut messages = Vec::new();
+        if let Err(e) = receive_batch(agent, gid, &mut messages).await {
+            tracing::error!("Failed to receive from {gid}: {e}");
+        }
+        let written = messages
+            .into_iter()
+            .try_for_each(|message| writeln!(out, "{message}"))
+            .and_then(|()| out.flush());
+        save_state_incrementally(state_path, storage, agent.state(), passphrase)?;
+        match written {
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => return Ok(()),
+            written => written?,
+        }
+        if !agent.state().gids().contains(&gid.to_string()) {
+            tracing::info!("No longer in {gid}; stopping");
+            return Ok(());
+        }
+        if let Err(e) = agent.wait_for_delivery(Duration::from_secs(interval)).await {
+            tracing::error!("Failed to watch for delivery: {e}");
+            sleep(Duration::from_secs(interval)).await;
+        }
+    }
+}
+
+/// Applies new commits and proposals of the group and collects its new messages as JSON.
+async fn receive_batch(
+    agent: &mut MySgmAgent,
+    gid: &str,
+    messages: &mut Vec<Value>,
+) -> Result<(), MySgmError> {
+    while agent.process_next_commit(gid).await? {}
+    if !agent.state().gids().contains(&gid.to_string()) {
+        return Ok(());
+    }
+    while agent.process_next_proposal(gid).await? {}
+    while let Some((sender, message)) = agent.process_next_message(gid).await? {
+        let message = String::from_utf8_lossy(&message).to_string();
+        messages.push(json!({"sender": sender, "message": message}));
+    }
+    Ok(())
+}
+
+/// Directory holding a directory per profile.
+fn profiles_dir() -> PathBuf {
+    let data_home = match env_var("XDG_DATA_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env_var("HOME").unwrap_or_default()).join(".local/share"),
+    };
+    data_home.join("mysgm")
+}
+
+/// Path of the state of the profile, creating its directory if needed.
+///
+/// An existing state file of the profile is picked by its name unless the storage is given.
+fn profile_state_path(profile: &str, storage: Option<Storage>) -> Result<String, MySgmError> {
+    let dir = profiles_dir().join(profile);
+    create_dir_all(&dir)?;
+    let (file, _) = PROFILE_STATE_FILES
+        .iter()
+        .find(|(file, file_storage)| match storage {
+            Some(storage) => *file_storage == storage,
+            None => dir.join(file).exists(),
+        })
+        .unwrap_or(&PROFILE_STATE_FILES[0]);
+    Ok(dir.join(file).to_string_lossy().to_string())
+}
+
+/// Names and state paths of the profiles holding state, sorted by name.
+fn list_profiles() -> Result<Vec<(String, String)>, MySgmError> {
+    let mut profiles = Vec::new();
+    let entries = match read_dir(profiles_dir()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(profiles),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let dir = entry?.path();
+        let Some(path) = PROFILE_STATE_FILES
+            .iter()
+            .map(|(file, _)| dir.join(file))
+            .find(|path| path.exists())
+        else {
+            continue;
+        };
+        let name = dir.file_name().unwrap_or_default().to_string_lossy();
+        profiles.push((name.to_string(), path.to_string_lossy().to_string()));
+    }
+    profiles.sort();
+    Ok(profiles)
+}
+
+/// Installs the subscriber writing log lines, of the crate and of the `log` records of its
+/// dependencies, in the format to the trace file or stderr.
+///
+/// Only errors are logged unless the filters, in the syntax of RUST_LOG, say otherwise.
+fn init_tracing(
+    filters: Option<&str>,
+    format: Output,
+    trace_file: Option<&str>,
+) -> Result<(), MySgmError> {
+    let writer = match trace_file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            BoxMakeWriter::new(std::sync::Mutex::new(file))
+        }
+        None => BoxMakeWriter::new(stderr),
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(filters.unwrap_or("error")))
+        .with_writer(writer)
+        .with_ansi(trace_file.is_none() && stderr().is_terminal());
+    match format {
+        Output::Text => subscriber.init(),
+        Output::Json => subscriber.json().init(),
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), MySgmError> {
+    let config = Config::load();
+    // cli args, over the config file
+    let matches = CliArgs::command().get_matches();
+    let mut args = CliArgs::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let filters = env_var("RUST_LOG")
+        .or_else(|_| env_var("MYSGM_LOG"))
+        .ok()
+        .or_else(|| config.as_ref().ok()?.log_level.clone());
+    init_tracing(
+        filters.as_deref(),
+        args.log_format,
+        args.trace_file.as_deref(),
+    )?;
+    config?.apply(&mut args, &matches)?;
+    if args
+        .backend
+        .iter()
+        .any(|backend| matches!(backend, Backend::Http))
+        && args.url.is_none()
+    {
+        CliArgs::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "the http backend requires --url",
+            )
+            .exit();
+    }
+    if args.log_secrets {
+        set_log_secrets(true);
+        tracing::warn!("Logging secrets; the logs of this command must not be shared");
+    }
+    let _command = args.main_command.span().entered();
+    tracing::info!("Command-line arguments: {args:?}");
+    if let Some(path) = &args.via_socket {
+        tracing::info!("Forwarding command to the daemon on {path}");
+        let response = forward(path, env_args().skip(1).collect()).await?;
+        print!("{}", response.output);
+        return match response.error {
+            Some(e) => Err(MySgmError::DaemonCommand(e)),
+            None => Ok(()),
+        };
+    }
+    match &args.main_command {
+        MainCommands::Completions { shell } => {
+            // generated into a buffer, since generating panics on write errors
+            let mut script = Vec::new();
+            generate(*shell, &mut CliArgs::command(), "mysgm", &mut script);
+            return Ok(stdout().write_all(&script)?);
+        }
+        MainCommands::Man {} => return Ok(Man::new(CliArgs::command()).render(&mut stdout())?),
+        MainCommands::Bench { members, messages } => {
+            let report = bench::run(*members, *messages).await?;
+            return print_bench_report(&mut stdout(), args.output, &report);
+        }
+        _ => {}
+    }
+    if let MainCommands::ListProfiles {} = &args.main_command {
+        let profiles = list_profiles()?;
+        return print_output(
+            &mut stdout(),
+            args.output,
+            profiles
+                .iter()
+                .map(|(name, path)| format!("{name} {path}"))
+                .collect(),
+            json!(
+                profiles
+                    .iter()
+                    .map(|(name, path)| json!({"name": name, "path": path}))
+                    .collect::<Vec<_>>()
+            ),
+        );
+    }
+    // crypto
+    let crypto: RustCrypto = Default::default();
+    // state
+    let state_path = match &args.state_path {
+        Some(path) => path.clone(),
+        None => profile_state_path(&args.profile, args.storage)?,
+    };
+    tracing::info!("Path to agent state: {state_path}");
+    tracing::info!("Reset state? {}", args.reset);
+    let passphrase = match &args.passphrase_file {
+        Some(path) => Some(
+            read_file_to_string(path)?
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+        ),
+        None => env_var("MYSGM_PASSPHRASE").ok(),
+    };
+    tracing::info!("Encrypt state? {}", passphrase.is_some());
+    let storage = args
+        .storage
+        .map_or_else(|| StateStorage::from_path(&state_path), Into::into);
+    tracing::info!("State storage: {storage:?}");
+    if let MainCommands::Restore { input } = &args.main_command {
+        tracing::info!("Restoring state from {input}");
+        let state = read_backup(input, passphrase.as_deref())?;
+        // a restore deliberately replaces whatever is stored
+        state.set_version(stored_version(&state_path, storage).unwrap_or(0));
+        let passphrase = passphrase
+            .as_deref()
+            .filter(|_| storage != StateStorage::Sqlite);
+        return save_state(&state_path, storage, &state, passphrase);
+    }
+    let bootstrap_label = match &args.main_command {
+        MainCommands::Bootstrap { pid_label, .. } => Some(pid_label),
+        _ => None,
+    };
+    let mut state = if args.reset || bootstrap_label.is_some() {
+        if !args.reset && stored_version(&state_path, storage).is_some() {
+            return Err(MySgmError::StateExists(state_path));
+        }
+        tracing::warn!("Resetting state");
+        let label = bootstrap_label.unwrap_or(&args.pid);
+        let ciphersuite = args
+            .ciphersuite
+            .unwrap_or(Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519);
+        let state = match &args.pkcs11_key_module {
+            Some(module) => {
+                let target = Pkcs11Target {
+                    module,
+                    slot: args.pkcs11_key_slot,
+                    pin: None,
+                };
+                let key_pair = SignatureKeyPair::from_token(&target, &crypto, ciphersuite.into())?;
+                tracing::info!("Generated signature key on token: {:?}", key_pair.token());
+                MySgmState::with_key_pair(label, ciphersuite, key_pair)
+            }
+            None => MySgmState::generate(label, ciphersuite, &crypto)?,
+        };
+        // a reset deliberately replaces whatever is stored
+        if let Some(version) = stored_version(&state_path, storage) {
+            state.set_version(version);
+        }
+        state
+    } else {
+        if args.ciphersuite.is_some() {
+            tracing::warn!("Ignoring ciphersuite without reset");
+        }
+        tracing::debug!("Attempting to load state from file");
+        match load_state(&state_path, storage, passphrase.as_deref()) {
+            Ok(state) => state,
+            Err(e) if matches!(args.main_command, MainCommands::Doctor {}) => {
+                return print_diagnoses(
+                    &mut stdout(),
+                    args.output,
+                    &[Diagnosis::new("state", Err(e))],
+                );
             }
+            Err(e) => return Err(e),
+        }
+    };
+    if let Some(host) = &args.dht_host {
+        state.set_dht_host(host);
+    }
+    if let Some(port) = args.dht_port {
+        state.set_dht_port(port);
+    }
+    if let Some(namespace) = &args.namespace {
+        state.set_namespace(namespace);
+    }
+    if let Some(limit) = args.history_limit {
+        state.set_history_limit(limit);
+    }
+    if let Some(window) = args.exporter_window {
+        state.set_exporter_window(window);
+    }
+    tracing::info!("State: {state:?}");
+    if let MainCommands::MigrateState { out, to } = &args.main_command {
+        let out = out.as_deref().unwrap_or(&state_path);
+        let to = to.map_or_else(|| StateStorage::from_path(out), Into::into);
+        tracing::info!("Migrating state to {out} as {to:?}");
+        // a new file must not already hold state; in place, the loaded version is kept
+        if out != state_path {
+            state.set_version(0);
+        }
+        if passphrase.is_some() && to == StateStorage::Sqlite {
+            tracing::warn!("SQLite state is not encrypted; {out} holds the state in plaintext");
+        }
+        let passphrase = passphrase.as_deref().filter(|_| to != StateStorage::Sqlite);
+        return save_state(out, to, &state, passphrase);
+    }
+    if let MainCommands::Backup { out, with_history } = &args.main_command {
+        tracing::info!("Backing up state to {out}");
+        if passphrase.is_none() {
+            tracing::warn!("No passphrase given; {out} holds the state in plaintext");
+        }
+        return write_backup(out, &state, *with_history, passphrase.as_deref());
+    }
+    // delivery adapter
+    let mut adapters: Vec<Box<dyn DeliveryAdapter>> = args
+        .backend
+        .iter()
+        .map(|backend| delivery_adapter(&args, &state, *backend))
+        .collect();
+    let adapter = match adapters.len() {
+        1 => adapters.remove(0),
+        _ => Box::new(CompositeAdapter::new(adapters)),
+    };
+    let adapter = match args.content_addressed {
+        true => Box::new(ContentAddressedAdapter::new(adapter)),
+        false => adapter,
+    };
+    tracing::info!("Delivery adapter: {adapter:?}");
+    // agent
+    let mut agent = MySgmAgent::new(state, crypto, adapter);
+    agent.set_strict_trust(args.strict);
+    agent.set_sync_concurrency(args.sync_concurrency);
+    agent.add_observer(Box::new(LogObserver));
+    // download key packages, welcome messages, and commits, unless working offline
+    if args.main_command.syncs_first() {
+        sync(&mut agent).await?;
+    }
+    // execute command
+    match &args.main_command {
+        MainCommands::Repl {} => {
+            repl(
+                &mut agent,
+                &state_path,
+                storage,
+                passphrase.as_deref(),
+                args.output,
+            )
+            .await?;
+        }
+        MainCommands::Run { script } => {
+            run_script(&mut agent, &read_file_to_string(script)?, args.output).await?;
+        }
+        MainCommands::Daemon {
+            interval,
+            grpc,
+            socket,
+        } => {
+            daemon(
+                &mut agent,
+                &state_path,
+                storage,
+                passphrase.as_deref(),
+                *interval,
+                args.output,
+                grpc.map(ControlServer::spawn),
+                socket.as_deref().map(SocketServer::bind).transpose()?,
+            )
+            .await?;
+        }
+        MainCommands::Group {
+            gid,
+            group_command:
+                GroupCommands::Receive {
+                    follow: true,
+                    interval,
+                },
+        } => {
+            follow(
+                &mut agent,
+                gid,
+                &state_path,
+                storage,
+                passphrase.as_deref(),
+                *interval,
+            )
+            .await?;
+        }
+        MainCommands::Bootstrap {
+            create_group,
+            join_via,
+            lifetime_days,
+            ..
+        } => {
+            // linking changes the pid, so it goes before advertising
+            let gids = match join_via {
+                Some(input) => agent.accept_link(&read_file(input)?).await?,
+                None => Vec::new(),
+            };
+            agent.advertise(lifetime_days * SECONDS_PER_DAY).await?;
+            let gids = match create_group {
+                Some(gid) => vec![agent.create_group(gid, true)?],
+                None => gids,
+            };
+            let pid = agent.state().my_pid();
+            let mut lines = vec![format!("pid: {pid}")];
+            lines.extend(gids.iter().map(|gid| format!("gid: {gid}")));
+            print_output(
+                &mut stdout(),
+                args.output,
+                lines,
+                json!({"pid": pid, "gids": gids}),
+            )?;
+        }
+        command => {
+            execute(&mut agent, command, args.output, &mut stdout()).await?;
         }
     }
     // save state
-    log::info!("State before saving: {:?}", provider.state());
-    write_string_to_file(&args.state_path, json_encode(provider.state()).unwrap()).unwrap();
+    tracing::info!("State before saving: {:?}", agent.state());
+    save_state(&state_path, storage, agent.state(), passphrase.as_deref())?;
     // done
+    Ok(())
 }
 /*
 
         }
         MainCommands::CreateGroup { gid } => {
-            log::debug!("Attempting to create new group");
-            log::info!("Group label to use for new group: {gid}");
+            tracing::debug!("Attempting to create new group");
+            tracing::info!("Group label to use for new group: {gid}");
             println!("{}", agent.create_group(gid).unwrap());
-            log::debug!("Created new group");
-            log::info!("Agent state after: {agent:?}");
-            log::debug!("Attempting to write state back to disk");
+            tracing::debug!("Created new group");
+            tracing::info!("Agent state after: {agent:?}");
+            tracing::debug!("Attempting to write state back to disk");
             agent.save(&args.state_path).unwrap();
-            log::debug!("Wrote state to disk");
+            tracing::debug!("Wrote state to disk");
         }
         MainCommands::Group { gid, group_command } => match group_command {
             GroupCommands::ExportSecret { label, length } => {
@@ -598,92 +3195,92 @@ fn main() {
 
 
         Commands::AddToGroup { gid } => {
-            log::debug!("Attempting to load state from file");
+            tracing::debug!("Attempting to load state from file");
             let mut agent = MySgmAgent::load(&args.state_path).unwrap();
-            log::debug!("Loaded agent state");
-            log::info!("Agent state before: {agent:?}");
-            log::info!("Group for adding agents: {gid}");
+            tracing::debug!("Loaded agent state");
+            tracing::info!("Agent state before: {agent:?}");
+            tracing::info!("Group for adding agents: {gid}");
             /*
             let handle = stdin().lock();
-            log::debug!("Reading lines from stdin as agents to add");
+            tracing::debug!("Reading lines from stdin as agents to add");
             let mut pids = Vec::new();
             for line in handle.lines() {
                 match line {
                     Ok(l) => {
-                        log::info!("Agent id: {l}");
+                        tracing::info!("Agent id: {l}");
                         pids.push(l);
                     }
                     Err(e) => {
-                        log::error!("Error reading line: {e}");
+                        tracing::error!("Error reading line: {e}");
                         break;
                     }
                 }
             }
             let pid_strs: Vec<&str> = pids.iter().map(String::as_str).collect();
             agent.add_to_group(gid, &pid_strs).unwrap();
-            log::info!("Agent state after: {agent:?}");
-            log::debug!("Attempting to write state back to disk");
+            tracing::info!("Agent state after: {agent:?}");
+            tracing::debug!("Attempting to write state back to disk");
             agent.save(&args.state_path).unwrap();
-            log::debug!("Wrote state to disk");
+            tracing::debug!("Wrote state to disk");
             */
         }
 
             */
         MainCommands::TestKeyPackages {} => {
-            log::debug!("Create alice");
+            tracing::debug!("Create alice");
             let mut alice = MySgmAgent::new("alice").unwrap();
-            log::debug!("Create bob");
+            tracing::debug!("Create bob");
             let mut bob = MySgmAgent::new("bob").unwrap();
-            log::debug!("Created agents");
+            tracing::debug!("Created agents");
             let mut kp_bytes_vec = Vec::new();
             kp_bytes_vec.push(alice.new_key_package().unwrap());
             kp_bytes_vec.push(bob.new_key_package().unwrap());
-            log::debug!("Generated key packages");
-            log::debug!("Processing key packages");
+            tracing::debug!("Generated key packages");
+            tracing::debug!("Processing key packages");
             for kp_bytes in kp_bytes_vec {
                 alice.process_as_key_package(&kp_bytes).unwrap();
                 bob.process_as_key_package(&kp_bytes).unwrap();
             }
-            log::debug!("Processed key packages");
-            log::debug!("Alice's peer list: {:?}", alice.pids());
-            log::debug!("Bob's peer list: {:?}", bob.pids());
+            tracing::debug!("Processed key packages");
+            tracing::debug!("Alice's peer list: {:?}", alice.pids());
+            tracing::debug!("Bob's peer list: {:?}", bob.pids());
         }
         MainCommands::TestAddToGroup {} => {
-            log::debug!("Create alice");
+            tracing::debug!("Create alice");
             let mut alice = MySgmAgent::new("alice").unwrap();
-            log::debug!("Create bob");
+            tracing::debug!("Create bob");
             let mut bob = MySgmAgent::new("bob").unwrap();
-            log::debug!("Created agents");
+            tracing::debug!("Created agents");
             let mut kp_bytes_vec = Vec::new();
             kp_bytes_vec.push(alice.new_key_package().unwrap());
             kp_bytes_vec.push(bob.new_key_package().unwrap());
-            log::debug!("Generated key packages");
-            log::debug!("Processing key packages");
+            tracing::debug!("Generated key packages");
+            tracing::debug!("Processing key packages");
             for kp_bytes in &kp_bytes_vec {
                 alice.process_as_key_package(kp_bytes).unwrap();
                 bob.process_as_key_package(kp_bytes).unwrap();
             }
-            log::debug!("Processed key packages");
-            log::debug!("Alice creating group");
+            tracing::debug!("Processed key packages");
+            tracing::debug!("Alice creating group");
             let gid = alice.create_group("group1").unwrap();
-            log::debug!("Alice created group");
-            log::debug!("Alice adding Bob to group");
+            tracing::debug!("Alice created group");
+            tracing::debug!("Alice adding Bob to group");
             let (cm_bytes, wm_bytes) = alice.add_to_group(&gid, &[&bob.my_pid()]).unwrap();
-            log::debug!("Alice added Bob to group");
-            log::debug!("Bob processing welcome message");
+            tracing::debug!("Alice added Bob to group");
+            tracing::debug!("Bob processing welcome message");
             assert_eq!(&bob.process_as_welcome_message(&wm_bytes).unwrap(), &gid);
-            log::debug!("Bob processed welcome message and joined {gid}");
+            tracing::debug!("Bob processed welcome message and joined {gid}");
             alice.merge(&gid).unwrap();
-            log::info!(
+            tracing::info!(
                 "Alice's group members: {:?}",
                 alice.group_members(&gid).unwrap()
             );
-            log::info!(
+            tracing::info!(
                 "Bob's group members: {:?}",
                 bob.group_members(&gid).unwrap()
             );
         }
     }
-    log::debug!("DONE!");
+    tracing::debug!("DONE!");
 }
 */
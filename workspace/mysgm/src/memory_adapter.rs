@@ -0,0 +1,51 @@
+//! In-memory delivery service, for tests and simulations without an OpenDHT proxy.
+//!
+//! Clones share the same store, so agents built with clones of one [`MemoryAdapter`] exchange
+//! key packages, welcomes, commits, and messages as if they shared a delivery service.
+
+use super::{adapter::DeliveryAdapter, error::MySgmError, lock::lock_ignoring_poison};
+
+use async_trait::async_trait;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryAdapter {
+    values: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl MemoryAdapter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Returns the keys set so far, in order.
+    pub fn keys(&self) -> Vec<String> {
+        self.values().keys().cloned().collect()
+    }
+    fn values(&self) -> MutexGuard<'_, BTreeMap<String, Vec<u8>>> {
+        lock_ignoring_poison(&self.values)
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl DeliveryAdapter for MemoryAdapter {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, MySgmError> {
+        Ok(self.values().get(key).cloned())
+    }
+    async fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
+        let mut values = self.values();
+        match values.contains_key(key) {
+            true => Err(MySgmError::KeyExists),
+            false => {
+                values.insert(key.to_string(), value.to_vec());
+                Ok(())
+            }
+        }
+    }
+    async fn delete(&self, key: &str) -> Result<bool, MySgmError> {
+        Ok(self.values().remove(key).is_some())
+    }
+}
@@ -0,0 +1,82 @@
+//! Upgrades of serialized agent state from older format versions.
+//!
+//! State is migrated as JSON, before it is decoded into a [`MySgmState`], so migrations can
+//! rename, reshape, and drop fields that the current struct no longer accepts. State files
+//! written before format versions were introduced are version 0.
+
+use super::{error::MySgmError, state::MySgmState};
+
+use serde_json::{Map, Value, from_value, json};
+
+/// Format version of the state written by this build.
+pub const STATE_FORMAT_VERSION: u32 = 2;
+
+/// Upgrades state of one format version to the next; the migration at index `i` upgrades
+/// version `i`.
+type Migration = fn(&mut Map<String, Value>) -> Result<(), MySgmError>;
+
+const MIGRATIONS: &[Migration] = &[namespace_key_package_counter, number_log_entries];
+
+/// Version 0 kept a single key package counter, which belongs to the default (empty) namespace.
+fn namespace_key_package_counter(fields: &mut Map<String, Value>) -> Result<(), MySgmError> {
+    if let Some(counter @ Value::Number(_)) = fields.get("key_package_counter") {
+        let counters = json!({ "": counter });
+        fields.insert("key_package_counter".to_string(), counters);
+    }
+    Ok(())
+}
+
+/// Version 1 kept history entries and audit events without their position in the group's log,
+/// which is their index as long as nothing was dropped from the front.
+fn number_log_entries(fields: &mut Map<String, Value>) -> Result<(), MySgmError> {
+    for field in ["history", "audit_log"] {
+        let Some(Value::Object(logs)) = fields.get_mut(field) else {
+            continue;
+        };
+        for entries in logs.values_mut() {
+            let Value::Array(entries) = entries else {
+                continue;
+            };
+            for (seq, entry) in entries.iter_mut().enumerate() {
+                if let Value::Object(entry) = entry {
+                    entry.insert("seq".to_string(), seq.into());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the format version of the serialized state.
+pub fn format_version(value: &Value) -> Result<u32, MySgmError> {
+    match value.get("format_version") {
+        None => Ok(0),
+        Some(version) => Ok(from_value(version.clone())?),
+    }
+}
+
+/// Upgrades serialized state to [`STATE_FORMAT_VERSION`] in place, returning the version it
+/// had.
+///
+/// Refuses with [`MySgmError::UnsupportedStateFormat`] state written by a newer build.
+pub fn migrate(value: &mut Value) -> Result<u32, MySgmError> {
+    let from = format_version(value)?;
+    if from > STATE_FORMAT_VERSION {
+        return Err(MySgmError::UnsupportedStateFormat(from));
+    }
+    let Value::Object(fields) = value else {
+        return Err(MySgmError::UnsupportedStateFormat(from));
+    };
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        tracing::info!("Migrating state from format version {version}");
+        migration(fields)?;
+    }
+    fields.insert("format_version".to_string(), STATE_FORMAT_VERSION.into());
+    Ok(from)
+}
+
+/// Decodes serialized state of any supported format version.
+pub fn decode(mut value: Value) -> Result<MySgmState, MySgmError> {
+    migrate(&mut value)?;
+    Ok(from_value(value)?)
+}
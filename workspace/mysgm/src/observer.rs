@@ -0,0 +1,29 @@
+use core::fmt::Debug;
+
+/// Receives events as the agent processes them, so that embedders can react to syncs without
+/// diffing state.
+///
+/// Observers are called synchronously in the order they were added, after the change is
+/// applied to the state; every method does nothing by default.
+pub trait AgentObserver: Debug + Send + Sync {
+    /// A key package of the pid was stored.
+    fn on_key_package(&self, pid: &str) {
+        let _ = pid;
+    }
+    /// The group was joined through a welcome.
+    fn on_welcome(&self, gid: &str) {
+        let _ = gid;
+    }
+    /// A commit, own or received, moved the group to the epoch.
+    fn on_commit_merged(&self, gid: &str, epoch: u64) {
+        let _ = (gid, epoch);
+    }
+    /// A commit made the pid a member of the group, by add or by external join.
+    fn on_member_added(&self, gid: &str, pid: &str) {
+        let _ = (gid, pid);
+    }
+    /// An application message of the sender was decrypted.
+    fn on_message(&self, gid: &str, sender: &str, message: &[u8]) {
+        let _ = (gid, sender, message);
+    }
+}
@@ -1,45 +1,172 @@
+use super::{
+    adapter::DeliveryAdapter,
+    error::MySgmError,
+    lock::lock_ignoring_poison,
+    timer::{sleep, timeout as with_timeout},
+};
+
+use async_trait::async_trait;
 use base64::{Engine, engine::general_purpose::STANDARD};
-use core::error::Error;
-use reqwest::blocking::Client as ReqwestClient;
-use serde_json::{Value, from_str as json_decode, json, to_string as json_encode};
+use futures::{StreamExt, future::select_all};
+use httpdate::parse_http_date;
+use reqwest::{Client as ReqwestClient, Method, header::DATE};
+use serde::{Deserialize, Serialize};
+use serde_json::{
+    Value, from_slice as json_decode_slice, from_str as json_decode, json, to_string as json_encode,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use web_time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Timeout and retry settings for requests to the OpenDHT proxy.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Timeout for each individual request.
+    pub timeout: Duration,
+    /// Number of times a transient `get` failure is retried.
+    pub max_retries: u32,
+    /// Delay before the first retry; doubled on each further retry.
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            max_retries: 3,
+            base_delay: Duration::from_millis(200),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Backoff before retry number `attempt` (from 0), with up to 100% random jitter added.
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay.saturating_mul(1 << attempt.min(16));
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        delay + delay.mul_f64(f64::from(nanos) / 1e9)
+    }
+}
+
+/// Limits on the requests sent to the OpenDHT proxy, so that a fleet of agents sharing a proxy
+/// does not overload it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Requests started per second, in bursts of up to as many; unlimited if `None`.
+    #[serde(default)]
+    pub requests_per_second: Option<f64>,
+    /// Requests in flight at once, not counting listens, which stay open; unlimited if `None`.
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
+}
+
+/// Token bucket and concurrency permits enforcing a [`RateLimit`], shared by clones of the
+/// adapter.
+#[derive(Debug)]
+struct Limiter {
+    requests_per_second: Option<f64>,
+    /// Tokens available and when they were last refilled.
+    bucket: Mutex<(f64, Instant)>,
+    permits: Option<Semaphore>,
+}
+
+impl Limiter {
+    fn new(rate_limit: RateLimit) -> Self {
+        let requests_per_second = rate_limit.requests_per_second.filter(|rate| *rate > 0.0);
+        Self {
+            requests_per_second,
+            bucket: Mutex::new((requests_per_second.unwrap_or(0.0).max(1.0), Instant::now())),
+            permits: rate_limit
+                .max_concurrent
+                .map(|max| Semaphore::new(max.max(1))),
+        }
+    }
+    /// Waits until the rate allows another request.
+    async fn throttle(&self) {
+        let Some(rate) = self.requests_per_second else {
+            return;
+        };
+        loop {
+            let wait = {
+                let mut bucket = lock_ignoring_poison(&self.bucket);
+                let (tokens, refilled_at) = &mut *bucket;
+                let now = Instant::now();
+                let refill = now.duration_since(*refilled_at).as_secs_f64() * rate;
+                *tokens = (*tokens + refill).min(rate.max(1.0));
+                *refilled_at = now;
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - *tokens) / rate)
+            };
+            sleep(wait).await;
+        }
+    }
+    /// Waits until the rate and the concurrency limit allow another request, returning the
+    /// permit to hold while it is in flight.
+    async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        let permit = match &self.permits {
+            // never closed
+            Some(permits) => permits.acquire().await.ok(),
+            None => None,
+        };
+        self.throttle().await;
+        permit
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct OpenDhtRestAdapter {
     proxy_address: String,
     proxy_port: u16,
+    /// Client for requests other than listens, each of which times out.
+    client: ReqwestClient,
+    /// Client for listen requests, which stay open indefinitely and so have no overall timeout.
+    listen_client: ReqwestClient,
+    retry_policy: RetryPolicy,
+    limiter: Arc<Limiter>,
 }
 
 impl OpenDhtRestAdapter {
     pub fn new(proxy_address: &str, proxy_port: u16) -> Self {
+        Self::with_retry_policy(proxy_address, proxy_port, RetryPolicy::default())
+    }
+    pub fn with_retry_policy(
+        proxy_address: &str,
+        proxy_port: u16,
+        retry_policy: RetryPolicy,
+    ) -> Self {
+        // fetch cannot time out connecting alone, so listens then rely on the browser's timeout
+        #[cfg(not(target_arch = "wasm32"))]
+        let listen_client = ReqwestClient::builder()
+            .connect_timeout(retry_policy.timeout)
+            .build()
+            .unwrap_or_default();
+        #[cfg(target_arch = "wasm32")]
+        let listen_client = ReqwestClient::new();
         Self {
             proxy_address: proxy_address.into(),
             proxy_port,
+            client: ReqwestClient::new(),
+            listen_client,
+            retry_policy,
+            limiter: Arc::new(Limiter::new(RateLimit::default())),
         }
     }
-    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
-        // Implementation for getting a value from OpenDHT via REST API using reqwest
-        let request_url = format!(
-            "http://{}:{}/key/{}",
-            self.proxy_address, self.proxy_port, key
-        );
-        let response = ReqwestClient::new()
-            .get(&request_url)
-            .send()
-            .map_err(Box::new)?
-            .error_for_status()
-            .map_err(Box::new)?;
-        let response_body = response.text()?;
-        if response_body.is_empty() {
-            Ok(None)
-        } else {
-            let json_value: Value = json_decode(&response_body).map_err(Box::new)?;
-            let data = STANDARD
-                .decode(json_value["data"].as_str().unwrap_or_default())
-                .map_err(Box::new)?;
-            Ok(Some(data))
-        }
+    /// Limits the requests sent to the proxy.
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.limiter = Arc::new(Limiter::new(rate_limit));
+        self
     }
-    pub fn put(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+    #[tracing::instrument(name = "dht_put", skip(self, value), fields(length = value.len()), err(level = "warn"))]
+    pub async fn put(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
         // Implementation for putting a value into OpenDHT via REST API using reqwest
         let request_url = format!(
             "http://{}:{}/key/{}",
@@ -48,22 +175,151 @@ impl OpenDhtRestAdapter {
         let request_payload = json_encode(&json!({
             "data": STANDARD.encode(value),
             "permanent": "true"
-        }))
-        .unwrap();
-        let _response = ReqwestClient::new()
+        }))?;
+        let _permit = self.limiter.acquire().await;
+        let _response = self
+            .client
             .post(&request_url)
+            .timeout(self.retry_policy.timeout)
             .body(request_payload)
             .send()
-            .map_err(Box::new)?
-            .error_for_status()
-            .map_err(Box::new)?;
+            .await?
+            .error_for_status()?;
         Ok(())
     }
-    pub fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
-        if let Ok(Some(_)) = self.get(key) {
-            Err("Key already exists".into())
+    /// Streams the values stored under the key from the proxy's `LISTEN` endpoint, current ones
+    /// first, calling `callback` with each until it returns `false` or the proxy closes the
+    /// stream.
+    #[tracing::instrument(name = "dht_listen", skip(self, callback), err(level = "warn"))]
+    pub async fn listen(
+        &self,
+        key: &str,
+        mut callback: impl FnMut(Vec<u8>) -> bool + Send,
+    ) -> Result<(), MySgmError> {
+        let request_url = format!(
+            "http://{}:{}/key/{}",
+            self.proxy_address, self.proxy_port, key
+        );
+        let method = Method::from_bytes(b"LISTEN").expect("LISTEN is a valid method");
+        self.limiter.throttle().await;
+        let response = self
+            .listen_client
+            .request(method, &request_url)
+            .send()
+            .await?
+            .error_for_status()?;
+        // one JSON value per line; expiration notices carry no data
+        let mut buffer = Vec::new();
+        let mut chunks = response.bytes_stream();
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk?;
+            buffer.extend_from_slice(&chunk);
+            while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                let json_value: Value = json_decode_slice(&line)?;
+                let Some(data) = json_value["data"].as_str() else {
+                    continue;
+                };
+                if !callback(STANDARD.decode(data)?) {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+    #[tracing::instrument(name = "dht_get", skip(self), err(level = "debug"))]
+    async fn get_once(&self, key: &str) -> Result<Option<Vec<u8>>, MySgmError> {
+        // Implementation for getting a value from OpenDHT via REST API using reqwest
+        let request_url = format!(
+            "http://{}:{}/key/{}",
+            self.proxy_address, self.proxy_port, key
+        );
+        let _permit = self.limiter.acquire().await;
+        let response = self
+            .client
+            .get(&request_url)
+            .timeout(self.retry_policy.timeout)
+            .send()
+            .await?
+            .error_for_status()?;
+        let response_body = response.text().await?;
+        if response_body.is_empty() {
+            Ok(None)
         } else {
-            self.put(key, value)
+            let json_value: Value = json_decode(&response_body)?;
+            let data = STANDARD.decode(json_value["data"].as_str().unwrap_or_default())?;
+            Ok(Some(data))
+        }
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl DeliveryAdapter for OpenDhtRestAdapter {
+    /// Gets the value under the key, retrying transient failures with exponential backoff.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, MySgmError> {
+        let mut attempt = 0;
+        loop {
+            match self.get_once(key).await {
+                Err(e) if e.is_transient() && attempt < self.retry_policy.max_retries => {
+                    let delay = self.retry_policy.delay(attempt);
+                    tracing::warn!("Retrying get of {key} in {delay:?}: {e}");
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+    /// Puts the value again; not retried, like [`Self::put_checked`].
+    async fn republish(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
+        self.put(key, value).await
+    }
+    /// Listens on all keys until the first value arrives or the timeout passes.
+    ///
+    /// If the proxy cannot listen, this falls back to waiting out the timeout.
+    async fn watch(&self, keys: &[String], timeout: Duration) -> Result<(), MySgmError> {
+        if keys.is_empty() {
+            sleep(timeout).await;
+            return Ok(());
+        }
+        let listens = keys.iter().map(|key| Box::pin(self.listen(key, |_| false)));
+        match with_timeout(timeout, select_all(listens)).await {
+            Some((Err(e), ..)) => {
+                tracing::warn!("Failed to listen, polling instead: {e}");
+                sleep(timeout).await;
+                Ok(())
+            }
+            Some((Ok(()), ..)) | None => Ok(()),
+        }
+    }
+    /// Reads the time from the `Date` header of the proxy's node info.
+    #[tracing::instrument(name = "dht_time", skip(self), err(level = "warn"))]
+    async fn server_time(&self) -> Result<Option<u64>, MySgmError> {
+        let request_url = format!("http://{}:{}/", self.proxy_address, self.proxy_port);
+        let _permit = self.limiter.acquire().await;
+        let response = self
+            .client
+            .get(&request_url)
+            .timeout(self.retry_policy.timeout)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response
+            .headers()
+            .get(DATE)
+            .and_then(|date| parse_http_date(date.to_str().ok()?).ok())
+            .and_then(|date| date.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_secs()))
+    }
+    /// Puts the value if the key is free; not retried, since a put may have landed before failing.
+    async fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
+        match self.get(key).await? {
+            Some(_) => Err(MySgmError::KeyExists),
+            None => self.put(key, value).await,
         }
     }
 }
@@ -0,0 +1,105 @@
+//! Padding of application messages to fixed size buckets, so that observers of the delivery
+//! service cannot infer the size of a message from the size of its ciphertext.
+//!
+//! A padded message is framed as [`PADDING_MAGIC`], the length of the message as a big-endian
+//! `u32`, the message, and zeros up to the bucket size. The frame is encrypted along with the
+//! message, and receivers strip it whatever their own policy is.
+
+use super::error::MySgmError;
+
+use serde::{Deserialize, Serialize};
+
+/// Prefix marking an application message as padded.
+const PADDING_MAGIC: &[u8] = b"mysgm padded\0";
+
+/// Bytes the frame adds before the message.
+const HEADER_LEN: usize = PADDING_MAGIC.len() + 4;
+
+/// Sizes, in bytes, that a group's application messages are padded to before encryption.
+///
+/// A message is padded to the smallest bucket holding it and its frame; larger messages are
+/// padded to a multiple of the largest bucket.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaddingPolicy {
+    buckets: Vec<usize>,
+}
+
+impl PaddingPolicy {
+    /// Policy padding to the given bucket sizes; sizes too small to hold a frame are dropped.
+    pub fn new(buckets: impl IntoIterator<Item = usize>) -> Result<Self, MySgmError> {
+        let mut buckets: Vec<usize> = buckets
+            .into_iter()
+            .filter(|bucket| *bucket > HEADER_LEN)
+            .collect();
+        buckets.sort_unstable();
+        buckets.dedup();
+        if buckets.is_empty() {
+            return Err(MySgmError::InvalidPadding(format!(
+                "no bucket larger than the {HEADER_LEN} byte frame"
+            )));
+        }
+        Ok(Self { buckets })
+    }
+    pub fn buckets(&self) -> &[usize] {
+        &self.buckets
+    }
+    /// Size that a message of the given length is padded to.
+    pub fn padded_len(&self, len: usize) -> usize {
+        let framed = len + HEADER_LEN;
+        match self.buckets.iter().find(|bucket| **bucket >= framed) {
+            Some(bucket) => *bucket,
+            None => match self.buckets.last() {
+                Some(largest) => framed.div_ceil(*largest) * largest,
+                // only a policy decoded from a tampered state has no buckets
+                None => framed,
+            },
+        }
+    }
+}
+
+impl Default for PaddingPolicy {
+    fn default() -> Self {
+        Self {
+            buckets: vec![256, 1024, 4096],
+        }
+    }
+}
+
+/// Frames the message for encryption, padded to a bucket of the policy if any.
+///
+/// Without a policy the message is left as it is, unless it could be taken for a padded one.
+pub fn pad_message(policy: Option<&PaddingPolicy>, message: &[u8]) -> Result<Vec<u8>, MySgmError> {
+    let padded_len = match policy {
+        Some(policy) => policy.padded_len(message.len()),
+        None if message.starts_with(PADDING_MAGIC) => message.len() + HEADER_LEN,
+        None => return Ok(message.to_vec()),
+    };
+    let len = u32::try_from(message.len())
+        .map_err(|_| MySgmError::InvalidPadding("message too large to pad".into()))?;
+    let mut padded = Vec::with_capacity(padded_len);
+    padded.extend(PADDING_MAGIC);
+    padded.extend(len.to_be_bytes());
+    padded.extend(message);
+    padded.resize(padded_len, 0);
+    Ok(padded)
+}
+
+/// Strips the padding of a decrypted application message received under the key; messages
+/// sent without padding are returned as they are.
+pub fn unpad_message(key: &str, message: Vec<u8>) -> Result<Vec<u8>, MySgmError> {
+    let Some(framed) = message.strip_prefix(PADDING_MAGIC) else {
+        return Ok(message);
+    };
+    let malformed = |detail: &str| MySgmError::MalformedPayload(key.to_string(), detail.into());
+    let (len, padded) = framed
+        .split_first_chunk::<4>()
+        .ok_or_else(|| malformed("padding frame is truncated"))?;
+    let len = u32::from_be_bytes(*len) as usize;
+    if len > padded.len() {
+        return Err(malformed("padded message is shorter than its length"));
+    }
+    if padded[len..].iter().any(|byte| *byte != 0) {
+        return Err(malformed("padding is not zeros"));
+    }
+    Ok(padded[..len].to_vec())
+}
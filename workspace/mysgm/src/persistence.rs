@@ -0,0 +1,531 @@
+#[cfg(not(target_arch = "wasm32"))]
+use super::sqlite;
+use super::{
+    error::MySgmError,
+    journal::{self, JournalBase, JournalEntry, JournalOp},
+    migration,
+    state::MySgmState,
+};
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit,
+    aead::{Aead, AeadCore, OsRng, rand_core::RngCore},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, from_str as json_decode, to_string as json_encode};
+use serde_with::{hex::Hex, serde_as};
+use std::{
+    fs::{
+        File, copy as copy_file, exists as file_exists, metadata, remove_file,
+        rename as rename_file,
+    },
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+};
+
+/// On-disk format of agent state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateStorage {
+    /// A single JSON file, optionally encrypted.
+    Json,
+    /// A single JSON file like [`Self::Json`], compressed with zstd.
+    CompressedJson,
+    /// An SQLite database with a table per part of the state.
+    Sqlite,
+}
+
+impl StateStorage {
+    /// Picks SQLite for `.db`, `.sqlite`, and `.sqlite3` paths, compressed JSON for `.zst`
+    /// paths such as `state.json.zst`, and JSON otherwise.
+    pub fn from_path(path: &str) -> Self {
+        match Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some("db" | "sqlite" | "sqlite3") => Self::Sqlite,
+            Some("zst") => Self::CompressedJson,
+            _ => Self::Json,
+        }
+    }
+}
+
+/// Version of the state on disk, read from plaintext and encrypted files alike.
+#[derive(Deserialize)]
+struct StoredVersion {
+    #[serde(default)]
+    version: u64,
+}
+
+/// On-disk wrapper for state encrypted under a passphrase-derived key.
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+struct EncryptedState {
+    /// Copy of the state's version, so that it can be checked without decrypting.
+    #[serde(default)]
+    version: u64,
+    kdf: String,
+    #[serde_as(as = "Hex")]
+    salt: Vec<u8>,
+    #[serde_as(as = "Hex")]
+    nonce: Vec<u8>,
+    #[serde_as(as = "Hex")]
+    ciphertext: Vec<u8>,
+}
+
+const KDF_ARGON2ID: &str = "argon2id";
+
+/// Journal entries after which [`save_state_incrementally`] saves the state in full again.
+pub const MAX_JOURNAL_ENTRIES: usize = 64;
+
+/// SQLite is not available under WebAssembly, where [`crate::browser`] keeps the state.
+#[cfg(target_arch = "wasm32")]
+mod sqlite {
+    use super::{MySgmError, MySgmState};
+
+    pub fn stored_version(_: &str) -> Option<u64> {
+        None
+    }
+    pub fn load(_: &str) -> Result<MySgmState, MySgmError> {
+        Err(MySgmError::UnsupportedPlatform("SQLite state"))
+    }
+    pub fn save(_: &str, _: &MySgmState) -> Result<(), MySgmError> {
+        Err(MySgmError::UnsupportedPlatform("SQLite state"))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod compression {
+    use super::MySgmError;
+
+    use std::io::{Read, Write};
+
+    /// Compresses what `write` writes into the writer.
+    pub fn compress(
+        writer: &mut dyn Write,
+        write: impl FnOnce(&mut dyn Write) -> Result<(), MySgmError>,
+    ) -> Result<(), MySgmError> {
+        let mut encoder = zstd::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+        write(&mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    }
+    pub fn decompress<'a>(reader: impl Read + 'a) -> Result<Box<dyn Read + 'a>, MySgmError> {
+        Ok(Box::new(zstd::Decoder::new(reader)?))
+    }
+}
+
+/// zstd is not built for WebAssembly, where the browser's local storage keeps plain JSON.
+#[cfg(target_arch = "wasm32")]
+mod compression {
+    use super::MySgmError;
+
+    use std::io::{Read, Write};
+
+    pub fn compress(
+        _: &mut dyn Write,
+        _: impl FnOnce(&mut dyn Write) -> Result<(), MySgmError>,
+    ) -> Result<(), MySgmError> {
+        Err(MySgmError::UnsupportedPlatform("compressed state"))
+    }
+    pub fn decompress<'a>(_: impl Read + 'a) -> Result<Box<dyn Read + 'a>, MySgmError> {
+        Err(MySgmError::UnsupportedPlatform("compressed state"))
+    }
+}
+
+fn derive_cipher(passphrase: &str, salt: &[u8]) -> Result<ChaCha20Poly1305, MySgmError> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| MySgmError::KeyDerivation(e.to_string()))?;
+    Ok(ChaCha20Poly1305::new(&key.into()))
+}
+
+/// Decrypts the JSON of an [`EncryptedState`] written by [`encrypt`].
+pub(crate) fn decrypt(contents: &str, passphrase: &str) -> Result<Vec<u8>, MySgmError> {
+    let encrypted: EncryptedState = json_decode(contents)?;
+    if encrypted.kdf != KDF_ARGON2ID {
+        return Err(MySgmError::UnsupportedKdf(encrypted.kdf));
+    }
+    derive_cipher(passphrase, &encrypted.salt)?
+        .decrypt(
+            encrypted.nonce.as_slice().into(),
+            encrypted.ciphertext.as_slice(),
+        )
+        .map_err(|_| MySgmError::StateDecryption)
+}
+
+/// Encrypts the plaintext under a key derived from the passphrase with a fresh salt, returning
+/// the JSON of an [`EncryptedState`] carrying the version.
+pub(crate) fn encrypt(
+    plaintext: &[u8],
+    version: u64,
+    passphrase: &str,
+) -> Result<String, MySgmError> {
+    let mut salt = vec![0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = derive_cipher(passphrase, &salt)?
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| MySgmError::StateEncryption)?;
+    Ok(json_encode(&EncryptedState {
+        version,
+        kdf: KDF_ARGON2ID.to_string(),
+        salt,
+        nonce: nonce.to_vec(),
+        ciphertext,
+    })?)
+}
+
+/// Decodes JSON written by [`write_json`], decrypting it if a passphrase is given.
+fn decode_json<T: for<'de> Deserialize<'de>>(
+    contents: &str,
+    passphrase: Option<&str>,
+) -> Result<T, MySgmError> {
+    Ok(match passphrase {
+        Some(passphrase) => serde_json::from_slice(&decrypt(contents, passphrase)?)?,
+        None => json_decode(contents)?,
+    })
+}
+
+pub(crate) fn decode_state(
+    contents: &str,
+    passphrase: Option<&str>,
+) -> Result<MySgmState, MySgmError> {
+    migration::decode(decode_json(contents, passphrase)?)
+}
+
+/// Decodes the state and replays the journal entries written after it, remembering what the
+/// state was as persisted if the journal could be read to its end.
+fn decode_journaled_state(
+    path: &str,
+    contents: &str,
+    passphrase: Option<&str>,
+) -> Result<MySgmState, MySgmError> {
+    let mut value: Value = decode_json(contents, passphrase)?;
+    let version = value.get("version").and_then(Value::as_u64).unwrap_or(0);
+    let (entries, complete) = read_journal(path, version, passphrase)?;
+    let replayed = entries.len();
+    for entry in entries {
+        journal::apply(&mut value, entry.ops)?;
+    }
+    let base = complete.then(|| JournalBase::of(&value, replayed));
+    let state = migration::decode(value)?;
+    state.set_journal_base(base);
+    Ok(state)
+}
+
+fn journal_path(path: &str) -> String {
+    format!("{path}.journal")
+}
+
+/// Reads the journal entries written after the state at the version, and whether the journal
+/// was read to its end; reading stops at the first entry that cannot be decoded, such as one
+/// cut short by a crash.
+fn read_journal(
+    path: &str,
+    version: u64,
+    passphrase: Option<&str>,
+) -> Result<(Vec<JournalEntry>, bool), MySgmError> {
+    let file = match File::open(journal_path(path)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((Vec::new(), true)),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        match line
+            .map_err(MySgmError::from)
+            .and_then(|line| decode_json::<JournalEntry>(&line, passphrase))
+        {
+            // left over from before the state was last saved in full
+            Ok(entry) if entry.version <= version => {}
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                tracing::warn!("Ignoring the rest of the journal of {path}: {e}");
+                return Ok((entries, false));
+            }
+        }
+    }
+    Ok((entries, true))
+}
+
+/// Returns the version of the latest readable entry of the journal, if any.
+fn journal_version(path: &str) -> Option<u64> {
+    BufReader::new(File::open(journal_path(path)).ok()?)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| json_decode::<StoredVersion>(&line).ok())
+        .map(|stored| stored.version)
+        .max()
+}
+
+/// Appends the entry to the journal, encrypted like the state if a passphrase is given.
+fn append_journal(
+    path: &str,
+    entry: &JournalEntry,
+    passphrase: Option<&str>,
+) -> Result<(), MySgmError> {
+    let mut line = Vec::new();
+    write_json(&mut line, entry, entry.version, passphrase)?;
+    line.push(b'\n');
+    let mut file = File::options()
+        .create(true)
+        .append(true)
+        .open(journal_path(path))?;
+    file.write_all(&line)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Whether the journal has grown larger than the state it applies to.
+fn journal_outgrew(path: &str) -> bool {
+    let size = |path: &str| metadata(path).map_or(0, |metadata| metadata.len());
+    size(&journal_path(path)) > size(path)
+}
+
+fn backup_path(path: &str) -> String {
+    format!("{path}.bak")
+}
+
+/// Opens the advisory lock file guarding the state file.
+///
+/// A separate file is locked since saving replaces the state file itself.
+fn lock_file(path: &str) -> Result<File, MySgmError> {
+    Ok(File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(format!("{path}.lock"))?)
+}
+
+/// Opens the JSON state file for reading, decompressing it if it is compressed.
+fn open_state_file(path: &str, storage: StateStorage) -> Result<Box<dyn Read>, MySgmError> {
+    let file = BufReader::new(File::open(path)?);
+    match storage {
+        StateStorage::CompressedJson => compression::decompress(file),
+        _ => Ok(Box::new(file)),
+    }
+}
+
+fn read_state_file(path: &str, storage: StateStorage) -> Result<String, MySgmError> {
+    let mut contents = String::new();
+    open_state_file(path, storage)?.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+/// Returns the version of the stored state, including its journal, or `None` if there is no
+/// readable state.
+pub fn stored_version(path: &str, storage: StateStorage) -> Option<u64> {
+    if storage == StateStorage::Sqlite {
+        return sqlite::stored_version(path);
+    }
+    let stored =
+        serde_json::from_reader::<_, StoredVersion>(open_state_file(path, storage).ok()?).ok()?;
+    Some(journal_version(path).map_or(stored.version, |version| version.max(stored.version)))
+}
+
+/// Returns the version of the encoded state, plaintext or encrypted.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn version_of(contents: &str) -> Option<u64> {
+    json_decode::<StoredVersion>(contents)
+        .ok()
+        .map(|stored| stored.version)
+}
+
+/// Loads agent state from the file, decrypting it if a passphrase is given, replaying the
+/// journal kept by [`save_state_incrementally`], and migrating it from older format versions.
+///
+/// SQLite state cannot be encrypted. For JSON state, falls back to the backup kept by
+/// [`save_state`], without the journal, if the file cannot be read or parsed.
+pub fn load_state(
+    path: &str,
+    storage: StateStorage,
+    passphrase: Option<&str>,
+) -> Result<MySgmState, MySgmError> {
+    if storage == StateStorage::Sqlite {
+        return match passphrase {
+            Some(_) => Err(MySgmError::SqliteEncryption),
+            None => sqlite::load(path),
+        };
+    }
+    let lock = lock_file(path)?;
+    lock.lock_shared()?;
+    match read_state_file(path, storage)
+        .and_then(|contents| decode_journaled_state(path, &contents, passphrase))
+    {
+        Ok(state) => Ok(state),
+        Err(e) => {
+            let backup = backup_path(path);
+            tracing::warn!("Failed to load state from {path}: {e}; trying {backup}");
+            match read_state_file(&backup, storage) {
+                Ok(contents) => decode_state(&contents, passphrase),
+                Err(_) => Err(e),
+            }
+        }
+    }
+}
+
+/// Replaces the file with what `write` writes to a buffered and synced temporary file, keeping
+/// the previous version as backup.
+fn write_atomically(
+    path: &str,
+    write: impl FnOnce(&mut dyn Write) -> Result<(), MySgmError>,
+) -> Result<(), MySgmError> {
+    let tmp_path = format!("{path}.tmp");
+    let mut file = BufWriter::new(File::create(&tmp_path)?);
+    write(&mut file)?;
+    let file = file.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    if file_exists(path)? {
+        copy_file(path, backup_path(path))?;
+    }
+    rename_file(&tmp_path, path)?;
+    let dir = match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn encode_state(
+    state: &MySgmState,
+    passphrase: Option<&str>,
+) -> Result<String, MySgmError> {
+    let plaintext = json_encode(state)?;
+    match passphrase {
+        Some(passphrase) => encrypt(plaintext.as_bytes(), state.version(), passphrase),
+        None => Ok(plaintext),
+    }
+}
+
+/// Writes the value as JSON, streamed unless it is encrypted along with the version, which
+/// takes the whole plaintext at once.
+fn write_json(
+    writer: &mut dyn Write,
+    value: &impl Serialize,
+    version: u64,
+    passphrase: Option<&str>,
+) -> Result<(), MySgmError> {
+    match passphrase {
+        Some(passphrase) => {
+            let plaintext = serde_json::to_vec(value)?;
+            writer.write_all(encrypt(&plaintext, version, passphrase)?.as_bytes())?
+        }
+        None => serde_json::to_writer(writer, value)?,
+    }
+    Ok(())
+}
+
+/// Replaces the file with the state at the version, dropping the journal, whose entries the
+/// state includes.
+fn write_snapshot(
+    path: &str,
+    storage: StateStorage,
+    state: &impl Serialize,
+    version: u64,
+    passphrase: Option<&str>,
+) -> Result<(), MySgmError> {
+    write_atomically(path, |file| match storage {
+        StateStorage::CompressedJson => compression::compress(file, |encoder| {
+            write_json(encoder, state, version, passphrase)
+        }),
+        _ => write_json(file, state, version, passphrase),
+    })?;
+    // entries left behind by a crash right here are older than the state, and skipped
+    match remove_file(journal_path(path)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the version of the state, refusing with [`MySgmError::StateConflict`] if another
+/// process saved the file since the state was loaded.
+fn check_stored_version(
+    path: &str,
+    storage: StateStorage,
+    state: &MySgmState,
+) -> Result<u64, MySgmError> {
+    let loaded = state.version();
+    match stored_version(path, storage) {
+        Some(stored) if stored != loaded => Err(MySgmError::StateConflict { loaded, stored }),
+        _ => Ok(loaded),
+    }
+}
+
+/// Saves agent state to the file, encrypting it if a passphrase is given.
+///
+/// Refuses with [`MySgmError::StateConflict`] if another process saved the file since the state
+/// was loaded; otherwise the state's version is incremented with the save.
+pub fn save_state(
+    path: &str,
+    storage: StateStorage,
+    state: &MySgmState,
+    passphrase: Option<&str>,
+) -> Result<(), MySgmError> {
+    if storage == StateStorage::Sqlite {
+        return match passphrase {
+            Some(_) => Err(MySgmError::SqliteEncryption),
+            None => sqlite::save(path, state),
+        };
+    }
+    let lock = lock_file(path)?;
+    lock.lock()?;
+    let loaded = check_stored_version(path, storage, state)?;
+    state.set_version(loaded + 1);
+    state.set_journal_base(None);
+    let result = write_snapshot(path, storage, state, loaded + 1, passphrase);
+    if result.is_err() {
+        state.set_version(loaded);
+    }
+    result
+}
+
+/// Saves agent state like [`save_state`], but only appends what changed since the state was
+/// last loaded or saved to a journal next to the file, for [`load_state`] to replay.
+///
+/// The state is saved in full instead, compacting the journal, when what it was as persisted
+/// is unknown, after [`MAX_JOURNAL_ENTRIES`] entries, or once the journal outgrows the file.
+/// Saves that change nothing write nothing, and SQLite state is always saved in full.
+pub fn save_state_incrementally(
+    path: &str,
+    storage: StateStorage,
+    state: &MySgmState,
+    passphrase: Option<&str>,
+) -> Result<(), MySgmError> {
+    if storage == StateStorage::Sqlite {
+        return save_state(path, storage, state, passphrase);
+    }
+    let lock = lock_file(path)?;
+    lock.lock()?;
+    let loaded = check_stored_version(path, storage, state)?;
+    let version = loaded + 1;
+    let mut value = serde_json::to_value(state)?;
+    // a failed save leaves the base taken, so that the next save is in full
+    let saved = match state.take_journal_base() {
+        Some(base) if base.entries < MAX_JOURNAL_ENTRIES && !journal_outgrew(path) => {
+            let mut ops = base.diff(&value);
+            if ops.is_empty() {
+                state.set_journal_base(Some(base));
+                return Ok(());
+            }
+            value["version"] = version.into();
+            ops.push(JournalOp::Set {
+                field: "version".to_string(),
+                value: version.into(),
+            });
+            append_journal(path, &JournalEntry { version, ops }, passphrase)
+                .map(|()| base.entries + 1)
+        }
+        _ => {
+            value["version"] = version.into();
+            write_snapshot(path, storage, &value, version, passphrase).map(|()| 0)
+        }
+    };
+    let entries = saved?;
+    state.set_version(version);
+    state.set_journal_base(Some(JournalBase::of(&value, entries)));
+    Ok(())
+}
@@ -1,8 +1,10 @@
-use super::state::{MySgmState, OpenMlsKeyValueStore};
+use super::{
+    keys::SignatureKeyPair,
+    state::{MySgmState, OpenMlsKeyValueStore},
+};
 use openmls_rust_crypto::RustCrypto;
 use openmls_traits::{
     OpenMlsProvider,
-    crypto::OpenMlsCrypto,
     signatures::{Signer, SignerError},
     types::SignatureScheme,
 };
@@ -40,17 +42,68 @@ impl OpenMlsProvider for MySgmProvider {
     }
 }
 
+/// Provider over a copy of the OpenMLS storage, for working out what a change would lead to,
+/// e.g. the ratchet tree after merging a staged commit, without touching the agent's state.
+#[derive(Debug)]
+pub struct ScratchProvider {
+    storage: OpenMlsKeyValueStore,
+    crypto: RustCrypto,
+}
+
+impl ScratchProvider {
+    pub fn new(storage: &OpenMlsKeyValueStore) -> Self {
+        Self {
+            storage: storage.clone(),
+            crypto: RustCrypto::default(),
+        }
+    }
+}
+
+impl OpenMlsProvider for ScratchProvider {
+    type CryptoProvider = RustCrypto;
+    type RandProvider = RustCrypto;
+    type StorageProvider = OpenMlsKeyValueStore;
+    fn storage(&self) -> &Self::StorageProvider {
+        &self.storage
+    }
+    fn crypto(&self) -> &Self::CryptoProvider {
+        &self.crypto
+    }
+    fn rand(&self) -> &Self::RandProvider {
+        &self.crypto
+    }
+}
+
+/// Signs with the current signature key pair of the agent.
 impl Signer for MySgmProvider {
     fn sign(&self, payload: &[u8]) -> Result<Vec<u8>, SignerError> {
-        self.crypto
-            .sign(
-                self.state.signature_key_pair().signature_scheme(),
-                payload,
-                self.state.signature_key_pair().private_key_raw(),
-            )
-            .map_err(SignerError::CryptoError)
+        KeyPairSigner::new(self.state.signature_key_pair(), &self.crypto).sign(payload)
     }
     fn signature_scheme(&self) -> SignatureScheme {
         self.state.signature_key_pair().signature_scheme()
     }
 }
+
+/// Signs with a given key pair, e.g. a previous one of the agent.
+#[derive(Debug)]
+pub struct KeyPairSigner<'a> {
+    key_pair: &'a SignatureKeyPair,
+    crypto: &'a RustCrypto,
+}
+
+impl<'a> KeyPairSigner<'a> {
+    pub fn new(key_pair: &'a SignatureKeyPair, crypto: &'a RustCrypto) -> Self {
+        Self { key_pair, crypto }
+    }
+}
+
+impl Signer for KeyPairSigner<'_> {
+    fn sign(&self, payload: &[u8]) -> Result<Vec<u8>, SignerError> {
+        self.key_pair
+            .sign(self.crypto, payload)
+            .map_err(SignerError::CryptoError)
+    }
+    fn signature_scheme(&self) -> SignatureScheme {
+        self.key_pair.signature_scheme()
+    }
+}
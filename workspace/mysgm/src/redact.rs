@@ -0,0 +1,35 @@
+//! Redaction of secrets from `Debug` output, which ends up in logs.
+//!
+//! Private keys, exporter secrets, the MLS key store, and received plaintexts are formatted as
+//! their size only, unless logging secrets was enabled for development with
+//! [`set_log_secrets`].
+
+use core::{
+    fmt::{Debug, Formatter, Result as FmtResult},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use hex::encode as hex_encode;
+
+static LOG_SECRETS: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether `Debug` output includes secrets, for the whole process.
+pub fn set_log_secrets(enabled: bool) {
+    LOG_SECRETS.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether `Debug` output includes secrets.
+pub fn log_secrets() -> bool {
+    LOG_SECRETS.load(Ordering::Relaxed)
+}
+
+/// Secret bytes, formatted in hex if secrets are logged, and as their length otherwise.
+pub struct Secret<'a>(pub &'a [u8]);
+
+impl Debug for Secret<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match log_secrets() {
+            true => write!(f, "0x{}", hex_encode(self.0)),
+            false => write!(f, "<{} bytes redacted>", self.0.len()),
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Destinations for exported secrets other than standard output, which leaks them into shell
+//! history, terminal scrollback, and logs.
+//!
+//! Each sink stores the secret and returns a handle naming where it went, which is safe to
+//! print in its place.
+
+use super::{
+    error::MySgmError,
+    token::{Pkcs11Target, open_session},
+};
+
+use cryptoki::object::{Attribute, KeyType, ObjectClass};
+use hex::encode as hex_encode;
+use keyring::Entry;
+use std::{fs::OpenOptions, io::Write};
+
+/// Keyring service that secrets are stored under.
+pub const KEYRING_SERVICE: &str = "mysgm";
+
+/// Stores the secret in the OS keyring under the account and returns its handle.
+pub fn store_in_keyring(account: &str, secret: &[u8]) -> Result<String, MySgmError> {
+    Entry::new(KEYRING_SERVICE, account)?.set_secret(secret)?;
+    Ok(format!("keyring:{KEYRING_SERVICE}/{account}"))
+}
+
+/// Writes the hex secret to a new file readable by its owner only and returns its path.
+///
+/// Existing files are not overwritten, since their permissions may be wider.
+pub fn write_secret_file(path: &str, secret: &[u8]) -> Result<String, MySgmError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", hex_encode(secret))?;
+    file.sync_all()?;
+    Ok(path.to_string())
+}
+
+/// Imports the secret into the token as a sensitive, non-extractable generic secret key
+/// labelled with the label, and returns its handle.
+pub fn import_into_pkcs11(
+    target: &Pkcs11Target<'_>,
+    label: &str,
+    secret: &[u8],
+) -> Result<String, MySgmError> {
+    let (session, slot) = open_session(target)?;
+    session.create_object(&[
+        Attribute::Class(ObjectClass::SECRET_KEY),
+        Attribute::KeyType(KeyType::GENERIC_SECRET),
+        Attribute::Token(true),
+        Attribute::Private(true),
+        Attribute::Sensitive(true),
+        Attribute::Extractable(false),
+        Attribute::Label(label.as_bytes().to_vec()),
+        Attribute::Value(secret.to_vec()),
+    ])?;
+    Ok(format!("pkcs11:slot-id={};object={label}", slot.id()))
+}
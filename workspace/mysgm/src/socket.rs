@@ -0,0 +1,131 @@
+//! Unix domain socket command interface of the daemon.
+//!
+//! Clients write one JSON [`SocketRequest`] per line, holding the command-line arguments of a
+//! command, and read one JSON [`SocketResponse`] per line back. As with the gRPC control API,
+//! the listener only forwards requests to the daemon, which owns the agent and runs them
+//! between syncs.
+
+use super::error::MySgmError;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str as json_decode, to_string as json_encode};
+use std::{fs::remove_file, io::ErrorKind, path::Path};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, oneshot},
+};
+
+/// Number of requests queued for the daemon before clients wait.
+const REQUEST_QUEUE: usize = 64;
+
+/// A command sent to the daemon.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SocketRequest {
+    /// Command-line arguments, as passed to a separate invocation.
+    pub args: Vec<String>,
+}
+
+/// The outcome of a command run by the daemon.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SocketResponse {
+    /// What the command printed.
+    #[serde(default)]
+    pub output: String,
+    /// Why the command failed, if it did.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// A request received on the socket, answered once the daemon ran it.
+#[derive(Debug)]
+pub struct SocketCommand {
+    pub request: SocketRequest,
+    pub reply: oneshot::Sender<SocketResponse>,
+}
+
+/// Daemon end of the socket interface.
+#[derive(Debug)]
+pub struct SocketServer {
+    /// Commands to run against the agent.
+    pub commands: mpsc::Receiver<SocketCommand>,
+}
+
+impl SocketServer {
+    /// Listens on the socket path in the background, replacing a socket left by an earlier
+    /// daemon.
+    pub fn bind(path: &str) -> Result<Self, MySgmError> {
+        match remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let listener = UnixListener::bind(Path::new(path))?;
+        tracing::info!("Listening for commands on {path}");
+        let (commands_tx, commands) = mpsc::channel(REQUEST_QUEUE);
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let commands = commands_tx.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = serve_connection(stream, commands).await {
+                                tracing::warn!("Socket connection failed: {e}");
+                            }
+                        });
+                    }
+                    Err(e) => tracing::error!("Failed to accept socket connection: {e}"),
+                }
+            }
+        });
+        Ok(Self { commands })
+    }
+}
+
+/// Answers the requests of one client until it closes the connection.
+async fn serve_connection(
+    stream: UnixStream,
+    commands: mpsc::Sender<SocketCommand>,
+) -> Result<(), MySgmError> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match json_decode::<SocketRequest>(&line) {
+            Ok(request) => {
+                let (reply, answer) = oneshot::channel();
+                commands
+                    .send(SocketCommand { request, reply })
+                    .await
+                    .map_err(|_| MySgmError::DaemonStopped)?;
+                answer.await.map_err(|_| MySgmError::DaemonStopped)?
+            }
+            Err(e) => SocketResponse {
+                error: Some(format!("Invalid request: {e}")),
+                ..Default::default()
+            },
+        };
+        let mut line = json_encode(&response)?;
+        line.push('\n');
+        writer.write_all(line.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Sends the command-line arguments to the daemon listening on the socket and returns its
+/// response.
+pub async fn forward(path: &str, args: Vec<String>) -> Result<SocketResponse, MySgmError> {
+    let stream = UnixStream::connect(path).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut line = json_encode(&SocketRequest { args })?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+    writer.shutdown().await?;
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or(MySgmError::DaemonStopped)?;
+    Ok(json_decode(&line)?)
+}
@@ -0,0 +1,337 @@
+//! SQLite storage for agent state.
+//!
+//! The state is split across tables so that a save only writes the rows that changed:
+//!
+//! - `meta`: top-level fields without a table of their own, as JSON
+//! - `group_lists`: the joined, left, and external-tree gid lists, in order
+//! - `key_packages`: last-resort and one-time key packages of other agents
+//! - `counters`: slot counters and advertisement times, per namespace or gid
+//! - `messages`: the per-group message history, by the sequence number of each entry
+//! - `audit`: the per-group audit log, by the sequence number of each event
+//! - `openmls_values`: the OpenMLS key-value store
+//!
+//! Every table has text key columns and a `value` column holding JSON.
+
+use super::{error::MySgmError, migration, state::MySgmState};
+
+use rusqlite::{Connection, OptionalExtension, Transaction, TransactionBehavior, params_from_iter};
+use serde_json::{Map, Value, from_str as json_decode, to_string as json_encode};
+use std::collections::{HashMap, HashSet};
+
+const TABLES: &[(&str, &[&str])] = &[
+    ("meta", &["name"]),
+    ("group_lists", &["list", "gid"]),
+    ("key_packages", &["pid", "slot"]),
+    ("counters", &["counter", "scope"]),
+    ("messages", &["gid", "seq"]),
+    ("audit", &["gid", "seq"]),
+    ("openmls_values", &["key"]),
+];
+
+const GROUP_LISTS: &[&str] = &["gids", "left_gids", "external_tree_gids"];
+
+const COUNTERS: &[&str] = &[
+    "welcome_counters",
+    "welcome_write_counters",
+    "key_package_counter",
+    "message_counters",
+    "proposal_counters",
+    "sent_message_counters",
+    "received_sequences",
+    "commit_heads",
+    "advertised_at",
+];
+
+const LAST_RESORT_SLOT: &str = "last_resort";
+
+type Rows = HashMap<Vec<String>, String>;
+
+fn open(path: &str) -> Result<Connection, MySgmError> {
+    let connection = Connection::open(path)?;
+    for (table, keys) in TABLES {
+        connection.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {table} ({}, value TEXT NOT NULL, PRIMARY KEY ({}))",
+                keys.iter()
+                    .map(|key| format!("{key} TEXT NOT NULL"))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                keys.join(", "),
+            ),
+            [],
+        )?;
+    }
+    Ok(connection)
+}
+
+fn read_table(connection: &Connection, table: &str, keys: &[&str]) -> Result<Rows, MySgmError> {
+    let mut statement =
+        connection.prepare(&format!("SELECT {}, value FROM {table}", keys.join(", ")))?;
+    let rows = statement.query_map([], |row| {
+        let key = (0..keys.len())
+            .map(|i| row.get(i))
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok((key, row.get(keys.len())?))
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// Makes the table hold exactly the rows, writing only rows that changed.
+fn write_table(
+    transaction: &Transaction,
+    table: &str,
+    keys: &[&str],
+    rows: &Rows,
+) -> Result<(), MySgmError> {
+    let matches = keys
+        .iter()
+        .map(|key| format!("{key} = ?"))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+    let existing: HashSet<Vec<String>> =
+        read_table(transaction, table, keys)?.into_keys().collect();
+    for key in existing.iter().filter(|key| !rows.contains_key(*key)) {
+        transaction.execute(
+            &format!("DELETE FROM {table} WHERE {matches}"),
+            params_from_iter(key),
+        )?;
+    }
+    let mut upsert = transaction.prepare(&format!(
+        "INSERT INTO {table} ({}, value) VALUES ({}?) ON CONFLICT ({}) DO UPDATE \
+         SET value = excluded.value WHERE value IS NOT excluded.value",
+        keys.join(", "),
+        "?, ".repeat(keys.len()),
+        keys.join(", "),
+    ))?;
+    for (key, value) in rows {
+        upsert.execute(params_from_iter(key.iter().chain([value])))?;
+    }
+    Ok(())
+}
+
+fn take_object(fields: &mut Map<String, Value>, name: &str) -> Map<String, Value> {
+    match fields.remove(name) {
+        Some(Value::Object(object)) => object,
+        _ => Map::new(),
+    }
+}
+
+fn take_array(fields: &mut Map<String, Value>, name: &str) -> Vec<Value> {
+    match fields.remove(name) {
+        Some(Value::Array(array)) => array,
+        _ => Vec::new(),
+    }
+}
+
+/// Splits the serialized state into the rows of each table.
+fn state_rows(state: &MySgmState) -> Result<HashMap<&'static str, Rows>, MySgmError> {
+    let Value::Object(mut fields) = serde_json::to_value(state)? else {
+        unreachable!("state serializes to an object");
+    };
+    let mut tables: HashMap<&str, Rows> = HashMap::new();
+    for list in GROUP_LISTS {
+        for (position, gid) in take_array(&mut fields, list).into_iter().enumerate() {
+            let gid = gid.as_str().unwrap_or_default().to_string();
+            tables
+                .entry("group_lists")
+                .or_default()
+                .insert(vec![list.to_string(), gid], position.to_string());
+        }
+    }
+    for (pid, key_package) in take_object(&mut fields, "key_packages") {
+        tables.entry("key_packages").or_default().insert(
+            vec![pid, LAST_RESORT_SLOT.to_string()],
+            json_encode(&key_package)?,
+        );
+    }
+    for (pid, key_packages) in take_object(&mut fields, "one_time_key_packages") {
+        let Value::Array(key_packages) = key_packages else {
+            continue;
+        };
+        for (index, key_package) in key_packages.into_iter().enumerate() {
+            tables.entry("key_packages").or_default().insert(
+                vec![pid.clone(), format!("one_time_{index:08}")],
+                json_encode(&key_package)?,
+            );
+        }
+    }
+    for counter in COUNTERS {
+        for (scope, value) in take_object(&mut fields, counter) {
+            tables
+                .entry("counters")
+                .or_default()
+                .insert(vec![counter.to_string(), scope], json_encode(&value)?);
+        }
+    }
+    for (field, table) in [("history", "messages"), ("audit_log", "audit")] {
+        for (gid, entries) in take_object(&mut fields, field) {
+            let Value::Array(entries) = entries else {
+                continue;
+            };
+            // keyed by sequence number, so dropping the oldest entries leaves the others' rows
+            for entry in entries {
+                let seq = entry.get("seq").and_then(Value::as_u64).unwrap_or_default();
+                tables.entry(table).or_default().insert(
+                    vec![gid.clone(), format!("{seq:020}")],
+                    json_encode(&entry)?,
+                );
+            }
+        }
+    }
+    for (key, value) in take_object(&mut fields, "openmls_values") {
+        tables
+            .entry("openmls_values")
+            .or_default()
+            .insert(vec![key], value.as_str().unwrap_or_default().to_string());
+    }
+    for (name, value) in fields {
+        tables
+            .entry("meta")
+            .or_default()
+            .insert(vec![name], json_encode(&value)?);
+    }
+    Ok(tables)
+}
+
+/// Loads agent state from the SQLite database.
+pub(crate) fn load(path: &str) -> Result<MySgmState, MySgmError> {
+    let connection = open(path)?;
+    let mut fields = Map::new();
+    for ([name], value) in read_table(&connection, "meta", &["name"])?
+        .into_iter()
+        .filter_map(|(key, value)| Some((<[String; 1]>::try_from(key).ok()?, value)))
+    {
+        fields.insert(name, json_decode(&value)?);
+    }
+    let mut group_lists: HashMap<String, Vec<(u64, String)>> = HashMap::new();
+    for (key, position) in read_table(&connection, "group_lists", &["list", "gid"])? {
+        let [list, gid] = <[String; 2]>::try_from(key).unwrap_or_default();
+        let position = position.parse().unwrap_or_default();
+        group_lists.entry(list).or_default().push((position, gid));
+    }
+    for list in GROUP_LISTS {
+        let mut gids = group_lists.remove(*list).unwrap_or_default();
+        gids.sort();
+        fields.insert(
+            list.to_string(),
+            gids.into_iter()
+                .map(|(_, gid)| Value::String(gid))
+                .collect(),
+        );
+    }
+    let mut key_packages = Map::new();
+    let mut one_time_key_packages: HashMap<String, Vec<(String, Value)>> = HashMap::new();
+    for (key, key_package) in read_table(&connection, "key_packages", &["pid", "slot"])? {
+        let [pid, slot] = <[String; 2]>::try_from(key).unwrap_or_default();
+        let key_package = json_decode(&key_package)?;
+        match slot.as_str() {
+            LAST_RESORT_SLOT => {
+                key_packages.insert(pid, key_package);
+            }
+            _ => one_time_key_packages
+                .entry(pid)
+                .or_default()
+                .push((slot, key_package)),
+        }
+    }
+    fields.insert("key_packages".to_string(), Value::Object(key_packages));
+    fields.insert(
+        "one_time_key_packages".to_string(),
+        one_time_key_packages
+            .into_iter()
+            .map(|(pid, mut key_packages)| {
+                key_packages.sort_by(|a, b| a.0.cmp(&b.0));
+                (pid, key_packages.into_iter().map(|(_, kp)| kp).collect())
+            })
+            .collect::<Map<_, _>>()
+            .into(),
+    );
+    let mut counters: HashMap<String, Map<String, Value>> = HashMap::new();
+    for (key, value) in read_table(&connection, "counters", &["counter", "scope"])? {
+        let [counter, scope] = <[String; 2]>::try_from(key).unwrap_or_default();
+        counters
+            .entry(counter)
+            .or_default()
+            .insert(scope, json_decode(&value)?);
+    }
+    for counter in COUNTERS {
+        fields.insert(
+            counter.to_string(),
+            Value::Object(counters.remove(*counter).unwrap_or_default()),
+        );
+    }
+    for (field, table) in [("history", "messages"), ("audit_log", "audit")] {
+        let mut logs: HashMap<String, Vec<(String, Value)>> = HashMap::new();
+        for (key, entry) in read_table(&connection, table, &["gid", "seq"])? {
+            let [gid, seq] = <[String; 2]>::try_from(key).unwrap_or_default();
+            logs.entry(gid)
+                .or_default()
+                .push((seq, json_decode(&entry)?));
+        }
+        fields.insert(
+            field.to_string(),
+            logs.into_iter()
+                .map(|(gid, mut entries)| {
+                    entries.sort_by(|a, b| a.0.cmp(&b.0));
+                    (gid, entries.into_iter().map(|(_, entry)| entry).collect())
+                })
+                .collect::<Map<_, _>>()
+                .into(),
+        );
+    }
+    fields.insert(
+        "openmls_values".to_string(),
+        read_table(&connection, "openmls_values", &["key"])?
+            .into_iter()
+            .map(|(mut key, value)| (key.remove(0), Value::String(value)))
+            .collect::<Map<_, _>>()
+            .into(),
+    );
+    migration::decode(Value::Object(fields))
+}
+
+/// Returns the version of the state in the database, or `None` if it holds no state.
+pub(crate) fn stored_version(path: &str) -> Option<u64> {
+    let connection = open(path).ok()?;
+    let version: Option<String> = connection
+        .query_row("SELECT value FROM meta WHERE name = 'version'", [], |row| {
+            row.get(0)
+        })
+        .optional()
+        .ok()?;
+    version?.parse().ok()
+}
+
+/// Saves agent state to the SQLite database in one transaction, refusing with
+/// [`MySgmError::StateConflict`] if another process saved it since the state was loaded.
+pub(crate) fn save(path: &str, state: &MySgmState) -> Result<(), MySgmError> {
+    let mut connection = open(path)?;
+    let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let loaded = state.version();
+    let stored: Option<String> = transaction
+        .query_row("SELECT value FROM meta WHERE name = 'version'", [], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    if let Some(stored) = stored.and_then(|stored| stored.parse().ok())
+        && stored != loaded
+    {
+        return Err(MySgmError::StateConflict { loaded, stored });
+    }
+    state.set_version(loaded + 1);
+    let result = state_rows(state).and_then(|mut tables| {
+        for (table, keys) in TABLES {
+            write_table(
+                &transaction,
+                table,
+                keys,
+                &tables.remove(table).unwrap_or_default(),
+            )?;
+        }
+        Ok(transaction.commit()?)
+    });
+    if result.is_err() {
+        state.set_version(loaded);
+    }
+    result
+}
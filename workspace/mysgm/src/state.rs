@@ -1,25 +1,557 @@
-use super::keys::SignatureKeyPair;
+use super::{
+    error::ArtifactViolation,
+    journal::JournalBase,
+    keys::SignatureKeyPair,
+    lock::{lock_ignoring_poison, read_ignoring_poison, write_ignoring_poison},
+    migration::STATE_FORMAT_VERSION,
+    opendht::RateLimit,
+    padding::PaddingPolicy,
+    redact::{Secret, log_secrets},
+};
 
 use hex::{decode as hex_decode, encode as hex_encode};
-use openmls::{key_packages::KeyPackage, versions::ProtocolVersion};
+use openmls::{
+    ciphersuite::hash_ref::KeyPackageRef, key_packages::KeyPackage, versions::ProtocolVersion,
+};
 use openmls_traits::{
+    crypto::OpenMlsCrypto,
     storage::{CURRENT_VERSION, Entity, StorageProvider, traits},
-    types::Ciphersuite,
+    types::{Ciphersuite, CryptoError},
 };
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::{collections::HashMap, sync::RwLock};
+use serde_with::{hex::Hex, serde_as};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        Arc, Mutex, RwLock, RwLockWriteGuard,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// A received application message, as kept in the group's history.
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Position of the entry in the group's history, counting entries since dropped; assigned
+    /// when the entry is appended.
+    #[serde(default)]
+    pub seq: u64,
+    pub sender: String,
+    pub epoch: u64,
+    /// Unix time at which the message was received.
+    pub timestamp: u64,
+    #[serde_as(as = "Hex")]
+    pub message: Vec<u8>,
+}
+
+impl core::fmt::Debug for HistoryEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HistoryEntry")
+            .field("seq", &self.seq)
+            .field("sender", &self.sender)
+            .field("epoch", &self.epoch)
+            .field("timestamp", &self.timestamp)
+            .field("message", &Secret(&self.message))
+            .finish()
+    }
+}
+
+/// A security-relevant change of a group, as kept in the group's audit log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditEvent {
+    /// Position of the event in the group's audit log; assigned when the event is appended.
+    #[serde(default)]
+    pub seq: u64,
+    /// Unix time at which the change was applied.
+    pub timestamp: u64,
+    /// Epoch of the group after the change.
+    pub epoch: u64,
+    /// Kind of the change: `create`, `join`, `commit`, `add`, `remove`, `key_rotation`,
+    /// `credential_change`, `group_context`, `psk`, `leave`, `evicted`, `reinit` with the new
+    /// gid as subject, `replay` for a rejected replayed or out-of-window application message, or
+    /// `policy_violation` for a welcome or group info the artifact policy rejected.
+    pub kind: String,
+    /// pid of the member who made the change, if known.
+    #[serde(default)]
+    pub actor: Option<String>,
+    /// pid of the agent added, removed, or whose leaf changed.
+    #[serde(default)]
+    pub subject: Option<String>,
+}
+
+/// The signature key an agent is trusted with, recorded when first seen.
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TrustRecord {
+    #[serde_as(as = "Hex")]
+    pub signature_key: Vec<u8>,
+    /// Whether the key was compared against a fingerprint obtained out of band.
+    pub verified: bool,
+    /// The latest different key seen for the agent, if any.
+    #[serde_as(as = "Option<Hex>")]
+    #[serde(default)]
+    pub changed_key: Option<Vec<u8>>,
+}
+
+/// A key package as stored when downloaded or imported.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyPackageLogEntry {
+    pub pid: String,
+    /// Hex reference of the key package.
+    pub key_package_ref: String,
+    pub ciphersuite: Ciphersuite,
+    /// Unix times the key package is valid from and until.
+    pub not_before: u64,
+    pub not_after: u64,
+    pub last_resort: bool,
+    /// Index of the key package slot it was downloaded from; `None` for key packages from the
+    /// directory or imported out of band.
+    pub slot: Option<u64>,
+    /// Whether an add used it up; last-resort key packages are never consumed.
+    pub consumed: bool,
+}
+
+/// An agent whose signature key was compromised, removed from the groups shared with it.
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Quarantine {
+    /// The compromised keys, refused in key packages and commits from now on.
+    #[serde_as(as = "Vec<Hex>")]
+    pub signature_keys: Vec<Vec<u8>>,
+    /// gids of the groups to add the agent back to once a key package with a new signature key
+    /// is held for it.
+    #[serde(default)]
+    pub reinvite_gids: Vec<String>,
+}
+
+/// Which incoming key packages, welcomes, and group infos this agent accepts, by number; an
+/// empty list accepts any supported value.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ArtifactPolicy {
+    #[serde(default)]
+    pub ciphersuites: Vec<u16>,
+    #[serde(default)]
+    pub protocol_versions: Vec<u16>,
+    /// Credential types of the leaf of a key package, or of every member of a group joined.
+    #[serde(default)]
+    pub credential_types: Vec<u16>,
+}
+
+impl ArtifactPolicy {
+    /// Checks an artifact in the ciphersuite and protocol version, carrying credentials of the
+    /// types.
+    pub fn check(
+        &self,
+        ciphersuite: Ciphersuite,
+        version: ProtocolVersion,
+        credential_types: impl IntoIterator<Item = u16>,
+    ) -> Result<(), ArtifactViolation> {
+        let accepts =
+            |accepted: &[u16], value: u16| accepted.is_empty() || accepted.contains(&value);
+        if !accepts(&self.ciphersuites, u16::from(ciphersuite)) {
+            return Err(ArtifactViolation::Ciphersuite(ciphersuite));
+        }
+        let version = match version {
+            ProtocolVersion::Mls10 => 1,
+            ProtocolVersion::Other(version) => version,
+        };
+        if !accepts(&self.protocol_versions, version) {
+            return Err(ArtifactViolation::ProtocolVersion(version));
+        }
+        match credential_types
+            .into_iter()
+            .find(|credential_type| !accepts(&self.credential_types, *credential_type))
+        {
+            Some(credential_type) => Err(ArtifactViolation::CredentialType(credential_type)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What a record of the delivery service holds.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordKind {
+    KeyPackage,
+    Welcome,
+    /// A commit to the group with the gid.
+    Commit(String),
+}
+
+/// A record of the delivery service that did not verify or decode, skipped by syncs from then
+/// on.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QuarantinedRecord {
+    /// Key the record is stored under.
+    pub key: String,
+    /// Hex SHA-256 digest of the record, so that another value under the key is not skipped.
+    pub digest: String,
+    pub kind: RecordKind,
+    pub error: String,
+    /// Unix time at which the record was quarantined.
+    pub quarantined_at: u64,
+}
+
+/// An add whose commit is staged but not yet merged, kept until both the commit and the welcome
+/// are published so that the add can be resumed after a failure.
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingAdd {
+    /// pids of the agents added.
+    pub pids: Vec<String>,
+    #[serde_as(as = "Hex")]
+    pub commit: Vec<u8>,
+    #[serde_as(as = "Hex")]
+    pub welcome: Vec<u8>,
+    /// Whether the welcome goes to the delivery service rather than out of band.
+    pub post_welcome: bool,
+    /// Whether the commit won its slot.
+    #[serde(default)]
+    pub commit_published: bool,
+}
+
+/// Exporter material retained from an epoch of a group, so that secrets of the epoch can still
+/// be derived after the group moved on.
+#[serde_as]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EpochExporter {
+    pub epoch: u64,
+    /// SHA-256 digest of the epoch's group context.
+    #[serde_as(as = "Hex")]
+    pub group_context_hash: Vec<u8>,
+    #[serde_as(as = "Hex")]
+    pub exporter_root: Vec<u8>,
+}
+
+impl core::fmt::Debug for EpochExporter {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EpochExporter")
+            .field("epoch", &self.epoch)
+            .field(
+                "group_context_hash",
+                &format!("0x{}", hex_encode(&self.group_context_hash)),
+            )
+            .field("exporter_root", &Secret(&self.exporter_root))
+            .finish()
+    }
+}
+
+/// How this agent joined a group through a welcome.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JoinRecord {
+    /// pid of the member whose commit added this agent, from their credential.
+    pub inviter: String,
+    /// Epoch of the group at which this agent joined.
+    pub epoch: u64,
+    /// Unix time at which the welcome was processed.
+    pub timestamp: u64,
+    /// Slot the welcome was fetched from, or `None` for an imported invitation.
+    pub welcome_slot: Option<String>,
+}
+
+/// A record this agent put to the delivery service, kept so it can be put again before the
+/// delivery service lets it expire.
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PublishedRecord {
+    /// Hex SHA-256 digest of the value.
+    pub sha256: String,
+    /// Unix time of the first put.
+    pub first_published_at: u64,
+    /// Unix time of the latest put.
+    pub published_at: u64,
+    #[serde_as(as = "Hex")]
+    pub value: Vec<u8>,
+}
+
+/// Limits on the groups this agent joins, kept apart from those of the groups it creates;
+/// unset limits keep the OpenMLS defaults.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct JoinLimits {
+    /// Past epochs whose message secrets are kept, to decrypt messages arriving after a commit.
+    #[serde(default)]
+    pub max_past_epochs: Option<usize>,
+    /// Generations a message may fall behind the sender's latest one and still be decrypted.
+    #[serde(default)]
+    pub out_of_order_tolerance: Option<u32>,
+    /// Generations a message may skip ahead of the sender's latest one.
+    #[serde(default)]
+    pub maximum_forward_distance: Option<u32>,
+}
+
+/// Which welcomes this agent joins as soon as they arrive; the others wait as
+/// [`PendingInvite`]s until accepted or declined.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct InvitePolicy {
+    /// Whether welcomes wait for confirmation.
+    #[serde(default)]
+    pub confirm: bool,
+    /// pids whose welcomes are joined right away; once there are any, welcomes of everyone
+    /// else wait for confirmation.
+    #[serde(default)]
+    pub auto_accept_from: Vec<String>,
+}
+
+impl InvitePolicy {
+    /// Whether a welcome sent by the inviter is joined without confirmation.
+    pub fn admits(&self, inviter: &str) -> bool {
+        (!self.confirm && self.auto_accept_from.is_empty())
+            || self.auto_accept_from.iter().any(|pid| pid == inviter)
+    }
+}
+
+/// A welcome that waits for this agent to accept or decline it.
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingInvite {
+    pub id: u64,
+    pub gid: String,
+    /// pid of the member who sent the welcome, from their credential.
+    pub inviter: String,
+    /// Epoch of the group the welcome joins at.
+    pub epoch: u64,
+    /// Unix time at which the welcome was received.
+    pub received_at: u64,
+    /// Slot the welcome was fetched from.
+    pub welcome_slot: Option<String>,
+    /// The TLS-serialized welcome.
+    #[serde_as(as = "Hex")]
+    pub welcome: Vec<u8>,
+}
+
+/// One group's share of the state, moved between agents of the same identity.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GroupExport {
+    pub gid: String,
+    pub pid: String,
+    /// Hex signature public key of the own leaf in the group.
+    pub signature_key: String,
+    /// OpenMLS storage entries of the group, hex keys to hex values.
+    openmls_values: HashMap<String, String>,
+    message_counter: Option<(u64, u64)>,
+    proposal_counter: Option<(u64, u64)>,
+    sent_message_counter: Option<(u64, u64)>,
+    received_sequences: HashMap<String, (u64, u64)>,
+    history: VecDeque<HistoryEntry>,
+    audit_log: Vec<AuditEvent>,
+    epoch_exporters: VecDeque<EpochExporter>,
+    seal_counter: Option<u64>,
+    commit_head: Option<String>,
+    external_tree: bool,
+    #[serde(default)]
+    padding_policy: Option<PaddingPolicy>,
+    #[serde(default)]
+    join_record: Option<JoinRecord>,
+}
+
+/// Lists the OpenMLS storage entries only if secrets are logged, since they hold the group's
+/// key material.
+impl core::fmt::Debug for GroupExport {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut debug = f.debug_struct("GroupExport");
+        debug
+            .field("gid", &self.gid)
+            .field("pid", &self.pid)
+            .field("signature_key", &self.signature_key);
+        match log_secrets() {
+            true => debug.field("openmls_values", &self.openmls_values),
+            false => debug.field(
+                "openmls_values",
+                &format_args!("<{} values redacted>", self.openmls_values.len()),
+            ),
+        };
+        debug
+            .field("message_counter", &self.message_counter)
+            .field("proposal_counter", &self.proposal_counter)
+            .field("sent_message_counter", &self.sent_message_counter)
+            .field("received_sequences", &self.received_sequences)
+            .field("history", &self.history)
+            .field("audit_log", &self.audit_log)
+            .field("epoch_exporters", &self.epoch_exporters)
+            .field("seal_counter", &self.seal_counter)
+            .field("commit_head", &self.commit_head)
+            .field("external_tree", &self.external_tree)
+            .field("padding_policy", &self.padding_policy)
+            .field("join_record", &self.join_record)
+            .finish()
+    }
+}
+
+/// A put whose local effects were already applied when it failed with a transient error, kept
+/// in the outbox until a flush gets it to the delivery service.
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Publication {
+    /// A value for the first free slot `{prefix}{index}`, at or after `index`.
+    Slot {
+        prefix: String,
+        index: u64,
+        #[serde_as(as = "Hex")]
+        value: Vec<u8>,
+        /// Public key of the key pair to sign with, if not the current one.
+        #[serde_as(as = "Option<Hex>")]
+        #[serde(default)]
+        signer: Option<Vec<u8>>,
+    },
+    /// A value for a fixed key, left as it is if the key is taken.
+    Record {
+        key: String,
+        #[serde_as(as = "Hex")]
+        value: Vec<u8>,
+        /// Public key of the key pair to sign with, if not the current one.
+        #[serde_as(as = "Option<Hex>")]
+        #[serde(default)]
+        signer: Option<Vec<u8>>,
+    },
+    /// An own key package for the directory.
+    KeyPackage {
+        #[serde_as(as = "Hex")]
+        value: Vec<u8>,
+    },
+    /// A welcome for the pid, to be posted to the directory.
+    Welcome {
+        pid: String,
+        #[serde_as(as = "Hex")]
+        value: Vec<u8>,
+    },
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MySgmState {
+    /// Incremented on every save, to detect saves by other processes since loading.
+    #[serde(default)]
+    version: AtomicU64,
+    /// Format version of the state, upgraded on load by [`crate::migration`].
+    format_version: u32,
     pid: String,
     signature_key_pair: SignatureKeyPair,
+    /// Key pairs replaced by [`Self::rotate_signature_key_pair`], kept while the own leaves of
+    /// some groups still carry them.
+    #[serde(default)]
+    previous_signature_key_pairs: Vec<SignatureKeyPair>,
+    /// Hex public keys of replaced key pairs no group uses anymore, oldest first.
+    #[serde(default)]
+    retired_signature_keys: Vec<String>,
     mls_version: ProtocolVersion,
     my_ciphersuite: Ciphersuite,
-    welcome_counter: u64,
-    key_package_counter: u64,
+    #[serde(default)]
+    namespace: String,
+    /// Read cursors of the welcome slots of own key packages, by namespace and key package.
+    #[serde(default)]
+    welcome_counters: HashMap<String, HashMap<String, u64>>,
+    /// Write cursors of the welcome slots of other agents' key packages, by namespace and key
+    /// package; kept apart from the read cursors so that posting never moves the scan position.
+    #[serde(default)]
+    welcome_write_counters: HashMap<String, HashMap<String, u64>>,
+    key_package_counter: HashMap<String, u64>,
+    #[serde(default)]
+    directory_welcome_counter: u64,
     key_packages: HashMap<String, KeyPackage>,
+    #[serde(default)]
+    one_time_key_packages: HashMap<String, Vec<KeyPackage>>,
+    #[serde(default)]
+    published_key_packages: Vec<KeyPackageRef>,
+    /// Key packages downloaded or imported so far, oldest first, until they expire.
+    #[serde(default)]
+    key_package_log: Vec<KeyPackageLogEntry>,
     gids: Vec<String>,
+    #[serde(default = "default_dht_host")]
+    dht_host: String,
+    #[serde(default = "default_dht_port")]
+    dht_port: u16,
+    /// Default limits on requests to the OpenDHT proxy.
+    #[serde(default)]
+    rate_limit: RateLimit,
+    #[serde(default)]
+    join_limits: JoinLimits,
+    #[serde(default)]
+    invite_policy: InvitePolicy,
+    #[serde(default)]
+    artifact_policy: ArtifactPolicy,
+    /// Welcomes waiting to be accepted, oldest first.
+    #[serde(default)]
+    pending_invites: Vec<PendingInvite>,
+    #[serde(default)]
+    next_invite_id: u64,
+    #[serde(default)]
+    left_gids: Vec<String>,
+    /// gids of reinitialized groups, mapped to the gid of the group that replaced them.
+    #[serde(default)]
+    successors: HashMap<String, String>,
+    /// gids of the groups this agent proposed to leave, mapped to the epoch of the proposal;
+    /// the groups are kept until a commit removes this agent.
+    #[serde(default)]
+    leaving_gids: HashMap<String, u64>,
+    #[serde(default)]
+    external_tree_gids: Vec<String>,
+    /// gids of the groups that syncs skip.
+    #[serde(default)]
+    archived_gids: Vec<String>,
+    /// Padding of the application messages sent to each group, by gid.
+    #[serde(default)]
+    padding_policies: HashMap<String, PaddingPolicy>,
+    /// How this agent joined each group it joined through a welcome, by gid.
+    #[serde(default)]
+    join_records: HashMap<String, JoinRecord>,
+    #[serde(default)]
+    message_counters: HashMap<String, (u64, u64)>,
+    #[serde(default)]
+    proposal_counters: HashMap<String, (u64, u64)>,
+    #[serde(default)]
+    sent_message_counters: HashMap<String, (u64, u64)>,
+    /// Epoch and sequence number of the latest application message received from each sender,
+    /// by gid and pid.
+    #[serde(default)]
+    received_sequences: HashMap<String, HashMap<String, (u64, u64)>>,
+    #[serde(default)]
+    advertised_at: HashMap<String, u64>,
+    #[serde(default)]
+    history: HashMap<String, VecDeque<HistoryEntry>>,
+    #[serde(default = "default_history_limit")]
+    history_limit: usize,
+    /// Append-only log of changes to each group, oldest first; kept after leaving the group.
+    #[serde(default)]
+    audit_log: HashMap<String, Vec<AuditEvent>>,
+    /// Exporters of the latest epochs of each group, oldest first.
+    #[serde(default)]
+    epoch_exporters: HashMap<String, VecDeque<EpochExporter>>,
+    #[serde(default = "default_exporter_window")]
+    exporter_window: usize,
+    /// Next nonce counter of messages sealed with [`crate::MySgmAgent::seal`], by gid.
+    #[serde(default)]
+    seal_counters: HashMap<String, u64>,
+    /// Human-readable names of agents, mapped to their pids.
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+    #[serde(default)]
+    trust: HashMap<String, TrustRecord>,
+    #[serde(default)]
+    quarantined: HashMap<String, Quarantine>,
+    /// Malformed records skipped by syncs, oldest first.
+    #[serde(default)]
+    quarantined_records: Vec<QuarantinedRecord>,
+    /// Adds staged but not yet merged, by gid.
+    #[serde(default)]
+    pending_adds: HashMap<String, PendingAdd>,
+    /// Hex SHA-256 digest of the latest commit of each group's commit chain, by gid.
+    #[serde(default)]
+    commit_heads: HashMap<String, String>,
+    /// Hex SHA-256 digests of the welcomes joined and the commits merged.
+    #[serde(default)]
+    processed_welcomes: HashSet<String>,
+    #[serde(default)]
+    processed_commits: HashSet<String>,
+    /// Records put to the delivery service, by key; recorded through shared state like the
+    /// version, since puts happen while the state is borrowed.
+    #[serde(default)]
+    published_records: RwLock<HashMap<String, PublishedRecord>>,
+    /// Publications that failed with a transient error, oldest first.
+    #[serde(default)]
+    outbox: VecDeque<Publication>,
     openmls_values: OpenMlsKeyValueStore,
+    /// What the state was when last persisted, for journaling the changes of the next save.
+    #[serde(skip)]
+    journal_base: Mutex<Option<JournalBase>>,
 }
 
 impl MySgmState {
@@ -30,17 +562,109 @@ impl MySgmState {
         mls_version: ProtocolVersion,
     ) -> Self {
         Self {
+            version: AtomicU64::new(0),
+            format_version: STATE_FORMAT_VERSION,
             pid,
             signature_key_pair,
+            previous_signature_key_pairs: Vec::new(),
+            retired_signature_keys: Vec::new(),
             my_ciphersuite,
             mls_version,
-            welcome_counter: 0,
-            key_package_counter: 0,
+            namespace: String::new(),
+            welcome_counters: HashMap::new(),
+            welcome_write_counters: HashMap::new(),
+            key_package_counter: HashMap::new(),
+            directory_welcome_counter: 0,
             key_packages: HashMap::new(),
+            one_time_key_packages: HashMap::new(),
+            published_key_packages: Vec::new(),
+            key_package_log: Vec::new(),
             gids: Vec::new(),
+            dht_host: default_dht_host(),
+            dht_port: default_dht_port(),
+            rate_limit: RateLimit::default(),
+            join_limits: JoinLimits::default(),
+            invite_policy: InvitePolicy::default(),
+            artifact_policy: ArtifactPolicy::default(),
+            pending_invites: Vec::new(),
+            next_invite_id: 0,
+            left_gids: Vec::new(),
+            successors: HashMap::new(),
+            leaving_gids: HashMap::new(),
+            external_tree_gids: Vec::new(),
+            archived_gids: Vec::new(),
+            padding_policies: HashMap::new(),
+            join_records: HashMap::new(),
+            message_counters: HashMap::new(),
+            proposal_counters: HashMap::new(),
+            sent_message_counters: HashMap::new(),
+            received_sequences: HashMap::new(),
+            advertised_at: HashMap::new(),
+            history: HashMap::new(),
+            history_limit: default_history_limit(),
+            audit_log: HashMap::new(),
+            epoch_exporters: HashMap::new(),
+            exporter_window: default_exporter_window(),
+            seal_counters: HashMap::new(),
+            aliases: HashMap::new(),
+            trust: HashMap::new(),
+            quarantined: HashMap::new(),
+            quarantined_records: Vec::new(),
+            pending_adds: HashMap::new(),
+            processed_welcomes: HashSet::new(),
+            commit_heads: HashMap::new(),
+            processed_commits: HashSet::new(),
+            published_records: Default::default(),
+            journal_base: Default::default(),
+            outbox: VecDeque::new(),
             openmls_values: Default::default(),
         }
     }
+    /// Creates state for a fresh identity, deriving the pid from the label and the new
+    /// signature public key.
+    pub fn generate(
+        label: &str,
+        ciphersuite: Ciphersuite,
+        crypto: &impl OpenMlsCrypto,
+    ) -> Result<Self, CryptoError> {
+        let signature_key_pair = SignatureKeyPair::from_crypto(crypto, ciphersuite.into())?;
+        Ok(Self::with_key_pair(label, ciphersuite, signature_key_pair))
+    }
+    /// Creates state for a fresh identity with the signature key pair, e.g. one generated on
+    /// a token, deriving the pid from the label and the signature public key.
+    pub fn with_key_pair(
+        label: &str,
+        ciphersuite: Ciphersuite,
+        signature_key_pair: SignatureKeyPair,
+    ) -> Self {
+        let pid = format!(
+            "{}_{}",
+            label,
+            hex_encode(signature_key_pair.public_key_raw())
+                .chars()
+                .take(3)
+                .collect::<String>()
+        );
+        Self::new(pid, signature_key_pair, ciphersuite, ProtocolVersion::Mls10)
+    }
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Relaxed)
+    }
+    /// Sets the version; saving takes shared state, so this does not need exclusive access.
+    pub fn set_version(&self, version: u64) {
+        self.version.store(version, Ordering::Relaxed);
+    }
+    /// Takes what the state was when last persisted, if it is known; saving takes shared state
+    /// like for the version.
+    pub(crate) fn take_journal_base(&self) -> Option<JournalBase> {
+        lock_ignoring_poison(&self.journal_base).take()
+    }
+    pub(crate) fn set_journal_base(&self, base: Option<JournalBase>) {
+        *lock_ignoring_poison(&self.journal_base) = base;
+    }
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
     pub fn my_ciphersuite(&self) -> Ciphersuite {
         self.my_ciphersuite
     }
@@ -50,9 +674,42 @@ impl MySgmState {
     pub fn my_pid(&self) -> &str {
         &self.pid
     }
+    /// Renames this agent, e.g. when linking it as a device of another identity.
+    pub fn set_pid(&mut self, pid: &str) {
+        self.pid = pid.to_string();
+    }
     pub fn signature_key_pair(&self) -> &SignatureKeyPair {
         &self.signature_key_pair
     }
+    pub fn previous_signature_key_pairs(&self) -> &[SignatureKeyPair] {
+        &self.previous_signature_key_pairs
+    }
+    pub fn retired_signature_keys(&self) -> &[String] {
+        &self.retired_signature_keys
+    }
+    /// Makes the key pair the current one, keeping the replaced one as a previous key pair.
+    pub fn rotate_signature_key_pair(&mut self, signature_key_pair: SignatureKeyPair) {
+        let previous = std::mem::replace(&mut self.signature_key_pair, signature_key_pair);
+        self.previous_signature_key_pairs.push(previous);
+    }
+    /// Retires the previous key pairs `f` rejects, dropping their private keys and keeping only
+    /// the public keys; returns how many were retired.
+    pub fn retain_previous_signature_key_pairs(
+        &mut self,
+        mut f: impl FnMut(&SignatureKeyPair) -> bool,
+    ) -> usize {
+        let (kept, retired): (Vec<_>, Vec<_>) = self
+            .previous_signature_key_pairs
+            .drain(..)
+            .partition(|key_pair| f(key_pair));
+        self.previous_signature_key_pairs = kept;
+        self.retired_signature_keys.extend(
+            retired
+                .iter()
+                .map(|key_pair| hex_encode(key_pair.public_key_raw())),
+        );
+        retired.len()
+    }
     pub fn openmls_values(&self) -> &OpenMlsKeyValueStore {
         &self.openmls_values
     }
@@ -62,35 +719,799 @@ impl MySgmState {
     pub fn set_key_package(&mut self, pid: &str, key_package: KeyPackage) {
         self.key_packages.insert(pid.to_string(), key_package);
     }
+    /// One-time key packages downloaded for the pid and not yet used in an add.
+    pub fn one_time_key_packages(&self, pid: &str) -> &[KeyPackage] {
+        self.one_time_key_packages
+            .get(pid)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+    pub fn add_one_time_key_package(&mut self, pid: &str, key_package: KeyPackage) {
+        self.one_time_key_packages
+            .entry(pid.to_string())
+            .or_default()
+            .push(key_package);
+    }
+    /// Drops the last-resort key packages that do not satisfy the predicate, returning how many.
+    pub fn retain_key_packages(&mut self, mut f: impl FnMut(&KeyPackage) -> bool) -> usize {
+        let before = self.key_packages.len();
+        self.key_packages.retain(|_, key_package| f(key_package));
+        before - self.key_packages.len()
+    }
+    /// Drops the one-time key packages of all pids that do not satisfy the predicate, returning
+    /// how many.
+    pub fn retain_all_one_time_key_packages(
+        &mut self,
+        mut f: impl FnMut(&KeyPackage) -> bool,
+    ) -> usize {
+        let mut dropped = 0;
+        self.one_time_key_packages.retain(|_, key_packages| {
+            let before = key_packages.len();
+            key_packages.retain(&mut f);
+            dropped += before - key_packages.len();
+            !key_packages.is_empty()
+        });
+        dropped
+    }
+    /// Number of key packages of other agents and refs of own published key packages held.
+    pub fn key_package_count(&self) -> usize {
+        self.key_packages.len()
+            + self
+                .one_time_key_packages
+                .values()
+                .map(Vec::len)
+                .sum::<usize>()
+            + self.published_key_packages.len()
+    }
+    /// Drops the pid's one-time key packages that do not satisfy the predicate.
+    pub fn retain_one_time_key_packages(&mut self, pid: &str, f: impl FnMut(&KeyPackage) -> bool) {
+        if let Some(key_packages) = self.one_time_key_packages.get_mut(pid) {
+            key_packages.retain(f);
+            if key_packages.is_empty() {
+                self.one_time_key_packages.remove(pid);
+            }
+        }
+    }
+    pub fn key_package_log(&self) -> &[KeyPackageLogEntry] {
+        &self.key_package_log
+    }
+    /// Appends the entry unless its key package was logged before.
+    pub fn log_key_package(&mut self, entry: KeyPackageLogEntry) {
+        if !self
+            .key_package_log
+            .iter()
+            .any(|logged| logged.key_package_ref == entry.key_package_ref)
+        {
+            self.key_package_log.push(entry);
+        }
+    }
+    pub fn mark_key_package_consumed(&mut self, key_package_ref: &KeyPackageRef) {
+        let key_package_ref = hex_encode(key_package_ref.as_slice());
+        for entry in &mut self.key_package_log {
+            if entry.key_package_ref == key_package_ref {
+                entry.consumed = true;
+            }
+        }
+    }
+    /// Drops the log entries that do not satisfy the predicate, returning how many.
+    pub fn retain_key_package_log(&mut self, f: impl FnMut(&KeyPackageLogEntry) -> bool) -> usize {
+        let before = self.key_package_log.len();
+        self.key_package_log.retain(f);
+        before - self.key_package_log.len()
+    }
+    /// Refs of key packages this agent published and may still hold keys for; welcomes are
+    /// polled for each.
+    pub fn published_key_packages(&self) -> &[KeyPackageRef] {
+        &self.published_key_packages
+    }
+    pub fn add_published_key_package(&mut self, key_package_ref: KeyPackageRef) {
+        self.published_key_packages.push(key_package_ref);
+    }
+    pub fn set_published_key_packages(&mut self, key_package_refs: Vec<KeyPackageRef>) {
+        self.published_key_packages = key_package_refs;
+    }
     pub fn pids(&self) -> Vec<String> {
-        self.key_packages.keys().cloned().collect()
+        let mut pids: Vec<String> = self.key_packages.keys().cloned().collect();
+        for pid in self.one_time_key_packages.keys() {
+            if !self.key_packages.contains_key(pid) {
+                pids.push(pid.clone());
+            }
+        }
+        pids
     }
     pub fn gids(&self) -> Vec<String> {
         self.gids.clone()
     }
+    /// gids of the joined groups that syncs fetch commits and messages of, all but the
+    /// archived ones.
+    pub fn synced_gids(&self) -> Vec<String> {
+        self.gids
+            .iter()
+            .filter(|gid| !self.archived(gid))
+            .cloned()
+            .collect()
+    }
     pub fn add_gid(&mut self, gid: String) {
         self.gids.push(gid);
     }
     pub fn remove_gid(&mut self, gid: &str) {
         self.gids.retain(|g| g != gid);
+        self.epoch_exporters.remove(gid);
+        self.seal_counters.remove(gid);
+        self.received_sequences.remove(gid);
+        self.commit_heads.remove(gid);
+        self.pending_adds.remove(gid);
+        self.padding_policies.remove(gid);
+        self.join_records.remove(gid);
+        self.archived_gids.retain(|g| g != gid);
+        self.leaving_gids.remove(gid);
+        self.quarantined_records
+            .retain(|record| record.kind != RecordKind::Commit(gid.to_string()));
+    }
+    /// Collects the group's share of the state for [`Self::import_group`]: its counters, history,
+    /// and audit log, and the OpenMLS storage entries whose keys contain one of the serialized
+    /// ids in `storage_ids`, such as the group id.
+    pub fn export_group(
+        &self,
+        gid: &str,
+        signature_key: &[u8],
+        storage_ids: &[Vec<u8>],
+    ) -> GroupExport {
+        GroupExport {
+            gid: gid.to_string(),
+            pid: self.pid.clone(),
+            signature_key: hex_encode(signature_key),
+            openmls_values: self.openmls_values.entries_containing(storage_ids),
+            message_counter: self.message_counters.get(gid).copied(),
+            proposal_counter: self.proposal_counters.get(gid).copied(),
+            sent_message_counter: self.sent_message_counters.get(gid).copied(),
+            received_sequences: self
+                .received_sequences
+                .get(gid)
+                .cloned()
+                .unwrap_or_default(),
+            history: self.history.get(gid).cloned().unwrap_or_default(),
+            audit_log: self.audit_log.get(gid).cloned().unwrap_or_default(),
+            epoch_exporters: self.epoch_exporters.get(gid).cloned().unwrap_or_default(),
+            seal_counter: self.seal_counters.get(gid).copied(),
+            commit_head: self.commit_heads.get(gid).cloned(),
+            external_tree: self.external_tree(gid),
+            padding_policy: self.padding_policies.get(gid).cloned(),
+            join_record: self.join_records.get(gid).cloned(),
+        }
+    }
+    /// Merges a group exported by another agent of the same identity and tracks it as joined,
+    /// replacing what was held for the gid.
+    pub fn import_group(&mut self, export: GroupExport) {
+        let gid = export.gid;
+        self.openmls_values.extend(export.openmls_values);
+        for (counters, counter) in [
+            (&mut self.message_counters, export.message_counter),
+            (&mut self.proposal_counters, export.proposal_counter),
+            (&mut self.sent_message_counters, export.sent_message_counter),
+        ] {
+            if let Some(counter) = counter {
+                counters.insert(gid.clone(), counter);
+            }
+        }
+        self.received_sequences
+            .insert(gid.clone(), export.received_sequences);
+        self.history.insert(gid.clone(), export.history);
+        self.audit_log.insert(gid.clone(), export.audit_log);
+        self.epoch_exporters
+            .insert(gid.clone(), export.epoch_exporters);
+        if let Some(seal_counter) = export.seal_counter {
+            self.seal_counters.insert(gid.clone(), seal_counter);
+        }
+        if let Some(commit_head) = export.commit_head {
+            self.commit_heads.insert(gid.clone(), commit_head);
+        }
+        if export.external_tree {
+            self.set_external_tree(&gid);
+        }
+        if let Some(policy) = export.padding_policy {
+            self.padding_policies.insert(gid.clone(), policy);
+        }
+        if let Some(record) = export.join_record {
+            self.join_records.insert(gid.clone(), record);
+        }
+        self.left_gids.retain(|g| *g != gid);
+        if !self.gids.contains(&gid) {
+            self.gids.push(gid);
+        }
+    }
+    pub fn left_gids(&self) -> Vec<String> {
+        self.left_gids.clone()
+    }
+    /// Stops tracking the group as joined and records that this agent left it.
+    pub fn mark_gid_left(&mut self, gid: &str) {
+        self.remove_gid(gid);
+        self.left_gids.push(gid.to_string());
+    }
+    /// Epoch at which this agent last proposed to leave the group, if it is leaving it.
+    pub fn leaving(&self, gid: &str) -> Option<u64> {
+        self.leaving_gids.get(gid).copied()
+    }
+    pub fn set_leaving(&mut self, gid: &str, epoch: u64) {
+        self.leaving_gids.insert(gid.to_string(), epoch);
+    }
+    /// gid of the group that replaced the reinitialized group, if any.
+    pub fn successor(&self, gid: &str) -> Option<&str> {
+        self.successors.get(gid).map(String::as_str)
+    }
+    pub fn set_successor(&mut self, gid: &str, successor: &str) {
+        self.successors
+            .insert(gid.to_string(), successor.to_string());
+    }
+    /// Whether the group's welcomes leave out the ratchet tree, which is posted separately.
+    pub fn external_tree(&self, gid: &str) -> bool {
+        self.external_tree_gids.iter().any(|g| g == gid)
+    }
+    pub fn set_external_tree(&mut self, gid: &str) {
+        if !self.external_tree(gid) {
+            self.external_tree_gids.push(gid.to_string());
+        }
+    }
+    /// Whether syncs skip the group, whose MLS state is kept as it is.
+    pub fn archived(&self, gid: &str) -> bool {
+        self.archived_gids.iter().any(|g| g == gid)
+    }
+    pub fn set_archived(&mut self, gid: &str, archived: bool) {
+        self.archived_gids.retain(|g| g != gid);
+        if archived {
+            self.archived_gids.push(gid.to_string());
+        }
+    }
+    /// Padding of the application messages sent to the group, if they are padded.
+    pub fn padding_policy(&self, gid: &str) -> Option<&PaddingPolicy> {
+        self.padding_policies.get(gid)
+    }
+    pub fn set_padding_policy(&mut self, gid: &str, policy: Option<PaddingPolicy>) {
+        match policy {
+            Some(policy) => self.padding_policies.insert(gid.to_string(), policy),
+            None => self.padding_policies.remove(gid),
+        };
     }
-    pub fn welcome_counter(&self) -> u64 {
-        self.welcome_counter
+    /// How this agent joined the group, if it joined through a welcome.
+    pub fn join_record(&self, gid: &str) -> Option<&JoinRecord> {
+        self.join_records.get(gid)
     }
-    pub fn increment_welcome_counter(&mut self) {
-        self.welcome_counter += 1;
+    pub fn set_join_record(&mut self, gid: &str, record: JoinRecord) {
+        self.join_records.insert(gid.to_string(), record);
+    }
+    /// Received application messages of the group, oldest first.
+    pub fn history(&self, gid: &str) -> impl Iterator<Item = &HistoryEntry> {
+        self.history.get(gid).into_iter().flatten()
+    }
+    /// Appends to the group's history, dropping the oldest entries beyond the history limit.
+    pub fn append_history(&mut self, gid: &str, mut entry: HistoryEntry) {
+        let entries = self.history.entry(gid.to_string()).or_default();
+        entry.seq = entries.back().map_or(0, |last| last.seq + 1);
+        entries.push_back(entry);
+        while entries.len() > self.history_limit {
+            entries.pop_front();
+        }
+    }
+    /// Audit events of the group, oldest first.
+    pub fn audit_log(&self, gid: &str) -> &[AuditEvent] {
+        self.audit_log.get(gid).map_or(&[], Vec::as_slice)
+    }
+    pub fn append_audit(&mut self, gid: &str, mut event: AuditEvent) {
+        let events = self.audit_log.entry(gid.to_string()).or_default();
+        event.seq = events.last().map_or(0, |last| last.seq + 1);
+        events.push(event);
+    }
+    /// Retained exporter of the group's epoch, if the epoch is within the exporter window.
+    pub fn epoch_exporter(&self, gid: &str, epoch: u64) -> Option<&EpochExporter> {
+        self.epoch_exporters
+            .get(gid)?
+            .iter()
+            .find(|exporter| exporter.epoch == epoch)
+    }
+    /// Retains the exporter of the group's newest epoch, dropping the oldest ones beyond the
+    /// exporter window.
+    pub fn retain_epoch_exporter(&mut self, gid: &str, exporter: EpochExporter) {
+        let exporters = self.epoch_exporters.entry(gid.to_string()).or_default();
+        exporters.retain(|retained| retained.epoch < exporter.epoch);
+        exporters.push_back(exporter);
+        while exporters.len() > self.exporter_window + 1 {
+            exporters.pop_front();
+        }
+    }
+    /// Number of past epochs per group whose exporters are retained besides the current one.
+    pub fn exporter_window(&self) -> usize {
+        self.exporter_window
+    }
+    pub fn set_exporter_window(&mut self, window: usize) {
+        self.exporter_window = window;
+        for exporters in self.epoch_exporters.values_mut() {
+            while exporters.len() > window + 1 {
+                exporters.pop_front();
+            }
+        }
+    }
+    /// Returns the next nonce counter for sealing a message to the group and advances it.
+    pub fn next_seal_counter(&mut self, gid: &str) -> u64 {
+        let counter = self.seal_counters.entry(gid.to_string()).or_default();
+        *counter += 1;
+        *counter - 1
+    }
+    /// Maximum number of messages kept per group; 0 disables the history.
+    pub fn history_limit(&self) -> usize {
+        self.history_limit
+    }
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history_limit = limit;
+        for entries in self.history.values_mut() {
+            while entries.len() > limit {
+                entries.pop_front();
+            }
+        }
+    }
+    /// Alias of the pid, if it has one.
+    pub fn alias(&self, pid: &str) -> Option<&str> {
+        self.aliases
+            .iter()
+            .find(|(_, aliased)| *aliased == pid)
+            .map(|(name, _)| name.as_str())
+    }
+    /// Names the pid, replacing its previous alias and any other pid's use of the name.
+    pub fn set_alias(&mut self, pid: &str, name: &str) {
+        self.remove_alias(pid);
+        self.aliases.insert(name.to_string(), pid.to_string());
+    }
+    pub fn remove_alias(&mut self, pid: &str) {
+        self.aliases.retain(|_, aliased| aliased != pid);
+    }
+    /// The pid an alias stands for; anything that is not an alias is returned as is.
+    pub fn resolve_pid(&self, pid_or_alias: &str) -> String {
+        self.aliases
+            .get(pid_or_alias)
+            .cloned()
+            .unwrap_or_else(|| pid_or_alias.to_string())
+    }
+    pub fn trust(&self, pid: &str) -> Option<&TrustRecord> {
+        self.trust.get(pid)
+    }
+    /// Records the signature key of the pid on first sight, returning whether it matches the
+    /// trusted key; a different key is remembered as the changed key.
+    pub fn observe_signature_key(&mut self, pid: &str, signature_key: &[u8]) -> bool {
+        let record = self
+            .trust
+            .entry(pid.to_string())
+            .or_insert_with(|| TrustRecord {
+                signature_key: signature_key.to_vec(),
+                verified: false,
+                changed_key: None,
+            });
+        if record.signature_key == signature_key {
+            return true;
+        }
+        record.changed_key = Some(signature_key.to_vec());
+        false
+    }
+    /// Replaces the trusted key of the pid with a new key vouched for by the trusted one, as when
+    /// the agent rotates its identity, keeping whether it was verified; returns whether the old
+    /// key was the trusted one.
+    pub fn rotate_trusted_key(&mut self, pid: &str, old_key: &[u8], new_key: &[u8]) -> bool {
+        match self.trust.get_mut(pid) {
+            Some(record) if record.signature_key == old_key => {
+                record.signature_key = new_key.to_vec();
+                record.changed_key = None;
+                true
+            }
+            _ => false,
+        }
+    }
+    /// Marks the key as verified for the pid, replacing the trusted key if it differs.
+    pub fn set_verified(&mut self, pid: &str, signature_key: &[u8]) {
+        self.trust.insert(
+            pid.to_string(),
+            TrustRecord {
+                signature_key: signature_key.to_vec(),
+                verified: true,
+                changed_key: None,
+            },
+        );
+    }
+    pub fn quarantine(&self, pid: &str) -> Option<&Quarantine> {
+        self.quarantined.get(pid)
+    }
+    /// Quarantines the pid, adding to an earlier quarantine of it; the pid's trust record and
+    /// held key packages are dropped, so that its next key is trusted on first use again.
+    pub fn quarantine_pid(
+        &mut self,
+        pid: &str,
+        signature_keys: Vec<Vec<u8>>,
+        reinvite_gids: Vec<String>,
+    ) {
+        let quarantine = self
+            .quarantined
+            .entry(pid.to_string())
+            .or_insert_with(|| Quarantine {
+                signature_keys: Vec::new(),
+                reinvite_gids: Vec::new(),
+            });
+        for key in signature_keys {
+            if !quarantine.signature_keys.contains(&key) {
+                quarantine.signature_keys.push(key);
+            }
+        }
+        for gid in reinvite_gids {
+            if !quarantine.reinvite_gids.contains(&gid) {
+                quarantine.reinvite_gids.push(gid);
+            }
+        }
+        self.trust.remove(pid);
+        self.key_packages.remove(pid);
+        self.one_time_key_packages.remove(pid);
+    }
+    /// Quarantined pids still to be added back to groups, with the gids.
+    pub fn pending_reinvites(&self) -> Vec<(String, Vec<String>)> {
+        self.quarantined
+            .iter()
+            .filter(|(_, quarantine)| !quarantine.reinvite_gids.is_empty())
+            .map(|(pid, quarantine)| (pid.clone(), quarantine.reinvite_gids.clone()))
+            .collect()
+    }
+    pub fn remove_reinvite(&mut self, pid: &str, gid: &str) {
+        if let Some(quarantine) = self.quarantined.get_mut(pid) {
+            quarantine.reinvite_gids.retain(|g| g != gid);
+        }
+    }
+    pub fn quarantined_records(&self) -> &[QuarantinedRecord] {
+        &self.quarantined_records
+    }
+    /// Whether the record with the digest under the key is quarantined.
+    pub fn record_quarantined(&self, key: &str, digest: &str) -> bool {
+        self.quarantined_records
+            .iter()
+            .any(|record| record.key == key && record.digest == digest)
+    }
+    /// Quarantines the record, replacing an earlier quarantine of the record under its key.
+    pub fn quarantine_record(&mut self, record: QuarantinedRecord) {
+        self.quarantined_records.retain(|r| r.key != record.key);
+        self.quarantined_records.push(record);
+    }
+    /// Releases the record under the key from quarantine, returning it.
+    pub fn take_quarantined_record(&mut self, key: &str) -> Option<QuarantinedRecord> {
+        let index = self
+            .quarantined_records
+            .iter()
+            .position(|record| record.key == key)?;
+        Some(self.quarantined_records.remove(index))
+    }
+    pub fn welcome_processed(&self, digest: &str) -> bool {
+        self.processed_welcomes.contains(digest)
+    }
+    pub fn mark_welcome_processed(&mut self, digest: String) {
+        self.processed_welcomes.insert(digest);
+    }
+    pub fn pending_add(&self, gid: &str) -> Option<&PendingAdd> {
+        self.pending_adds.get(gid)
+    }
+    /// gids of the groups with a pending add, in order.
+    pub fn pending_add_gids(&self) -> Vec<String> {
+        let mut gids: Vec<String> = self.pending_adds.keys().cloned().collect();
+        gids.sort();
+        gids
+    }
+    pub fn set_pending_add(&mut self, gid: &str, pending_add: PendingAdd) {
+        self.pending_adds.insert(gid.to_string(), pending_add);
+    }
+    pub fn mark_pending_add_published(&mut self, gid: &str) {
+        if let Some(pending_add) = self.pending_adds.get_mut(gid) {
+            pending_add.commit_published = true;
+        }
+    }
+    pub fn remove_pending_add(&mut self, gid: &str) {
+        self.pending_adds.remove(gid);
+    }
+    pub fn commit_processed(&self, digest: &str) -> bool {
+        self.processed_commits.contains(digest)
+    }
+    /// Digest of the last commit merged into the group, which the next link of its commit chain
+    /// must name; `None` until one was merged since joining.
+    pub fn commit_head(&self, gid: &str) -> Option<&str> {
+        self.commit_heads.get(gid).map(String::as_str)
+    }
+    pub fn set_commit_head(&mut self, gid: &str, digest: String) {
+        self.commit_heads.insert(gid.to_string(), digest);
+    }
+    pub fn mark_commit_processed(&mut self, digest: String) {
+        self.processed_commits.insert(digest);
+    }
+    /// Records published to the delivery service, by key.
+    pub fn published_records(&self) -> HashMap<String, PublishedRecord> {
+        read_ignoring_poison(&self.published_records).clone()
+    }
+    /// Remembers a put of the value under the key at `timestamp`, keeping the first publish
+    /// time if the same value was put before.
+    pub fn record_published(&self, key: &str, sha256: String, value: Vec<u8>, timestamp: u64) {
+        let mut records = write_ignoring_poison(&self.published_records);
+        let first_published_at = records
+            .get(key)
+            .filter(|record| record.sha256 == sha256)
+            .map_or(timestamp, |record| record.first_published_at);
+        records.insert(
+            key.to_string(),
+            PublishedRecord {
+                sha256,
+                first_published_at,
+                published_at: timestamp,
+                value,
+            },
+        );
+    }
+    pub fn forget_published(&self, key: &str) -> bool {
+        write_ignoring_poison(&self.published_records)
+            .remove(key)
+            .is_some()
+    }
+    /// Publications waiting to be retried, oldest first.
+    pub fn outbox(&self) -> &VecDeque<Publication> {
+        &self.outbox
+    }
+    pub fn queue_publication(&mut self, publication: Publication) {
+        self.outbox.push_back(publication);
+    }
+    pub fn pop_publication(&mut self) -> Option<Publication> {
+        self.outbox.pop_front()
+    }
+    /// Puts the publication back at the front of the outbox, e.g. after a retry failed again.
+    pub fn requeue_publication(&mut self, publication: Publication) {
+        self.outbox.push_front(publication);
+    }
+    pub fn dht_host(&self) -> &str {
+        &self.dht_host
+    }
+    pub fn dht_port(&self) -> u16 {
+        self.dht_port
+    }
+    pub fn set_dht_host(&mut self, host: &str) {
+        self.dht_host = host.to_string();
+    }
+    pub fn set_dht_port(&mut self, port: u16) {
+        self.dht_port = port;
+    }
+    pub fn rate_limit(&self) -> RateLimit {
+        self.rate_limit
+    }
+    pub fn set_rate_limit(&mut self, rate_limit: RateLimit) {
+        self.rate_limit = rate_limit;
+    }
+    pub fn join_limits(&self) -> JoinLimits {
+        self.join_limits
+    }
+    /// Sets the limits of groups joined from now on; groups already joined keep theirs.
+    pub fn set_join_limits(&mut self, join_limits: JoinLimits) {
+        self.join_limits = join_limits;
+    }
+    pub fn invite_policy(&self) -> &InvitePolicy {
+        &self.invite_policy
+    }
+    pub fn set_invite_policy(&mut self, invite_policy: InvitePolicy) {
+        self.invite_policy = invite_policy;
+    }
+    pub fn artifact_policy(&self) -> &ArtifactPolicy {
+        &self.artifact_policy
+    }
+    /// Sets the policy incoming artifacts are checked against from now on; groups already
+    /// joined and key packages already held are kept.
+    pub fn set_artifact_policy(&mut self, artifact_policy: ArtifactPolicy) {
+        self.artifact_policy = artifact_policy;
+    }
+    pub fn pending_invites(&self) -> &[PendingInvite] {
+        &self.pending_invites
+    }
+    /// Queues the invite under a fresh id, which is returned.
+    pub fn add_pending_invite(&mut self, mut invite: PendingInvite) -> u64 {
+        self.next_invite_id += 1;
+        invite.id = self.next_invite_id;
+        self.pending_invites.push(invite);
+        self.next_invite_id
+    }
+    /// Removes the invite with the id from the queue, returning it.
+    pub fn take_pending_invite(&mut self, id: u64) -> Option<PendingInvite> {
+        let index = self
+            .pending_invites
+            .iter()
+            .position(|invite| invite.id == id)?;
+        Some(self.pending_invites.remove(index))
+    }
+    /// Rendezvous string prefixing the shared key package and welcome slots.
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+    pub fn set_namespace(&mut self, namespace: &str) {
+        self.namespace = namespace.to_string();
+    }
+    /// Number of welcomes addressed to the key package already processed in the current
+    /// namespace.
+    pub fn welcome_counter(&self, key_package_ref: &KeyPackageRef) -> u64 {
+        self.welcome_counters
+            .get(&self.namespace)
+            .and_then(|counters| counters.get(&hex_encode(key_package_ref.as_slice())))
+            .copied()
+            .unwrap_or_default()
+    }
+    /// Forgets welcome counters of key packages that are no longer published, in all
+    /// namespaces.
+    pub fn retain_welcome_counters(&mut self, key_package_refs: &[KeyPackageRef]) {
+        let published: Vec<String> = key_package_refs
+            .iter()
+            .map(|key_package_ref| hex_encode(key_package_ref.as_slice()))
+            .collect();
+        for counters in self.welcome_counters.values_mut() {
+            counters.retain(|key_package_ref, _| published.contains(key_package_ref));
+        }
+    }
+    pub fn set_welcome_counter(&mut self, key_package_ref: &KeyPackageRef, counter: u64) {
+        self.welcome_counters
+            .entry(self.namespace.clone())
+            .or_default()
+            .insert(hex_encode(key_package_ref.as_slice()), counter);
+    }
+    pub fn increment_welcome_counter(&mut self, key_package_ref: &KeyPackageRef) {
+        *self
+            .welcome_counters
+            .entry(self.namespace.clone())
+            .or_default()
+            .entry(hex_encode(key_package_ref.as_slice()))
+            .or_default() += 1;
+    }
+    /// First welcome slot of the key package in the current namespace that may be free, as far
+    /// as this agent posted to it.
+    pub fn welcome_write_counter(&self, key_package_ref: &KeyPackageRef) -> u64 {
+        self.welcome_write_counters
+            .get(&self.namespace)
+            .and_then(|counters| counters.get(&hex_encode(key_package_ref.as_slice())))
+            .copied()
+            .unwrap_or_default()
+    }
+    pub fn set_welcome_write_counter(&mut self, key_package_ref: &KeyPackageRef, counter: u64) {
+        self.welcome_write_counters
+            .entry(self.namespace.clone())
+            .or_default()
+            .insert(hex_encode(key_package_ref.as_slice()), counter);
     }
     pub fn key_package_counter(&self) -> u64 {
         self.key_package_counter
+            .get(&self.namespace)
+            .copied()
+            .unwrap_or_default()
+    }
+    pub fn set_key_package_counter(&mut self, counter: u64) {
+        self.key_package_counter
+            .insert(self.namespace.clone(), counter);
     }
     pub fn increment_key_package_counter(&mut self) {
-        self.key_package_counter += 1;
+        *self
+            .key_package_counter
+            .entry(self.namespace.clone())
+            .or_default() += 1;
+    }
+    /// Number of welcomes addressed to this agent in the directory that were already processed.
+    pub fn directory_welcome_counter(&self) -> u64 {
+        self.directory_welcome_counter
+    }
+    pub fn set_directory_welcome_counter(&mut self, counter: u64) {
+        self.directory_welcome_counter = counter;
+    }
+    pub fn increment_directory_welcome_counter(&mut self) {
+        self.directory_welcome_counter += 1;
+    }
+    /// Unix time of the last key package advertisement in the current namespace.
+    pub fn advertised_at(&self) -> Option<u64> {
+        self.advertised_at.get(&self.namespace).copied()
+    }
+    pub fn set_advertised_at(&mut self, timestamp: u64) {
+        self.advertised_at.insert(self.namespace.clone(), timestamp);
+    }
+    /// Next application message slot to read for the group; resets whenever the epoch changes.
+    pub fn message_counter(&self, gid: &str, epoch: u64) -> u64 {
+        epoch_counter(&self.message_counters, gid, epoch)
+    }
+    pub fn set_message_counter(&mut self, gid: &str, epoch: u64, counter: u64) {
+        self.message_counters
+            .insert(gid.to_string(), (epoch, counter));
+    }
+    pub fn increment_message_counter(&mut self, gid: &str, epoch: u64) {
+        increment_epoch_counter(&mut self.message_counters, gid, epoch);
+    }
+    /// Next proposal slot to read for the group; resets whenever the epoch changes.
+    pub fn proposal_counter(&self, gid: &str, epoch: u64) -> u64 {
+        epoch_counter(&self.proposal_counters, gid, epoch)
+    }
+    pub fn increment_proposal_counter(&mut self, gid: &str, epoch: u64) {
+        increment_epoch_counter(&mut self.proposal_counters, gid, epoch);
     }
+    /// Sequence number of the next application message sent to the group; resets whenever the
+    /// epoch changes.
+    pub fn sent_message_counter(&self, gid: &str, epoch: u64) -> u64 {
+        epoch_counter(&self.sent_message_counters, gid, epoch)
+    }
+    pub fn increment_sent_message_counter(&mut self, gid: &str, epoch: u64) {
+        increment_epoch_counter(&mut self.sent_message_counters, gid, epoch);
+    }
+    /// Epoch and sequence number of the latest application message received from the sender in
+    /// the group.
+    pub fn received_sequence(&self, gid: &str, sender: &str) -> Option<(u64, u64)> {
+        self.received_sequences.get(gid)?.get(sender).copied()
+    }
+    pub fn set_received_sequence(&mut self, gid: &str, sender: &str, epoch: u64, sequence: u64) {
+        self.received_sequences
+            .entry(gid.to_string())
+            .or_default()
+            .insert(sender.to_string(), (epoch, sequence));
+    }
+}
+
+fn default_dht_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_dht_port() -> u16 {
+    8000
+}
+
+fn default_history_limit() -> usize {
+    1000
+}
+
+fn default_exporter_window() -> usize {
+    4
+}
+
+fn epoch_counter(counters: &HashMap<String, (u64, u64)>, gid: &str, epoch: u64) -> u64 {
+    match counters.get(gid) {
+        Some((counter_epoch, counter)) if *counter_epoch == epoch => *counter,
+        _ => 0,
+    }
+}
+
+fn increment_epoch_counter(counters: &mut HashMap<String, (u64, u64)>, gid: &str, epoch: u64) {
+    let counter = epoch_counter(counters, gid, epoch) + 1;
+    counters.insert(gid.to_string(), (epoch, counter));
+}
+
+/// Source of the generations of all key stores, so that no two stores share one.
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+
+fn next_generation() -> u64 {
+    NEXT_GENERATION.fetch_add(1, Ordering::Relaxed)
 }
 
-#[derive(Debug, Default)]
 pub struct OpenMlsKeyValueStore {
     values: RwLock<HashMap<String, String>>,
+    generation: Arc<AtomicU64>,
+}
+
+impl Default for OpenMlsKeyValueStore {
+    fn default() -> Self {
+        Self {
+            values: Default::default(),
+            generation: Arc::new(AtomicU64::new(next_generation())),
+        }
+    }
+}
+
+/// Lists the values only if secrets are logged, since they include every group's secrets.
+impl core::fmt::Debug for OpenMlsKeyValueStore {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let values = read_ignoring_poison(&self.values);
+        match log_secrets() {
+            true => f
+                .debug_struct("OpenMlsKeyValueStore")
+                .field("values", &*values)
+                .finish(),
+            false => write!(
+                f,
+                "OpenMlsKeyValueStore {{ <{} values redacted> }}",
+                values.len()
+            ),
+        }
+    }
 }
 
 impl Clone for OpenMlsKeyValueStore {
@@ -98,6 +1519,7 @@ impl Clone for OpenMlsKeyValueStore {
         let values = self.values.read().unwrap();
         Self {
             values: RwLock::new(values.clone()),
+            generation: Arc::new(AtomicU64::new(next_generation())),
         }
     }
 }
@@ -120,11 +1542,143 @@ impl<'de> Deserialize<'de> for OpenMlsKeyValueStore {
         let values = HashMap::deserialize(deserializer)?;
         Ok(Self {
             values: RwLock::new(values),
+            generation: Arc::new(AtomicU64::new(next_generation())),
         })
     }
 }
 
+/// Storage key of the group's entity under the label.
+fn group_entity_key(
+    label: &[u8],
+    group_id: &impl traits::GroupId<CURRENT_VERSION>,
+) -> Result<String, OpenMlsKeyValueStoreError> {
+    Ok(hex_encode(build_key_from_vec::<CURRENT_VERSION>(
+        label,
+        serde_json::to_vec(group_id)?,
+    )))
+}
+
+/// Decodes a stored entity as JSON.
+fn decode_entity(value: &str) -> Result<serde_json::Value, OpenMlsKeyValueStoreError> {
+    let bytes = hex_decode(value).map_err(|_| OpenMlsKeyValueStoreError::SerializationError)?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
 impl OpenMlsKeyValueStore {
+    /// Changes whenever a value is written, and differs between stores, so that what was read
+    /// from the store can be reused for as long as the generation stays the same.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+    /// The generation of the store, shared so that it can be read without borrowing the store.
+    pub(crate) fn shared_generation(&self) -> Arc<AtomicU64> {
+        self.generation.clone()
+    }
+    /// Locks the values for writing, moving the store to a new generation.
+    fn values_mut(&self) -> RwLockWriteGuard<'_, HashMap<String, String>> {
+        let values = self.values.write().unwrap();
+        self.generation.store(next_generation(), Ordering::Relaxed);
+        values
+    }
+    /// Updates the JSON of the group's entity under the label in place, returning what `update`
+    /// returns, or `None` if the group has no such entity.
+    fn update_group_entity<T>(
+        &self,
+        label: &[u8],
+        group_id: &impl traits::GroupId<CURRENT_VERSION>,
+        update: impl FnOnce(&mut serde_json::Value) -> Option<T>,
+    ) -> Result<Option<T>, OpenMlsKeyValueStoreError> {
+        let mut values = self.values_mut();
+        let Some(value) = values.get_mut(&group_entity_key(label, group_id)?) else {
+            return Ok(None);
+        };
+        let mut entity = decode_entity(value)?;
+        let updated = update(&mut entity).ok_or(OpenMlsKeyValueStoreError::SerializationError)?;
+        *value = hex_encode(serde_json::to_vec(&entity)?);
+        Ok(Some(updated))
+    }
+    /// Reads the JSON of the group's entity under the label, returning what `read` returns, or
+    /// `None` if the group has no such entity; unlike updating, this keeps the generation.
+    fn read_group_entity<T>(
+        &self,
+        label: &[u8],
+        group_id: &impl traits::GroupId<CURRENT_VERSION>,
+        read: impl FnOnce(&serde_json::Value) -> Option<T>,
+    ) -> Result<Option<T>, OpenMlsKeyValueStoreError> {
+        let values = self.values.read().unwrap();
+        let Some(value) = values.get(&group_entity_key(label, group_id)?) else {
+            return Ok(None);
+        };
+        let read =
+            read(&decode_entity(value)?).ok_or(OpenMlsKeyValueStoreError::SerializationError)?;
+        Ok(Some(read))
+    }
+    /// The epochs whose message secrets are retained for the group, oldest first, and the most
+    /// that are retained, or `None` for an unknown group.
+    ///
+    /// OpenMLS has no API for its message secrets store, which it serializes with the most
+    /// epochs it retains as `max_epochs` and the retained epochs' trees, oldest first, as
+    /// `past_epoch_trees`.
+    pub fn past_epochs(
+        &self,
+        group_id: &impl traits::GroupId<CURRENT_VERSION>,
+    ) -> Result<Option<(usize, Vec<u64>)>, OpenMlsKeyValueStoreError> {
+        self.read_group_entity(MESSAGE_SECRETS_LABEL, group_id, |store| {
+            let max = store["max_epochs"].as_u64()? as usize;
+            let epochs = store["past_epoch_trees"]
+                .as_array()?
+                .iter()
+                .map(|tree| tree["epoch"].as_u64())
+                .collect::<Option<_>>()?;
+            Some((max, epochs))
+        })
+    }
+    /// Drops the message secrets of all but the `keep` latest past epochs of the group,
+    /// returning how many were dropped, or `None` for an unknown group; with `max` given, at
+    /// most that many are retained from now on, also by the group when it is loaded again.
+    pub fn retain_past_epochs(
+        &self,
+        group_id: &impl traits::GroupId<CURRENT_VERSION>,
+        keep: usize,
+        max: Option<usize>,
+    ) -> Result<Option<usize>, OpenMlsKeyValueStoreError> {
+        let dropped = self.update_group_entity(MESSAGE_SECRETS_LABEL, group_id, |store| {
+            let trees = store["past_epoch_trees"].as_array_mut()?;
+            let dropped = trees.len().saturating_sub(keep);
+            trees.drain(..dropped);
+            if let Some(max) = max {
+                store["max_epochs"] = max.into();
+            }
+            Some(dropped)
+        })?;
+        if let Some(max) = max {
+            self.update_group_entity(JOIN_CONFIG_LABEL, group_id, |config| {
+                config.get_mut("max_past_epochs")?;
+                config["max_past_epochs"] = max.into();
+                Some(())
+            })?;
+        }
+        Ok(dropped)
+    }
+    /// Entries whose storage keys contain one of the byte strings.
+    fn entries_containing(&self, needles: &[Vec<u8>]) -> HashMap<String, String> {
+        let values = self.values.read().unwrap();
+        values
+            .iter()
+            .filter(|(key, _)| {
+                hex_decode(key).is_ok_and(|key| {
+                    needles.iter().any(|needle| {
+                        key.windows(needle.len())
+                            .any(|window| window == needle.as_slice())
+                    })
+                })
+            })
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+    fn extend(&self, entries: HashMap<String, String>) {
+        self.values_mut().extend(entries);
+    }
     /// Internal helper to abstract write operations.
     #[inline(always)]
     fn write<const VERSION: u16>(
@@ -133,10 +1687,10 @@ impl OpenMlsKeyValueStore {
         key: &[u8],
         value: Vec<u8>,
     ) -> Result<(), <Self as StorageProvider<CURRENT_VERSION>>::Error> {
-        let mut values = self.values.write().unwrap();
+        let mut values = self.values_mut();
         let storage_key = build_key_from_vec::<VERSION>(label, key.to_vec());
 
-        log::trace!("{}", std::backtrace::Backtrace::capture());
+        tracing::trace!("{}", std::backtrace::Backtrace::capture());
 
         values.insert(hex_encode(storage_key), hex_encode(value));
         Ok(())
@@ -148,23 +1702,22 @@ impl OpenMlsKeyValueStore {
         key: &[u8],
         value: Vec<u8>,
     ) -> Result<(), <Self as StorageProvider<CURRENT_VERSION>>::Error> {
-        let mut values = self.values.write().unwrap();
+        let mut values = self.values_mut();
         let storage_key = build_key_from_vec::<VERSION>(label, key.to_vec());
 
-        log::trace!("{}", std::backtrace::Backtrace::capture());
+        tracing::trace!("{}", std::backtrace::Backtrace::capture());
 
         // fetch value from db, falling back to an empty list if doens't exist
         let list_bytes = values
             .entry(hex_encode(storage_key))
-            .or_insert("[]".to_owned());
+            .or_insert_with(|| hex_encode("[]"));
 
         // parse old value and push new data
         let mut list: Vec<Vec<u8>> = serde_json::from_slice(&hex_decode(&list_bytes).unwrap())?;
         list.push(value);
 
-        // write back, reusing the old buffer
-        list_bytes.truncate(0);
-        serde_json::to_writer(hex_decode(list_bytes).unwrap(), &list)?;
+        // write back
+        *list_bytes = hex_encode(serde_json::to_vec(&list)?);
 
         Ok(())
     }
@@ -175,15 +1728,15 @@ impl OpenMlsKeyValueStore {
         key: &[u8],
         value: Vec<u8>,
     ) -> Result<(), <Self as StorageProvider<CURRENT_VERSION>>::Error> {
-        let mut values = self.values.write().unwrap();
+        let mut values = self.values_mut();
         let storage_key = build_key_from_vec::<VERSION>(label, key.to_vec());
 
-        log::trace!("{}", std::backtrace::Backtrace::capture());
+        tracing::trace!("{}", std::backtrace::Backtrace::capture());
 
         // fetch value from db, falling back to an empty list if doens't exist
         let list_bytes = values
             .entry(hex_encode(storage_key))
-            .or_insert("[]".to_owned());
+            .or_insert_with(|| hex_encode("[]"));
 
         // parse old value, find value to delete and remove it from list
         let mut list: Vec<Vec<u8>> = serde_json::from_slice(&hex_decode(&list_bytes).unwrap())?;
@@ -191,9 +1744,8 @@ impl OpenMlsKeyValueStore {
             list.remove(pos);
         }
 
-        // write back, reusing the old buffer
-        list_bytes.truncate(0);
-        serde_json::to_writer(hex_decode(list_bytes).unwrap(), &list)?;
+        // write back
+        *list_bytes = hex_encode(serde_json::to_vec(&list)?);
 
         Ok(())
     }
@@ -208,7 +1760,7 @@ impl OpenMlsKeyValueStore {
         let values = self.values.read().unwrap();
         let storage_key = build_key_from_vec::<VERSION>(label, key.to_vec());
 
-        log::trace!("{}", std::backtrace::Backtrace::capture());
+        tracing::trace!("{}", std::backtrace::Backtrace::capture());
 
         let value = values.get(&hex_encode(storage_key));
 
@@ -234,7 +1786,7 @@ impl OpenMlsKeyValueStore {
         storage_key.extend_from_slice(key);
         storage_key.extend_from_slice(&u16::to_be_bytes(VERSION));
 
-        log::trace!("{}", std::backtrace::Backtrace::capture());
+        tracing::trace!("{}", std::backtrace::Backtrace::capture());
 
         let value: Vec<Vec<u8>> = match values.get(&hex_encode(storage_key)) {
             Some(list_bytes) => serde_json::from_slice(&hex_decode(list_bytes).unwrap()).unwrap(),
@@ -255,13 +1807,13 @@ impl OpenMlsKeyValueStore {
         label: &[u8],
         key: &[u8],
     ) -> Result<(), <Self as StorageProvider<CURRENT_VERSION>>::Error> {
-        let mut values = self.values.write().unwrap();
+        let mut values = self.values_mut();
 
         let mut storage_key = label.to_vec();
         storage_key.extend_from_slice(key);
         storage_key.extend_from_slice(&u16::to_be_bytes(VERSION));
 
-        log::trace!("{}", std::backtrace::Backtrace::capture());
+        tracing::trace!("{}", std::backtrace::Backtrace::capture());
 
         values.remove(&hex_encode(storage_key));
 
@@ -357,7 +1909,7 @@ impl StorageProvider<CURRENT_VERSION> for OpenMlsKeyValueStore {
         group_id: &GroupId,
         interim_transcript_hash: &InterimTranscriptHash,
     ) -> Result<(), Self::Error> {
-        let mut values = self.values.write().unwrap();
+        let mut values = self.values_mut();
         let key = build_key::<CURRENT_VERSION, &GroupId>(INTERIM_TRANSCRIPT_HASH_LABEL, group_id);
         let value = serde_json::to_vec(&interim_transcript_hash).unwrap();
 
@@ -373,7 +1925,7 @@ impl StorageProvider<CURRENT_VERSION> for OpenMlsKeyValueStore {
         group_id: &GroupId,
         group_context: &GroupContext,
     ) -> Result<(), Self::Error> {
-        let mut values = self.values.write().unwrap();
+        let mut values = self.values_mut();
         let key = build_key::<CURRENT_VERSION, &GroupId>(GROUP_CONTEXT_LABEL, group_id);
         let value = serde_json::to_vec(&group_context).unwrap();
 
@@ -389,7 +1941,7 @@ impl StorageProvider<CURRENT_VERSION> for OpenMlsKeyValueStore {
         group_id: &GroupId,
         confirmation_tag: &ConfirmationTag,
     ) -> Result<(), Self::Error> {
-        let mut values = self.values.write().unwrap();
+        let mut values = self.values_mut();
         let key = build_key::<CURRENT_VERSION, &GroupId>(CONFIRMATION_TAG_LABEL, group_id);
         let value = serde_json::to_vec(&confirmation_tag).unwrap();
 
@@ -405,7 +1957,7 @@ impl StorageProvider<CURRENT_VERSION> for OpenMlsKeyValueStore {
         public_key: &SignaturePublicKey,
         signature_key_pair: &SignatureKeyPair,
     ) -> Result<(), Self::Error> {
-        let mut values = self.values.write().unwrap();
+        let mut values = self.values_mut();
         let key =
             build_key::<CURRENT_VERSION, &SignaturePublicKey>(SIGNATURE_KEY_PAIR_LABEL, public_key);
         let value = serde_json::to_vec(&signature_key_pair).unwrap();
@@ -825,7 +2377,7 @@ impl StorageProvider<CURRENT_VERSION> for OpenMlsKeyValueStore {
     ) -> Result<(), Self::Error> {
         let key = epoch_key_pairs_id(group_id, epoch, leaf_index)?;
         let value = serde_json::to_vec(key_pairs)?;
-        log::debug!("Writing encryption epoch key pairs");
+        tracing::debug!("Writing encryption epoch key pairs");
 
         self.write::<CURRENT_VERSION>(EPOCH_KEY_PAIRS_LABEL, &key, value)
     }
@@ -842,7 +2394,7 @@ impl StorageProvider<CURRENT_VERSION> for OpenMlsKeyValueStore {
     ) -> Result<Vec<HpkeKeyPair>, Self::Error> {
         let key = epoch_key_pairs_id(group_id, epoch, leaf_index)?;
         let storage_key = build_key_from_vec::<CURRENT_VERSION>(EPOCH_KEY_PAIRS_LABEL, key);
-        log::debug!("Reading encryption epoch key pairs");
+        tracing::debug!("Reading encryption epoch key pairs");
 
         let values = self.values.read().unwrap();
         let value = values.get(&hex_encode(storage_key));
@@ -877,7 +2429,7 @@ impl StorageProvider<CURRENT_VERSION> for OpenMlsKeyValueStore {
         // Get all proposal refs for this group.
         let proposal_refs: Vec<ProposalRef> =
             self.read_list(PROPOSAL_QUEUE_REFS_LABEL, &serde_json::to_vec(group_id)?)?;
-        let mut values = self.values.write().unwrap();
+        let mut values = self.values_mut();
         for proposal_ref in proposal_refs {
             // Delete all proposals.
             let key = serde_json::to_vec(&(group_id, proposal_ref))?;
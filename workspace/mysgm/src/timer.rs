@@ -0,0 +1,28 @@
+//! Timers running on tokio natively and on the browser's event loop under WebAssembly, where
+//! tokio has no timer.
+
+use core::{future::Future, time::Duration};
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use tokio::time::sleep;
+
+#[cfg(target_arch = "wasm32")]
+pub async fn sleep(duration: Duration) {
+    gloo_timers::future::sleep(duration).await
+}
+
+/// Runs the future until it completes or the timeout passes, returning `None` on timeout.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    tokio::time::timeout(duration, future).await.ok()
+}
+
+/// Runs the future until it completes or the timeout passes, returning `None` on timeout.
+#[cfg(target_arch = "wasm32")]
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    use futures::future::{Either, select};
+    match select(Box::pin(future), Box::pin(sleep(duration))).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
@@ -0,0 +1,72 @@
+//! Access to PKCS#11 tokens, e.g. an HSM or a YubiKey.
+//!
+//! Each module is loaded and initialized once per process, since PKCS#11 refuses to initialize
+//! a module twice, and the user is logged in on the first session opened on a token.
+
+use super::{error::MySgmError, lock::lock_ignoring_poison};
+
+use cryptoki::{
+    context::{CInitializeArgs, Pkcs11},
+    error::{Error as Pkcs11Error, RvError},
+    session::{Session, UserType},
+    slot::Slot,
+    types::AuthPin,
+};
+use std::{
+    collections::BTreeMap,
+    env::var as env_var,
+    sync::{Mutex, MutexGuard},
+};
+
+/// Environment variable holding the user PIN of tokens.
+pub const PIN_VAR: &str = "MYSGM_PKCS11_PIN";
+
+/// Loaded modules, by path.
+static CONTEXTS: Mutex<BTreeMap<String, Pkcs11>> = Mutex::new(BTreeMap::new());
+
+/// A token to use through a PKCS#11 module.
+#[derive(Debug)]
+pub struct Pkcs11Target<'a> {
+    /// Path of the PKCS#11 module, e.g. `/usr/lib/softhsm/libsofthsm2.so`.
+    pub module: &'a str,
+    /// ID of the slot holding the token; the first slot with a token by default.
+    pub slot: Option<u64>,
+    /// User PIN of the token; [`PIN_VAR`] is used otherwise, and no login without either.
+    pub pin: Option<&'a str>,
+}
+
+fn contexts() -> MutexGuard<'static, BTreeMap<String, Pkcs11>> {
+    lock_ignoring_poison(&CONTEXTS)
+}
+
+/// Opens a read-write session on the target's token, logged in as its user.
+pub fn open_session(target: &Pkcs11Target<'_>) -> Result<(Session, Slot), MySgmError> {
+    let pkcs11 = match contexts().get(target.module) {
+        Some(pkcs11) => pkcs11.clone(),
+        None => {
+            let pkcs11 = Pkcs11::new(target.module)?;
+            pkcs11.initialize(CInitializeArgs::OsThreads)?;
+            contexts().insert(target.module.to_string(), pkcs11.clone());
+            pkcs11
+        }
+    };
+    let slots = pkcs11.get_slots_with_token()?;
+    let slot = match target.slot {
+        Some(id) => slots.into_iter().find(|slot| slot.id() == id),
+        None => slots.into_iter().next(),
+    }
+    .ok_or_else(|| MySgmError::NoPkcs11Token(target.module.to_string()))?;
+    let session = pkcs11.open_rw_session(slot)?;
+    let pin = match target.pin {
+        Some(pin) => Some(pin.to_string()),
+        None => env_var(PIN_VAR).ok(),
+    };
+    if let Some(pin) = pin {
+        match session.login(UserType::User, Some(&AuthPin::new(pin))) {
+            // logins last as long as the module stays loaded
+            Ok(()) | Err(Pkcs11Error::Pkcs11(RvError::UserAlreadyLoggedIn, _)) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok((session, slot))
+}
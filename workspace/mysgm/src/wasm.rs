@@ -0,0 +1,124 @@
+//! JavaScript bindings of the agent, under WebAssembly.
+//!
+//! An [`Agent`] talks to an OpenDHT proxy through `fetch` and keeps its state in the browser's
+//! local storage under its profile, saving it after each call that changes it. Errors are
+//! thrown as JavaScript `Error`s carrying the message of the [`MySgmError`].
+
+use super::{
+    agent::MySgmAgent, browser::BrowserStorage, error::MySgmError, opendht::OpenDhtRestAdapter,
+    state::MySgmState,
+};
+
+use openmls::prelude::Ciphersuite;
+use openmls_rust_crypto::RustCrypto;
+use serde_json::{Value, json};
+use wasm_bindgen::prelude::*;
+
+/// Converts the JSON value to a JavaScript value.
+fn to_js(value: Value) -> Result<JsValue, JsError> {
+    js_sys::JSON::parse(&value.to_string()).map_err(|_| JsError::new("Invalid JSON"))
+}
+
+fn js_error(e: MySgmError) -> JsError {
+    JsError::new(&e.to_string())
+}
+
+/// An agent whose state lives in the browser.
+#[wasm_bindgen]
+pub struct Agent {
+    agent: MySgmAgent,
+    storage: BrowserStorage,
+    passphrase: Option<String>,
+}
+
+#[wasm_bindgen]
+impl Agent {
+    /// Loads the profile's state, decrypting it with the passphrase if given, or creates a
+    /// fresh identity with the label if the profile has none yet.
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        profile: &str,
+        label: &str,
+        proxy_host: &str,
+        proxy_port: u16,
+        passphrase: Option<String>,
+    ) -> Result<Agent, JsError> {
+        let storage = BrowserStorage::new(profile);
+        let crypto = RustCrypto::default();
+        let state = match storage.load(passphrase.as_deref()).map_err(js_error)? {
+            Some(state) => state,
+            None => {
+                let state = MySgmState::generate(
+                    label,
+                    Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519,
+                    &crypto,
+                )
+                .map_err(|e| js_error(e.into()))?;
+                storage
+                    .save(&state, passphrase.as_deref())
+                    .map_err(js_error)?;
+                state
+            }
+        };
+        let adapter = OpenDhtRestAdapter::new(proxy_host, proxy_port);
+        Ok(Self {
+            agent: MySgmAgent::new(state, crypto, Box::new(adapter)),
+            storage,
+            passphrase,
+        })
+    }
+    /// The pid of this agent, which others add to groups.
+    #[wasm_bindgen(getter)]
+    pub fn pid(&self) -> String {
+        self.agent.state().my_pid().to_string()
+    }
+    fn save(&self) -> Result<(), JsError> {
+        self.storage
+            .save(self.agent.state(), self.passphrase.as_deref())
+            .map_err(js_error)
+    }
+    /// Publishes a key package valid for the lifetime in seconds.
+    pub async fn advertise(&mut self, lifetime: u64) -> Result<(), JsError> {
+        let result = self.agent.advertise(lifetime).await;
+        self.save()?;
+        result.map_err(js_error)
+    }
+    /// Downloads and processes key packages, welcomes, and commits, then receives the
+    /// application messages of every group.
+    ///
+    /// Returns an object with the gids of the groups joined, the number of commits merged, the
+    /// messages received as `{gid, sender, message}`, and the errors of slots that could not be
+    /// processed.
+    pub async fn sync(&mut self) -> Result<JsValue, JsError> {
+        let result = self.agent.sync_and_receive().await;
+        self.save()?;
+        let (report, messages) = result.map_err(js_error)?;
+        let messages: Vec<_> = messages
+            .into_iter()
+            .map(|(gid, (sender, message))| {
+                json!({
+                    "gid": gid,
+                    "sender": sender,
+                    "message": String::from_utf8_lossy(&message),
+                })
+            })
+            .collect();
+        to_js(json!({
+            "key_packages": report.key_packages,
+            "welcomes": report.welcomes,
+            "commits": report.commits,
+            "messages": messages,
+            "errors": report
+                .errors
+                .iter()
+                .map(|(slot, e)| json!({"slot": slot, "error": e.to_string()}))
+                .collect::<Vec<_>>(),
+        }))
+    }
+    /// Sends the message to the group.
+    pub async fn send(&mut self, gid: &str, message: &str) -> Result<(), JsError> {
+        let result = self.agent.send_message(gid, message.as_bytes()).await;
+        self.save()?;
+        result.map_err(js_error)
+    }
+}
@@ -0,0 +1,50 @@
+mod common;
+
+use common::Harness;
+use mysgm::{ArtifactPolicy, ArtifactViolation, MySgmError};
+
+#[tokio::test]
+async fn key_packages_outside_the_policy_are_rejected() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    let bob = harness.pid(1);
+    let policy = ArtifactPolicy {
+        credential_types: vec![2],
+        ..Default::default()
+    };
+    harness.agent(0).state_mut().set_artifact_policy(policy);
+    harness.advertise_all().await.unwrap();
+    let report = harness.agent(0).sync(false).await.unwrap();
+    assert!(report.key_packages.is_empty());
+    assert!(report.errors.iter().any(|(_, e)| matches!(
+        e,
+        MySgmError::ArtifactRejected(_, ArtifactViolation::CredentialType(1))
+    )));
+    assert!(harness.agent(0).state().key_package(&bob).is_none());
+}
+
+#[tokio::test]
+async fn welcomes_outside_the_policy_are_rejected_and_audited() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    let policy = ArtifactPolicy {
+        protocol_versions: vec![2],
+        ..Default::default()
+    };
+    harness.agent(1).state_mut().set_artifact_policy(policy);
+    harness.advertise_all().await.unwrap();
+    let bob = harness.pid(1);
+    let alice = harness.agent(0);
+    alice.sync(false).await.unwrap();
+    let gid = alice.create_group("g", true).unwrap();
+    alice.add_to_group(&gid, &[bob]).await.unwrap();
+
+    let report = harness.agent(1).sync(false).await.unwrap();
+    assert!(report.welcomes.is_empty());
+    assert!(report.errors.iter().any(|(_, e)| matches!(
+        e,
+        MySgmError::ArtifactRejected(_, ArtifactViolation::ProtocolVersion(1))
+    )));
+    assert!(harness.agent(1).state().gids().is_empty());
+    let audit = harness.agent(1).state().audit_log(&gid).to_vec();
+    assert_eq!(audit[0].kind, "policy_violation");
+    assert_eq!(audit[0].actor, Some(harness.pid(0)));
+}
@@ -0,0 +1,16 @@
+use mysgm::{MySgmError, bench::run};
+
+#[tokio::test]
+async fn bench_times_every_phase() {
+    let report = run(3, 5).await.unwrap();
+    // the creator also downloads its own key package
+    assert_eq!(report.key_package_validation.count, 3);
+    assert_eq!(report.welcome_processing.count, 2);
+    assert_eq!(report.commit_merging.count, 2);
+    assert_eq!(report.encryption.count, 5);
+    assert_eq!(report.decryption.count, 5);
+    assert!(matches!(
+        run(1, 5).await,
+        Err(MySgmError::InvalidArgument(_))
+    ));
+}
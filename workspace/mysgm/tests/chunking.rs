@@ -0,0 +1,38 @@
+use mysgm::{ChunkingAdapter, DeliveryAdapter, MemoryAdapter, MySgmError};
+
+/// Stores a manifest for a chunked value directly in the backing adapter.
+async fn forge_manifest(adapter: &MemoryAdapter, key: &str, len: usize, parts: usize) {
+    let mut record = b"mysgm chunked\0".to_vec();
+    record.extend(format!(r#"{{"len":{len},"parts":{parts},"sha256":"00"}}"#).as_bytes());
+    adapter.put_checked(key, &record).await.unwrap();
+}
+
+#[tokio::test]
+async fn large_values_round_trip_through_parts() {
+    let backing = MemoryAdapter::new();
+    let adapter = ChunkingAdapter::with_max_value_size(Box::new(backing.clone()), 4);
+    adapter.put_checked("kp0", b"0123456789").await.unwrap();
+    assert!(backing.get("kp0").await.unwrap().unwrap().len() > 10);
+    assert_eq!(
+        adapter.get("kp0").await.unwrap(),
+        Some(b"0123456789".to_vec())
+    );
+}
+
+#[tokio::test]
+async fn forged_manifests_are_refused_without_allocating() {
+    let backing = MemoryAdapter::new();
+    let adapter = ChunkingAdapter::with_max_value_size(Box::new(backing.clone()), 4)
+        .with_max_chunked_size(1024);
+    // a length beyond the limit, parts inconsistent with the length, and a length that
+    // would overflow any allocation
+    forge_manifest(&backing, "wm0", 4096, 1024).await;
+    forge_manifest(&backing, "wm1", 16, 1_000_000).await;
+    forge_manifest(&backing, "wm2", usize::MAX, usize::MAX.div_ceil(4)).await;
+    for key in ["wm0", "wm1", "wm2"] {
+        assert!(matches!(
+            adapter.get(key).await,
+            Err(MySgmError::CorruptChunks(_))
+        ));
+    }
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use async_trait::async_trait;
+use common::Harness;
+use mysgm::{DeliveryAdapter, MemoryAdapter, MySgmError, agent::commit_chain_key};
+
+/// Hides the commits stored under exporter-derived keys, as if they had expired.
+#[derive(Debug)]
+struct MissingCommitsAdapter(MemoryAdapter);
+
+#[async_trait]
+impl DeliveryAdapter for MissingCommitsAdapter {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, MySgmError> {
+        match key.starts_with("cm") && !key.starts_with("cm_") {
+            true => Ok(None),
+            false => self.0.get(key).await,
+        }
+    }
+    async fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
+        self.0.put_checked(key, value).await
+    }
+}
+
+#[tokio::test]
+async fn members_walk_the_commit_chain() {
+    let mut harness = Harness::with_adapter(&["alice", "bob", "carol"], |adapter| {
+        Box::new(MissingCommitsAdapter(adapter))
+    });
+    let gid = harness.group_of_all("g").await.unwrap();
+    assert!(harness.adapter.keys().contains(&commit_chain_key(&gid, 0)));
+    harness.agent(1).self_update(&gid).await.unwrap();
+    harness.agent(2).self_update(&gid).await.unwrap_err();
+    harness.agent(2).sync(false).await.unwrap();
+    harness.agent(2).self_update(&gid).await.unwrap();
+    harness.sync_all(false).await.unwrap();
+    for i in 0..3 {
+        assert_eq!(harness.agent(i).group_epoch(&gid).unwrap(), 3);
+    }
+}
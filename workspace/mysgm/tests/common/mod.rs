@@ -0,0 +1,78 @@
+//! Harness running several agents against one in-memory delivery service.
+
+// each test crate uses only part of the harness
+#![allow(dead_code)]
+
+use mysgm::{DeliveryAdapter, MemoryAdapter, MySgmAgent, MySgmError, MySgmState, SyncReport};
+use openmls::prelude::Ciphersuite;
+use openmls_rust_crypto::RustCrypto;
+
+/// Lifetime of the key packages advertised by the agents, in seconds.
+const KEY_PACKAGE_LIFETIME: u64 = 86400;
+
+/// Agents sharing one [`MemoryAdapter`].
+pub struct Harness {
+    pub adapter: MemoryAdapter,
+    pub agents: Vec<MySgmAgent>,
+}
+
+impl Harness {
+    /// Creates an agent for each label, all using the default ciphersuite.
+    pub fn new(labels: &[&str]) -> Self {
+        Self::with_adapter(labels, |adapter| Box::new(adapter))
+    }
+    /// Creates an agent for each label like [`Self::new`], reaching the shared store through
+    /// the adapter that `wrap` builds around it.
+    pub fn with_adapter(
+        labels: &[&str],
+        wrap: impl Fn(MemoryAdapter) -> Box<dyn DeliveryAdapter>,
+    ) -> Self {
+        let adapter = MemoryAdapter::new();
+        let agents = labels
+            .iter()
+            .map(|label| {
+                let crypto = RustCrypto::default();
+                let state = MySgmState::generate(
+                    label,
+                    Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519,
+                    &crypto,
+                )
+                .expect("generate state");
+                MySgmAgent::new(state, crypto, wrap(adapter.clone()))
+            })
+            .collect();
+        Self { adapter, agents }
+    }
+    pub fn agent(&mut self, index: usize) -> &mut MySgmAgent {
+        &mut self.agents[index]
+    }
+    pub fn pid(&self, index: usize) -> String {
+        self.agents[index].state().my_pid().to_string()
+    }
+    /// Has every agent advertise a key package.
+    pub async fn advertise_all(&mut self) -> Result<(), MySgmError> {
+        for agent in &mut self.agents {
+            agent.advertise(KEY_PACKAGE_LIFETIME).await?;
+        }
+        Ok(())
+    }
+    /// Syncs every agent once, in order, returning their reports.
+    pub async fn sync_all(&mut self, receive: bool) -> Result<Vec<SyncReport>, MySgmError> {
+        let mut reports = Vec::new();
+        for agent in &mut self.agents {
+            reports.push(agent.sync(receive).await?);
+        }
+        Ok(reports)
+    }
+    /// Creates a group at the first agent and adds all the others to it, returning the gid.
+    pub async fn group_of_all(&mut self, gid: &str) -> Result<String, MySgmError> {
+        self.advertise_all().await?;
+        let pids: Vec<String> = (1..self.agents.len()).map(|i| self.pid(i)).collect();
+        let creator = &mut self.agents[0];
+        creator.sync(false).await?;
+        let gid = creator.create_group(gid, true)?;
+        creator.add_to_group(&gid, &pids).await?;
+        self.sync_all(false).await?;
+        Ok(gid)
+    }
+}
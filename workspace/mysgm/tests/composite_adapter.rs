@@ -0,0 +1,45 @@
+use async_trait::async_trait;
+use mysgm::{CompositeAdapter, DeliveryAdapter, MemoryAdapter, MySgmError};
+
+/// A delivery service that is down.
+#[derive(Debug)]
+struct DownAdapter;
+
+#[async_trait]
+impl DeliveryAdapter for DownAdapter {
+    async fn get(&self, _key: &str) -> Result<Option<Vec<u8>>, MySgmError> {
+        Err(std::io::Error::other("down").into())
+    }
+    async fn put_checked(&self, _key: &str, _value: &[u8]) -> Result<(), MySgmError> {
+        Err(std::io::Error::other("down").into())
+    }
+}
+
+#[tokio::test]
+async fn puts_reach_every_backend_that_is_up() {
+    let (first, second) = (MemoryAdapter::new(), MemoryAdapter::new());
+    let composite = CompositeAdapter::new(vec![
+        Box::new(DownAdapter),
+        Box::new(first.clone()),
+        Box::new(second.clone()),
+    ]);
+    composite.put_checked("k", b"v").await.unwrap();
+    assert_eq!(first.get("k").await.unwrap(), Some(b"v".to_vec()));
+    assert_eq!(second.get("k").await.unwrap(), Some(b"v".to_vec()));
+    assert_eq!(composite.get("k").await.unwrap(), Some(b"v".to_vec()));
+    assert!(matches!(
+        composite.put_checked("k", b"w").await,
+        Err(MySgmError::KeyExists)
+    ));
+}
+
+#[tokio::test]
+async fn gets_merge_values_held_by_any_backend() {
+    let (first, second) = (MemoryAdapter::new(), MemoryAdapter::new());
+    second.put_checked("k", b"v").await.unwrap();
+    let composite = CompositeAdapter::new(vec![Box::new(first), Box::new(second)]);
+    assert_eq!(composite.get("k").await.unwrap(), Some(b"v".to_vec()));
+    assert_eq!(composite.get("missing").await.unwrap(), None);
+    let down = CompositeAdapter::new(vec![Box::new(DownAdapter)]);
+    assert!(down.get("k").await.is_err());
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use async_trait::async_trait;
+use common::Harness;
+use core::time::Duration;
+use mysgm::{DeliveryAdapter, MemoryAdapter, MySgmError, SyncReport};
+use std::{
+    slice::from_ref,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+/// Tracks the most gets in flight at once, each taking a moment like a round trip would.
+#[derive(Debug)]
+struct InFlightAdapter {
+    inner: MemoryAdapter,
+    in_flight: Arc<AtomicUsize>,
+    peak: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl DeliveryAdapter for InFlightAdapter {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, MySgmError> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(in_flight, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let value = self.inner.get(key).await;
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        value
+    }
+    async fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
+        self.inner.put_checked(key, value).await
+    }
+}
+
+#[tokio::test]
+async fn groups_are_fetched_concurrently_up_to_the_limit() {
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let mut harness = Harness::with_adapter(&["alice", "bob"], |inner| {
+        Box::new(InFlightAdapter {
+            inner,
+            in_flight: in_flight.clone(),
+            peak: peak.clone(),
+        })
+    });
+    let mut gids = vec![harness.group_of_all("g").await.unwrap()];
+    let bob = harness.pid(1);
+    for name in ["h", "i"] {
+        let alice = harness.agent(0);
+        let gid = alice.create_group(name, true).unwrap();
+        alice.add_to_group(&gid, from_ref(&bob)).await.unwrap();
+        gids.push(gid);
+    }
+    harness.agent(1).sync(false).await.unwrap();
+    assert_eq!(harness.agent(1).state().gids().len(), 3);
+    for gid in &gids {
+        let alice = harness.agent(0);
+        alice.self_update(gid).await.unwrap();
+        alice.send_message(gid, b"hello").await.unwrap();
+    }
+
+    let bob = harness.agent(1);
+    bob.set_sync_concurrency(2);
+    peak.store(0, Ordering::SeqCst);
+    let mut report = SyncReport::default();
+    bob.download_commits(&mut report).await.unwrap();
+    assert_eq!(report.commits, 3);
+    assert_eq!(peak.load(Ordering::SeqCst), 2);
+    assert_eq!(bob.sync(true).await.unwrap().messages, 3);
+    for gid in &gids {
+        assert_eq!(bob.group_epoch(gid).unwrap(), 2);
+    }
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use common::Harness;
+use mysgm::{ContentAddressedAdapter, DeliveryAdapter, MemoryAdapter};
+
+#[tokio::test]
+async fn groups_work_over_content_addressed_records() {
+    let mut harness = Harness::with_adapter(&["alice", "bob", "carol"], |adapter| {
+        Box::new(ContentAddressedAdapter::new(Box::new(adapter)))
+    });
+    let gid = harness.group_of_all("g").await.unwrap();
+    let keys = harness.adapter.keys();
+    assert!(keys.iter().any(|key| key.starts_with("kpp")));
+    assert!(!keys.iter().any(|key| key.starts_with("kp0")));
+    harness.agent(2).send_message(&gid, b"hello").await.unwrap();
+    let carol = harness.pid(2);
+    let received = harness.agent(1).process_next_message(&gid).await.unwrap();
+    assert_eq!(received, Some((carol, b"hello".to_vec())));
+}
+
+#[tokio::test]
+async fn tampered_values_are_detected() {
+    let store = MemoryAdapter::new();
+    let adapter = ContentAddressedAdapter::new(Box::new(store.clone()));
+    adapter.put_checked("k", b"value").await.unwrap();
+    assert_eq!(adapter.get("k").await.unwrap(), Some(b"value".to_vec()));
+    let content_key = store.keys().into_iter().find(|key| key != "k").unwrap();
+    let tampered = MemoryAdapter::new();
+    tampered
+        .put_checked("k", &store.get("k").await.unwrap().unwrap())
+        .await
+        .unwrap();
+    tampered.put_checked(&content_key, b"other").await.unwrap();
+    let adapter = ContentAddressedAdapter::new(Box::new(tampered));
+    assert!(adapter.get("k").await.is_err());
+}
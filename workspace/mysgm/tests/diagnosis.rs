@@ -0,0 +1,35 @@
+mod common;
+
+use common::Harness;
+
+#[tokio::test]
+async fn healthy_agents_pass_every_check() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    let diagnoses = harness.agent(0).diagnose().await;
+    let checks: Vec<_> = diagnoses.iter().map(|d| d.check.as_str()).collect();
+    assert_eq!(
+        checks,
+        [
+            "signature key",
+            "delivery service",
+            "clock",
+            &format!("group {gid}")
+        ]
+    );
+    assert!(diagnoses.iter().all(|d| d.passed), "{diagnoses:?}");
+    assert_eq!(diagnoses[3].detail, "epoch 1, 2 members");
+    assert!(
+        diagnoses[1]
+            .detail
+            .starts_with("stored, read back, and deleted "),
+        "{diagnoses:?}"
+    );
+    assert!(
+        harness
+            .adapter
+            .keys()
+            .iter()
+            .all(|key| !key.contains("diagnosis_"))
+    );
+}
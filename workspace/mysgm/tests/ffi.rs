@@ -0,0 +1,123 @@
+use mysgm::ffi::*;
+use std::{
+    env::temp_dir,
+    ffi::{CStr, CString, c_char},
+    fs::{create_dir_all, remove_dir_all},
+    process::id as process_id,
+    ptr::null,
+};
+
+fn take_string(string: *mut c_char) -> String {
+    assert!(!string.is_null(), "{}", last_error());
+    let value = unsafe { CStr::from_ptr(string) }
+        .to_str()
+        .unwrap()
+        .to_string();
+    unsafe { mysgm_string_free(string) };
+    value
+}
+
+fn last_error() -> String {
+    let error = mysgm_last_error();
+    match error.is_null() {
+        true => String::new(),
+        false => unsafe { CStr::from_ptr(error) }.to_string_lossy().into(),
+    }
+}
+
+fn c(string: &str) -> CString {
+    CString::new(string).unwrap()
+}
+
+#[test]
+fn agents_embedded_through_the_c_api_exchange_messages() {
+    let dir = temp_dir().join(format!("mysgm-ffi-{}", process_id()));
+    create_dir_all(dir.join("delivery")).unwrap();
+    let path = |name: &str| c(dir.join(name).to_str().unwrap());
+    let file_dir = path("delivery");
+    assert_eq!(mysgm_abi_version(), MYSGM_ABI_VERSION);
+
+    unsafe {
+        let alice = mysgm_new(
+            path("alice.json").as_ptr(),
+            c("alice").as_ptr(),
+            null(),
+            file_dir.as_ptr(),
+        );
+        let bob = mysgm_new(
+            path("bob.json").as_ptr(),
+            c("bob").as_ptr(),
+            null(),
+            file_dir.as_ptr(),
+        );
+        assert!(!alice.is_null() && !bob.is_null(), "{}", last_error());
+        // state is never silently replaced
+        assert!(
+            mysgm_new(
+                path("bob.json").as_ptr(),
+                c("bob").as_ptr(),
+                null(),
+                file_dir.as_ptr()
+            )
+            .is_null()
+        );
+        assert!(last_error().contains("already exists"));
+
+        assert_eq!(mysgm_advertise(bob, 3600), 0, "{}", last_error());
+        let gid = c(&take_string(mysgm_create_group(alice, c("g").as_ptr())));
+        let bob_pid = c(&take_string(mysgm_pid(bob)));
+        let pids = [bob_pid.as_ptr()];
+        // alice picks up bob's key package
+        take_string(mysgm_sync(alice));
+        assert_eq!(
+            mysgm_add_to_group(alice, gid.as_ptr(), pids.as_ptr(), 1),
+            0,
+            "{}",
+            last_error()
+        );
+        take_string(mysgm_sync(bob));
+        let message = b"hello over ffi";
+        assert_eq!(
+            mysgm_send(alice, gid.as_ptr(), message.as_ptr(), message.len()),
+            0,
+            "{}",
+            last_error()
+        );
+
+        // a handle reloaded from the saved state picks up where the first left off
+        mysgm_free(bob);
+        let bob = mysgm_load(path("bob.json").as_ptr(), null(), file_dir.as_ptr());
+        assert!(!bob.is_null(), "{}", last_error());
+        let report: serde_json::Value =
+            serde_json::from_str(&take_string(mysgm_sync(bob))).unwrap();
+        assert_eq!(report["messages"][0]["message"], "hello over ffi");
+        assert_eq!(report["messages"][0]["gid"], gid.to_str().unwrap());
+
+        let (mut ours, mut theirs) = ([0u8; 32], [0u8; 32]);
+        let label = c("test");
+        assert_eq!(
+            mysgm_export_secret(alice, gid.as_ptr(), label.as_ptr(), ours.as_mut_ptr(), 32),
+            0
+        );
+        assert_eq!(
+            mysgm_export_secret(bob, gid.as_ptr(), label.as_ptr(), theirs.as_mut_ptr(), 32),
+            0
+        );
+        assert_eq!(ours, theirs);
+        mysgm_free(alice);
+        mysgm_free(bob);
+    }
+    remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn invalid_arguments_fail_with_an_error() {
+    unsafe {
+        assert!(mysgm_load(null(), null(), null()).is_null());
+        assert_eq!(last_error(), "Invalid argument: state_path");
+        assert_eq!(mysgm_advertise(std::ptr::null_mut(), 3600), -1);
+        assert_eq!(last_error(), "Invalid argument: handle");
+        mysgm_free(std::ptr::null_mut());
+        mysgm_string_free(std::ptr::null_mut());
+    }
+}
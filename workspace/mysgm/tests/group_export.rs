@@ -0,0 +1,39 @@
+mod common;
+
+use common::Harness;
+use mysgm::{MySgmAgent, MySgmError, MySgmState};
+use openmls_rust_crypto::RustCrypto;
+
+#[tokio::test]
+async fn moved_group_keeps_working_at_the_importer() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    // a second agent of alice's identity, but without her groups
+    let state = serde_json::to_vec(harness.agent(0).state()).unwrap();
+    let state: MySgmState = serde_json::from_slice(&state).unwrap();
+    let mut dedicated = MySgmAgent::new(
+        state,
+        RustCrypto::default(),
+        Box::new(harness.adapter.clone()),
+    );
+    let gid = harness.group_of_all("g").await.unwrap();
+    harness
+        .agent(1)
+        .send_message(&gid, b"before")
+        .await
+        .unwrap();
+    harness.agent(0).sync(true).await.unwrap();
+    let export = harness.agent(0).export_group(&gid, true).unwrap();
+    assert!(!harness.agent(0).state().gids().contains(&gid));
+    assert!(matches!(
+        harness.agent(1).import_group(export.clone()),
+        Err(MySgmError::ForeignGroupExport(_))
+    ));
+    assert_eq!(dedicated.import_group(export).unwrap(), gid);
+    assert_eq!(dedicated.state().history(&gid).count(), 1);
+    dedicated.send_message(&gid, b"moved").await.unwrap();
+    assert_eq!(harness.agent(1).sync(true).await.unwrap().messages, 1);
+    harness.agent(1).self_update(&gid).await.unwrap();
+    harness.agent(1).send_message(&gid, b"after").await.unwrap();
+    let report = dedicated.sync(true).await.unwrap();
+    assert_eq!((report.commits, report.messages), (1, 1));
+}
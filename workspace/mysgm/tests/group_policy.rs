@@ -0,0 +1,48 @@
+mod common;
+
+use common::Harness;
+use mysgm::{GroupPolicy, MySgmError};
+
+#[tokio::test]
+async fn adds_beyond_max_members_are_refused() {
+    let mut harness = Harness::new(&["alice", "bob", "carol"]);
+    harness.advertise_all().await.unwrap();
+    let (bob, carol) = (harness.pid(1), harness.pid(2));
+    let alice = harness.agent(0);
+    alice.sync(false).await.unwrap();
+    let gid = alice.create_group("g", true).unwrap();
+    let policy = GroupPolicy {
+        max_members: Some(2),
+        ..Default::default()
+    };
+    alice.set_group_policy(&gid, policy.clone()).await.unwrap();
+    alice.add_to_group(&gid, &[bob]).await.unwrap();
+    let refused = alice.add_to_group(&gid, &[carol]).await;
+    assert!(matches!(refused, Err(MySgmError::GroupFull(_, 2))));
+    assert_eq!(alice.group_info(&gid).unwrap().member_count, 2);
+    harness.agent(1).sync(false).await.unwrap();
+    assert_eq!(harness.agent(1).group_policy(&gid).unwrap(), policy);
+}
+
+#[tokio::test]
+async fn candidates_violating_the_policy_are_skipped() {
+    let mut harness = Harness::new(&["alice", "bob", "carol"]);
+    // bob's key package expires too soon for the policy
+    harness.agent(1).advertise(60).await.unwrap();
+    harness.agent(2).advertise(86400).await.unwrap();
+    let pids = vec![harness.pid(1), harness.pid(2)];
+    let alice = harness.agent(0);
+    alice.sync(false).await.unwrap();
+    let gid = alice.create_group("g", true).unwrap();
+    let policy = GroupPolicy {
+        min_key_package_lifetime: Some(3600),
+        ..Default::default()
+    };
+    alice.set_group_policy(&gid, policy).await.unwrap();
+    let report = alice.add_available_to_group(&gid, &pids).await.unwrap();
+    assert_eq!(report.added, vec![pids[1].clone()]);
+    assert!(matches!(
+        report.skipped.as_slice(),
+        [(pid, MySgmError::PolicyViolation(..))] if *pid == pids[0]
+    ));
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use async_trait::async_trait;
+use common::Harness;
+use mysgm::{DeliveryAdapter, MemoryAdapter, MySgmError};
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+/// A delivery service whose commit slots can be made to look taken.
+#[derive(Debug)]
+struct TakenCommitsAdapter {
+    inner: MemoryAdapter,
+    taken: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl DeliveryAdapter for TakenCommitsAdapter {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, MySgmError> {
+        self.inner.get(key).await
+    }
+    async fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
+        if key.starts_with("cm") && self.taken.load(Ordering::SeqCst) {
+            return Err(MySgmError::KeyExists);
+        }
+        self.inner.put_checked(key, value).await
+    }
+}
+
+#[tokio::test]
+async fn rotated_identity_keeps_group_memberships() {
+    let mut harness = Harness::new(&["alice", "bob", "carol"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    let alice = harness.pid(0);
+    harness.agent(1).set_strict_trust(true);
+    let old_key = harness
+        .agent(0)
+        .state()
+        .signature_key_pair()
+        .public_key_raw()
+        .to_vec();
+    let rotated = harness.agent(0).rotate_identity(86400).await.unwrap();
+    assert_eq!(rotated, vec![gid.clone()]);
+    let new_key = harness
+        .agent(0)
+        .state()
+        .signature_key_pair()
+        .public_key_raw()
+        .to_vec();
+    assert_ne!(old_key, new_key);
+    assert_eq!(harness.pid(0), alice);
+    assert!(
+        harness
+            .agent(0)
+            .state()
+            .previous_signature_key_pairs()
+            .is_empty()
+    );
+    assert_eq!(harness.agent(0).state().retired_signature_keys().len(), 1);
+    // members trust the new key, vouched for by the old one
+    assert_eq!(harness.agent(1).sync(false).await.unwrap().commits, 1);
+    let trust = harness.agent(1).state().trust(&alice).unwrap();
+    assert_eq!(trust.signature_key, new_key);
+    assert!(trust.changed_key.is_none());
+    harness.agent(2).sync(false).await.unwrap();
+    harness.agent(0).send_message(&gid, b"hello").await.unwrap();
+    assert_eq!(harness.agent(1).sync(true).await.unwrap().messages, 1);
+    assert_eq!(harness.agent(2).sync(true).await.unwrap().messages, 1);
+    // new groups add the agent with the key package carrying the new key
+    let bob = harness.agent(1);
+    let other = bob.create_group("h", true).unwrap();
+    bob.add_to_group(&other, &[alice]).await.unwrap();
+    assert_eq!(
+        harness.agent(0).sync(false).await.unwrap().welcomes.len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn groups_not_yet_rotated_keep_the_old_key() {
+    let taken = Arc::new(AtomicBool::new(false));
+    let mut harness = Harness::with_adapter(&["alice", "bob"], |inner| {
+        Box::new(TakenCommitsAdapter {
+            inner,
+            taken: taken.clone(),
+        })
+    });
+    let gid = harness.group_of_all("g").await.unwrap();
+    taken.store(true, Ordering::SeqCst);
+    assert!(
+        harness
+            .agent(0)
+            .rotate_identity(86400)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    taken.store(false, Ordering::SeqCst);
+    assert_eq!(
+        harness
+            .agent(0)
+            .state()
+            .previous_signature_key_pairs()
+            .len(),
+        1
+    );
+
+    // messages and commits are signed with the key the own leaf still carries
+    let alice = harness.pid(0);
+    harness.agent(0).send_message(&gid, b"hello").await.unwrap();
+    let received = harness.agent(1).process_next_message(&gid).await.unwrap();
+    assert_eq!(received, Some((alice, b"hello".to_vec())));
+    harness.agent(0).self_update(&gid).await.unwrap();
+    assert_eq!(harness.agent(1).sync(false).await.unwrap().commits, 1);
+
+    // the next sync commits the new key
+    let report = harness.agent(0).sync(false).await.unwrap();
+    assert_eq!(report.rotated, vec![gid.clone()]);
+    assert_eq!(harness.agent(1).sync(false).await.unwrap().commits, 1);
+    assert_eq!(
+        harness.agent(0).group_epoch(&gid).unwrap(),
+        harness.agent(1).group_epoch(&gid).unwrap()
+    );
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use common::Harness;
+use mysgm::{InvitePolicy, MySgmError};
+
+#[tokio::test]
+async fn confirmed_welcomes_wait_until_accepted() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    let policy = InvitePolicy {
+        confirm: true,
+        auto_accept_from: Vec::new(),
+    };
+    harness.agent(1).state_mut().set_invite_policy(policy);
+    let gid = harness.group_of_all("g").await.unwrap();
+    assert!(harness.agent(1).state().gids().is_empty());
+
+    let invite = harness.agent(1).state().pending_invites()[0].clone();
+    assert_eq!(invite.gid, gid);
+    assert_eq!(invite.inviter, harness.pid(0));
+    // the welcome is not queued again by the next sync
+    harness.agent(1).sync(false).await.unwrap();
+    assert_eq!(harness.agent(1).state().pending_invites().len(), 1);
+
+    assert_eq!(
+        harness.agent(1).accept_invite(invite.id).await.unwrap(),
+        gid
+    );
+    assert!(harness.agent(1).state().pending_invites().is_empty());
+    harness.agent(0).send_message(&gid, b"hello").await.unwrap();
+    let received = harness.agent(1).process_next_message(&gid).await.unwrap();
+    assert_eq!(received, Some((harness.pid(0), b"hello".to_vec())));
+}
+
+#[tokio::test]
+async fn welcomes_from_the_allowlist_are_joined_right_away() {
+    let mut harness = Harness::new(&["alice", "bob", "carol"]);
+    let policy = InvitePolicy {
+        confirm: false,
+        auto_accept_from: vec![harness.pid(0)],
+    };
+    harness
+        .agent(2)
+        .state_mut()
+        .set_invite_policy(policy.clone());
+    harness.agent(1).state_mut().set_invite_policy(policy);
+    let gid = harness.group_of_all("g").await.unwrap();
+    assert_eq!(harness.agent(1).state().gids(), vec![gid.clone()]);
+
+    // a group bob creates waits for carol's confirmation
+    let carol = harness.pid(2);
+    let bob = harness.agent(1);
+    let other = bob.create_group("h", true).unwrap();
+    bob.add_to_group(&other, &[carol]).await.unwrap();
+    harness.agent(2).sync(false).await.unwrap();
+    assert_eq!(harness.agent(2).state().gids(), vec![gid]);
+    let invite = harness.agent(2).state().pending_invites()[0].clone();
+    assert_eq!(invite.gid, other);
+
+    harness.agent(2).decline_invite(invite.id).unwrap();
+    assert!(harness.agent(2).state().pending_invites().is_empty());
+    assert!(matches!(
+        harness.agent(2).accept_invite(invite.id).await,
+        Err(MySgmError::NoPendingInvite(_))
+    ));
+}
@@ -0,0 +1,343 @@
+mod common;
+
+use common::Harness;
+use mysgm::{JoinLimits, MySgmAgent, MySgmError, ScanPrefix};
+
+#[tokio::test]
+async fn members_join_and_exchange_messages() {
+    let mut harness = Harness::new(&["alice", "bob", "carol"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    for i in 0..3 {
+        assert_eq!(harness.agent(i).group_members(&gid).unwrap().len(), 3);
+        assert_eq!(harness.agent(i).group_epoch(&gid).unwrap(), 1);
+    }
+    let keys = harness.adapter.keys().len();
+    harness.agent(1).send_message(&gid, b"hello").await.unwrap();
+    assert_eq!(harness.adapter.keys().len(), keys + 1);
+    let bob = harness.pid(1);
+    for i in [0, 2] {
+        let received = harness.agent(i).process_next_message(&gid).await.unwrap();
+        assert_eq!(received, Some((bob.clone(), b"hello".to_vec())));
+        let received = harness.agent(i).process_next_message(&gid).await.unwrap();
+        assert_eq!(received, None);
+    }
+}
+
+#[tokio::test]
+async fn commits_keep_members_in_the_same_epoch() {
+    let mut harness = Harness::new(&["alice", "bob", "carol"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    harness.agent(2).self_update(&gid).await.unwrap();
+    harness.sync_all(false).await.unwrap();
+    let secret = harness.agent(0).export_secret(&gid, "test", 32).unwrap();
+    for i in 0..3 {
+        assert_eq!(harness.agent(i).group_epoch(&gid).unwrap(), 2);
+        let exported = harness.agent(i).export_secret(&gid, "test", 32).unwrap();
+        assert_eq!(exported, secret);
+    }
+}
+
+#[tokio::test]
+async fn messages_of_an_epoch_are_received_after_its_commit_is_merged() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    harness
+        .agent(0)
+        .send_message(&gid, b"before")
+        .await
+        .unwrap();
+    harness.agent(0).self_update(&gid).await.unwrap();
+    harness.agent(0).send_message(&gid, b"after").await.unwrap();
+
+    let report = harness.agent(1).sync(false).await.unwrap();
+    assert_eq!(report.commits, 1);
+    assert_eq!(harness.agent(1).group_epoch(&gid).unwrap(), 2);
+    let alice = harness.pid(0);
+    for message in [&b"before"[..], b"after"] {
+        let received = harness.agent(1).process_next_message(&gid).await.unwrap();
+        assert_eq!(received, Some((alice.clone(), message.to_vec())));
+    }
+    assert_eq!(
+        harness.agent(1).process_next_message(&gid).await.unwrap(),
+        None
+    );
+}
+
+#[tokio::test]
+async fn removed_members_leave_the_group() {
+    let mut harness = Harness::new(&["alice", "bob", "carol"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    let carol = harness.pid(2);
+    harness
+        .agent(0)
+        .remove_from_group(&gid, &[carol])
+        .await
+        .unwrap();
+    harness.sync_all(false).await.unwrap();
+    assert_eq!(harness.agent(1).group_members(&gid).unwrap().len(), 2);
+    assert!(!harness.agent(2).state().gids().contains(&gid));
+    harness.agent(0).send_message(&gid, b"bye").await.unwrap();
+    let received = harness.agent(1).process_next_message(&gid).await.unwrap();
+    assert_eq!(received.map(|(_, message)| message), Some(b"bye".to_vec()));
+}
+
+#[tokio::test]
+async fn leaving_members_are_removed_by_the_first_admin() {
+    let mut harness = Harness::new(&["alice", "bob", "carol"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    harness.agent(2).leave_group(&gid).await.unwrap();
+    // bob is no admin and leaves the removal to alice instead of racing for the commit slot
+    assert_eq!(harness.agent(1).sync(false).await.unwrap().commits, 0);
+    assert_eq!(harness.agent(1).group_members(&gid).unwrap().len(), 3);
+    assert_eq!(harness.agent(0).sync(false).await.unwrap().commits, 1);
+    assert_eq!(harness.agent(1).sync(false).await.unwrap().commits, 1);
+    for i in 0..2 {
+        assert_eq!(harness.agent(i).group_members(&gid).unwrap().len(), 2);
+    }
+    // carol keeps the group until she sees the commit removing her
+    assert!(harness.agent(2).group_info(&gid).unwrap().leaving);
+    harness.agent(2).sync(false).await.unwrap();
+    assert!(!harness.agent(2).state().gids().contains(&gid));
+    assert!(harness.agent(2).state().left_gids().contains(&gid));
+}
+
+#[tokio::test]
+async fn leaving_members_propose_again_after_an_unrelated_commit() {
+    let mut harness = Harness::new(&["alice", "bob", "carol"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    harness.agent(2).leave_group(&gid).await.unwrap();
+    // alice rotates her keys before seeing the proposal, which voids it
+    harness.agent(0).self_update(&gid).await.unwrap();
+    harness.sync_all(false).await.unwrap();
+    assert!(harness.agent(2).state().gids().contains(&gid));
+    harness.sync_all(false).await.unwrap();
+    harness.sync_all(false).await.unwrap();
+    assert!(!harness.agent(2).state().gids().contains(&gid));
+    assert_eq!(harness.agent(1).group_members(&gid).unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn key_package_log_records_slots_and_validity() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    harness.group_of_all("g").await.unwrap();
+    let bob = harness.pid(1);
+    let log = harness.agent(0).state().key_package_log().to_vec();
+    let entry = log.iter().find(|entry| entry.pid == bob).unwrap();
+    assert!(entry.slot.is_some());
+    assert!(entry.last_resort);
+    assert!(!entry.consumed);
+    assert!(entry.not_before < entry.not_after);
+}
+
+#[tokio::test]
+async fn adds_skip_pids_without_key_packages() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    harness.advertise_all().await.unwrap();
+    let bob = harness.pid(1);
+    let alice = harness.agent(0);
+    alice.sync(false).await.unwrap();
+    let gid = alice.create_group("g", true).unwrap();
+    let pids = [bob.clone(), "nobody".to_string()];
+    let report = alice.add_available_to_group(&gid, &pids).await.unwrap();
+    assert_eq!(report.added, vec![bob]);
+    assert_eq!(report.skipped.len(), 1);
+    assert!(report.failed.is_empty());
+    assert_eq!(alice.group_members(&gid).unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn members_join_under_their_own_join_limits() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    let limits = JoinLimits {
+        max_past_epochs: Some(2),
+        out_of_order_tolerance: Some(4),
+        maximum_forward_distance: Some(100),
+    };
+    harness.agent(1).state_mut().set_join_limits(limits);
+    let gid = harness.group_of_all("g").await.unwrap();
+    // the limits were applied to the group bob joined, not the one alice created
+    let bob_retention = harness.agent(1).epoch_retention(&gid).unwrap();
+    assert_eq!(bob_retention.max_past_epochs, 2);
+    let alice_retention = harness.agent(0).epoch_retention(&gid).unwrap();
+    assert_eq!(alice_retention.max_past_epochs, 0);
+    harness.agent(0).send_message(&gid, b"hello").await.unwrap();
+    let received = harness.agent(1).process_next_message(&gid).await.unwrap();
+    assert_eq!(received, Some((harness.pid(0), b"hello".to_vec())));
+}
+
+#[tokio::test]
+async fn members_record_who_invited_them() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    let alice = harness.pid(0);
+    let bob = harness.pid(1);
+    assert!(harness.agent(0).state().join_record(&gid).is_none());
+
+    let record = harness.agent(1).group_info(&gid).unwrap().joined.unwrap();
+    assert_eq!(record.inviter, alice);
+    assert_eq!(record.epoch, 1);
+    assert!(record.welcome_slot.unwrap().starts_with("wm"));
+    let join = harness.agent(1).state().audit_log(&gid)[0].clone();
+    assert_eq!(join.kind, "join");
+    assert_eq!(join.actor, Some(alice));
+    assert_eq!(join.subject, Some(bob));
+}
+
+#[tokio::test]
+async fn archived_groups_catch_up_when_unarchived() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    harness.agent(1).archive_group(&gid).unwrap();
+    harness.agent(0).self_update(&gid).await.unwrap();
+    harness.agent(0).send_message(&gid, b"hello").await.unwrap();
+
+    let report = harness.agent(1).sync(true).await.unwrap();
+    assert_eq!((report.commits, report.messages), (0, 0));
+    assert_eq!(harness.agent(1).group_epoch(&gid).unwrap(), 1);
+    assert!(harness.agent(1).watched_keys().unwrap().len() < 3);
+
+    let report = harness.agent(1).unarchive_group(&gid).await.unwrap();
+    assert_eq!((report.commits, report.messages), (1, 1));
+    assert_eq!(harness.agent(1).group_epoch(&gid).unwrap(), 2);
+    assert!(harness.agent(1).unarchive_group(&gid).await.is_err());
+}
+
+#[tokio::test]
+async fn past_epoch_secrets_are_bounded_and_purged() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    let alice = harness.agent(0);
+    assert_eq!(alice.set_max_past_epochs(&gid, 3).unwrap(), 0);
+    for _ in 0..4 {
+        alice.self_update(&gid).await.unwrap();
+    }
+    let retention = alice.epoch_retention(&gid).unwrap();
+    assert_eq!(retention.max_past_epochs, 3);
+    assert_eq!(retention.past_epochs, vec![2, 3, 4]);
+
+    assert_eq!(alice.purge_epochs(&gid, 1).unwrap(), 2);
+    assert_eq!(alice.epoch_retention(&gid).unwrap().past_epochs, vec![4]);
+    // the maximum still holds for later epochs
+    alice.self_update(&gid).await.unwrap();
+    assert_eq!(
+        alice.group_info(&gid).unwrap().retention.past_epochs,
+        vec![4, 5]
+    );
+    // joiners retain none by default
+    harness.agent(1).sync(false).await.unwrap();
+    assert!(
+        harness
+            .agent(1)
+            .epoch_retention(&gid)
+            .unwrap()
+            .past_epochs
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn reset_cursors_rescan_without_duplicates() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    harness.agent(0).send_message(&gid, b"hello").await.unwrap();
+    harness.agent(1).sync(true).await.unwrap();
+    let position = |agent: &MySgmAgent, prefix: &ScanPrefix| {
+        let cursors = agent.cursors().unwrap();
+        cursors
+            .into_iter()
+            .find(|c| &c.prefix == prefix)
+            .unwrap()
+            .position
+    };
+    let messages: ScanPrefix = format!("msg_{gid}").parse().unwrap();
+    assert_eq!(messages, ScanPrefix::Messages(gid.clone()));
+    assert_eq!(position(harness.agent(1), &messages), 1);
+
+    let bob = harness.agent(1);
+    bob.reset_cursor(&messages, 0).unwrap();
+    bob.reset_cursor(&ScanPrefix::KeyPackages, 0).unwrap();
+    assert_eq!(position(bob, &messages), 0);
+    let report = bob.sync(true).await.unwrap();
+    assert_eq!(report.messages, 0);
+    assert_eq!(bob.state().history(&gid).count(), 1);
+    assert_eq!(position(bob, &messages), 1);
+    assert_eq!(position(bob, &ScanPrefix::KeyPackages), 2);
+
+    assert!(matches!(
+        bob.reset_cursor(&ScanPrefix::Commits(gid), 0),
+        Err(MySgmError::CursorNotResettable(_))
+    ));
+    assert!("am".parse::<ScanPrefix>().is_err());
+}
+
+#[tokio::test]
+async fn remote_slots_show_what_a_sync_would_process() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    harness.agent(0).send_message(&gid, b"hello").await.unwrap();
+    let messages = ScanPrefix::Messages(gid.clone());
+
+    let slots = harness.agent(1).list_remote(&messages, 2).await.unwrap();
+    assert!(slots[0].size.is_some() && !slots[0].processed);
+    assert_eq!(slots[1].size, None);
+    harness.agent(1).sync(true).await.unwrap();
+    let slots = harness.agent(1).list_remote(&messages, 2).await.unwrap();
+    assert!(slots[0].processed && !slots[1].processed);
+
+    let key_packages = harness
+        .agent(1)
+        .list_remote(&ScanPrefix::KeyPackages, 3)
+        .await
+        .unwrap();
+    let found: Vec<bool> = key_packages.iter().map(|s| s.size.is_some()).collect();
+    assert_eq!(found, [true, true, false]);
+    let commits = ScanPrefix::Commits(gid);
+    let slots = harness.agent(1).list_remote(&commits, 2).await.unwrap();
+    assert!(slots[0].processed && !slots[1].processed);
+}
+
+#[tokio::test]
+async fn kept_groups_follow_changes_to_the_store() {
+    let mut harness = Harness::new(&["alice", "bob", "carol"]);
+    harness.advertise_all().await.unwrap();
+    let (bob, carol) = (harness.pid(1), harness.pid(2));
+    let alice = harness.agent(0);
+    alice.sync(false).await.unwrap();
+    let gid = alice.create_group("g", true).unwrap();
+    alice.add_to_group(&gid, &[bob]).await.unwrap();
+    // loaded again, then kept for the loads after
+    for _ in 0..3 {
+        assert_eq!(alice.group_members(&gid).unwrap().len(), 2);
+    }
+    let secret = alice.export_secret(&gid, "label", 32).unwrap();
+    assert_eq!(alice.export_secret(&gid, "label", 32).unwrap(), secret);
+
+    alice.add_to_group(&gid, &[carol]).await.unwrap();
+    assert_eq!(alice.group_members(&gid).unwrap().len(), 3);
+    harness.agent(1).sync(false).await.unwrap();
+    harness.agent(1).self_update(&gid).await.unwrap();
+    let alice = harness.agent(0);
+    alice.sync(false).await.unwrap();
+    assert_eq!(alice.group_epoch(&gid).unwrap(), 3);
+    assert_ne!(alice.export_secret(&gid, "label", 32).unwrap(), secret);
+}
+
+#[tokio::test]
+async fn unchanged_groups_are_loaded_from_the_cache() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    let alice = harness.agent(0);
+    alice.group_epoch(&gid).unwrap();
+    assert_eq!(alice.cached_groups(), [gid.clone()]);
+    // read without changing the store, so every load after the first reuses the cached group
+    alice.group_info(&gid).unwrap();
+    alice.epoch_authenticator(&gid).unwrap();
+    alice.watched_keys().unwrap();
+    assert_eq!(alice.cached_groups(), [gid.clone()]);
+    // changed through the cached group, which is kept again
+    alice.send_message(&gid, b"hello").await.unwrap();
+    assert_eq!(alice.cached_groups(), [gid.clone()]);
+    // changed behind the group's back
+    alice.purge_epochs(&gid, 0).unwrap();
+    assert!(alice.cached_groups().is_empty());
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use common::Harness;
+use mysgm::AgentObserver;
+use std::{
+    slice::from_ref,
+    sync::{Arc, Mutex},
+};
+
+/// Records the events it is told about.
+#[derive(Debug, Default)]
+struct RecordingObserver {
+    events: Arc<Mutex<Vec<String>>>,
+}
+
+impl AgentObserver for RecordingObserver {
+    fn on_welcome(&self, gid: &str) {
+        self.events.lock().unwrap().push(format!("welcome {gid}"));
+    }
+    fn on_commit_merged(&self, gid: &str, epoch: u64) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("commit {gid} {epoch}"));
+    }
+    fn on_member_added(&self, gid: &str, pid: &str) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("added {gid} {pid}"));
+    }
+    fn on_message(&self, gid: &str, sender: &str, message: &[u8]) {
+        let message = String::from_utf8_lossy(message);
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("message {gid} {sender} {message}"));
+    }
+}
+
+#[tokio::test]
+async fn observers_see_welcomes_commits_and_messages() {
+    let mut harness = Harness::new(&["alice", "bob", "carol"]);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    harness.agent(1).add_observer(Box::new(RecordingObserver {
+        events: events.clone(),
+    }));
+    harness.advertise_all().await.unwrap();
+    let (alice, bob, carol) = (harness.pid(0), harness.pid(1), harness.pid(2));
+    let creator = harness.agent(0);
+    creator.sync(false).await.unwrap();
+    let gid = creator.create_group("g", true).unwrap();
+    creator.add_to_group(&gid, from_ref(&bob)).await.unwrap();
+    harness.agent(1).sync(false).await.unwrap();
+    let creator = harness.agent(0);
+    creator.add_to_group(&gid, from_ref(&carol)).await.unwrap();
+    creator.send_message(&gid, b"hi").await.unwrap();
+    harness.agent(1).sync(true).await.unwrap();
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            format!("welcome {gid}"),
+            format!("commit {gid} 2"),
+            format!("added {gid} {carol}"),
+            format!("message {gid} {alice} hi"),
+        ]
+    );
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use async_trait::async_trait;
+use common::Harness;
+use mysgm::{DeliveryAdapter, MemoryAdapter, MySgmError};
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+/// A delivery service that can be taken down, failing puts as an unreachable proxy would.
+#[derive(Debug)]
+struct FlakyAdapter {
+    inner: MemoryAdapter,
+    down: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl DeliveryAdapter for FlakyAdapter {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, MySgmError> {
+        self.inner.get(key).await
+    }
+    async fn put_checked(&self, key: &str, value: &[u8]) -> Result<(), MySgmError> {
+        if self.down.load(Ordering::SeqCst) {
+            // nothing listens on the port, so connecting fails like it does for a proxy that
+            // is down
+            return Err(reqwest::get("http://127.0.0.1:1").await.unwrap_err().into());
+        }
+        self.inner.put_checked(key, value).await
+    }
+}
+
+#[tokio::test]
+async fn publications_queued_while_down_are_flushed() {
+    let down = Arc::new(AtomicBool::new(false));
+    let mut harness = Harness::with_adapter(&["alice", "bob"], |inner| {
+        Box::new(FlakyAdapter {
+            inner,
+            down: down.clone(),
+        })
+    });
+    let gid = harness.group_of_all("g").await.unwrap();
+    down.store(true, Ordering::SeqCst);
+    harness.agent(0).send_message(&gid, b"hello").await.unwrap();
+    harness.agent(1).advertise(86400).await.unwrap();
+    assert_eq!(harness.agent(0).state().outbox().len(), 1);
+    assert_eq!(harness.agent(0).flush().await, 0);
+    assert_eq!(harness.agent(0).state().outbox().len(), 1);
+    down.store(false, Ordering::SeqCst);
+    assert_eq!(harness.agent(0).flush().await, 1);
+    assert!(harness.agent(0).state().outbox().is_empty());
+    assert_eq!(harness.agent(1).sync(true).await.unwrap().messages, 1);
+    let keys_before = harness.adapter.keys().len();
+    assert_eq!(harness.agent(1).flush().await, 1);
+    assert_eq!(harness.adapter.keys().len(), keys_before + 1);
+}
+
+#[tokio::test]
+async fn adds_that_fail_to_publish_are_completed_later() {
+    let down = Arc::new(AtomicBool::new(false));
+    let mut harness = Harness::with_adapter(&["alice", "bob"], |inner| {
+        Box::new(FlakyAdapter {
+            inner,
+            down: down.clone(),
+        })
+    });
+    harness.advertise_all().await.unwrap();
+    harness.agent(0).sync(false).await.unwrap();
+    let gid = harness.agent(0).create_group("g", false).unwrap();
+    let bob = vec![harness.pid(1)];
+    down.store(true, Ordering::SeqCst);
+    let added = harness.agent(0).add_to_group(&gid, &bob).await;
+    assert!(added.unwrap_err().is_transient());
+    assert!(harness.agent(0).state().pending_add(&gid).is_some());
+    assert!(matches!(
+        harness.agent(0).add_to_group(&gid, &bob).await,
+        Err(MySgmError::AddPending(_))
+    ));
+    down.store(false, Ordering::SeqCst);
+    let report = harness.agent(0).sync(false).await.unwrap();
+    assert_eq!(report.adds, vec![gid.clone()]);
+    assert!(harness.agent(0).state().pending_add(&gid).is_none());
+    let report = harness.agent(1).sync(false).await.unwrap();
+    assert_eq!(report.welcomes, vec![gid.clone()]);
+    assert_eq!(harness.agent(0).group_epoch(&gid).unwrap(), 1);
+    assert_eq!(harness.agent(1).group_epoch(&gid).unwrap(), 1);
+}
@@ -0,0 +1,74 @@
+mod common;
+
+use common::Harness;
+use mysgm::{DeliveryAdapter, PaddingPolicy};
+
+/// Sends the message from alice and returns the length of the record it was published as.
+async fn published_len(harness: &mut Harness, gid: &str, message: &[u8]) -> usize {
+    let keys = harness.adapter.keys();
+    harness.agent(0).send_message(gid, message).await.unwrap();
+    let key = harness
+        .adapter
+        .keys()
+        .into_iter()
+        .find(|key| !keys.contains(key))
+        .unwrap();
+    harness.adapter.get(&key).await.unwrap().unwrap().len()
+}
+
+#[tokio::test]
+async fn padded_messages_share_a_size_and_arrive_unpadded() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    let short = published_len(&mut harness, &gid, b"hi").await;
+    let long = published_len(&mut harness, &gid, &[7; 100]).await;
+    assert_ne!(short, long);
+
+    harness
+        .agent(0)
+        .set_padding_policy(&gid, Some(PaddingPolicy::default()))
+        .unwrap();
+    let short = published_len(&mut harness, &gid, b"hi").await;
+    let long = published_len(&mut harness, &gid, &[7; 100]).await;
+    assert_eq!(short, long);
+    let larger = published_len(&mut harness, &gid, &[7; 300]).await;
+    assert!(larger > long);
+
+    let alice = harness.pid(0);
+    for expected in [b"hi".to_vec(), vec![7; 100], b"hi".to_vec(), vec![7; 100]] {
+        let received = harness.agent(1).process_next_message(&gid).await.unwrap();
+        assert_eq!(received, Some((alice.clone(), expected)));
+    }
+    let received = harness.agent(1).process_next_message(&gid).await.unwrap();
+    assert_eq!(received, Some((alice, vec![7; 300])));
+}
+
+#[test]
+fn messages_are_padded_to_buckets() {
+    let policy = PaddingPolicy::new([1024, 64, 256]).unwrap();
+    assert_eq!(policy.buckets(), &[64, 256, 1024]);
+    assert_eq!(policy.padded_len(0), 64);
+    assert_eq!(policy.padded_len(100), 256);
+    assert_eq!(policy.padded_len(2000), 2048);
+    assert!(PaddingPolicy::new([8]).is_err());
+}
+
+#[tokio::test]
+async fn padding_is_local_to_the_group() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    harness
+        .agent(0)
+        .set_padding_policy(&gid, Some(PaddingPolicy::default()))
+        .unwrap();
+    assert!(harness.agent(0).group_info(&gid).unwrap().padding.is_some());
+    assert!(harness.agent(1).group_info(&gid).unwrap().padding.is_none());
+    assert!(
+        harness
+            .agent(0)
+            .set_padding_policy("missing", Some(PaddingPolicy::default()))
+            .is_err()
+    );
+    harness.agent(0).set_padding_policy(&gid, None).unwrap();
+    assert!(harness.agent(0).group_info(&gid).unwrap().padding.is_none());
+}
@@ -0,0 +1,139 @@
+use mysgm::{
+    HistoryEntry, MySgmState, StateStorage, load_state, persistence::MAX_JOURNAL_ENTRIES,
+    save_state, save_state_incrementally, stored_version,
+};
+use openmls_rust_crypto::RustCrypto;
+use openmls_traits::types::Ciphersuite;
+use std::{
+    env::temp_dir,
+    fs::{create_dir_all, exists, read, remove_dir_all},
+    process::id as process_id,
+};
+
+/// Leading bytes of a zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[test]
+fn compressed_state_round_trips_with_and_without_passphrase() {
+    let dir = temp_dir().join(format!("mysgm-persistence-{}", process_id()));
+    create_dir_all(&dir).unwrap();
+    let state = MySgmState::generate(
+        "alice",
+        Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519,
+        &RustCrypto::default(),
+    )
+    .unwrap();
+
+    for (name, passphrase) in [
+        ("plain.json.zst", None),
+        ("sealed.json.zst", Some("secret")),
+    ] {
+        let path = dir.join(name).to_string_lossy().to_string();
+        let storage = StateStorage::from_path(&path);
+        assert_eq!(storage, StateStorage::CompressedJson);
+        save_state(&path, storage, &state, passphrase).unwrap();
+        save_state(&path, storage, &state, passphrase).unwrap();
+        assert_eq!(read(&path).unwrap()[..4], ZSTD_MAGIC);
+        assert_eq!(stored_version(&path, storage), Some(state.version()));
+
+        let loaded = load_state(&path, storage, passphrase).unwrap();
+        assert_eq!(loaded.my_pid(), state.my_pid());
+        assert_eq!(loaded.version(), state.version());
+        // the backup kept by the second save is compressed too
+        let backup = load_state(&format!("{path}.bak"), storage, passphrase).unwrap();
+        assert_eq!(backup.version(), state.version() - 1);
+    }
+    remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn incremental_saves_are_journaled_replayed_and_compacted() {
+    let dir = temp_dir().join(format!("mysgm-journal-{}", process_id()));
+    create_dir_all(&dir).unwrap();
+    let state = MySgmState::generate(
+        "alice",
+        Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519,
+        &RustCrypto::default(),
+    )
+    .unwrap();
+
+    for (name, passphrase) in [("plain.json", None), ("sealed.json", Some("secret"))] {
+        let path = dir.join(name).to_string_lossy().to_string();
+        let journal = format!("{path}.journal");
+        let storage = StateStorage::from_path(&path);
+        save_state(&path, storage, &state, passphrase).unwrap();
+        let snapshot = read(&path).unwrap();
+
+        let mut loaded = load_state(&path, storage, passphrase).unwrap();
+        loaded.set_alias("bob", "b");
+        loaded.set_key_package_counter(7);
+        save_state_incrementally(&path, storage, &loaded, passphrase).unwrap();
+        assert_eq!(read(&path).unwrap(), snapshot);
+        assert_eq!(stored_version(&path, storage), Some(loaded.version()));
+        // saving again without changes writes nothing
+        let entry = read(&journal).unwrap();
+        save_state_incrementally(&path, storage, &loaded, passphrase).unwrap();
+        assert_eq!(read(&journal).unwrap(), entry);
+
+        let mut replayed = load_state(&path, storage, passphrase).unwrap();
+        assert_eq!(replayed.version(), loaded.version());
+        assert_eq!(replayed.resolve_pid("b"), "bob");
+        assert_eq!(replayed.key_package_counter(), 7);
+
+        for counter in 0..MAX_JOURNAL_ENTRIES as u64 {
+            replayed.set_key_package_counter(counter);
+            save_state_incrementally(&path, storage, &replayed, passphrase).unwrap();
+        }
+        // some save wrote the state in full and dropped the journal so far
+        assert_ne!(read(&path).unwrap(), snapshot);
+        let entries = match exists(&journal).unwrap() {
+            true => read(&journal).unwrap().split(|byte| *byte == b'\n').count() - 1,
+            false => 0,
+        };
+        assert!(entries < MAX_JOURNAL_ENTRIES);
+        let compacted = load_state(&path, storage, passphrase).unwrap();
+        assert_eq!(compacted.version(), replayed.version());
+        assert_eq!(
+            compacted.key_package_counter(),
+            MAX_JOURNAL_ENTRIES as u64 - 1
+        );
+    }
+    remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn sqlite_history_keeps_sequence_numbers_across_trimming() {
+    let dir = temp_dir().join(format!("mysgm-sqlite-{}", process_id()));
+    create_dir_all(&dir).unwrap();
+    let path = dir.join("state.db").to_string_lossy().to_string();
+    let storage = StateStorage::from_path(&path);
+    let mut state = MySgmState::generate(
+        "alice",
+        Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519,
+        &RustCrypto::default(),
+    )
+    .unwrap();
+    state.set_history_limit(2);
+    let entry = |message: &[u8]| HistoryEntry {
+        seq: 0,
+        sender: "bob".to_string(),
+        epoch: 1,
+        timestamp: 0,
+        message: message.to_vec(),
+    };
+    state.append_history("g", entry(b"one"));
+    state.append_history("g", entry(b"two"));
+    save_state(&path, storage, &state, None).unwrap();
+
+    let mut loaded = load_state(&path, storage, None).unwrap();
+    // dropping the oldest entry leaves the sequence numbers of the others as they were
+    loaded.append_history("g", entry(b"three"));
+    save_state(&path, storage, &loaded, None).unwrap();
+    let reloaded = load_state(&path, storage, None).unwrap();
+    let history: Vec<(u64, Vec<u8>)> = reloaded
+        .history("g")
+        .map(|entry| (entry.seq, entry.message.clone()))
+        .collect();
+    assert_eq!(history, [(1, b"two".to_vec()), (2, b"three".to_vec())]);
+    remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,46 @@
+mod common;
+
+use common::Harness;
+use mysgm::{DeliveryAdapter, MySgmError, RecordKind, ScanPrefix, agent::welcome_message_key};
+
+#[tokio::test]
+async fn malformed_welcomes_are_quarantined_and_skipped() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    harness.advertise_all().await.unwrap();
+    let bob = harness.agent(1);
+    let kp_ref = bob.state().published_key_packages()[0].clone();
+    let key = welcome_message_key(bob.state().namespace(), &kp_ref, 0);
+    harness.adapter.put_checked(&key, b"garbage").await.unwrap();
+
+    // alice's welcome goes to the next free slot
+    let bob_pid = harness.pid(1);
+    let alice = harness.agent(0);
+    alice.sync(false).await.unwrap();
+    let gid = alice.create_group("g", true).unwrap();
+    alice.add_to_group(&gid, &[bob_pid]).await.unwrap();
+    let report = harness.agent(1).sync(false).await.unwrap();
+    assert_eq!(report.welcomes, vec![gid]);
+    assert_eq!(report.errors.len(), 1);
+    let records = harness.agent(1).state().quarantined_records().to_vec();
+    assert_eq!(records.len(), 1);
+    assert_eq!(
+        (&records[0].key, &records[0].kind),
+        (&key, &RecordKind::Welcome)
+    );
+
+    // a rescan skips the quarantined record instead of failing on it again
+    let bob = harness.agent(1);
+    bob.reset_cursor(&ScanPrefix::Welcomes, 0).unwrap();
+    assert!(bob.sync(false).await.unwrap().errors.is_empty());
+
+    let report = bob.retry_quarantined(&key).await.unwrap();
+    assert!(matches!(
+        report.errors[0].1,
+        MySgmError::MalformedPayload(..)
+    ));
+    assert_eq!(bob.state().quarantined_records().len(), 1);
+    assert!(matches!(
+        bob.retry_quarantined("wm_unknown").await,
+        Err(MySgmError::NotQuarantined(_))
+    ));
+}
@@ -0,0 +1,29 @@
+mod common;
+
+use common::Harness;
+use hex::encode as hex_encode;
+use mysgm::redact::set_log_secrets;
+
+// one test, since whether secrets are logged is set for the whole process
+#[tokio::test]
+async fn debug_output_includes_secrets_only_when_enabled() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    let export = harness.agent(0).export_group(&gid, false).unwrap();
+    let state = harness.agent(0).state();
+    let private = hex_encode(state.signature_key_pair().private_key_raw());
+
+    let redacted = format!("{state:?}");
+    assert!(!redacted.contains(&private));
+    assert!(redacted.contains("bytes redacted"));
+    assert!(redacted.contains("values redacted"));
+    assert!(format!("{export:?}").contains("values redacted"));
+
+    set_log_secrets(true);
+    let logged = format!("{state:?}");
+    let logged_export = format!("{export:?}");
+    set_log_secrets(false);
+    assert!(logged.contains(&private));
+    assert!(!logged.contains("redacted"));
+    assert!(!logged_export.contains("redacted"));
+}
@@ -0,0 +1,29 @@
+mod common;
+
+use common::Harness;
+use mysgm::secret_sink::write_secret_file;
+use std::{env::temp_dir, fs::read_to_string, process::id as process_id};
+
+#[tokio::test]
+async fn secrets_are_written_to_new_private_files() {
+    let mut harness = Harness::new(&["alice", "bob"]);
+    let gid = harness.group_of_all("g").await.unwrap();
+    let secret = harness.agent(0).export_secret(&gid, "test", 32).unwrap();
+    let path = temp_dir().join(format!("mysgm-secret-{}", process_id()));
+    let path = path.to_str().unwrap();
+
+    assert_eq!(write_secret_file(path, &secret).unwrap(), path);
+    assert_eq!(
+        read_to_string(path).unwrap(),
+        format!("{}\n", hex::encode(&secret))
+    );
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    // never clobbers a file whose permissions may be wider
+    assert!(write_secret_file(path, &secret).is_err());
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,50 @@
+use mysgm::{MySgmError, keys::SignatureKeyPair, token::Pkcs11Target};
+use openmls_rust_crypto::RustCrypto;
+use openmls_traits::{crypto::OpenMlsCrypto, types::SignatureScheme};
+
+#[test]
+fn software_key_pairs_sign_and_keep_no_token() {
+    let crypto = RustCrypto::default();
+    let key_pair = SignatureKeyPair::from_crypto(&crypto, SignatureScheme::ED25519).unwrap();
+    assert!(key_pair.token().is_none());
+    assert!(!serde_json::to_string(&key_pair).unwrap().contains("token"));
+    let signature = key_pair.sign(&crypto, b"payload").unwrap();
+    crypto
+        .verify_signature(
+            SignatureScheme::ED25519,
+            b"payload",
+            key_pair.public_key_raw(),
+            &signature,
+        )
+        .unwrap();
+}
+
+#[test]
+fn token_key_pairs_hold_only_a_reference() {
+    let json = r#"{
+        "private": [],
+        "public": [1, 2, 3],
+        "signature_scheme": "ED25519",
+        "token": {"module": "/nonexistent/libpkcs11.so", "slot": 0, "label": "mysgm-00"}
+    }"#;
+    let key_pair: SignatureKeyPair = serde_json::from_str(json).unwrap();
+    assert!(key_pair.private_key_raw().is_empty());
+    assert_eq!(key_pair.token().unwrap().label, "mysgm-00");
+    // signing goes to the token, which cannot be loaded here
+    assert!(key_pair.sign(&RustCrypto::default(), b"payload").is_err());
+}
+
+#[test]
+fn missing_modules_fail_key_generation() {
+    let target = Pkcs11Target {
+        module: "/nonexistent/libpkcs11.so",
+        slot: None,
+        pin: None,
+    };
+    let result =
+        SignatureKeyPair::from_token(&target, &RustCrypto::default(), SignatureScheme::ED25519);
+    assert!(matches!(result, Err(MySgmError::Pkcs11(_))));
+    let result =
+        SignatureKeyPair::from_token(&target, &RustCrypto::default(), SignatureScheme::ED448);
+    assert!(matches!(result, Err(MySgmError::UnsupportedTokenScheme(_))));
+}